@@ -0,0 +1,97 @@
+//! Packet parsing and serialization, and a read transfer driven without a socket: `cargo bench`
+
+#![allow(clippy::needless_return)]
+
+use std::hint::black_box;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use tokio_tftpserver::tftp::tftpprotocol::{self, Action, Command, ServerOptions, Strictness};
+
+/// Largest block size, the payload of a full-size DATA
+const MAX_BLKSIZE: usize = 65464;
+
+/// Read by the simulated transfer
+const TRANSFER_SIZE: usize = 1024 * 1024;
+
+fn rrq(filename: &str, options: &[(&str, &str)]) -> Bytes {
+    let options = options.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+    let request = Command::RRQ { filename: filename.as_bytes().to_vec(), mode: "octet".to_string(), options };
+    return tftpprotocol::get_buffer_for_command(request).unwrap();
+}
+
+fn parse(c: &mut Criterion) {
+    let rrq = rrq("pxelinux.cfg/01-aa-bb-cc-dd-ee-ff", &[("blksize", "1468"), ("tsize", "0"), ("timeout", "3"), ("windowsize", "4")]);
+    c.bench_function("parse RRQ with options", |b| b.iter(|| tftpprotocol::parse_packet(black_box(&rrq), Strictness::Lenient)));
+
+    let data = Bytes::from([&[0, 3, 0, 1][..], &[0x5a; MAX_BLKSIZE]].concat());
+    let mut group = c.benchmark_group("parse full-size DATA");
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    // The payload is copied from a received buffer, or kept as a slice of the packet
+    group.bench_function("copied", |b| b.iter(|| tftpprotocol::parse_packet(black_box(&data), Strictness::Lenient)));
+    group.bench_function("sliced", |b| b.iter(|| tftpprotocol::process_packet(black_box(&data), Strictness::Lenient)));
+    group.finish();
+}
+
+fn serialize(c: &mut Criterion) {
+    let commands = [
+        // The packet of a DATA sent is built with its payload, serializing it is a copy
+        ("DATA", Command::DATA { blocknum: 1, data: Bytes::from([&[0, 3, 0, 1][..], &[0x5a; 1468]].concat()) }),
+        ("ACK", Command::ACK { blocknum: 1 }),
+        ("ERROR", Command::ERROR { errorcode: 1, errmsg: "File not found".to_string() }),
+        ("OACK", Command::OACK { options: vec![("blksize".to_string(), "1468".to_string()), ("tsize".to_string(), "1048576".to_string())] }),
+    ];
+    let mut group = c.benchmark_group("serialize");
+    let mut buf = vec![0; MAX_BLKSIZE + 4];
+    for (name, command) in &commands {
+        group.bench_function(format!("{} in a buffer", name), |b| b.iter(|| tftpprotocol::write_command(black_box(command), &mut buf).unwrap()));
+        group.bench_function(format!("{} allocated", name), |b| {
+            b.iter_batched(|| command.clone(), |command| tftpprotocol::get_buffer_for_command(command).unwrap(), BatchSize::SmallInput)
+        });
+    }
+    group.finish();
+}
+
+/// Whole read of content with the protocol state machine, from the request to the last ACK
+fn read_transfer(request: &[u8], content: &Arc<Vec<u8>>) -> u64 {
+    let peer: SocketAddr = "127.0.0.1:2000".parse().unwrap();
+    let mut context = tftpprotocol::recv_request(request, request.len(), peer, &ServerOptions::default()).unwrap();
+    // Served from memory, like a virtual file
+    context.content = Some(content.clone());
+    let blksize = context.options.blksize as usize;
+    let mut bytes = 0;
+    if let Some(Command::OACK { .. }) = tftpprotocol::get_reply_command(&context) {
+        assert!(matches!(tftpprotocol::recv(&mut context, &[0, 4, 0, 0]), Action::Reply));
+    }
+    let mut blocknum: u16 = 1;
+    loop {
+        let Some(Command::DATA { data, .. }) = tftpprotocol::get_reply_command(&context) else {
+            panic!("DATA {} expected", blocknum);
+        };
+        // The packet, header included
+        let payload = data.len() - 4;
+        bytes += payload as u64;
+        let ack = [&[0, 4][..], &blocknum.to_be_bytes()].concat();
+        assert!(matches!(tftpprotocol::recv(&mut context, &ack), Action::Reply));
+        if payload < blksize {
+            return bytes;
+        }
+        blocknum = blocknum.wrapping_add(1);
+    }
+}
+
+fn transfer(c: &mut Criterion) {
+    let content = Arc::new((0..TRANSFER_SIZE).map(|i| (i % 251) as u8).collect::<Vec<u8>>());
+    let mut group = c.benchmark_group("1 MB read transfer");
+    group.throughput(Throughput::Bytes(TRANSFER_SIZE as u64));
+    let requests = [("blksize 512", rrq("memory.bin", &[])), ("blksize 1468", rrq("memory.bin", &[("blksize", "1468")]))];
+    for (name, request) in &requests {
+        group.bench_function(*name, |b| b.iter(|| assert_eq!(read_transfer(request, &content), TRANSFER_SIZE as u64)));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, parse, serialize, transfer);
+criterion_main!(benches);
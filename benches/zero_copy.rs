@@ -0,0 +1,75 @@
+//! CPU time of the process for a read over the loopback with and without --zero-copy, client included:
+//! `cargo bench --bench zero_copy`
+
+#![allow(clippy::needless_return)]
+
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use tokio_tftpserver::server::Server;
+use tokio_tftpserver::socket::{self, ListenOptions};
+use tokio_tftpserver::testing::TestClient;
+use tokio_tftpserver::tftp::tftpprotocol::ServerOptions;
+
+/// Largest block size, fewer and larger sends
+const BLKSIZE: &str = "65464";
+
+/// Read by each transfer
+const FILE_SIZE: usize = 64 << 20;
+
+/// User and system CPU time of the process, server and client threads included
+#[cfg(unix)]
+fn cpu_time() -> Duration {
+    // SAFETY: rusage only holds integers and timevals, for which all zeros is a valid value
+    let mut usage = unsafe { std::mem::zeroed::<libc::rusage>() };
+    // SAFETY: usage is a valid rusage to fill
+    assert_eq!(unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) }, 0);
+    let time = |tv: libc::timeval| Duration::new(tv.tv_sec as u64, tv.tv_usec as u32 * 1000);
+    return time(usage.ru_utime) + time(usage.ru_stime);
+}
+
+/// Elapsed time instead where the CPU time is not available
+#[cfg(not(unix))]
+fn cpu_time() -> Duration {
+    static START: std::sync::OnceLock<std::time::Instant> = std::sync::OnceLock::new();
+    return START.get_or_init(std::time::Instant::now).elapsed();
+}
+
+fn zero_copy(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let filename = format!("target/tftp-zero-copy-bench-{}.bin", std::process::id());
+    std::fs::write(&filename, vec![0x5a; FILE_SIZE]).unwrap();
+    let mut group = c.benchmark_group("64 MB read over the loopback, CPU time");
+    group.throughput(Throughput::Bytes(FILE_SIZE as u64));
+    group.sample_size(10);
+    for zero_copy in [false, true] {
+        let server_addr = runtime.block_on(async {
+            let socket = socket::bind_udp("127.0.0.1:0".parse().unwrap(), &ListenOptions::default()).unwrap();
+            let server_addr = socket.local_addr().unwrap();
+            let options = ServerOptions { zero_copy, ..ServerOptions::default() };
+            tokio::spawn(Server::new(socket, Arc::new(AtomicBool::new(false))).with_options(options).run());
+            return server_addr;
+        });
+        let name = if zero_copy { "zero copy" } else { "copy" };
+        group.bench_function(name, |b| {
+            b.iter_custom(|iters| {
+                let cpu = cpu_time();
+                for _ in 0..iters {
+                    let content = runtime.block_on(async {
+                        let mut client = TestClient::connect(server_addr).await.unwrap();
+                        return client.get(&filename, &[("blksize", BLKSIZE)]).await.unwrap();
+                    });
+                    assert_eq!(content.len(), FILE_SIZE);
+                }
+                return cpu_time() - cpu;
+            })
+        });
+    }
+    group.finish();
+    std::fs::remove_file(&filename).unwrap();
+}
+
+criterion_group!(benches, zero_copy);
+criterion_main!(benches);
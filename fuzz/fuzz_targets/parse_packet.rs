@@ -0,0 +1,14 @@
+//! Any datagram, parsed as the server does in both strictness modes: `cargo +nightly fuzz run parse_packet`
+
+#![no_main]
+
+use bytes::Bytes;
+use libfuzzer_sys::fuzz_target;
+use tokio_tftpserver::codec::{parse_packet, process_packet, Strictness};
+
+fuzz_target!(|packet: &[u8]| {
+    for strictness in [Strictness::Lenient, Strictness::Strict] {
+        parse_packet(packet, strictness);
+        process_packet(&Bytes::copy_from_slice(packet), strictness);
+    }
+});
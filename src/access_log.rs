@@ -0,0 +1,131 @@
+//! `--access-log`: one line per finished request, in the spirit of httpd access logs
+//!
+//! Format, space separated:
+//! `TIMESTAMP CLIENT_IP:PORT RRQ|WRQ "FILENAME" OK|ERROR:CODE BYTES DURATION_MS`
+//! - TIMESTAMP is RFC 3339 UTC with milliseconds
+//! - FILENAME is quoted, `"` and `\` are escaped with `\`, other control characters as `\xNN`
+//! - CODE is the TFTP error code, `-` for a failure without ERROR packet (e.g. a timeout)
+//!
+//! Lines are written by a dedicated thread, rotated like the log file.
+
+use std::thread;
+use tokio::sync::mpsc::Receiver;
+use tokio_tftpserver::server::TransferResult;
+
+use crate::logging::{format_timestamp, RotatingFile};
+
+pub fn access_line(result: &TransferResult) -> String {
+    let outcome = match (&result.error, result.error_code) {
+        (None, _) => "OK".to_string(),
+        (Some(_), Some(code)) => format!("ERROR:{}", code),
+        (Some(_), None) => "ERROR:-".to_string(),
+    };
+    return format!(
+        "{} {} {} {} {} {} {}\n",
+        format_timestamp(result.finished),
+        result.peer,
+        if result.write { "WRQ" } else { "RRQ" },
+        quote(&result.filename),
+        outcome,
+        result.bytes,
+        result.duration.as_millis()
+    );
+}
+
+fn quote(filename: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in filename.chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            c if c.is_control() => quoted.push_str(&format!("\\x{:02x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    return quoted;
+}
+
+/// Write the results on a thread of its own, the disk never blocks the transfers
+pub fn spawn_writer(mut file: RotatingFile, mut results: Receiver<TransferResult>) -> thread::JoinHandle<()> {
+    return thread::spawn(move || {
+        while let Some(result) = results.blocking_recv() {
+            if let Err(e) = file.write_line(&access_line(&result)) {
+                log::error!("Error {} writing access log", e);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use crate::access_log::*;
+    use std::net::SocketAddr;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    /// Fields of a line, the filename unquoted
+    fn parse_line(line: &str) -> Vec<String> {
+        let line = line.strip_suffix('\n').unwrap();
+        let (head, rest) = line.split_once(" \"").unwrap();
+        let mut fields: Vec<String> = head.split(' ').map(str::to_string).collect();
+        let mut filename = String::new();
+        let mut chars = rest.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => break,
+                '\\' => match chars.next().unwrap() {
+                    'x' => {
+                        let hex: String = chars.by_ref().take(2).collect();
+                        filename.push(char::from_u32(u32::from_str_radix(&hex, 16).unwrap()).unwrap());
+                    }
+                    escaped => filename.push(escaped),
+                },
+                c => filename.push(c),
+            }
+        }
+        fields.push(filename);
+        fields.extend(chars.as_str().split_whitespace().map(str::to_string));
+        return fields;
+    }
+
+    fn result(filename: &str) -> TransferResult {
+        return TransferResult {
+            transfer_id: 1,
+            peer: "10.0.0.42:40123".parse::<SocketAddr>().unwrap(),
+            write: false,
+            filename: filename.to_string(),
+            bytes: 1234,
+            retransmits: 0,
+            duration: Duration::from_millis(812),
+            finished: UNIX_EPOCH + Duration::from_millis(1_709_210_096_789),
+            error: None,
+            error_code: None,
+        };
+    }
+
+    #[test]
+    fn line_parses_back() {
+        let line = access_line(&result("boot/pxelinux.0"));
+        assert_eq!(line, "2024-02-29T12:34:56.789Z 10.0.0.42:40123 RRQ \"boot/pxelinux.0\" OK 1234 812\n");
+        assert_eq!(parse_line(&line),
+                   ["2024-02-29T12:34:56.789Z", "10.0.0.42:40123", "RRQ", "boot/pxelinux.0", "OK", "1234", "812"]);
+    }
+
+    #[test]
+    fn odd_filenames_and_errors() {
+        let mut failed = result("my \"file\"\\ with\nnewline");
+        failed.write = true;
+        failed.error = Some("File not found".to_string());
+        failed.error_code = Some(1);
+        let line = access_line(&failed);
+        assert_eq!(line.matches('\n').count(), 1);
+        let fields = parse_line(&line);
+        assert_eq!(fields[2], "WRQ");
+        assert_eq!(fields[3], "my \"file\"\\ with\nnewline");
+        assert_eq!(fields[4], "ERROR:1");
+        failed.error_code = None;
+        assert_eq!(parse_line(&access_line(&failed))[4], "ERROR:-");
+    }
+}
@@ -0,0 +1,124 @@
+//! `--audit-log`: one JSON object per finished transfer, appended to a file
+//!
+//! Records are written by a single task fed by the servers, through a buffer
+//! flushed whenever no other record is waiting.
+
+use serde::Serialize;
+use std::io;
+use std::path::Path;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc::Receiver;
+use tokio_tftpserver::server::TransferResult;
+
+use crate::logging::format_timestamp;
+
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    timestamp: String,
+    transfer_id: u64,
+    peer: String,
+    direction: &'static str,
+    filename: &'a str,
+    bytes: u64,
+    duration_ms: u128,
+    outcome: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_code: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+}
+
+/// Opened before dropping privileges, the path may not be reachable afterwards
+pub async fn open(path: &Path) -> io::Result<File> {
+    return OpenOptions::new().create(true).append(true).open(path).await;
+}
+
+pub fn audit_line(result: &TransferResult) -> String {
+    let record = AuditRecord {
+        timestamp: format_timestamp(result.finished),
+        transfer_id: result.transfer_id,
+        peer: result.peer.to_string(),
+        direction: if result.write { "write" } else { "read" },
+        filename: &result.filename,
+        bytes: result.bytes,
+        duration_ms: result.duration.as_millis(),
+        outcome: if result.error.is_none() { "ok" } else { "error" },
+        error_code: result.error_code,
+        error: result.error.as_deref(),
+    };
+    // Only strings and numbers, serialization cannot fail
+    return serde_json::to_string(&record).unwrap() + "\n";
+}
+
+/// Write the results until all the senders are gone
+pub async fn write(file: File, mut results: Receiver<TransferResult>) -> io::Result<()> {
+    let mut writer = BufWriter::new(file);
+    while let Some(result) = results.recv().await {
+        writer.write_all(audit_line(&result).as_bytes()).await?;
+        if results.is_empty() {
+            writer.flush().await?;
+        }
+    }
+    return writer.flush().await;
+}
+
+#[cfg(test)]
+mod test {
+    use crate::audit;
+    use serde_json::Value;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::net::UdpSocket;
+    use tokio::sync::mpsc;
+    use tokio_tftpserver::server::Server;
+
+    /// Send a RRQ and acknowledge the single block, or return on ERROR
+    async fn read_request(server: std::net::SocketAddr, filename: &str) {
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let rrq = [&[0, 1][..], filename.as_bytes(), b"\0octet\0"].concat();
+        client.send_to(&rrq, server).await.unwrap();
+        let mut buf = [0; 1024];
+        let (_, from) = client.recv_from(&mut buf).await.unwrap();
+        if buf[1] == 3 {
+            client.send_to(&[0, 4, buf[2], buf[3]], from).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn one_json_line_per_transfer() {
+        let path = std::env::temp_dir().join(format!("tftp-audit-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let (sender, receiver) = mpsc::channel(16);
+        tokio::spawn(audit::write(audit::open(&path).await.unwrap(), receiver));
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = socket.local_addr().unwrap();
+        tokio::spawn(Server::new(socket, Arc::new(AtomicBool::new(false))).with_results(sender).run());
+
+        read_request(server, "tests/fixtures/files/hello.txt").await;
+        read_request(server, "../outside.txt").await;
+
+        let mut lines = Vec::new();
+        for _ in 0..50 {
+            lines = std::fs::read_to_string(&path).unwrap().lines().map(str::to_string).collect();
+            if lines.len() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(lines.len(), 2);
+        let records: Vec<Value> = lines.iter().map(|line| serde_json::from_str(line).unwrap()).collect();
+        let ok = records.iter().find(|record| record["outcome"] == "ok").unwrap();
+        assert_eq!(ok["filename"], "tests/fixtures/files/hello.txt");
+        assert_eq!(ok["direction"], "read");
+        assert_eq!(ok["bytes"], 27);
+        assert!(ok["peer"].as_str().unwrap().starts_with("127.0.0.1:"));
+        assert!(ok["timestamp"].as_str().unwrap().ends_with('Z'));
+        assert!(ok.get("error_code").is_none());
+        let error = records.iter().find(|record| record["outcome"] == "error").unwrap();
+        assert_eq!(error["filename"], "../outside.txt");
+        assert_eq!(error["error_code"], 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,401 @@
+//! Opening of the requested files, confined to the served directory (the working directory)
+//!
+//! Requests are sanitized lexically, a symlink inside the served directory can still point out of it.
+//! On Linux 5.6+ the kernel resolves the whole path with `openat2(2)` and
+//! `RESOLVE_BENEATH | RESOLVE_NO_MAGICLINKS`: no component can lead out, even one swapped for a symlink
+//! while the file is opened. Elsewhere, or on older kernels, the canonical path is checked to be inside
+//! the directory before opening, which a concurrent rename can still defeat.
+//! The directories searched by reads after the served one are confined the same way with `open_in`.
+//! With `Symlinks::Refused` no symlink is followed at all (`RESOLVE_NO_SYMLINKS`, elsewhere each component
+//! is checked before opening), for trees where a symlink is never expected.
+
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io::{self, ErrorKind};
+use std::path::Path;
+
+use log::warn;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    /// Read only, opening a fifo does not block
+    Read,
+    /// Write from the start, the current content is dropped. mode of a created file, before the umask (Unix)
+    Truncate { create: bool, mode: u32 },
+    /// Write over the current content
+    Update { create: bool, mode: u32 },
+    /// Write after the current content, whatever the file position
+    Append { create: bool, mode: u32 },
+}
+
+/// Symlinks met while resolving a path
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Symlinks {
+    /// Followed as long as they stay inside the directory
+    #[default]
+    Inside,
+    /// Refused with PermissionDenied, even leading inside the directory
+    Refused,
+}
+
+/// Open a path relative to the served directory, a path leading out of it fails with PermissionDenied
+pub fn open(path: &Path, access: Access) -> io::Result<File> {
+    return open_in(Path::new(""), path, access, Symlinks::Inside);
+}
+
+/// Open a path relative to root, the served directory when empty, a path leading out of root fails with PermissionDenied
+pub fn open_in(root: &Path, path: &Path, access: Access, symlinks: Symlinks) -> io::Result<File> {
+    #[cfg(target_os = "linux")]
+    if let Some(result) = openat2::open(root, path, access, symlinks) {
+        return result;
+    }
+    return open_portable(root, path, access, symlinks);
+}
+
+fn open_portable(root: &Path, path: &Path, access: Access, symlinks: Symlinks) -> io::Result<File> {
+    if symlinks == Symlinks::Refused {
+        check_no_symlink(root, path)?;
+    }
+    let path = &root.join(path);
+    check_canonical(root, path, access)?;
+    let mut options = OpenOptions::new();
+    match access {
+        Access::Read => {
+            options.read(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::custom_flags(&mut options, libc::O_NONBLOCK | libc::O_NOCTTY);
+        }
+        Access::Truncate { create, mode } => {
+            options.write(true).truncate(true).create(create);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, mode);
+        }
+        Access::Update { create, mode } => {
+            options.write(true).create(create);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, mode);
+        }
+        Access::Append { create, mode } => {
+            options.append(true).create(create);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, mode);
+        }
+    }
+    return options.open(path);
+}
+
+/// Create the missing directories of a path relative to the served directory, each one from a parent
+/// checked to be inside it. mode is exact whatever the umask, 0o777 before the umask when None (Unix).
+/// A symlink swapped while creating can still place an empty directory out of it, not a file:
+/// files are then opened with `open`.
+pub fn create_dirs(path: &Path, mode: Option<u32>, symlinks: Symlinks) -> io::Result<()> {
+    return create_dirs_in(Path::new(""), path, mode, symlinks);
+}
+
+/// Same as create_dirs for a path relative to root, the served directory when empty
+pub fn create_dirs_in(root: &Path, path: &Path, mode: Option<u32>, symlinks: Symlinks) -> io::Result<()> {
+    let mut dir = root.to_path_buf();
+    for component in path.components() {
+        dir.push(component);
+        match fs::symlink_metadata(&dir) {
+            Ok(metadata) if metadata.is_symlink() && symlinks == Symlinks::Refused => return Err(symlink(&dir)),
+            Ok(_) => {
+                check_canonical(root, &dir, Access::Read)?;
+                continue;
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+        let mut builder = DirBuilder::new();
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, mode.unwrap_or(0o777));
+        match builder.create(&dir) {
+            Ok(()) => {
+                #[cfg(unix)]
+                if let Some(mode) = mode {
+                    use std::os::unix::fs::PermissionsExt;
+                    fs::set_permissions(&dir, fs::Permissions::from_mode(mode))?;
+                }
+            }
+            // Created meanwhile by a concurrent upload
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => check_canonical(root, &dir, Access::Read)?,
+            Err(e) => return Err(e),
+        }
+    }
+    return Ok(());
+}
+
+/// The canonical path, or the directory of a file to create, must be inside root, the working directory when empty
+fn check_canonical(root: &Path, path: &Path, access: Access) -> io::Result<()> {
+    let root = match root.as_os_str().is_empty() {
+        true => std::env::current_dir()?.canonicalize()?,
+        false => root.canonicalize()?,
+    };
+    let creating = matches!(access, Access::Truncate { create: true, .. } | Access::Update { create: true, .. } | Access::Append { create: true, .. });
+    let resolved = match path.canonicalize() {
+        Ok(resolved) => resolved,
+        // A dangling symlink would be followed by the creation
+        Err(e) if e.kind() == ErrorKind::NotFound && creating && path.symlink_metadata().is_err() => {
+            let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+            dir.canonicalize()?
+        }
+        Err(e) => return Err(e),
+    };
+    if !resolved.starts_with(&root) {
+        return Err(escape(path));
+    }
+    return Ok(());
+}
+
+/// No existing component of path is a symlink, the missing ones are left to the creation
+fn check_no_symlink(root: &Path, path: &Path) -> io::Result<()> {
+    let mut resolved = root.to_path_buf();
+    for component in path.components() {
+        resolved.push(component);
+        match fs::symlink_metadata(&resolved) {
+            Ok(metadata) if metadata.is_symlink() => return Err(symlink(&resolved)),
+            Ok(_) => (),
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        }
+    }
+    return Ok(());
+}
+
+fn escape(path: &Path) -> io::Error {
+    warn!("{} leads out of the served directory, refused", path.display());
+    return io::Error::new(ErrorKind::PermissionDenied, "path leads out of the served directory");
+}
+
+fn symlink(path: &Path) -> io::Error {
+    warn!("{} goes through a symlink, refused", path.display());
+    return io::Error::new(ErrorKind::PermissionDenied, "path goes through a symlink");
+}
+
+#[cfg(target_os = "linux")]
+mod openat2 {
+    use std::ffi::CString;
+    use std::fs::File;
+    use std::io;
+    use std::os::fd::{AsRawFd, FromRawFd};
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use log::warn;
+
+    use crate::beneath::{escape, symlink, Access, Symlinks};
+
+    /// struct open_how of the kernel ABI, the libc one cannot be built outside of libc
+    #[repr(C)]
+    struct OpenHow {
+        flags: u64,
+        mode: u64,
+        resolve: u64,
+    }
+
+    /// Set on the first ENOSYS, the kernel predates openat2 (5.6) or a seccomp filter refuses it
+    static UNAVAILABLE: AtomicBool = AtomicBool::new(false);
+
+    pub fn available() -> bool {
+        return !UNAVAILABLE.load(Ordering::Relaxed);
+    }
+
+    /// None when openat2 is not available, to fall back on the portable check
+    pub fn open(root: &Path, path: &Path, access: Access, symlinks: Symlinks) -> Option<io::Result<File>> {
+        if !available() {
+            return None;
+        }
+        // Only a path to resolve from, not opened for reading
+        let dir = match root.as_os_str().is_empty() {
+            true => None,
+            false => match File::options().read(true).custom_flags(libc::O_PATH | libc::O_DIRECTORY).open(root) {
+                Ok(dir) => Some(dir),
+                Err(e) => return Some(Err(e)),
+            },
+        };
+        let dirfd = dir.as_ref().map_or(libc::AT_FDCWD, |dir| dir.as_raw_fd());
+        let c_path = match CString::new(path.as_os_str().as_bytes()) {
+            Ok(c_path) => c_path,
+            Err(e) => return Some(Err(e.into())),
+        };
+        let create = |create: bool| if create { libc::O_CREAT } else { 0 };
+        let (flags, mode) = match access {
+            Access::Read => (libc::O_RDONLY | libc::O_NONBLOCK, 0),
+            Access::Truncate { create: c, mode } => (libc::O_WRONLY | libc::O_TRUNC | create(c), mode),
+            Access::Update { create: c, mode } => (libc::O_WRONLY | create(c), mode),
+            Access::Append { create: c, mode } => (libc::O_WRONLY | libc::O_APPEND | create(c), mode),
+        };
+        let flags = flags | libc::O_CLOEXEC | libc::O_NOCTTY;
+        let how = OpenHow {
+            flags: flags as u64,
+            // Only given with O_CREAT
+            mode: if flags & libc::O_CREAT != 0 { mode as u64 } else { 0 },
+            resolve: match symlinks {
+                Symlinks::Inside => libc::RESOLVE_BENEATH | libc::RESOLVE_NO_MAGICLINKS,
+                Symlinks::Refused => libc::RESOLVE_BENEATH | libc::RESOLVE_NO_SYMLINKS,
+            },
+        };
+        // SAFETY: c_path is NUL terminated, dir and how outlive the call, with the size of how given
+        let fd = unsafe {
+            libc::syscall(libc::SYS_openat2, dirfd, c_path.as_ptr(), &how as *const OpenHow,
+                          std::mem::size_of::<OpenHow>())
+        };
+        if fd >= 0 {
+            // SAFETY: the descriptor was just opened and belongs to nothing else
+            return Some(Ok(unsafe { File::from_raw_fd(fd as i32) }));
+        }
+        let error = io::Error::last_os_error();
+        match error.raw_os_error() {
+            Some(libc::ENOSYS) => {
+                UNAVAILABLE.store(true, Ordering::Relaxed);
+                warn!("openat2 not available, a symlink swapped while opening could escape the served directory");
+                return None;
+            }
+            Some(libc::EXDEV) => return Some(Err(escape(&root.join(path)))),
+            Some(libc::ELOOP) if symlinks == Symlinks::Refused => return Some(Err(symlink(&root.join(path)))),
+            _ => return Some(Err(error)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::beneath::*;
+    use std::fs;
+    use std::io::Read;
+    use std::path::PathBuf;
+
+    /// Test directory inside the working directory, with a symlink to a directory outside of it
+    fn setup(name: &str) -> (PathBuf, PathBuf) {
+        let inside = PathBuf::from(format!("target/tftp-beneath-{}-{}", name, std::process::id()));
+        let outside = std::env::temp_dir().join(format!("tftp-beneath-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&inside);
+        fs::create_dir_all(inside.join("dir")).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(inside.join("dir/file.txt"), "inside").unwrap();
+        fs::write(outside.join("file.txt"), "secret").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(&outside, inside.join("escape")).unwrap();
+        return (inside, outside);
+    }
+
+    fn portable(path: &Path, access: Access) -> io::Result<File> {
+        return open_portable(Path::new(""), path, access, Symlinks::Inside);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_out_is_refused() {
+        let (inside, _outside) = setup("symlink");
+        assert!(open(&inside.join("dir/file.txt"), Access::Read).is_ok());
+        for open_with in [open, portable] {
+            let error = open_with(&inside.join("escape/file.txt"), Access::Read).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+            let error = open_with(&inside.join("escape/new.txt"), Access::Truncate { create: true, mode: 0o666 }).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+        }
+        // A dangling symlink to a file to create outside
+        std::os::unix::fs::symlink(std::env::temp_dir().join("tftp-beneath-dangling"), inside.join("dangling")).unwrap();
+        for open_with in [open, portable] {
+            assert!(open_with(&inside.join("dangling"), Access::Truncate { create: true, mode: 0o666 }).is_err());
+        }
+        assert!(!std::env::temp_dir().join("tftp-beneath-dangling").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn other_root() {
+        let (inside, outside) = setup("root");
+        std::os::unix::fs::symlink(std::path::absolute(inside.join("dir/file.txt")).unwrap(), outside.join("link.txt")).unwrap();
+        for open_with in [open_in, open_portable] {
+            let mut content = String::new();
+            open_with(&outside, Path::new("file.txt"), Access::Read, Symlinks::Inside).unwrap().read_to_string(&mut content).unwrap();
+            assert_eq!(content, "secret");
+            // Out of this root, even to the served directory
+            let error = open_with(&outside, Path::new("link.txt"), Access::Read, Symlinks::Inside).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn create_dirs_inside_only() {
+        let (inside, outside) = setup("dirs");
+        create_dirs(&inside.join("dir/new/nested"), None, Symlinks::Inside).unwrap();
+        assert!(inside.join("dir/new/nested").is_dir());
+        // Again, all exist
+        create_dirs(&inside.join("dir/new/nested"), None, Symlinks::Inside).unwrap();
+        assert_eq!(create_dirs(&inside.join("escape/new"), None, Symlinks::Inside).unwrap_err().kind(), ErrorKind::PermissionDenied);
+        assert!(!outside.join("new").exists());
+        std::os::unix::fs::symlink("new", inside.join("dir/alias")).unwrap();
+        create_dirs(&inside.join("dir/alias/other"), None, Symlinks::Inside).unwrap();
+        assert_eq!(create_dirs(&inside.join("dir/alias/more"), None, Symlinks::Refused).unwrap_err().kind(), ErrorKind::PermissionDenied);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_refused() {
+        let (inside, _outside) = setup("nosymlinks");
+        std::os::unix::fs::symlink("file.txt", inside.join("dir/link.txt")).unwrap();
+        for open_with in [open_in, open_portable] {
+            assert!(open_with(Path::new(""), &inside.join("dir/link.txt"), Access::Read, Symlinks::Inside).is_ok());
+            assert!(open_with(Path::new(""), &inside.join("dir/file.txt"), Access::Read, Symlinks::Refused).is_ok());
+            let error = open_with(Path::new(""), &inside.join("dir/link.txt"), Access::Read, Symlinks::Refused).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+            let error = open_with(Path::new(""), &inside.join("escape/file.txt"), Access::Read, Symlinks::Refused).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+        }
+    }
+
+    /// The kernel refuses both a symlink and a '..' leading out, even one coming back in
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn openat2_escapes() {
+        let (inside, _outside) = setup("openat2");
+        let cwd = std::env::current_dir().unwrap();
+        let back_in = Path::new("..").join(cwd.file_name().unwrap()).join("Cargo.toml");
+        for symlinks in [Symlinks::Inside, Symlinks::Refused] {
+            let Some(result) = openat2::open(Path::new(""), &inside.join("escape/file.txt"), Access::Read, symlinks) else {
+                eprintln!("openat2 not available, nothing to test");
+                return;
+            };
+            assert_eq!(result.unwrap_err().kind(), ErrorKind::PermissionDenied);
+            let result = openat2::open(Path::new(""), &back_in, Access::Read, symlinks).unwrap();
+            assert_eq!(result.unwrap_err().kind(), ErrorKind::PermissionDenied);
+            assert!(openat2::open(Path::new(""), Path::new("Cargo.toml"), Access::Read, symlinks).unwrap().is_ok());
+        }
+    }
+
+    /// Best effort: swap a directory and a symlink leading out while opening through them
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn symlink_swap_race() {
+        let (inside, _outside) = setup("race");
+        open(&inside.join("dir/file.txt"), Access::Read).unwrap();
+        if !openat2::available() {
+            eprintln!("openat2 not available, the race is only closed by the kernel");
+            return;
+        }
+        let swapped = inside.join("swapped");
+        let swapper = {
+            let (inside, swapped) = (inside.clone(), swapped.clone());
+            std::thread::spawn(move || {
+                for _ in 0..2000 {
+                    for entry in ["dir", "escape"] {
+                        fs::rename(inside.join(entry), &swapped).unwrap();
+                        fs::rename(&swapped, inside.join(entry)).unwrap();
+                    }
+                }
+            })
+        };
+        while !swapper.is_finished() {
+            if let Ok(mut file) = open(&swapped.join("file.txt"), Access::Read) {
+                let mut content = String::new();
+                file.read_to_string(&mut content).unwrap();
+                assert_eq!(content, "inside");
+            }
+        }
+        swapper.join().unwrap();
+    }
+}
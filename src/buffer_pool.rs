@@ -0,0 +1,123 @@
+//! Fixed size packet buffers reused across transfers, to avoid an allocation per packet
+//!
+//! A checked out buffer goes back to the pool when its guard is dropped. The pool keeps
+//! at most `cap` idle buffers, a burst beyond it allocates buffers freed on return.
+
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug)]
+pub struct BufferPool {
+    buffer_size: usize,
+    cap: usize,
+    idle: Mutex<Vec<Box<[u8]>>>,
+    /// Buffers in existence, checked out or idle
+    allocated: AtomicUsize,
+}
+
+impl BufferPool {
+    pub fn new(buffer_size: usize, cap: usize) -> Arc<BufferPool> {
+        return Arc::new(BufferPool {
+            buffer_size,
+            cap,
+            idle: Mutex::new(Vec::with_capacity(cap)),
+            allocated: AtomicUsize::new(0),
+        });
+    }
+
+    /// An idle buffer, or a new one when all are in use. Its content is left from the previous use.
+    pub fn checkout(self: &Arc<Self>) -> PooledBuffer {
+        let reused = self.idle.lock().unwrap_or_else(|e| e.into_inner()).pop();
+        let buf = reused.unwrap_or_else(|| {
+            self.allocated.fetch_add(1, Ordering::Relaxed);
+            return vec![0; self.buffer_size].into_boxed_slice();
+        });
+        return PooledBuffer { buf: Some(buf), pool: self.clone() };
+    }
+
+    pub fn buffer_size(&self) -> usize {
+        return self.buffer_size;
+    }
+
+    pub fn cap(&self) -> usize {
+        return self.cap;
+    }
+
+    pub fn idle(&self) -> usize {
+        return self.idle.lock().unwrap_or_else(|e| e.into_inner()).len();
+    }
+
+    pub fn allocated(&self) -> usize {
+        return self.allocated.load(Ordering::Relaxed);
+    }
+
+    fn give_back(&self, buf: Box<[u8]>) {
+        let mut idle = self.idle.lock().unwrap_or_else(|e| e.into_inner());
+        if idle.len() < self.cap {
+            idle.push(buf);
+        } else {
+            self.allocated.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Buffer checked out of a pool, returned to it on drop
+#[derive(Debug)]
+pub struct PooledBuffer {
+    // Only None while dropping
+    buf: Option<Box<[u8]>>,
+    pool: Arc<BufferPool>,
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        return self.buf.as_deref().unwrap();
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        return self.buf.as_deref_mut().unwrap();
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        if let Some(buf) = self.buf.take() {
+            self.pool.give_back(buf);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::buffer_pool::*;
+
+    #[test]
+    fn steady_state_reuses_buffers() {
+        let pool = BufferPool::new(516, 4);
+        for _ in 0..1000 {
+            // A transfer uses a receive and a send buffer
+            let mut recv = pool.checkout();
+            let send = pool.checkout();
+            recv[0] = 1;
+            assert_eq!(send.len(), 516);
+        }
+        assert_eq!(pool.allocated(), 2);
+        assert_eq!(pool.idle(), 2);
+    }
+
+    #[test]
+    fn burst_beyond_cap_is_freed() {
+        let pool = BufferPool::new(516, 4);
+        let burst: Vec<PooledBuffer> = (0..10).map(|_| pool.checkout()).collect();
+        assert_eq!(pool.allocated(), 10);
+        assert_eq!(pool.idle(), 0);
+        drop(burst);
+        assert_eq!(pool.idle(), pool.cap());
+        assert_eq!(pool.allocated(), pool.cap());
+    }
+}
@@ -0,0 +1,821 @@
+//! TFTP packets: parsing and serialization of the commands (RFC 1350, options of RFC 2347)
+//!
+//! Only needs `core` and `alloc`, this is the part of the crate built without the `std`
+//! feature, e.g. to reuse the codec on an embedded target. File access and sockets are in
+//! the std-only modules.
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use bytes::Bytes;
+use core::fmt;
+use log::{debug, trace};
+
+/// Longest filename of a request, the longest path of most systems (PATH_MAX)
+pub const MAX_PATH_LEN: usize = 4096;
+/// Longest mode of a request, "netascii" is 8 bytes
+pub const MAX_MODE_LEN: usize = 32;
+/// Longest option name or value, after the conversion of the bytes which are not UTF-8
+pub const MAX_OPTION_LEN: usize = 255;
+/// Most options in a request or an OACK
+pub const MAX_OPTIONS: usize = 32;
+/// Longest error message kept, a longer one is truncated
+pub const MAX_ERRMSG_LEN: usize = 512;
+/// Largest DATA payload, the largest block size of RFC 2348
+pub const MAX_DATA_LEN: usize = 65464;
+
+#[derive(Debug, PartialEq)]
+pub enum Opcode {
+    RRQ = 1, // Read request
+    WRQ = 2, // Write request
+    DATA = 3,
+    ACK = 4,
+    ERROR = 5,
+    OACK = 6, // Option acknowledgment (RFC 2347)
+}
+
+impl TryFrom<u16> for Opcode {
+    type Error = &'static str;
+
+    fn try_from(opcode: u16) -> Result<Self, Self::Error> {
+        match opcode {
+            1 => Ok(Opcode::RRQ),
+            2 => Ok(Opcode::WRQ),
+            3 => Ok(Opcode::DATA),
+            4 => Ok(Opcode::ACK),
+            5 => Ok(Opcode::ERROR),
+            6 => Ok(Opcode::OACK),
+            _ => Err("Unknown opcode"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    // The filename is kept as sent, it is not always UTF-8
+    RRQ { filename: Vec<u8>, mode: String, options: Vec<(String, String)> },
+    WRQ { filename: Vec<u8>, mode: String, options: Vec<(String, String)> },
+    DATA { blocknum: u16, data: Bytes },
+    ACK { blocknum: u16 },
+    ERROR { errorcode: u16, errmsg: String },
+    OACK { options: Vec<(String, String)> },
+}
+
+impl Command {
+    /// An ERROR with its message prefixed by `[tag] `, telling the client which server sent it.
+    /// The other commands are unchanged
+    pub fn tagged(self, tag: &str) -> Command {
+        match self {
+            Command::ERROR { errorcode, errmsg } => return Command::ERROR { errorcode, errmsg: format!("[{}] {}", tag, errmsg) },
+            command => return command,
+        }
+    }
+
+    /// An ERROR with the message of the RFC for its code, without the detail of the server.
+    /// The other commands are unchanged
+    pub fn generic(self) -> Command {
+        match self {
+            Command::ERROR { errorcode, .. } => {
+                return Command::ERROR { errorcode, errmsg: TftpError::from_error_code(errorcode).generic_message() };
+            }
+            command => return command,
+        }
+    }
+}
+
+/// How far the received packets may deviate from the RFCs
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Strictness {
+    /// The known quirks of real clients are tolerated, each one explicitly: bytes after the last
+    /// terminator of a request, an option name without value, extra NULs after the mode, a last string
+    /// (mode, option, error message) without terminator, a mode other than netascii and octet (served as
+    /// octet), DATA blocks out of order in an upload (answered with the ACK of the last block written)
+    #[default]
+    Lenient,
+    /// Any deviation is answered with an ERROR, refusing the request or ending the transfer
+    Strict,
+}
+
+impl fmt::Display for Strictness {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Strictness::Lenient => return write!(f, "lenient"),
+            Strictness::Strict => return write!(f, "strict"),
+        }
+    }
+}
+
+/// Error codes defined by RFC 1350
+#[derive(Debug, Clone, PartialEq)]
+pub enum TftpError {
+    NotDefined(String), // 0, carries the free-form message
+    FileNotFound,       // 1
+    AccessViolation,    // 2
+    DiskFull,           // 3
+    IllegalOperation,   // 4
+    UnknownTransferId,  // 5
+    FileAlreadyExists,  // 6
+    NoSuchUser,         // 7
+    MalformedPacket,    // 4 with a precise message: truncated or oversized packet
+}
+
+impl TftpError {
+    pub fn from_error_code(errorcode: u16) -> TftpError {
+        match errorcode {
+            1 => TftpError::FileNotFound,
+            2 => TftpError::AccessViolation,
+            3 => TftpError::DiskFull,
+            4 => TftpError::IllegalOperation,
+            5 => TftpError::UnknownTransferId,
+            6 => TftpError::FileAlreadyExists,
+            7 => TftpError::NoSuchUser,
+            _ => TftpError::NotDefined(String::new()),
+        }
+    }
+
+    pub fn error_code(&self) -> u16 {
+        match self {
+            TftpError::NotDefined(_) => 0,
+            TftpError::FileNotFound => 1,
+            TftpError::AccessViolation => 2,
+            TftpError::DiskFull => 3,
+            TftpError::IllegalOperation => 4,
+            TftpError::UnknownTransferId => 5,
+            TftpError::FileAlreadyExists => 6,
+            TftpError::NoSuchUser => 7,
+            TftpError::MalformedPacket => 4,
+        }
+    }
+
+    pub fn default_message(&self) -> String {
+        match self {
+            TftpError::NotDefined(msg) => msg.clone(),
+            TftpError::FileNotFound => "File not found".to_string(),
+            TftpError::AccessViolation => "Access violation".to_string(),
+            TftpError::DiskFull => "Disk full or allocation exceeded".to_string(),
+            TftpError::IllegalOperation => "Illegal TFTP operation".to_string(),
+            TftpError::UnknownTransferId => "Unknown transfer ID".to_string(),
+            TftpError::FileAlreadyExists => "File already exists".to_string(),
+            TftpError::NoSuchUser => "No such user".to_string(),
+            TftpError::MalformedPacket => "Malformed packet".to_string(),
+        }
+    }
+
+    /// Message of the RFC for the code: the text of NotDefined and the precision of MalformedPacket
+    /// are left out, they stay in default_message for the logs
+    pub fn generic_message(&self) -> String {
+        match self {
+            TftpError::NotDefined(_) => return "Not defined".to_string(),
+            TftpError::MalformedPacket => return TftpError::IllegalOperation.default_message(),
+            error => return error.default_message(),
+        }
+    }
+
+    pub fn to_command(&self) -> Command {
+        return Command::ERROR { errorcode: self.error_code(), errmsg: self.default_message() };
+    }
+}
+
+impl fmt::Display for TftpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return f.write_str(&self.default_message());
+    }
+}
+
+impl core::error::Error for TftpError {}
+
+/// The error kind follows the TFTP code, the TftpError is kept as the inner error
+#[cfg(feature = "std")]
+impl From<TftpError> for std::io::Error {
+    fn from(error: TftpError) -> std::io::Error {
+        use std::io::ErrorKind;
+        let kind = match error {
+            TftpError::FileNotFound => ErrorKind::NotFound,
+            TftpError::AccessViolation | TftpError::NoSuchUser => ErrorKind::PermissionDenied,
+            TftpError::DiskFull => ErrorKind::StorageFull,
+            TftpError::FileAlreadyExists => ErrorKind::AlreadyExists,
+            TftpError::IllegalOperation | TftpError::UnknownTransferId | TftpError::MalformedPacket => ErrorKind::InvalidData,
+            TftpError::NotDefined(_) => ErrorKind::Other,
+        };
+        return std::io::Error::new(kind, error);
+    }
+}
+
+/// Build the error reported by a client ERROR packet.
+/// Code 0 has no standard message, so keep the one sent by the client.
+pub fn get_client_error(errorcode: u16, errmsg: String) -> TftpError {
+    match errorcode {
+        0 => TftpError::NotDefined(errmsg),
+        _ => TftpError::from_error_code(errorcode),
+    }
+}
+
+pub fn get_client_error_message(error: &TftpError) -> String {
+    return format!(
+        "Aborting command, received from client error {} with message {}",
+        error.error_code(),
+        error.default_message()
+    );
+}
+
+/// Read side of a packet, the subset of `std::io::BufRead` the parser needs
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn read_u16(&mut self) -> Option<u16> {
+        let (value, rest) = self.buf.split_first_chunk::<2>()?;
+        self.buf = rest;
+        return Some(u16::from_be_bytes(*value));
+    }
+
+    /// Up to and including the next 0, or the rest of the packet without terminator
+    fn read_until_nul(&mut self) -> &'a [u8] {
+        let end = self.buf.iter().position(|byte| *byte == 0).map_or(self.buf.len(), |nul| nul + 1);
+        let (read, rest) = self.buf.split_at_checked(end).unwrap_or((self.buf, &[]));
+        self.buf = rest;
+        return read;
+    }
+
+    /// Up to the next 0 excluded and whether it was found, the rest of the packet when it is not
+    fn read_string(&mut self) -> (&'a [u8], bool) {
+        let read = self.read_until_nul();
+        match read.split_last() {
+            Some((0, string)) => return (string, true),
+            _ => return (read, false),
+        }
+    }
+
+    /// Count of the 0 skipped
+    fn skip_nuls(&mut self) -> usize {
+        let count = self.buf.iter().take_while(|byte| **byte == 0).count();
+        self.buf = self.buf.get(count..).unwrap_or_default();
+        return count;
+    }
+
+    fn read_to_end(&mut self) -> &'a [u8] {
+        return core::mem::take(&mut self.buf);
+    }
+}
+
+fn parse_command(opcode: Opcode, reader: &mut Reader<'_>, strictness: Strictness) -> Command {
+    // Inner function for RRQ/WRQ shared parsing logic
+    // Filename, mode and options
+    type Request = (Vec<u8>, String, Vec<(String, String)>);
+    let strict = strictness == Strictness::Strict;
+
+    fn parse_filename_mode(reader: &mut Reader<'_>, strict: bool) -> Result<Request, TftpError> {
+        let (filename, terminated) = reader.read_string();
+        // Not even a mode
+        if !terminated {
+            debug!("Filename without terminator");
+            return Err(TftpError::MalformedPacket);
+        }
+        if filename.len() > MAX_PATH_LEN {
+            debug!("Filename of {} bytes, longer than {}", filename.len(), MAX_PATH_LEN);
+            return Err(TftpError::MalformedPacket);
+        }
+        let (mode, terminated) = reader.read_string();
+        // Quirk: the mode ends the packet without its terminator
+        if !terminated {
+            if strict {
+                debug!("Mode without terminator");
+                return Err(TftpError::MalformedPacket);
+            }
+            trace!("Mode without terminator, accepted");
+        }
+        if mode.len() > MAX_MODE_LEN {
+            debug!("Mode of {} bytes, longer than {}", mode.len(), MAX_MODE_LEN);
+            return Err(TftpError::MalformedPacket);
+        }
+        let mode = match core::str::from_utf8(mode) {
+            Ok(mode) if mode.is_ascii() => mode.to_string(),
+            _ => {
+                debug!("Mode {:?} is not ASCII", String::from_utf8_lossy(mode));
+                return Err(TftpError::MalformedPacket);
+            }
+        };
+        // Quirk: other modes (mail, empty, misspelled) are sent as octet
+        if !mode.eq_ignore_ascii_case("netascii") && !mode.eq_ignore_ascii_case("octet") {
+            if strict {
+                debug!("Unsupported mode {:?}", mode);
+                return Err(TftpError::IllegalOperation);
+            }
+            debug!("Unsupported mode {:?}, served as octet", mode);
+        }
+        // Quirk: NULs padding the request after the mode
+        let padding = reader.skip_nuls();
+        if padding > 0 {
+            if strict {
+                debug!("{} NULs after the mode", padding);
+                return Err(TftpError::MalformedPacket);
+            }
+            trace!("Ignoring {} NULs after the mode", padding);
+        }
+        let options = parse_options(reader, strict)?;
+
+        return Ok((filename.to_vec(), mode, options));
+    }
+
+    // Name and value pairs until the end of the packet (RFC 2347)
+    fn parse_options(reader: &mut Reader<'_>, strict: bool) -> Result<Vec<(String, String)>, TftpError> {
+        let mut options = Vec::new();
+        // Name waiting for its value
+        let mut name: Option<String> = None;
+        loop {
+            let (string, terminated) = reader.read_string();
+            if !terminated {
+                // Quirk: garbage appended by some clients, the complete pairs before it are kept
+                if !string.is_empty() {
+                    if strict {
+                        debug!("{} trailing bytes without terminator", string.len());
+                        return Err(TftpError::MalformedPacket);
+                    }
+                    debug!("Ignoring {} trailing bytes without terminator", string.len());
+                }
+                break;
+            }
+            // Checked once converted, at most 3 bytes per byte received
+            let string = String::from_utf8_lossy(string.get(..MAX_OPTION_LEN + 1).unwrap_or(string)).into_owned();
+            if string.len() > MAX_OPTION_LEN {
+                debug!("Option name or value longer than {} bytes", MAX_OPTION_LEN);
+                return Err(TftpError::MalformedPacket);
+            }
+            match name.take() {
+                None => name = Some(string),
+                Some(_) if options.len() == MAX_OPTIONS => {
+                    debug!("More than {} options", MAX_OPTIONS);
+                    return Err(TftpError::MalformedPacket);
+                }
+                Some(name) => options.push((name, string)),
+            }
+        }
+        // Quirk: a name without value is ignored
+        if let Some(name) = name {
+            if strict {
+                debug!("Option {} without value", name);
+                return Err(TftpError::MalformedPacket);
+            }
+            debug!("Ignoring option {} without value", name);
+        }
+        return Ok(options);
+    }
+
+    match opcode {
+        Opcode::RRQ => {
+            let (filename, mode, options) = match parse_filename_mode(reader, strict) {
+                Ok(request) => request,
+                Err(e) => return e.to_command()
+            };
+            debug!("Read FileName: {}, Mode: {}, Options: {:?}", String::from_utf8_lossy(&filename), mode, options);
+            return Command::RRQ { filename, mode, options };
+        }
+        Opcode::WRQ => {
+            let (filename, mode, options) = match parse_filename_mode(reader, strict) {
+                Ok(request) => request,
+                Err(e) => return e.to_command()
+            };
+            debug!("Write FileName: {}, Mode: {}, Options: {:?}", String::from_utf8_lossy(&filename), mode, options);
+            return Command::WRQ { filename, mode, options };
+        }
+        Opcode::OACK => {
+            match parse_options(reader, strict) {
+                Ok(options) => return Command::OACK { options },
+                Err(e) => return e.to_command()
+            }
+        }
+        Opcode::ACK => {
+            let Some(blocknum) = reader.read_u16() else {
+                return TftpError::MalformedPacket.to_command();
+            };
+            trace!("ACK {}", blocknum);
+            return Command::ACK { blocknum };
+        }
+        Opcode::ERROR => {
+            let Some(errcode) = reader.read_u16() else {
+                return TftpError::MalformedPacket.to_command();
+            };
+            let (message, terminated) = reader.read_string();
+            // Quirk: the message ends the packet without its terminator, it is kept whole
+            if !terminated {
+                if strict {
+                    debug!("Error message without terminator");
+                    return TftpError::MalformedPacket.to_command();
+                }
+                trace!("Error message without terminator, accepted");
+            }
+            // Only logged, a message which is not UTF-8 is still shown
+            let mut error = String::from_utf8_lossy(message.get(..MAX_ERRMSG_LEN).unwrap_or(message)).into_owned();
+            let mut end = error.len().min(MAX_ERRMSG_LEN);
+            while !error.is_char_boundary(end) {
+                end -= 1;
+            }
+            error.truncate(end);
+            return Command::ERROR { errorcode: errcode, errmsg: error };
+        }
+        Opcode::DATA => {
+            let Some(blocknum) = reader.read_u16() else {
+                return TftpError::MalformedPacket.to_command();
+            };
+            // Up to the negotiated block size, the caller buffer is sized for it
+            let data = reader.read_to_end();
+            if data.len() > MAX_DATA_LEN {
+                debug!("DATA block {} of {} bytes, larger than {}", blocknum, data.len(), MAX_DATA_LEN);
+                return TftpError::MalformedPacket.to_command();
+            }
+            trace!("DATA Blknum: {}, len: {}", blocknum, data.len());
+            return Command::DATA { blocknum, data: Bytes::copy_from_slice(data) };
+        }
+    }
+}
+
+/// Lenient parse_packet
+pub fn process_buffer(buf: &[u8], _size: usize) -> Command {
+    return parse_packet(buf, Strictness::Lenient);
+}
+
+/// Command of a received packet, an ERROR for a packet which cannot be parsed under strictness
+pub fn parse_packet(buf: &[u8], strictness: Strictness) -> Command {
+    let mut reader = Reader { buf };
+    let Some(opcode) = reader.read_u16() else {
+        debug!("Packet of {} bytes, too short for an opcode", buf.len());
+        return TftpError::MalformedPacket.to_command();
+    };
+    let opcode = match Opcode::try_from(opcode) {
+        Ok(opcode) => opcode,
+        Err(e) => {
+            debug!("{}", e);
+            return TftpError::IllegalOperation.to_command();
+        }
+    };
+    return parse_command(opcode, &mut reader, strictness);
+}
+
+/// Same as parse_packet, the payload of a DATA is kept as a slice of the packet rather than copied
+pub fn process_packet(packet: &Bytes, strictness: Strictness) -> Command {
+    if let Some((&[opcode_high, opcode_low, high, low], payload)) = packet.split_first_chunk::<4>() {
+        if [opcode_high, opcode_low] == (Opcode::DATA as u16).to_be_bytes() && payload.len() <= MAX_DATA_LEN {
+            let blocknum = u16::from_be_bytes([high, low]);
+            trace!("DATA Blknum: {}, len: {}", blocknum, payload.len());
+            return Command::DATA { blocknum, data: packet.slice(4..) };
+        }
+    }
+    return parse_packet(packet, strictness);
+}
+
+/// Packet of a command in a new buffer, see write_command to reuse one
+pub fn get_buffer_for_command(command: Command) -> Option<Bytes> {
+    // The packet of a DATA is already built
+    if let Command::DATA { blocknum: _, data } = command {
+        return Some(data);
+    }
+    let mut result = vec![0; packet_len(&command)];
+    let size = write_command(&command, &mut result).ok()?;
+    result.truncate(size);
+    return Some(Bytes::from(result));
+}
+
+/// Serialize into an existing buffer (e.g. from the buffer pool) and return the packet size.
+/// An ERROR message too long for the buffer is truncated, other packets must fit.
+pub fn write_command(command: &Command, buf: &mut [u8]) -> Result<usize, TftpError> {
+    let capacity = buf.len();
+    let too_small = || TftpError::NotDefined(format!("{} bytes buffer too small for {} bytes packet", capacity, packet_len(command)));
+    let mut writer = Writer { buf, position: 0 };
+    let written = match command {
+        Command::ERROR { errorcode, errmsg } => {
+            // Header and final 0
+            let room = capacity.checked_sub(5).ok_or_else(too_small)?;
+            let mut end = errmsg.len().min(room);
+            while !errmsg.is_char_boundary(end) {
+                end -= 1;
+            }
+            write_error(&mut writer, *errorcode, &errmsg[..end])
+        }
+        _ => serialize_command(command, &mut writer),
+    };
+    written.map_err(|_| too_small())?;
+    return Ok(writer.position);
+}
+
+/// Size of the packet of a command, ERROR message included
+fn packet_len(command: &Command) -> usize {
+    let options_len = |options: &Vec<(String, String)>| options.iter().map(|(name, value)| name.len() + value.len() + 2).sum::<usize>();
+    match command {
+        Command::RRQ { filename, mode, options } | Command::WRQ { filename, mode, options } => {
+            return 2 + filename.len() + 1 + mode.len() + 1 + options_len(options);
+        }
+        Command::DATA { blocknum: _, data } => return data.len(),
+        Command::ACK { .. } => return 4,
+        Command::ERROR { errorcode: _, errmsg } => return 4 + errmsg.len() + 1,
+        Command::OACK { options } => return 2 + options_len(options),
+    }
+}
+
+/// The packet does not fit in the caller buffer
+struct BufferFull;
+
+/// Write side of a packet, into a caller buffer
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    position: usize,
+}
+
+impl Writer<'_> {
+    fn write_all(&mut self, bytes: &[u8]) -> Result<(), BufferFull> {
+        let end = self.position + bytes.len();
+        self.buf.get_mut(self.position..end).ok_or(BufferFull)?.copy_from_slice(bytes);
+        self.position = end;
+        return Ok(());
+    }
+
+    fn write_u16(&mut self, value: u16) -> Result<(), BufferFull> {
+        return self.write_all(&value.to_be_bytes());
+    }
+}
+
+fn write_error(writer: &mut Writer<'_>, errorcode: u16, errmsg: &str) -> Result<(), BufferFull> {
+    writer.write_u16(Opcode::ERROR as u16)?;
+    writer.write_u16(errorcode)?;
+    writer.write_all(errmsg.as_bytes())?;
+    return writer.write_all(&[0]);
+}
+
+fn write_options(writer: &mut Writer<'_>, options: &[(String, String)]) -> Result<(), BufferFull> {
+    for (name, value) in options {
+        writer.write_all(name.as_bytes())?;
+        writer.write_all(&[0])?;
+        writer.write_all(value.as_bytes())?;
+        writer.write_all(&[0])?;
+    }
+    return Ok(());
+}
+
+fn serialize_command(command: &Command, writer: &mut Writer<'_>) -> Result<(), BufferFull> {
+    match command {
+        Command::RRQ { filename, mode, options } | Command::WRQ { filename, mode, options } => {
+            let opcode = if matches!(command, Command::RRQ { .. }) { Opcode::RRQ } else { Opcode::WRQ };
+            writer.write_u16(opcode as u16)?;
+            writer.write_all(filename)?;
+            writer.write_all(&[0])?;
+            writer.write_all(mode.as_bytes())?;
+            writer.write_all(&[0])?;
+            return write_options(writer, options);
+        }
+        Command::DATA { blocknum: _, data } => {
+            return writer.write_all(data);
+        }
+        Command::ACK { blocknum } => {
+            writer.write_u16(Opcode::ACK as u16)?;
+            return writer.write_u16(*blocknum);
+        }
+        Command::ERROR { errorcode, errmsg } => {
+            return write_error(writer, *errorcode, errmsg);
+        }
+        Command::OACK { options } => {
+            writer.write_u16(Opcode::OACK as u16)?;
+            return write_options(writer, options);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::codec::*;
+
+    #[test]
+    fn error_command_round_trip() {
+        let buffer = get_buffer_for_command(TftpError::FileNotFound.to_command()).unwrap();
+        match process_buffer(&buffer, buffer.len()) {
+            Command::ERROR{ errorcode, errmsg } => {
+                assert_eq!(errorcode, 1);
+                assert_eq!(errmsg, "File not found");
+            }
+            _ => { panic!("Serialized ERROR was not parsed back as ERROR");}
+        }
+    }
+
+    #[test]
+    fn write_command_into_buffer() {
+        let mut buf = [0; 24];
+        assert_eq!(write_command(&Command::ACK{blocknum: 258}, &mut buf), Ok(4));
+        assert_eq!(&buf[..4], &[0, 4, 1, 2]);
+        let error = TftpError::FileNotFound.to_command();
+        let size = write_command(&error, &mut buf).unwrap();
+        assert_eq!(&buf[..size], &get_buffer_for_command(error).unwrap()[..]);
+        // The message is truncated to fit
+        assert_eq!(write_command(&TftpError::IllegalOperation.to_command(), &mut buf), Ok(24));
+        assert_eq!(&buf, b"\x00\x05\x00\x04Illegal TFTP operat\x00");
+        let error = Command::ERROR{errorcode: 0, errmsg: "d\u{e9}j\u{e0} vu".to_string()};
+        let size = write_command(&error, &mut buf[..8]).unwrap();
+        assert!(matches!(process_buffer(&buf, size), Command::ERROR{ ref errmsg, .. } if errmsg == "d\u{e9}"));
+        assert!(write_command(&error, &mut buf[..4]).is_err());
+        // Other packets are not truncated
+        let oack = Command::OACK{options: vec![("blksize".to_string(), "1468".to_string()), ("tsize".to_string(), "1048576".to_string())]};
+        assert!(matches!(write_command(&oack, &mut buf), Err(TftpError::NotDefined(_))));
+    }
+
+    #[test]
+    fn serialize_round_trip() {
+        let options = vec![("blksize".to_string(), "1024".to_string()), ("tsize".to_string(), "0".to_string())];
+        let commands = [
+            Command::RRQ{filename: b"boot/pxelinux.0".to_vec(), mode: "octet".to_string(), options: options.clone()},
+            Command::WRQ{filename: b"upload\xe9.bin".to_vec(), mode: "netascii".to_string(), options: vec![]},
+            Command::ACK{blocknum: 65535},
+            TftpError::DiskFull.to_command(),
+            Command::ERROR{errorcode: 0, errmsg: "custom".to_string()},
+            Command::OACK{options},
+        ];
+        let mut buf = [0; 64];
+        for command in commands {
+            let size = write_command(&command, &mut buf).unwrap();
+            assert_eq!(&buf[..size], &get_buffer_for_command(command.clone()).unwrap()[..]);
+            assert_eq!(process_buffer(&buf[..size], size), command);
+        }
+        // DATA packets carry their header, the parsed payload does not
+        let data = Command::DATA{blocknum: 7, data: Bytes::from_static(b"\x00\x03\x00\x07payload")};
+        let size = write_command(&data, &mut buf).unwrap();
+        match process_buffer(&buf[..size], size) {
+            Command::DATA{ blocknum, data } => {
+                assert_eq!(blocknum, 7);
+                assert_eq!(data, &b"payload"[..]);
+            }
+            other => { panic!("DATA was not parsed back as DATA, got {:?}", other);}
+        }
+    }
+
+    #[test]
+    fn error_display() {
+        assert_eq!(TftpError::FileNotFound.to_string(), "File not found");
+        assert_eq!(TftpError::NotDefined("Server busy".to_string()).to_string(), "Server busy");
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn error_into_io_error() {
+        fn open() -> std::io::Result<()> {
+            Err(TftpError::FileNotFound)?;
+            return Ok(());
+        }
+        let error = open().unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::NotFound);
+        assert_eq!(error.to_string(), "File not found");
+        assert_eq!(error.into_inner().unwrap().downcast::<TftpError>().unwrap().as_ref(), &TftpError::FileNotFound);
+    }
+
+    #[test]
+    fn mode_must_be_ascii() {
+        let rrq = b"\x00\x01pxelinux.0\x00oct\xc3\xa9t\x00";
+        assert_eq!(process_buffer(rrq, rrq.len()), TftpError::MalformedPacket.to_command());
+    }
+
+    #[test]
+    fn tagged_error() {
+        let command = TftpError::FileNotFound.to_command().tagged("srv-dc1");
+        assert_eq!(command, Command::ERROR { errorcode: 1, errmsg: "[srv-dc1] File not found".to_string() });
+        assert_eq!(Command::ACK { blocknum: 3 }.tagged("srv-dc1"), Command::ACK { blocknum: 3 });
+    }
+
+    #[test]
+    fn truncated_packets() {
+        for packet in [&b""[..], b"\x00", b"\x00\x04", b"\x00\x03\x00", b"\x00\x05"] {
+            assert_eq!(process_buffer(packet, packet.len()), TftpError::MalformedPacket.to_command(), "{:?}", packet);
+        }
+        let error = b"\x00\x05\x00\x00disk \xff\x00";
+        assert_eq!(process_buffer(error, error.len()), Command::ERROR { errorcode: 0, errmsg: "disk \u{fffd}".to_string() });
+    }
+
+    #[test]
+    fn generic_error_messages() {
+        let detailed = TftpError::NotDefined("Cannot send /srv/tftp/pipe: unsupported".to_string()).to_command();
+        assert_eq!(detailed.generic(), Command::ERROR { errorcode: 0, errmsg: "Not defined".to_string() });
+        assert_eq!(TftpError::MalformedPacket.to_command().generic(), TftpError::IllegalOperation.to_command());
+        let busy = Command::ERROR { errorcode: 2, errmsg: "File is busy".to_string() };
+        assert_eq!(busy.generic(), TftpError::AccessViolation.to_command());
+        assert_eq!(TftpError::FileNotFound.to_command().generic(), TftpError::FileNotFound.to_command());
+        assert_eq!(Command::ACK { blocknum: 3 }.generic(), Command::ACK { blocknum: 3 });
+    }
+
+    #[test]
+    fn data_payload_any_size() {
+        for len in [0, 1, 511, 512, 513, 1428, 8192, 65464] {
+            let mut packet = vec![0, 3, 0x12, 0x34];
+            packet.extend((0..len).map(|index| (index % 251) as u8));
+            let expected = Command::DATA { blocknum: 0x1234, data: Bytes::copy_from_slice(&packet[4..]) };
+            assert_eq!(process_buffer(&packet, packet.len()), expected, "{} bytes", len);
+            let packet = Bytes::from(packet);
+            let parsed = process_packet(&packet, Strictness::Lenient);
+            assert_eq!(parsed, expected, "{} bytes", len);
+            // Same memory as the packet
+            if let (Command::DATA { data, .. }, true) = (&parsed, len > 0) {
+                assert_eq!(data.as_ptr(), packet[4..].as_ptr());
+            }
+        }
+    }
+
+    #[test]
+    fn quirks_tolerated_in_lenient_mode() {
+        let rrq = |mode: &str, options: &[(&str, &str)]| Command::RRQ {
+            filename: b"pxelinux.0".to_vec(),
+            mode: mode.to_string(),
+            options: options.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect(),
+        };
+        let quirks: [(&str, &[u8], Command); 7] = [
+            ("trailing bytes", b"\x00\x01pxelinux.0\x00octet\x00x", rrq("octet", &[])),
+            ("option without value", b"\x00\x01pxelinux.0\x00octet\x00blksize\x001024\x00tsize\x00", rrq("octet", &[("blksize", "1024")])),
+            ("NULs after the mode", b"\x00\x01pxelinux.0\x00octet\x00\x00\x00blksize\x001024\x00", rrq("octet", &[("blksize", "1024")])),
+            ("mode without terminator", b"\x00\x01pxelinux.0\x00octet", rrq("octet", &[])),
+            ("unsupported mode", b"\x00\x01pxelinux.0\x00mail\x00", rrq("mail", &[])),
+            ("error message without terminator", b"\x00\x05\x00\x01no such file", Command::ERROR { errorcode: 1, errmsg: "no such file".to_string() }),
+            ("empty error message without terminator", b"\x00\x05\x00\x01", Command::ERROR { errorcode: 1, errmsg: String::new() }),
+        ];
+        for (quirk, packet, command) in quirks {
+            assert_eq!(parse_packet(packet, Strictness::Lenient), command, "{}", quirk);
+            assert!(matches!(parse_packet(packet, Strictness::Strict), Command::ERROR { errorcode: 4, .. }), "{}", quirk);
+        }
+        let unsupported = parse_packet(b"\x00\x02config\x00mail\x00", Strictness::Strict);
+        assert_eq!(unsupported, TftpError::IllegalOperation.to_command());
+        // The RFC makes the mode case-insensitive, not a quirk
+        let upper = b"\x00\x01pxelinux.0\x00OCTET\x00blksize\x001024\x00";
+        assert_eq!(parse_packet(upper, Strictness::Strict), rrq("OCTET", &[("blksize", "1024")]));
+        // Not a request in any mode
+        assert_eq!(parse_packet(b"\x00\x01pxelinux.0", Strictness::Lenient), TftpError::MalformedPacket.to_command());
+    }
+
+    #[test]
+    fn bounded_strings_and_payloads() {
+        let request = |filename: &[u8], mode: &[u8], options: &[u8]| [&b"\x00\x01"[..], filename, b"\x00", mode, b"\x00", options].concat();
+        let malformed = TftpError::MalformedPacket.to_command();
+        assert!(matches!(process_buffer(&request(&[b'a'; MAX_PATH_LEN], b"octet", b""), 0), Command::RRQ { .. }));
+        assert_eq!(process_buffer(&request(&[b'a'; MAX_PATH_LEN + 1], b"octet", b""), 0), malformed);
+        assert_eq!(process_buffer(&request(b"pxelinux.0", &[b'o'; MAX_MODE_LEN + 1], b""), 0), malformed);
+        let options: Vec<u8> = (0..MAX_OPTIONS + 1).flat_map(|index| format!("x-{}\x001\x00", index).into_bytes()).collect();
+        let Command::RRQ { options: parsed, .. } = process_buffer(&request(b"pxelinux.0", b"octet", &options[4..]), 0) else { panic!("RRQ expected") };
+        assert_eq!(parsed.len(), MAX_OPTIONS);
+        assert_eq!(process_buffer(&request(b"pxelinux.0", b"octet", &options), 0), malformed);
+        // 255 bytes which are not UTF-8 are 765 once converted
+        for value in [&[b'1'; MAX_OPTION_LEN + 1][..], &[0xff; MAX_OPTION_LEN]] {
+            let option = [&b"blksize\x00"[..], value, b"\x00"].concat();
+            assert_eq!(process_buffer(&request(b"pxelinux.0", b"octet", &option), 0), malformed);
+        }
+        // A long error message is cut, at a character boundary
+        let error = [&b"\x00\x05\x00\x00\xc3"[..], &"é".repeat(MAX_ERRMSG_LEN).into_bytes(), b"\x00"].concat();
+        let Command::ERROR { errmsg, .. } = process_buffer(&error, 0) else { panic!("ERROR expected") };
+        assert_eq!(errmsg, format!("\u{fffd}{}", "é".repeat((MAX_ERRMSG_LEN - 3) / 2)));
+        let data = [&b"\x00\x03\x00\x01"[..], &[7; MAX_DATA_LEN + 1]].concat();
+        assert_eq!(process_buffer(&data, 0), malformed);
+        assert_eq!(process_packet(&Bytes::from(data), Strictness::Lenient), malformed);
+    }
+
+    /// Allocations of a parsed command, bounded by the caps whatever the packet
+    fn within_caps(command: &Command) -> bool {
+        let options_within = |options: &Vec<(String, String)>| {
+            return options.len() <= MAX_OPTIONS && options.iter().all(|(name, value)| name.len() <= MAX_OPTION_LEN && value.len() <= MAX_OPTION_LEN);
+        };
+        match command {
+            Command::RRQ { filename, mode, options } | Command::WRQ { filename, mode, options } => {
+                return filename.len() <= MAX_PATH_LEN && mode.len() <= MAX_MODE_LEN && options_within(options);
+            }
+            Command::DATA { data, .. } => return data.len() <= MAX_DATA_LEN,
+            Command::ACK { .. } => return true,
+            Command::ERROR { errmsg, .. } => return errmsg.len() <= MAX_ERRMSG_LEN,
+            Command::OACK { options } => return options_within(options),
+        }
+    }
+
+    // Packets of up to 2050 bytes, bounded_strings_and_payloads reaches the caps
+    proptest::proptest! {
+        #[test]
+        fn arbitrary_packets(opcode in 0u16..8, strict in proptest::bool::ANY,
+                             body in proptest::collection::vec(proptest::prop_oneof![3 => proptest::num::u8::ANY, 1 => proptest::strategy::Just(0u8)], 0..2048)) {
+            let packet = [&opcode.to_be_bytes()[..], &body].concat();
+            let strictness = if strict { Strictness::Strict } else { Strictness::Lenient };
+            let parsed = parse_packet(&packet, strictness);
+            proptest::prop_assert!(within_caps(&parsed), "{:?}", parsed);
+            let parsed = process_packet(&Bytes::from(packet), strictness);
+            proptest::prop_assert!(within_caps(&parsed), "{:?}", parsed);
+        }
+    }
+
+    #[test]
+    fn recv_invalid() {
+        // Invalid Opcode
+        let invalid: [u8; 3] = [9,9,9];
+        assert!(matches!(process_buffer(&invalid, 3), Command::ERROR{..}));
+        // Opcode 9
+        let invalid: [u8; 4] = [0,9,0,1];
+        match process_buffer(&invalid, 4) {
+            Command::ERROR{ errorcode, .. } => assert_eq!(errorcode, TftpError::IllegalOperation.error_code()),
+            _ => { panic!("Opcode 9 must return an ERROR command");}
+        }
+    }
+
+    #[test]
+    fn opcode_conversion() {
+        assert_eq!(Opcode::try_from(1), Ok(Opcode::RRQ));
+        assert_eq!(Opcode::try_from(5), Ok(Opcode::ERROR));
+        assert!(Opcode::try_from(0).is_err());
+        assert!(Opcode::try_from(99).is_err());
+    }
+}
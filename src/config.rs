@@ -0,0 +1,200 @@
+//! TOML configuration file, loaded with `--config`
+//!
+//! Every key mirrors a command line flag, flags given on the command line
+//! take precedence over the values read from the file.
+
+use tokio_tftpserver::options::{TftpOption, MAX_BLKSIZE, MIN_BLKSIZE};
+use tokio_tftpserver::remap::RemapRule;
+use tokio_tftpserver::server::ErrorDetail;
+use tokio_tftpserver::socket::BindSpec;
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub bind: Option<Vec<BindSpec>>,
+    pub port: Option<u16>,
+    pub allow_subnet: Option<Vec<ipnet::IpNet>>,
+    #[cfg(unix)]
+    pub systemd_socket: Option<bool>,
+    pub dual_stack: Option<bool>,
+    pub reuse_addr: Option<bool>,
+    pub recv_buffer_size: Option<usize>,
+    pub dscp: Option<u8>,
+    pub ttl: Option<u8>,
+    pub dont_fragment: Option<bool>,
+    pub max_blksize: Option<MaxBlksize>,
+    pub blksize_power_of_two: Option<bool>,
+    pub disable_option: Option<Vec<TftpOption>>,
+    pub no_options: Option<bool>,
+    pub workers: Option<u16>,
+    pub health_addr: Option<std::net::SocketAddr>,
+    #[cfg(unix)]
+    pub control_socket: Option<PathBuf>,
+    pub log_level: Option<log::LevelFilter>,
+    pub log_file: Option<PathBuf>,
+    pub log_stderr: Option<bool>,
+    pub log_rotate_size: Option<u64>,
+    pub log_keep: Option<usize>,
+    pub audit_log: Option<PathBuf>,
+    pub access_log: Option<PathBuf>,
+    pub progress_interval: Option<u64>,
+    pub on_complete: Option<PathBuf>,
+
+    #[cfg(all(unix, feature = "privdrop"))]
+    pub user: Option<String>,
+
+    pub directory: Option<PathBuf>,
+    /// Several directories, as `--directory` repeated, instead of directory. Also `search_dir`
+    #[serde(alias = "search_dir")]
+    pub roots: Option<Vec<PathBuf>>,
+    #[cfg(all(target_os = "linux", feature = "landlock"))]
+    pub landlock: Option<bool>,
+    pub no_create: Option<bool>,
+    pub no_symlinks: Option<bool>,
+    pub serve_hidden: Option<bool>,
+    /// Also `max_filename_len`
+    #[serde(alias = "max_filename_len")]
+    pub max_filename_length: Option<usize>,
+    pub ascii_filenames: Option<bool>,
+    pub strict: Option<bool>,
+    pub zero_copy: Option<bool>,
+    pub create_upload_dirs: Option<bool>,
+    pub atomic_uploads: Option<bool>,
+    pub max_out_of_order: Option<u32>,
+    pub ignore_case: Option<bool>,
+    pub upload_mode: Option<FileMode>,
+    #[cfg(unix)]
+    pub upload_owner: Option<String>,
+    pub umask: Option<FileMode>,
+    pub auto_decompress: Option<bool>,
+    pub lowercase_names: Option<bool>,
+    pub prefix: Option<PathBuf>,
+    pub default_file: Option<PathBuf>,
+    pub fallback_file: Option<PathBuf>,
+    pub max_transfers: Option<usize>,
+    pub reply_busy: Option<bool>,
+    pub server_tag: Option<String>,
+    pub error_detail: Option<ErrorDetail>,
+    pub max_retries: Option<u32>,
+    pub client_quota: Option<u64>,
+    pub max_error_rate: Option<u32>,
+    pub max_error_rate_global: Option<u32>,
+    pub tracked_files: Option<usize>,
+    /// `[[remap]]` tables, tried in order
+    pub remap: Option<Vec<RemapRule>>,
+}
+
+/// Unix permission bits written in octal, e.g. `0660`, set-id and sticky bits excluded
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct FileMode(pub u32);
+
+impl FromStr for FileMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<FileMode, String> {
+        let digits = mode.strip_prefix("0o").unwrap_or(mode);
+        let bits = u32::from_str_radix(digits, 8).map_err(|_| format!("{} is not an octal mode", mode))?;
+        if digits.starts_with('+') || bits > 0o777 {
+            return Err(format!("{} is not a mode between 000 and 0777", mode));
+        }
+        return Ok(FileMode(bits));
+    }
+}
+
+impl TryFrom<String> for FileMode {
+    type Error = String;
+
+    fn try_from(mode: String) -> Result<FileMode, String> {
+        return FileMode::from_str(&mode);
+    }
+}
+
+impl fmt::Display for FileMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "{:04o}", self.0);
+    }
+}
+
+/// Ceiling of the blksize option: bytes, or `auto` for the path MTU of each client less the headers
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "MaxBlksizeValue")]
+pub enum MaxBlksize {
+    Bytes(u16),
+    Auto,
+}
+
+/// max_blksize in the configuration file, a number or "auto"
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MaxBlksizeValue {
+    Bytes(i64),
+    Name(String),
+}
+
+impl FromStr for MaxBlksize {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<MaxBlksize, String> {
+        if value.eq_ignore_ascii_case("auto") {
+            return Ok(MaxBlksize::Auto);
+        }
+        return match value.parse::<u16>() {
+            Ok(bytes) if (MIN_BLKSIZE..=MAX_BLKSIZE).contains(&bytes) => Ok(MaxBlksize::Bytes(bytes)),
+            _ => Err(format!("{} is not auto nor a block size between {} and {}", value, MIN_BLKSIZE, MAX_BLKSIZE)),
+        };
+    }
+}
+
+impl TryFrom<MaxBlksizeValue> for MaxBlksize {
+    type Error = String;
+
+    fn try_from(value: MaxBlksizeValue) -> Result<MaxBlksize, String> {
+        return match value {
+            MaxBlksizeValue::Bytes(bytes) => MaxBlksize::from_str(&bytes.to_string()),
+            MaxBlksizeValue::Name(name) => MaxBlksize::from_str(&name),
+        };
+    }
+}
+
+impl fmt::Display for MaxBlksize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            MaxBlksize::Bytes(bytes) => write!(f, "{}", bytes),
+            MaxBlksize::Auto => f.write_str("auto"),
+        };
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read configuration file {}: {}", path.display(), e))?;
+        let config = Config::parse(&content)
+            .map_err(|e| format!("Invalid configuration file {}: {}", path.display(), e))?;
+        if config.directory.is_some() && config.roots.is_some() {
+            return Err(format!("Invalid configuration file {}: directory and roots cannot both be set", path.display()));
+        }
+        // Relative paths are relative to the configuration file, not to the working directory
+        let base_dir = path.parent().unwrap_or(Path::new(""));
+        return Ok(config.resolve_paths(base_dir));
+    }
+
+    pub fn parse(content: &str) -> Result<Config, toml::de::Error> {
+        return toml::from_str(content);
+    }
+
+    fn resolve_paths(mut self, base_dir: &Path) -> Config {
+        self.directory = self.directory.map(|dir| base_dir.join(dir));
+        self.roots = self.roots.map(|roots| roots.into_iter().map(|dir| base_dir.join(dir)).collect());
+        self.log_file = self.log_file.map(|file| base_dir.join(file));
+        self.audit_log = self.audit_log.map(|file| base_dir.join(file));
+        self.access_log = self.access_log.map(|file| base_dir.join(file));
+        return self;
+    }
+}
@@ -0,0 +1,85 @@
+//! Administration commands on a Unix socket (`--control-socket`), one command per line
+//!
+//! - `sessions`: the transfers in progress, as logged on SIGUSR1
+//! - `cancel IP[:PORT] [silent]`: stop the transfers of this client, all its ports without PORT.
+//!   The client gets an ERROR unless `silent`, a partial upload is removed.
+//!
+//! e.g. `echo 'cancel 10.0.0.42' | socat - UNIX-CONNECT:/run/tftp.sock`
+
+use std::net::{IpAddr, SocketAddr};
+
+use log::{info, warn};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::session::Sessions;
+
+pub async fn serve(listener: UnixListener, sessions: Sessions) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(respond(stream, sessions.clone()));
+            }
+            Err(e) => warn!("Error {e} accepting control connection"),
+        }
+    }
+}
+
+async fn respond(stream: UnixStream, sessions: Sessions) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut response = execute(&line, &sessions);
+        response.push('\n');
+        if writer.write_all(response.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Response to a command line, several lines for `sessions`
+pub fn execute(line: &str, sessions: &Sessions) -> String {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match words.as_slice() {
+        ["sessions"] => {
+            let snapshot = sessions.snapshot();
+            let mut response = format!("{} active sessions", snapshot.len());
+            for session in snapshot {
+                response.push_str(&format!("\n{}", session));
+            }
+            return response;
+        }
+        ["cancel", peer] | ["cancel", peer, "silent"] => {
+            let (ip, port) = match (peer.parse::<SocketAddr>(), peer.parse::<IpAddr>()) {
+                (Ok(addr), _) => (addr.ip(), Some(addr.port())),
+                (_, Ok(ip)) => (ip, None),
+                _ => return format!("error: {} is not IP or IP:PORT", peer),
+            };
+            let cancelled = sessions.cancel(ip, port, words.len() == 2);
+            if cancelled.is_empty() {
+                return format!("error: no transfer with {}", peer);
+            }
+            let ids: Vec<String> = cancelled.iter().map(|id| format!("#{}", id)).collect();
+            info!("Cancelling the transfers with {}: {}", peer, ids.join(" "));
+            return format!("cancelled {}", ids.join(" "));
+        }
+        _ => return format!("error: unknown command {:?}, expected sessions or cancel IP[:PORT] [silent]", line.trim()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::control::*;
+
+    #[test]
+    fn commands() {
+        let sessions = Sessions::new();
+        assert_eq!(execute("sessions", &sessions), "0 active sessions");
+        assert_eq!(execute("cancel 10.0.0.42:2001", &sessions), "error: no transfer with 10.0.0.42:2001");
+        assert!(execute("cancel tftp.example.com", &sessions).starts_with("error: tftp.example.com is not"));
+        assert!(execute("stop 10.0.0.42", &sessions).starts_with("error: unknown command \"stop 10.0.0.42\""));
+    }
+}
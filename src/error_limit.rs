@@ -0,0 +1,139 @@
+//! Rate of the ERROR packets sent to clients without an established transfer (anti-amplification)
+//!
+//! A request with a spoofed source answered with an ERROR sends a packet to the victim. Each client IP
+//! has a token bucket of `per_ip` errors per second, and all of them share a bucket of `global` errors
+//! per second; beyond them the ERROR is not sent. The buckets of the `MAX_TRACKED` most recently
+//! refused or answered IPs are kept, so a flood of sources cannot grow the memory use.
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Client IPs with a bucket, the least recently seen one is dropped beyond
+pub const MAX_TRACKED: usize = 4096;
+
+#[derive(Debug)]
+pub struct ErrorLimiter {
+    per_ip: u32,
+    global: u32,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    global: Bucket,
+    /// Bucket and last use of each client
+    clients: HashMap<IpAddr, (Bucket, u64)>,
+    /// Clients by last use, the first one is evicted
+    recent: BTreeMap<u64, IpAddr>,
+    uses: u64,
+}
+
+/// Tokens refilled at rate per second, up to rate (a burst of one second)
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Bucket {
+    fn full(rate: u32, now: Instant) -> Bucket {
+        return Bucket { tokens: rate as f64, refilled: now };
+    }
+
+    fn refill(&mut self, rate: u32, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+        self.refilled = now;
+    }
+}
+
+impl ErrorLimiter {
+    /// ERROR packets per second to a client IP and to all of them
+    pub fn new(per_ip: u32, global: u32) -> Arc<ErrorLimiter> {
+        let state = State { global: Bucket::full(global, Instant::now()), clients: HashMap::new(), recent: BTreeMap::new(), uses: 0 };
+        return Arc::new(ErrorLimiter { per_ip, global, state: Mutex::new(state) });
+    }
+
+    /// An ERROR can be sent to this client now, it is then counted
+    pub fn allow(&self, ip: IpAddr) -> bool {
+        return self.allow_at(ip, Instant::now());
+    }
+
+    /// Client IPs with a bucket
+    pub fn tracked(&self) -> usize {
+        return self.state.lock().unwrap_or_else(|e| e.into_inner()).clients.len();
+    }
+
+    fn allow_at(&self, ip: IpAddr, now: Instant) -> bool {
+        // An IPv4 client reaching a dual stack socket is the same client
+        let ip = ip.to_canonical();
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let State { global, clients, recent, uses } = &mut *state;
+        *uses += 1;
+        match clients.get_mut(&ip) {
+            Some((_, used)) => {
+                recent.remove(used);
+                *used = *uses;
+            }
+            None => {
+                if clients.len() >= MAX_TRACKED {
+                    if let Some((_, evicted)) = recent.pop_first() {
+                        clients.remove(&evicted);
+                    }
+                }
+                clients.insert(ip, (Bucket::full(self.per_ip, now), *uses));
+            }
+        }
+        recent.insert(*uses, ip);
+        let client = &mut clients.get_mut(&ip).unwrap().0;
+        client.refill(self.per_ip, now);
+        global.refill(self.global, now);
+        if client.tokens < 1.0 || global.tokens < 1.0 {
+            return false;
+        }
+        client.tokens -= 1.0;
+        global.tokens -= 1.0;
+        return true;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::error_limit::*;
+    use std::time::Duration;
+
+    #[test]
+    fn per_ip_and_global_buckets() {
+        let limiter = ErrorLimiter::new(3, 5);
+        let now = Instant::now();
+        let client: IpAddr = "10.0.0.42".parse().unwrap();
+        let other: IpAddr = "10.0.0.43".parse().unwrap();
+        assert_eq!((0..10).filter(|_| limiter.allow_at(client, now)).count(), 3);
+        // Same client through a dual stack socket
+        assert!(!limiter.allow_at("::ffff:10.0.0.42".parse().unwrap(), now));
+        // What is left of the global bucket
+        assert_eq!((0..10).filter(|_| limiter.allow_at(other, now)).count(), 2);
+        // Refilled at the rate
+        let later = now + Duration::from_millis(400);
+        assert!(limiter.allow_at(client, later));
+        assert!(!limiter.allow_at(client, later));
+        assert!(limiter.allow_at(other, later));
+        assert!(!limiter.allow_at(other, later));
+    }
+
+    #[test]
+    fn least_recently_seen_evicted() {
+        let limiter = ErrorLimiter::new(1, u32::MAX);
+        let now = Instant::now();
+        let first: IpAddr = "10.0.0.1".parse().unwrap();
+        assert!(limiter.allow_at(first, now));
+        for index in 0..MAX_TRACKED as u32 {
+            limiter.allow_at(IpAddr::from(std::net::Ipv6Addr::from((0xfd00u128 << 112) + index as u128)), now);
+        }
+        assert_eq!(limiter.tracked(), MAX_TRACKED);
+        // Forgotten, it gets a full bucket again
+        assert!(limiter.allow_at(first, now));
+    }
+}
@@ -0,0 +1,210 @@
+//! Reproducible packet faults on the transfer sockets, for tests only (`chaos` feature)
+//!
+//! Unlike `inject`, which delays or randomly drops the packets sent by a running server, the faults
+//! are chosen by packet number, counted from 1 in each direction of each transfer, or drawn from a
+//! generator seeded per transfer: the same injector gives the same faults at each run. Packets
+//! received by the server go through the faults too, a duplicated one is handled twice.
+//! Without the feature this module is not built and the transfer sockets are used directly.
+
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::debug;
+use tokio::net::UdpSocket;
+
+/// Packets of one direction of a transfer, numbered from 1
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Packets {
+    /// These packet numbers
+    pub numbers: Vec<u64>,
+    /// Every Nth packet: N, 2N, 3N...
+    pub every: Option<u64>,
+    /// Each packet with this probability, from 0.0 (never) to 1.0 (always), drawn from the seeded generator
+    pub probability: f64,
+}
+
+impl Packets {
+    pub fn numbers(numbers: &[u64]) -> Packets {
+        return Packets { numbers: numbers.to_vec(), ..Packets::default() };
+    }
+
+    pub fn every(every: u64) -> Packets {
+        return Packets { every: Some(every), ..Packets::default() };
+    }
+
+    pub fn probability(probability: f64) -> Packets {
+        return Packets { probability, ..Packets::default() };
+    }
+
+    fn contains(&self, number: u64, rng: &mut Rng) -> bool {
+        // Drawn for each packet whatever the other rules, the sequence only depends on the seed
+        let drawn = self.probability > 0.0 && rng.next_unit() < self.probability;
+        return drawn || self.numbers.contains(&number) || self.every.is_some_and(|every| every > 0 && number.is_multiple_of(every));
+    }
+}
+
+/// Faults of the packets going one way
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Faults {
+    pub drop: Packets,
+    /// Sent twice, or handed twice to the transfer when received
+    pub duplicate: Packets,
+    /// Before each packet
+    pub delay: Duration,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FaultInjector {
+    /// Of the generator of each transfer
+    pub seed: u64,
+    /// Packets sent by the server
+    pub send: Faults,
+    /// Packets received from the client
+    pub recv: Faults,
+}
+
+/// What happens to a packet
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Fate {
+    Deliver,
+    Drop,
+    Duplicate,
+}
+
+impl Faults {
+    fn fate(&self, number: u64, rng: &mut Rng) -> Fate {
+        if self.drop.contains(number, rng) {
+            return Fate::Drop;
+        }
+        if self.duplicate.contains(number, rng) {
+            return Fate::Duplicate;
+        }
+        return Fate::Deliver;
+    }
+}
+
+/// xorshift64*, a seed of 0 is replaced since it would only give 0
+#[derive(Debug)]
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        return Rng(if seed == 0 { 0x9e37_79b9_7f4a_7c15 } else { seed });
+    }
+
+    /// Uniform in [0, 1)
+    fn next_unit(&mut self) -> f64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        return (self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11) as f64 / (1u64 << 53) as f64;
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    sent: u64,
+    received: u64,
+    rng: Rng,
+    /// Received packet to hand to the transfer again
+    duplicate: Option<Vec<u8>>,
+}
+
+/// Faults of one transfer, its own counters and generator
+#[derive(Debug)]
+pub(crate) struct TransferFaults {
+    injector: Arc<FaultInjector>,
+    state: Mutex<State>,
+}
+
+impl TransferFaults {
+    pub(crate) fn new(injector: Arc<FaultInjector>) -> TransferFaults {
+        let state = State { sent: 0, received: 0, rng: Rng::new(injector.seed), duplicate: None };
+        return TransferFaults { injector, state: Mutex::new(state) };
+    }
+
+    pub(crate) async fn send(&self, socket: &UdpSocket, packet: &[u8]) -> io::Result<usize> {
+        let (number, fate) = {
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            state.sent += 1;
+            let number = state.sent;
+            (number, self.injector.send.fate(number, &mut state.rng))
+        };
+        if !self.injector.send.delay.is_zero() {
+            tokio::time::sleep(self.injector.send.delay).await;
+        }
+        match fate {
+            Fate::Deliver => return socket.send(packet).await,
+            Fate::Drop => {
+                debug!("Injected drop of sent packet {}", number);
+                return Ok(packet.len());
+            }
+            Fate::Duplicate => {
+                debug!("Injected duplicate of sent packet {}", number);
+                socket.send(packet).await?;
+                return socket.send(packet).await;
+            }
+        }
+    }
+
+    /// A dropped packet is skipped, the call waits for the next one. Cancelled during the delay,
+    /// the packet is lost as if dropped
+    pub(crate) async fn recv(&self, socket: &UdpSocket, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(packet) = self.state.lock().unwrap_or_else(|e| e.into_inner()).duplicate.take() {
+                let size = packet.len().min(buf.len());
+                buf[..size].copy_from_slice(&packet[..size]);
+                return Ok(size);
+            }
+            let size = socket.recv(buf).await?;
+            let (number, fate) = {
+                let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+                state.received += 1;
+                let number = state.received;
+                (number, self.injector.recv.fate(number, &mut state.rng))
+            };
+            if !self.injector.recv.delay.is_zero() {
+                tokio::time::sleep(self.injector.recv.delay).await;
+            }
+            match fate {
+                Fate::Deliver => return Ok(size),
+                Fate::Drop => debug!("Injected drop of received packet {}", number),
+                Fate::Duplicate => {
+                    debug!("Injected duplicate of received packet {}", number);
+                    self.state.lock().unwrap_or_else(|e| e.into_inner()).duplicate = Some(buf[..size].to_vec());
+                    return Ok(size);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::fault::*;
+
+    fn fates(faults: &Faults, seed: u64, count: u64) -> Vec<Fate> {
+        let mut rng = Rng::new(seed);
+        return (1..=count).map(|number| faults.fate(number, &mut rng)).collect();
+    }
+
+    #[test]
+    fn packets_by_number() {
+        let faults = Faults { drop: Packets::numbers(&[1]), duplicate: Packets::every(3), ..Faults::default() };
+        assert_eq!(fates(&faults, 0, 6), [Fate::Drop, Fate::Deliver, Fate::Duplicate, Fate::Deliver, Fate::Deliver, Fate::Duplicate]);
+        // Dropped rather than duplicated
+        let faults = Faults { drop: Packets::every(2), duplicate: Packets::every(2), ..Faults::default() };
+        assert_eq!(fates(&faults, 0, 2), [Fate::Deliver, Fate::Drop]);
+    }
+
+    #[test]
+    fn reproducible_with_a_seed() {
+        let faults = Faults { drop: Packets::probability(0.5), ..Faults::default() };
+        let first = fates(&faults, 42, 200);
+        assert_eq!(first, fates(&faults, 42, 200));
+        assert_ne!(first, fates(&faults, 43, 200));
+        let dropped = first.iter().filter(|fate| **fate == Fate::Drop).count();
+        assert!((60..140).contains(&dropped), "{}", dropped);
+    }
+}
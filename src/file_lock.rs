@@ -0,0 +1,66 @@
+//! Advisory locks (flock) on the transferred files, held for the duration of a transfer
+//!
+//! A read takes a shared lock and an upload an exclusive one, without waiting: a file locked by another
+//! transfer, or by another process using flock, is busy and the request is refused. The lock is held by
+//! a descriptor of its own and released when it is dropped. Without flock (not Unix) locking always succeeds.
+
+use std::fs::File;
+use std::io;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LockMode {
+    /// Readers, any number of them
+    Shared,
+    /// A single writer, without readers
+    Exclusive,
+}
+
+/// Released when dropped, whether the transfer completed or not
+#[derive(Debug)]
+pub struct FileLock {
+    _file: File,
+}
+
+/// Lock the opened file, fails with ErrorKind::WouldBlock when a conflicting lock is held
+#[cfg(unix)]
+pub fn try_lock(file: File, mode: LockMode) -> io::Result<FileLock> {
+    use std::os::fd::AsRawFd;
+    let operation = match mode {
+        LockMode::Shared => libc::LOCK_SH,
+        LockMode::Exclusive => libc::LOCK_EX,
+    };
+    // SAFETY: flock on a descriptor owned by file
+    if unsafe { libc::flock(file.as_raw_fd(), operation | libc::LOCK_NB) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    return Ok(FileLock { _file: file });
+}
+
+#[cfg(not(unix))]
+pub fn try_lock(file: File, _mode: LockMode) -> io::Result<FileLock> {
+    return Ok(FileLock { _file: file });
+}
+
+#[cfg(test)]
+mod test {
+    use crate::file_lock::*;
+    use std::io::ErrorKind;
+
+    #[cfg(unix)]
+    #[test]
+    fn conflicting_locks() {
+        let path = format!("target/tftp-flock-{}.bin", std::process::id());
+        std::fs::write(&path, b"content").unwrap();
+        let open = || File::open(&path).unwrap();
+        let first = try_lock(open(), LockMode::Shared).unwrap();
+        let second = try_lock(open(), LockMode::Shared).unwrap();
+        assert_eq!(try_lock(open(), LockMode::Exclusive).unwrap_err().kind(), ErrorKind::WouldBlock);
+        drop((first, second));
+        let writer = try_lock(open(), LockMode::Exclusive).unwrap();
+        assert_eq!(try_lock(open(), LockMode::Exclusive).unwrap_err().kind(), ErrorKind::WouldBlock);
+        assert_eq!(try_lock(open(), LockMode::Shared).unwrap_err().kind(), ErrorKind::WouldBlock);
+        drop(writer);
+        assert!(try_lock(open(), LockMode::Shared).is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
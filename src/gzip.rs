@@ -0,0 +1,109 @@
+//! Files served decompressed from their gzip version, for `ServerOptions::auto_decompress`
+//!
+//! The content is decompressed while the blocks are sent, never held in memory.
+//! Blocks are read in order, a retransmitted block comes from the last block kept aside,
+//! going further back decompresses again from the start of the file.
+
+use std::fs::File;
+use std::io::{self, BufReader, ErrorKind, Read};
+use std::path::{Path, PathBuf};
+
+use flate2::read::MultiGzDecoder;
+
+use crate::beneath::{self, Access, Symlinks};
+
+#[derive(Debug)]
+pub struct GzipFile {
+    /// Served directory holding the file, empty for the working directory
+    root: PathBuf,
+    /// Inside root
+    path: PathBuf,
+    symlinks: Symlinks,
+    decoder: MultiGzDecoder<BufReader<File>>,
+    /// Decompressed bytes already read from the decoder
+    position: u64,
+    /// Offset and content of the last block read
+    last_block: Option<(u64, Vec<u8>)>,
+    /// Decompressed size once counted by size or reached by read_at, or the failure of size
+    size: Option<Result<u64, String>>,
+}
+
+impl GzipFile {
+    pub fn open(root: &Path, path: &Path, symlinks: Symlinks) -> io::Result<GzipFile> {
+        return Ok(GzipFile {
+            root: root.to_path_buf(),
+            path: path.to_path_buf(),
+            symlinks,
+            decoder: decoder(root, path, symlinks)?,
+            position: 0,
+            last_block: None,
+            size: None,
+        });
+    }
+
+    pub fn path(&self) -> PathBuf {
+        return self.root.join(&self.path);
+    }
+
+    /// Decompressed size, unless already known the whole file is decompressed to count it: a long
+    /// blocking call for a large file. A failure is kept for known_size
+    pub fn size(&mut self) -> io::Result<u64> {
+        if let Some(size) = self.known_size()? {
+            return Ok(size);
+        }
+        match io::copy(&mut decoder(&self.root, &self.path, self.symlinks)?, &mut io::sink()) {
+            Ok(size) => {
+                self.size = Some(Ok(size));
+                return Ok(size);
+            }
+            Err(e) => {
+                self.size = Some(Err(e.to_string()));
+                return Err(e);
+            }
+        }
+    }
+
+    /// Decompressed size without decompressing anything: counted by size, or found by read_at at the end
+    /// of the content, None before
+    pub fn known_size(&self) -> io::Result<Option<u64>> {
+        match &self.size {
+            Some(Ok(size)) => return Ok(Some(*size)),
+            Some(Err(e)) => return Err(io::Error::new(ErrorKind::InvalidData, e.clone())),
+            None => return Ok(None),
+        }
+    }
+
+    /// Fill buf with the decompressed content from offset, shorter only at the end of the content
+    pub fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some((last_offset, block)) = &self.last_block {
+            if *last_offset == offset && block.len() <= buf.len() {
+                buf[..block.len()].copy_from_slice(block);
+                return Ok(block.len());
+            }
+        }
+        if offset < self.position {
+            self.decoder = decoder(&self.root, &self.path, self.symlinks)?;
+            self.position = 0;
+        }
+        self.position += io::copy(&mut (&mut self.decoder).take(offset - self.position), &mut io::sink())?;
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self.decoder.read(&mut buf[filled..]) {
+                Ok(0) => break,
+                Ok(read) => filled += read,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        self.position += filled as u64;
+        if filled < buf.len() && self.size.is_none() {
+            self.size = Some(Ok(self.position));
+        }
+        self.last_block = Some((offset, buf[..filled].to_vec()));
+        return Ok(filled);
+    }
+}
+
+fn decoder(root: &Path, path: &Path, symlinks: Symlinks) -> io::Result<MultiGzDecoder<BufReader<File>>> {
+    return Ok(MultiGzDecoder::new(BufReader::new(beneath::open_in(root, path, Access::Read, symlinks)?)));
+}
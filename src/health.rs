@@ -0,0 +1,56 @@
+//! Minimal TCP liveness endpoint (`--health-addr`)
+//!
+//! Any connection gets an HTTP 200 "ok" while the UDP loop is running, 503 otherwise.
+//! The request content is not interpreted, so it works with an HTTP probe as well as a TCP one.
+
+use log::warn;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Marks the UDP loop as alive for its lifetime
+pub struct AliveGuard {
+    alive: Arc<AtomicBool>,
+}
+
+impl AliveGuard {
+    pub fn new(alive: Arc<AtomicBool>) -> AliveGuard {
+        alive.store(true, Ordering::SeqCst);
+        return AliveGuard { alive };
+    }
+}
+
+impl Drop for AliveGuard {
+    fn drop(&mut self) {
+        self.alive.store(false, Ordering::SeqCst);
+    }
+}
+
+pub async fn serve(listener: TcpListener, alive: Arc<AtomicBool>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(respond(stream, alive.load(Ordering::SeqCst)));
+            }
+            Err(e) => warn!("Error {e} accepting health check connection"),
+        }
+    }
+}
+
+async fn respond(mut stream: TcpStream, alive: bool) {
+    // Drain (part of) the probe request, its content does not matter
+    let mut request = [0; 512];
+    let _ = stream.read(&mut request).await;
+    let (status, body) = match alive {
+        true => ("200 OK", "ok"),
+        false => ("503 Service Unavailable", "down"),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+}
@@ -0,0 +1,115 @@
+//! `--on-complete`: a program run after each completed transfer, e.g. to validate an uploaded image
+//!
+//! The program is run without a shell, with the arguments `FILENAME CLIENT_IP:PORT RRQ|WRQ`
+//! (FILENAME as requested) and the same values in the environment: `TFTP_FILENAME`, `TFTP_PEER`,
+//! `TFTP_REQUEST`, plus `TFTP_BYTES` and `TFTP_OUTCOME` (`OK`). Failed transfers do not run it.
+//! The transfers never wait for it, a program failing to start or exiting with an error is only logged.
+
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+use tokio::sync::mpsc::Receiver;
+use tokio::task::JoinHandle;
+use tokio_tftpserver::server::TransferResult;
+
+/// Command running program for a completed transfer
+pub fn command(program: &Path, result: &TransferResult) -> Command {
+    let request = if result.write { "WRQ" } else { "RRQ" };
+    let peer = result.peer.to_string();
+    let mut command = Command::new(program);
+    command.args([result.filename.as_str(), &peer, request])
+        .env("TFTP_FILENAME", &result.filename)
+        .env("TFTP_PEER", &peer)
+        .env("TFTP_REQUEST", request)
+        .env("TFTP_BYTES", result.bytes.to_string())
+        .env("TFTP_OUTCOME", "OK")
+        .stdin(std::process::Stdio::null());
+    return command;
+}
+
+/// Run program for each completed transfer received, each one waited for by a task of its own
+pub fn spawn_runner(program: PathBuf, mut results: Receiver<TransferResult>) -> JoinHandle<()> {
+    return tokio::spawn(async move {
+        while let Some(result) = results.recv().await {
+            if result.error.is_some() {
+                continue;
+            }
+            let mut child = match command(&program, &result).spawn() {
+                Ok(child) => child,
+                Err(e) => {
+                    log::warn!("Cannot run {} after the transfer of {}: {}", program.display(), result.filename, e);
+                    continue;
+                }
+            };
+            let program = program.clone();
+            tokio::spawn(async move {
+                match child.wait().await {
+                    Ok(status) if status.success() => log::debug!("{} done for {}", program.display(), result.filename),
+                    Ok(status) => log::warn!("{} failed for {}: {}", program.display(), result.filename, status),
+                    Err(e) => log::warn!("Cannot wait for {} run for {}: {}", program.display(), result.filename, e),
+                }
+            });
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use crate::hook::*;
+    use std::net::SocketAddr;
+    use std::time::{Duration, SystemTime};
+
+    fn result(filename: &str) -> TransferResult {
+        return TransferResult {
+            transfer_id: 1,
+            peer: "10.0.0.42:40123".parse::<SocketAddr>().unwrap(),
+            write: true,
+            filename: filename.to_string(),
+            bytes: 1300,
+            retransmits: 0,
+            duration: Duration::from_millis(20),
+            finished: SystemTime::now(),
+            error: None,
+            error_code: None,
+        };
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn run_with_the_filename() {
+        let output = command(Path::new("echo"), &result("images/switch.bin")).output().await.unwrap();
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "images/switch.bin 10.0.0.42:40123 WRQ\n");
+        let command = command(Path::new("echo"), &result("images/switch.bin"));
+        let env: Vec<_> = command.as_std().get_envs().map(|(name, value)| (name.to_str().unwrap(), value.unwrap().to_str().unwrap())).collect();
+        assert!(env.contains(&("TFTP_FILENAME", "images/switch.bin")));
+        assert!(env.contains(&("TFTP_OUTCOME", "OK")));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn only_completed_transfers() {
+        let (sender, receiver) = tokio::sync::mpsc::channel(4);
+        let script = std::env::temp_dir().join(format!("tftp-on-complete-{}.sh", std::process::id()));
+        let marks = std::env::temp_dir().join(format!("tftp-on-complete-{}.txt", std::process::id()));
+        std::fs::write(&script, format!("#!/bin/sh\necho \"$1\" >> {}\n", marks.display())).unwrap();
+        std::fs::set_permissions(&script, std::os::unix::fs::PermissionsExt::from_mode(0o755)).unwrap();
+        let runner = spawn_runner(script.clone(), receiver);
+        let mut failed = result("failed.bin");
+        failed.error = Some("Transfer timed out".to_string());
+        sender.send(failed).await.unwrap();
+        sender.send(result("config/router.cfg")).await.unwrap();
+        drop(sender);
+        runner.await.unwrap();
+        let mut content = String::new();
+        for _ in 0..100 {
+            content = std::fs::read_to_string(&marks).unwrap_or_default();
+            if !content.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        std::fs::remove_file(&script).unwrap();
+        let _ = std::fs::remove_file(&marks);
+        assert_eq!(content, "config/router.cfg\n");
+    }
+}
@@ -0,0 +1,50 @@
+//! Fault injection on the packets sent by transfers, to test the retransmission logic of clients
+//!
+//! A development aid: delayed and dropped packets slow down or break every transfer,
+//! never enable it on a production server.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::debug;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Injection {
+    /// Sleep before sending each packet
+    pub delay: Duration,
+    /// Probability to drop each packet, from 0.0 (never) to 1.0 (always)
+    pub drop_probability: f64,
+}
+
+impl Injection {
+    pub fn is_active(&self) -> bool {
+        return !self.delay.is_zero() || self.drop_probability > 0.0;
+    }
+
+    /// Apply the delay, false when the packet must be dropped instead of sent
+    pub(crate) async fn before_send(&self) -> bool {
+        if !self.delay.is_zero() {
+            tokio::time::sleep(self.delay).await;
+        }
+        if self.drop_probability > 0.0 && random_unit() < self.drop_probability {
+            debug!("Injected packet drop");
+            return false;
+        }
+        return true;
+    }
+}
+
+/// Uniform in [0, 1), xorshift64* seeded from the clock, good enough to drop packets
+fn random_unit() -> f64 {
+    static STATE: AtomicU64 = AtomicU64::new(0);
+    let mut x = STATE.load(Ordering::Relaxed);
+    if x == 0 {
+        x = SystemTime::now().duration_since(UNIX_EPOCH).map_or(1, |now| now.as_nanos() as u64) | 1;
+    }
+    x ^= x >> 12;
+    x ^= x << 25;
+    x ^= x >> 27;
+    // Concurrent callers may get the same value, which does not matter here
+    STATE.store(x, Ordering::Relaxed);
+    return (x.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11) as f64 / (1u64 << 53) as f64;
+}
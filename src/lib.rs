@@ -0,0 +1,66 @@
+//! A TFTP server based on Async tokio, usable as a library
+//!
+//! The `tokio_tftpserver` binary adds the command line, configuration file,
+//! logging backend and privilege drop on top of it.
+//!
+//! Without the default `std` feature only the packet codec (`codec`) is built,
+//! with `core` and `alloc`.
+
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+#![warn(rust_2018_idioms)]
+// Explicit returns and RFC opcode names (RRQ, WRQ...) are the house style
+#![allow(clippy::needless_return, clippy::upper_case_acronyms)]
+
+extern crate alloc;
+
+pub mod codec;
+#[cfg(feature = "std")]
+pub mod beneath;
+#[cfg(feature = "std")]
+pub mod buffer_pool;
+#[cfg(all(feature = "std", unix))]
+pub mod control;
+#[cfg(feature = "std")]
+pub mod error_limit;
+#[cfg(feature = "chaos")]
+pub mod fault;
+#[cfg(feature = "std")]
+pub mod file_lock;
+#[cfg(feature = "std")]
+pub mod gzip;
+#[cfg(feature = "std")]
+pub mod health;
+#[cfg(feature = "std")]
+pub mod inject;
+#[cfg(feature = "std")]
+pub mod netascii;
+#[cfg(feature = "std")]
+pub mod options;
+#[cfg(feature = "std")]
+pub mod quota;
+#[cfg(feature = "std")]
+pub mod read_ahead;
+#[cfg(feature = "std")]
+pub mod remap;
+#[cfg(feature = "std")]
+pub mod server;
+#[cfg(feature = "std")]
+pub mod session;
+#[cfg(feature = "std")]
+pub mod socket;
+#[cfg(feature = "std")]
+pub mod stats;
+#[cfg(feature = "std")]
+pub mod stream;
+#[cfg(feature = "std")]
+pub mod testing;
+#[cfg(feature = "std")]
+pub mod tftp;
+#[cfg(feature = "std")]
+pub mod variables;
+#[cfg(feature = "std")]
+pub mod virtual_file;
+#[cfg(feature = "std")]
+pub mod write_lock;
+#[cfg(feature = "std")]
+pub mod zero_copy;
@@ -0,0 +1,268 @@
+//! `log` backend writing to stderr and/or a log file rotated by size
+//!
+//! Rotation renames `file` to `file.1`, `file.1` to `file.2`... keeping `keep` old files,
+//! then reopens `file`. It happens under the same lock as the writes so no line is lost.
+
+use log::{LevelFilter, Log, Metadata, Record};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio_tftpserver::server::current_transfer_id;
+
+pub struct LogConfig {
+    pub level: LevelFilter,
+    pub file: Option<PathBuf>,
+    /// Also log to stderr when logging to a file
+    pub stderr: bool,
+    pub rotate_size: Option<u64>,
+    pub keep: usize,
+}
+
+pub struct Logger {
+    level: LevelFilter,
+    stderr: bool,
+    file: Option<Mutex<RotatingFile>>,
+}
+
+impl Logger {
+    pub fn new(config: &LogConfig) -> io::Result<Logger> {
+        let file = match &config.file {
+            Some(path) => Some(Mutex::new(RotatingFile::open(path, config.rotate_size, config.keep)?)),
+            None => None,
+        };
+        // Without a log file, stderr is the only output
+        let stderr = config.stderr || file.is_none();
+        return Ok(Logger { level: config.level, stderr, file });
+    }
+
+    /// Install as the global logger
+    pub fn init(config: &LogConfig) -> Result<(), Box<dyn std::error::Error>> {
+        let logger = Logger::new(config)?;
+        log::set_boxed_logger(Box::new(logger))?;
+        log::set_max_level(config.level);
+        return Ok(());
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        return metadata.level() <= self.level;
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = format_line(SystemTime::now(), record, current_transfer_id());
+        if self.stderr {
+            let _ = io::stderr().write_all(line.as_bytes());
+        }
+        if let Some(file) = &self.file {
+            let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+            if let Err(e) = file.write_line(&line) {
+                let _ = writeln!(io::stderr(), "Error {} writing to log file {}", e, file.path.display());
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = &self.file {
+            let _ = file.lock().unwrap_or_else(|e| e.into_inner()).file.flush();
+        }
+    }
+}
+
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: Option<u64>,
+    keep: usize,
+}
+
+impl RotatingFile {
+    pub fn open(path: &Path, max_size: Option<u64>, keep: usize) -> io::Result<RotatingFile> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        return Ok(RotatingFile { path: path.to_path_buf(), file, size, max_size, keep });
+    }
+
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if let Some(max_size) = self.max_size {
+            // A line is never split, a line longer than max_size gets its own file
+            if self.size > 0 && self.size + line.len() as u64 > max_size {
+                if let Err(e) = self.rotate() {
+                    // Keep writing to the current file rather than losing lines
+                    let _ = writeln!(io::stderr(), "Error {} rotating log file {}", e, self.path.display());
+                }
+            }
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        return Ok(());
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", index));
+        return PathBuf::from(name);
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for index in (1..self.keep).rev() {
+                let from = self.rotated_path(index);
+                if from.exists() {
+                    fs::rename(&from, self.rotated_path(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        return Ok(());
+    }
+}
+
+/// Log line, tagged with the transfer ID when logged from a transfer
+fn format_line(time: SystemTime, record: &Record<'_>, transfer_id: Option<u64>) -> String {
+    let message = escape_controls(&record.args().to_string());
+    return match transfer_id {
+        Some(id) => format!("{} {:<5} [#{}] {}\n", format_timestamp(time), record.level(), id, message),
+        None => format!("{} {:<5} {}\n", format_timestamp(time), record.level(), message),
+    };
+}
+
+/// Control characters of a message as `\xNN`, a requested filename cannot split a line or reach the terminal
+fn escape_controls(message: &str) -> String {
+    if !message.chars().any(char::is_control) {
+        return message.to_string();
+    }
+    let mut escaped = String::with_capacity(message.len() + 8);
+    for c in message.chars() {
+        match c {
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    return escaped;
+}
+
+/// RFC 3339 UTC timestamp with milliseconds, e.g. 2024-06-01T12:34:56.789Z
+pub fn format_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, day_secs) = (secs / 86400, secs % 86400);
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    return format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        day_secs / 3600,
+        day_secs % 3600 / 60,
+        day_secs % 60,
+        since_epoch.subsec_millis()
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use crate::logging::*;
+    use log::Level;
+    use std::time::Duration;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tftp-logging-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        return dir;
+    }
+
+    #[test]
+    fn timestamp_format() {
+        assert_eq!(format_timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        let time = UNIX_EPOCH + Duration::from_millis(1_709_210_096_789);
+        assert_eq!(format_timestamp(time), "2024-02-29T12:34:56.789Z");
+    }
+
+    #[test]
+    fn line_transfer_id() {
+        let record = Record::builder().level(Level::Info).args(format_args!("Served pxelinux.0")).build();
+        assert_eq!(format_line(UNIX_EPOCH, &record, Some(12)), "1970-01-01T00:00:00.000Z INFO  [#12] Served pxelinux.0\n");
+        assert_eq!(format_line(UNIX_EPOCH, &record, None), "1970-01-01T00:00:00.000Z INFO  Served pxelinux.0\n");
+    }
+
+    #[test]
+    fn line_control_characters() {
+        let record = Record::builder().level(Level::Info).args(format_args!("Transfer of evil\n\x1b[2Jname")).build();
+        assert_eq!(format_line(UNIX_EPOCH, &record, None), "1970-01-01T00:00:00.000Z INFO  Transfer of evil\\x0a\\x1b[2Jname\n");
+    }
+
+    #[test]
+    fn rotation_keeps_every_line() {
+        let dir = scratch_dir("rotation");
+        let path = dir.join("tftpd.log");
+        let mut file = RotatingFile::open(&path, Some(40), 10).unwrap();
+        let lines: Vec<String> = (0..10).map(|i| format!("line number {:02}\n", i)).collect();
+        for line in &lines {
+            file.write_line(line).unwrap();
+        }
+        // 15 bytes lines, two per 40 bytes file: 4 rotated files plus the current one
+        assert!(file.rotated_path(4).exists());
+        assert!(!file.rotated_path(5).exists());
+        let mut content = String::new();
+        for index in (1..=4).rev() {
+            content += &fs::read_to_string(file.rotated_path(index)).unwrap();
+        }
+        content += &fs::read_to_string(&path).unwrap();
+        assert_eq!(content, lines.concat());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rotation_drops_oldest_files() {
+        let dir = scratch_dir("keep");
+        let path = dir.join("tftpd.log");
+        let mut file = RotatingFile::open(&path, Some(20), 2).unwrap();
+        for i in 0..6 {
+            file.write_line(&format!("line {}\n", i)).unwrap();
+        }
+        // 7 bytes lines, two per file: line 4-5 current, 2-3 in .1, 0-1 in .2
+        assert_eq!(fs::read_to_string(&path).unwrap(), "line 4\nline 5\n");
+        assert_eq!(fs::read_to_string(file.rotated_path(1)).unwrap(), "line 2\nline 3\n");
+        assert_eq!(fs::read_to_string(file.rotated_path(2)).unwrap(), "line 0\nline 1\n");
+        assert!(!file.rotated_path(3).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn logger_writes_to_file_with_level() {
+        let dir = scratch_dir("logger");
+        let path = dir.join("tftpd.log");
+        let config = LogConfig { level: LevelFilter::Info, file: Some(path.clone()), stderr: false, rotate_size: None, keep: 5 };
+        let logger = Logger::new(&config).unwrap();
+        for (level, message) in [(Level::Info, "kept"), (Level::Debug, "filtered")] {
+            logger.log(&Record::builder().level(level).args(format_args!("{}", message)).build());
+        }
+        logger.flush();
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(content.ends_with(" INFO  kept\n"), "{}", content);
+        assert!(!content.contains("filtered"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
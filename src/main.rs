@@ -1,107 +1,1208 @@
 //! An UDP tftp_server based on Async tokio with privilege drop
 //!
-//! 
+//! Privileges are only dropped when a user is given, otherwise the server runs
+//! as the current user, confined by software to the served directory.
+//! Privilege drop is Unix only and can be compiled out by disabling the `privdrop` feature.
 
 #![warn(rust_2018_idioms)]
+// Explicit returns and RFC opcode names (RRQ, WRQ...) are the house style
+#![allow(clippy::needless_return, clippy::upper_case_acronyms)]
 
 use std::error::Error;
 use std::net::SocketAddr;
-use std::{io,str::FromStr};
-use clap::Parser;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser};
+use clap::parser::ValueSource;
+use ipnet::IpNet;
+use log::{error, info, warn, LevelFilter};
 
-use tokio::net::UdpSocket;
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinSet;
 
-#[cfg(unix)]
-use privdrop;
+use tokio_tftpserver::error_limit::ErrorLimiter;
+use tokio_tftpserver::health;
+use tokio_tftpserver::inject::Injection;
+use tokio_tftpserver::options::{Limits, TftpOption, DEFAULT_MAX_BLKSIZE, MAX_BLKSIZE};
+use tokio_tftpserver::quota::QuotaTracker;
+use tokio_tftpserver::remap::{Remap, RemapRule};
+use tokio_tftpserver::server::{ErrorDetail, Server};
+use tokio_tftpserver::session::Sessions;
+use tokio_tftpserver::socket::{self, BindSpec, ListenOptions, PacketMarking};
+use tokio_tftpserver::stats::{ServerStats, DEFAULT_TRACKED_FILES};
+use tokio_tftpserver::tftp::tftpprotocol::{sanitize_filename, ServerOptions, Strictness, MAX_FILENAME_LEN};
+use tokio_tftpserver::variables;
+use tokio_tftpserver::write_lock::WriteLocks;
 
-mod tftp;
-use tftp::tftpprotocol;
+mod access_log;
+mod audit;
 
-struct Server {
-    socket: UdpSocket,
-    buf: Vec<u8>,
-    to_send: Option<(usize, SocketAddr)>,
-}
+mod config;
+use config::{Config, FileMode, MaxBlksize};
+
+mod hook;
+
+mod logging;
+use logging::{LogConfig, Logger, RotatingFile};
+
+#[cfg(unix)]
+mod owner;
+
+#[cfg(all(target_os = "linux", feature = "landlock"))]
+mod sandbox;
 
 #[derive(Parser,Debug)]
 struct Args {
-    #[arg(short,long,default_value_t = std::net::IpAddr::from_str("127.0.0.1").unwrap())]
-    bind: std::net::IpAddr,
+    /// TOML file with default values for these options
+    #[arg(short,long,value_name ="CONFIG_FILE", value_hint = clap::ValueHint::FilePath)]
+    config: Option<PathBuf>,
 
+    /// IP, IP%zone or [IP%zone]:PORT, repeatable. With =DIR, DIR is served on this address
+    #[arg(short,long,value_name ="ADDR",default_value = "127.0.0.1")]
+    bind: Vec<BindSpec>,
+
+    /// Port used for the addresses given without one
     #[arg(short,long,default_value_t = 69)]
     port: u16,
 
+    /// Only answer the clients in this subnet, e.g. 10.20.0.0/16, repeatable [default: all clients]
+    #[arg(long, value_name = "CIDR")]
+    allow_subnet: Vec<IpNet>,
+
+    /// Serve the UDP sockets passed by systemd socket activation, bind the addresses when there are none
+    #[cfg(unix)]
+    #[arg(long)]
+    systemd_socket: bool,
+
+    /// With an IPv6 bind address, also accept IPv4 clients
+    #[arg(long)]
+    dual_stack: bool,
+
+    /// Set SO_REUSEADDR on the listening sockets, for restarts while the previous ones linger
+    #[arg(long)]
+    reuse_addr: bool,
+
+    /// SO_RCVBUF of the listening sockets, for bursts of requests [default: system]
+    #[arg(long, value_name = "BYTES")]
+    recv_buffer_size: Option<usize>,
+
+    /// DSCP of the packets sent, e.g. 8 for CS1 [default: system]
+    #[arg(long, value_name = "0-63", value_parser = clap::value_parser!(u8).range(0..=63))]
+    dscp: Option<u8>,
+
+    /// TTL / hop limit of the packets sent, keeps the traffic within this many routers [default: system]
+    #[arg(long, value_name = "1-255", value_parser = clap::value_parser!(u8).range(1..))]
+    ttl: Option<u8>,
+
+    /// Set the Don't Fragment bit, a block over the path MTU fails instead of being fragmented (Linux only)
+    #[arg(long)]
+    dont_fragment: bool,
+
+    /// Largest block size granted to a client asking for more with the blksize option,
+    /// auto: the path MTU to the client less the headers
+    #[arg(long, value_name = "BYTES|auto", default_value_t = MaxBlksize::Bytes(DEFAULT_MAX_BLKSIZE))]
+    max_blksize: MaxBlksize,
+
+    /// Round the granted block size down to a power of two, for clients only working with those
+    #[arg(long)]
+    blksize_power_of_two: bool,
+
+    /// Ignore this option in the requests: blksize, timeout, tsize or windowsize, repeatable
+    #[arg(long, value_name = "OPTION")]
+    disable_option: Vec<TftpOption>,
+
+    /// Ignore all the options in the requests, never sending an OACK (RFC 1350 transfers)
+    #[arg(long)]
+    no_options: bool,
+
+    /// Receive loops per bind address, sharing it with SO_REUSEPORT (Linux only above 1)
+    #[arg(long, value_name = "COUNT", default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+    workers: u16,
+
+    /// Answer HTTP liveness probes on this TCP address
+    #[arg(long,value_name ="HEALTH_ADDR")]
+    health_addr: Option<SocketAddr>,
+
+    /// Accept administration commands on this Unix socket: sessions, cancel IP[:PORT] [silent]
     #[cfg(unix)]
+    #[arg(long, value_name = "PATH")]
+    control_socket: Option<PathBuf>,
+
+    /// error, warn, info, debug or trace [default: RUST_LOG or info]
+    #[arg(long,value_name ="LEVEL")]
+    log_level: Option<LevelFilter>,
+
+    /// Write the log to this file instead of stderr
+    #[arg(long,value_name ="LOG_FILE", value_hint = clap::ValueHint::FilePath)]
+    log_file: Option<PathBuf>,
+
+    /// Log to stderr as well as to --log-file
+    #[arg(long)]
+    log_stderr: bool,
+
+    /// Rotate the log file when it would exceed this size
+    #[arg(long,value_name ="BYTES")]
+    log_rotate_size: Option<u64>,
+
+    /// Number of rotated log files kept
+    #[arg(long,value_name ="COUNT",default_value_t = 5)]
+    log_keep: usize,
+
+    /// Append a JSON line per finished transfer to this file
+    #[arg(long,value_name ="AUDIT_FILE", value_hint = clap::ValueHint::FilePath)]
+    audit_log: Option<PathBuf>,
+
+    /// Append a line per finished request to this file, rotated like --log-file
+    #[arg(long,value_name ="ACCESS_FILE", value_hint = clap::ValueHint::FilePath)]
+    access_log: Option<PathBuf>,
+
+    /// Log the progress of each transfer at this interval, 0 disables
+    #[arg(long,value_name ="SECONDS",default_value_t = 10)]
+    progress_interval: u64,
+
+    /// Run this program after each completed transfer, with the filename, client and RRQ or WRQ as arguments
+    #[arg(long, value_name = "PROGRAM", value_hint = clap::ValueHint::CommandName)]
+    on_complete: Option<PathBuf>,
+
+    /// Drop privileges to this user, requires starting as root
+    #[cfg(all(unix, feature = "privdrop"))]
     #[arg(short,long,value_name ="USER_TO_DROP_PRIVILEGES_TO")]
-    user: String,
+    user: Option<String>,
+
+    /// Directory to serve, chroot in it when dropping privileges. Repeatable: reads are searched
+    /// in the directories in order, uploads go to the first one
+    #[arg(short,long,visible_alias = "search-dir",value_name ="BASE_DIRECTORY", value_hint = clap::ValueHint::DirPath)]
+    directory: Vec<PathBuf>,
 
+    /// Confine the process to the directory with Landlock, Linux 5.13+
+    #[cfg(all(target_os = "linux", feature = "landlock"))]
+    #[arg(long)]
+    landlock: bool,
+
+    /// Refuse the requested paths going through a symlink, even one staying in the served directory
+    #[arg(long)]
+    no_symlinks: bool,
+
+    /// Serve the requested paths with a component starting with '.' (.git, editor files), refused by default
+    #[arg(long)]
+    serve_hidden: bool,
+
+    /// Refuse the requested filenames longer than this many bytes
+    #[arg(long, visible_alias = "max-filename-len", value_name = "BYTES", default_value_t = MAX_FILENAME_LEN, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    max_filename_length: usize,
+
+    /// Refuse the requested filenames with non-ASCII bytes
+    #[arg(long)]
+    ascii_filenames: bool,
+
+    /// Answer any deviation from the RFCs with an error, instead of tolerating the known quirks of clients
+    #[arg(long)]
+    strict: bool,
+
+    /// Send the blocks of the files read in octet mode with sendfile, without copying them (Linux, ignored elsewhere)
+    #[arg(long)]
+    zero_copy: bool,
+
+    /// Only accept uploads replacing an existing file
+    #[arg(long)]
+    no_create: bool,
+
+    /// Create the missing directories of an upload path
+    #[arg(long)]
+    create_upload_dirs: bool,
+
+    /// Write uploads to a temporary file, renamed to the requested name once complete
+    #[arg(long)]
+    atomic_uploads: bool,
+
+    /// Abort an upload after this many consecutive DATA blocks out of order, otherwise each one is answered
+    /// with the ACK of the last block written
+    #[arg(long, value_name = "COUNT")]
+    max_out_of_order: Option<u32>,
+
+    /// Run at most this many transfers at once per bind address, requests beyond the queue are dropped
+    #[arg(long, value_name = "COUNT")]
+    max_transfers: Option<usize>,
+
+    /// Answer the dropped requests with a "Server busy" error
+    #[arg(long)]
+    reply_busy: bool,
+
+    /// Prefix the messages of the errors sent with [TAG], to tell which server answered
+    #[arg(long, value_name = "TAG", value_parser = parse_server_tag)]
+    server_tag: Option<String>,
+
+    /// Message of the errors sent to the clients, full: with the detail of the failure, generic: only
+    /// the message of the RFC for the code. The detail is logged either way
+    #[arg(long, value_name = "full|generic", default_value_t = ErrorDetail::Full)]
+    error_detail: ErrorDetail,
+
+    /// Refuse the requests of a client IP once it transferred this many bytes today (UTC)
+    #[arg(long, value_name = "BYTES")]
+    client_quota: Option<u64>,
+
+    /// ERROR replies per second to a client IP without a transfer, the others are not sent
+    #[arg(long, value_name = "PER_SECOND", default_value_t = 20, value_parser = clap::value_parser!(u32).range(1..))]
+    max_error_rate: u32,
+
+    /// ERROR replies per second to all the clients without a transfer
+    #[arg(long, value_name = "PER_SECOND", default_value_t = 500, value_parser = clap::value_parser!(u32).range(1..))]
+    max_error_rate_global: u32,
+
+    /// Count the reads of this many files, the least recently served one is forgotten beyond
+    #[arg(long, value_name = "COUNT", default_value_t = DEFAULT_TRACKED_FILES, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    tracked_files: usize,
+
+    /// Abort a transfer after this many retransmissions of the same packet [default: after 10 s of silence]
+    #[arg(long, value_name = "COUNT")]
+    max_retries: Option<u32>,
+
+    /// Retry a missing file with a case-insensitive lookup, for clients from case-insensitive systems
+    #[arg(long)]
+    ignore_case: bool,
+
+    /// Permissions of the files created or replaced by uploads, in octal (Unix only)
+    #[arg(long, value_name = "MODE")]
+    upload_mode: Option<FileMode>,
+
+    /// Owner of the uploaded files, set once complete. Needs root, kept through --user (Unix only)
     #[cfg(unix)]
-    #[arg(short,long,value_name ="BASE_DIRECTORY", value_hint = clap::ValueHint::DirPath)]
-    directory: std::path::PathBuf,
-
-}
-
-impl Server {
-    async fn run(self) -> Result<(), io::Error> {
-        let Server {
-            socket,
-            mut buf,
-            mut to_send,
-        } = self;
-
-        let mut context = None;
-        loop {
-            if let Some((size, peer)) = to_send {
-                let new_context = tftpprotocol::recv(&buf[..size],size, context);
-                context = new_context.clone();
-                match new_context {
-                    Some(ctx) => {
-                        let reply_to_send = tftpprotocol::get_reply_command(ctx).unwrap();
-                        let send = tftpprotocol::get_buffer_for_command(reply_to_send).unwrap();
-                        match socket.send_to(&send, &peer).await {
-                            Err(e) => {println!("Error {e} sending to client")},
-                            Ok(_) => ()
-                        }
-                    }
-                    None => {return Ok(())}
+    #[arg(long, value_name = "USER[:GROUP]")]
+    upload_owner: Option<String>,
+
+    /// Umask of the process, in octal (Unix only)
+    #[arg(long, value_name = "MODE")]
+    umask: Option<FileMode>,
+
+    /// Lowercase the requested filenames
+    #[arg(long)]
+    lowercase_names: bool,
+
+    /// Serve the requested filenames from this subdirectory of the served directory
+    #[arg(long, value_name = "DIR", value_hint = clap::ValueHint::DirPath)]
+    prefix: Option<PathBuf>,
+
+    /// File read by a request of an empty filename
+    #[arg(long, value_name = "NAME")]
+    default_file: Option<PathBuf>,
+
+    /// File read instead of a requested file which does not exist, uploads excepted.
+    /// ${ip}, ${ip_dashed} and ${port} are replaced by those of the client
+    #[arg(long, value_name = "NAME")]
+    fallback_file: Option<PathBuf>,
+
+    /// Serve NAME.gz decompressed when a requested NAME does not exist
+    #[arg(long)]
+    auto_decompress: bool,
+
+    /// Development only: sleep this long before sending each transfer packet
+    #[arg(long, value_name = "MS", hide = true)]
+    inject_delay: Option<u64>,
+
+    /// Development only: drop each transfer packet with this probability, from 0.0 to 1.0
+    #[arg(long, value_name = "PROBABILITY", hide = true, value_parser = parse_probability)]
+    inject_drop: Option<f64>,
+
+    /// Filename rewrite rules, only set by the configuration file
+    #[arg(skip)]
+    remap: Vec<RemapRule>,
+}
+
+/// What the process is allowed to do when it starts
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct Privileges {
+    root: bool,
+    /// Bind ports below 1024 without root, e.g. granted by systemd AmbientCapabilities=CAP_NET_BIND_SERVICE
+    net_bind_service: bool,
+}
+
+impl Privileges {
+    fn current() -> Privileges {
+        return Privileges { root: is_root(), net_bind_service: has_net_bind_service() };
+    }
+}
+
+/// What to do with the process once the socket is bound
+#[derive(Debug, PartialEq)]
+enum Startup {
+    /// Keep the current user and serve the directory (current one if None)
+    Serve { directory: Option<PathBuf> },
+    /// Same as Serve, but root was kept because no user to drop privileges to was given
+    ServeAsRoot { directory: Option<PathBuf> },
+    /// Switch to user, after a chroot in the directory if given
+    #[cfg(all(unix, feature = "privdrop"))]
+    DropPrivileges { user: String, chroot: Option<PathBuf> },
+}
+
+/// Root with a user: chroot and setuid once bound. Root without a user: keep root, with a warning.
+/// Not root: serve as the current user, binding port 69 then requires CAP_NET_BIND_SERVICE.
+fn startup_plan(args: &Args, privileges: Privileges) -> Result<Startup, String> {
+    // Every request would be refused
+    if let Some(prefix) = &args.prefix {
+        sanitize_filename(prefix).map_err(|_| format!("--prefix {} must be a subdirectory of the served directory", prefix.display()))?;
+    }
+    for (option, file) in [("--default-file", &args.default_file), ("--fallback-file", &args.fallback_file)] {
+        if let Some(file) = file {
+            sanitize_filename(file).map_err(|_| format!("{} {} must be inside the served directory", option, file.display()))?;
+        }
+    }
+    // Not checked by the parser when read from the configuration file
+    if let Some(tag) = &args.server_tag {
+        parse_server_tag(tag).map_err(|e| format!("--server-tag {}: {}", tag, e))?;
+    }
+    if let Some(file) = &args.fallback_file {
+        variables::check(&file.to_string_lossy(), |_| false).map_err(|e| format!("--fallback-file {}: {}", file.display(), e))?;
+    }
+    #[cfg(all(unix, feature = "privdrop"))]
+    if let Some(user) = &args.user {
+        if !privileges.root {
+            return Err(format!("Dropping privileges to user {} requires starting as root, remove --user to run unprivileged", user));
+        }
+        // The user lookup reads /etc/passwd, refused once sandboxed
+        #[cfg(all(target_os = "linux", feature = "landlock"))]
+        if args.landlock {
+            return Err("--landlock is the unprivileged alternative to --user, use one or the other".to_string());
+        }
+        // The other directories are out of the chroot
+        if args.directory.len() > 1 {
+            return Err("--directory can only be given once with --user, which chroots in it".to_string());
+        }
+        if let (Some(_), Some(bind)) = (args.directory.first(), args.bind.iter().find(|bind| bind.root.is_some())) {
+            return Err(format!("--bind {} serves a directory out of the chroot of --user in --directory", bind));
+        }
+        return Ok(Startup::DropPrivileges { user: user.clone(), chroot: args.directory.first().cloned() });
+    }
+    if privileges.root {
+        return Ok(Startup::ServeAsRoot { directory: args.directory.first().cloned() });
+    }
+    return Ok(Startup::Serve { directory: args.directory.first().cloned() });
+}
+
+/// Error of a failed bind, with the way out when it is the privileged port
+fn bind_error(addr: SocketAddr, error: std::io::Error, privileges: Privileges) -> String {
+    if error.kind() == std::io::ErrorKind::PermissionDenied && !privileges.root && !privileges.net_bind_service {
+        return format!("Cannot bind {}: {}, ports below 1024 require starting as root or the CAP_NET_BIND_SERVICE capability", addr, error);
+    }
+    return format!("Cannot bind {}: {}", addr, error);
+}
+
+/// Longest --server-tag, in bytes
+const MAX_SERVER_TAG_LEN: usize = 64;
+
+/// Short and printable, the tagged message must fit in the ERROR packet
+fn parse_server_tag(value: &str) -> Result<String, String> {
+    if value.is_empty() || value.len() > MAX_SERVER_TAG_LEN {
+        return Err(format!("must be 1 to {} bytes long", MAX_SERVER_TAG_LEN));
+    }
+    if value.chars().any(char::is_control) {
+        return Err("must not contain control characters".to_string());
+    }
+    return Ok(value.to_string());
+}
+
+fn parse_probability(value: &str) -> Result<f64, String> {
+    let probability: f64 = value.parse().map_err(|e| format!("{e}"))?;
+    if !(0.0..=1.0).contains(&probability) {
+        return Err(format!("{} is not between 0.0 and 1.0", value));
+    }
+    return Ok(probability);
+}
+
+#[cfg(unix)]
+fn is_root() -> bool {
+    // SAFETY: geteuid has no preconditions and cannot fail
+    return unsafe { libc::geteuid() } == 0;
+}
+
+#[cfg(not(unix))]
+fn is_root() -> bool {
+    return false;
+}
+
+#[cfg(target_os = "linux")]
+fn has_net_bind_service() -> bool {
+    return std::fs::read_to_string("/proc/self/status").is_ok_and(|status| effective_capability(&status, CAP_NET_BIND_SERVICE));
+}
+
+#[cfg(not(target_os = "linux"))]
+fn has_net_bind_service() -> bool {
+    return false;
+}
+
+#[cfg(any(target_os = "linux", test))]
+const CAP_NET_BIND_SERVICE: u32 = 10;
+
+/// Whether the capability is in the CapEff mask of a /proc/PID/status
+#[cfg(any(target_os = "linux", test))]
+fn effective_capability(status: &str, capability: u32) -> bool {
+    return status.lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .and_then(|mask| u64::from_str_radix(mask.trim(), 16).ok())
+        .is_some_and(|mask| mask & (1 << capability) != 0);
+}
+
+impl Args {
+    /// Build the arguments from the parsed command line, completed by the configuration file if any
+    fn from_matches(matches: &ArgMatches) -> Result<Args, Box<dyn Error>> {
+        let mut args = Args::from_arg_matches(matches)?;
+        if let Some(path) = &args.config {
+            let config = Config::load(path)?;
+            args.merge_config(config, matches);
+        }
+        return Ok(args);
+    }
+
+    fn merge_config(&mut self, config: Config, matches: &ArgMatches) {
+        // Only take the file value when the flag was not explicitly given on the command line
+        fn merge<T>(matches: &ArgMatches, id: &str, arg: &mut T, value: Option<T>) {
+            if let Some(value) = value {
+                if matches.value_source(id) != Some(ValueSource::CommandLine) {
+                    *arg = value;
                 }
             }
-            to_send = Some(match socket.recv_from(&mut buf).await {
-                // Ugly single retry as recv_from sometime fails on Windows
-                Err(_) =>  socket.recv_from(&mut buf).await?,
-                Ok(v) => v
-            });
         }
+        merge(matches, "bind", &mut self.bind, config.bind);
+        merge(matches, "port", &mut self.port, config.port);
+        merge(matches, "allow_subnet", &mut self.allow_subnet, config.allow_subnet);
+        #[cfg(unix)]
+        merge(matches, "systemd_socket", &mut self.systemd_socket, config.systemd_socket);
+        merge(matches, "dual_stack", &mut self.dual_stack, config.dual_stack);
+        merge(matches, "reuse_addr", &mut self.reuse_addr, config.reuse_addr);
+        merge(matches, "recv_buffer_size", &mut self.recv_buffer_size, config.recv_buffer_size.map(Some));
+        merge(matches, "dscp", &mut self.dscp, config.dscp.map(Some));
+        merge(matches, "ttl", &mut self.ttl, config.ttl.map(Some));
+        merge(matches, "dont_fragment", &mut self.dont_fragment, config.dont_fragment);
+        merge(matches, "max_blksize", &mut self.max_blksize, config.max_blksize);
+        merge(matches, "blksize_power_of_two", &mut self.blksize_power_of_two, config.blksize_power_of_two);
+        merge(matches, "disable_option", &mut self.disable_option, config.disable_option);
+        merge(matches, "no_options", &mut self.no_options, config.no_options);
+        merge(matches, "workers", &mut self.workers, config.workers);
+        merge(matches, "health_addr", &mut self.health_addr, config.health_addr.map(Some));
+        #[cfg(unix)]
+        merge(matches, "control_socket", &mut self.control_socket, config.control_socket.map(Some));
+        merge(matches, "log_level", &mut self.log_level, config.log_level.map(Some));
+        merge(matches, "log_file", &mut self.log_file, config.log_file.map(Some));
+        merge(matches, "log_stderr", &mut self.log_stderr, config.log_stderr);
+        merge(matches, "log_rotate_size", &mut self.log_rotate_size, config.log_rotate_size.map(Some));
+        merge(matches, "log_keep", &mut self.log_keep, config.log_keep);
+        merge(matches, "audit_log", &mut self.audit_log, config.audit_log.map(Some));
+        merge(matches, "access_log", &mut self.access_log, config.access_log.map(Some));
+        merge(matches, "progress_interval", &mut self.progress_interval, config.progress_interval);
+        merge(matches, "on_complete", &mut self.on_complete, config.on_complete.map(Some));
+        #[cfg(all(unix, feature = "privdrop"))]
+        merge(matches, "user", &mut self.user, config.user.map(Some));
+        merge(matches, "directory", &mut self.directory, config.roots.or(config.directory.map(|dir| vec![dir])));
+        #[cfg(all(target_os = "linux", feature = "landlock"))]
+        merge(matches, "landlock", &mut self.landlock, config.landlock);
+        merge(matches, "no_create", &mut self.no_create, config.no_create);
+        merge(matches, "no_symlinks", &mut self.no_symlinks, config.no_symlinks);
+        merge(matches, "serve_hidden", &mut self.serve_hidden, config.serve_hidden);
+        merge(matches, "max_filename_length", &mut self.max_filename_length, config.max_filename_length);
+        merge(matches, "ascii_filenames", &mut self.ascii_filenames, config.ascii_filenames);
+        merge(matches, "strict", &mut self.strict, config.strict);
+        merge(matches, "zero_copy", &mut self.zero_copy, config.zero_copy);
+        merge(matches, "create_upload_dirs", &mut self.create_upload_dirs, config.create_upload_dirs);
+        merge(matches, "atomic_uploads", &mut self.atomic_uploads, config.atomic_uploads);
+        merge(matches, "max_out_of_order", &mut self.max_out_of_order, config.max_out_of_order.map(Some));
+        merge(matches, "ignore_case", &mut self.ignore_case, config.ignore_case);
+        merge(matches, "auto_decompress", &mut self.auto_decompress, config.auto_decompress);
+        merge(matches, "upload_mode", &mut self.upload_mode, config.upload_mode.map(Some));
+        #[cfg(unix)]
+        merge(matches, "upload_owner", &mut self.upload_owner, config.upload_owner.map(Some));
+        merge(matches, "umask", &mut self.umask, config.umask.map(Some));
+        merge(matches, "lowercase_names", &mut self.lowercase_names, config.lowercase_names);
+        merge(matches, "prefix", &mut self.prefix, config.prefix.map(Some));
+        merge(matches, "default_file", &mut self.default_file, config.default_file.map(Some));
+        merge(matches, "fallback_file", &mut self.fallback_file, config.fallback_file.map(Some));
+        merge(matches, "max_transfers", &mut self.max_transfers, config.max_transfers.map(Some));
+        merge(matches, "reply_busy", &mut self.reply_busy, config.reply_busy);
+        merge(matches, "server_tag", &mut self.server_tag, config.server_tag.map(Some));
+        merge(matches, "error_detail", &mut self.error_detail, config.error_detail);
+        merge(matches, "max_retries", &mut self.max_retries, config.max_retries.map(Some));
+        merge(matches, "client_quota", &mut self.client_quota, config.client_quota.map(Some));
+        merge(matches, "max_error_rate", &mut self.max_error_rate, config.max_error_rate);
+        merge(matches, "max_error_rate_global", &mut self.max_error_rate_global, config.max_error_rate_global);
+        merge(matches, "tracked_files", &mut self.tracked_files, config.tracked_files);
+        if let Some(remap) = config.remap {
+            self.remap = remap;
+        }
+    }
+
+    fn listen_options(&self) -> ListenOptions {
+        return ListenOptions {
+            dual_stack: self.dual_stack,
+            reuse_addr: self.reuse_addr,
+            recv_buffer_size: self.recv_buffer_size,
+            reuse_port: self.workers > 1,
+            marking: self.marking(),
+        };
+    }
+
+    fn marking(&self) -> PacketMarking {
+        return PacketMarking { dscp: self.dscp, ttl: self.ttl, dont_fragment: self.dont_fragment };
+    }
+
+    fn strictness(&self) -> Strictness {
+        return match self.strict {
+            true => Strictness::Strict,
+            false => Strictness::Lenient,
+        };
+    }
+
+    fn server_options(&self) -> ServerOptions {
+        return ServerOptions {
+            no_create: self.no_create,
+            no_symlinks: self.no_symlinks,
+            serve_hidden: self.serve_hidden,
+            max_filename_len: Some(self.max_filename_length),
+            ascii_filenames: self.ascii_filenames,
+            ignore_case: self.ignore_case,
+            auto_decompress: self.auto_decompress,
+            lowercase_names: self.lowercase_names,
+            prefix: self.prefix.clone(),
+            upload_mode: self.upload_mode.map(|mode| mode.0),
+            create_upload_dirs: self.create_upload_dirs,
+            atomic_uploads: self.atomic_uploads,
+            max_out_of_order: self.max_out_of_order,
+            strictness: self.strictness(),
+            zero_copy: self.zero_copy,
+            default_file: self.default_file.clone(),
+            fallback_file: self.fallback_file.clone(),
+            limits: Limits {
+                max_blksize: match self.max_blksize {
+                    MaxBlksize::Bytes(bytes) => bytes,
+                    MaxBlksize::Auto => MAX_BLKSIZE,
+                },
+                blksize_from_mtu: self.max_blksize == MaxBlksize::Auto,
+                blksize_power_of_two: self.blksize_power_of_two,
+                disabled: self.disable_option.clone(),
+                no_options: self.no_options,
+                ..Limits::default()
+            },
+            ..ServerOptions::default()
+        };
+    }
+
+    #[cfg(all(target_os = "linux", feature = "landlock"))]
+    fn sandbox(&self) -> Result<sandbox::Sandbox, std::io::Error> {
+        let mut log_dirs = Vec::new();
+        for file in [&self.log_file, &self.audit_log, &self.access_log].into_iter().flatten() {
+            if let Some(dir) = std::path::absolute(file)?.parent() {
+                log_dirs.push(dir.to_path_buf());
+            }
+        }
+        return Ok(sandbox::Sandbox {
+            root: self.directory.first().cloned().unwrap_or_else(|| PathBuf::from(".")),
+            bind_roots: self.bind.iter().filter_map(|bind| bind.root.clone()).collect(),
+            read_roots: self.directory.iter().skip(1).cloned().collect(),
+            // The temporary file of an atomic upload is created even when replacing a file
+            no_create: self.no_create && !self.atomic_uploads,
+            create_dirs: self.create_upload_dirs && !self.no_create,
+            log_dirs,
+        });
+    }
+
+    fn injection(&self) -> Injection {
+        return Injection {
+            delay: Duration::from_millis(self.inject_delay.unwrap_or(0)),
+            drop_probability: self.inject_drop.unwrap_or(0.0),
+        };
+    }
+
+    fn log_config(&self) -> Result<LogConfig, std::io::Error> {
+        let level = self.log_level
+            .or_else(|| std::env::var("RUST_LOG").ok().and_then(|level| level.parse().ok()))
+            .unwrap_or(LevelFilter::Info);
+        // Absolute so that rotation still finds it after a directory change
+        let file = match &self.log_file {
+            Some(file) => Some(std::path::absolute(file)?),
+            None => None,
+        };
+        return Ok(LogConfig {
+            level,
+            file,
+            stderr: self.log_stderr,
+            rotate_size: self.log_rotate_size,
+            keep: self.log_keep,
+        });
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    let args = Args::parse();
-    let addr = format!("{}:{}",args.bind,args.port); 
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::from_matches(&Args::command().get_matches())?;
+    Logger::init(&args.log_config()?)?;
+    info!("Configuration: {:?}", args);
+    // Checked before binding so a wrong combination fails early
+    let privileges = Privileges::current();
+    let startup = startup_plan(&args, privileges)?;
+    apply_umask(&args);
+    // Resolved while /etc/passwd is still reachable
+    #[cfg(unix)]
+    let upload_owner = args.upload_owner.as_deref().map(owner::resolve).transpose()?;
+    #[cfg(not(unix))]
+    let upload_owner = None;
 
-    let socket = UdpSocket::bind(&addr).await?;
-    println!("Listening on: {}", socket.local_addr()?);
-    
+    // Before the runtime, its threads inherit the ruleset
+    #[cfg(all(target_os = "linux", feature = "landlock"))]
+    if args.landlock {
+        sandbox::report(&args.sandbox()?.restrict_self()?);
+    }
+
+    return tokio::runtime::Runtime::new()?.block_on(serve(args, privileges, startup, upload_owner));
+}
+
+async fn serve(args: Args, privileges: Privileges, startup: Startup, upload_owner: Option<(u32, u32)>) -> Result<(), Box<dyn Error>> {
+    let injection = args.injection();
+    if injection.is_active() {
+        warn!("Fault injection enabled ({:?}), transfers are slowed down or fail on purpose", injection);
+    }
+
+    // Absolute before moving to the first one
+    let extra_roots = args.directory.iter().skip(1)
+        .map(|dir| dir.canonicalize().map_err(|e| format!("Cannot serve directory {}: {}", dir.display(), e)))
+        .collect::<Result<Vec<_>, _>>()?;
+    for dir in &extra_roots {
+        info!("Serving the files missing from the served directory from {}", dir.display());
+    }
+    info!("Protocol compliance: {}", args.strictness());
+
+    let remap = Remap::new(&args.remap)?;
     #[cfg(unix)]
-    println!("Dropping privileges");
+    if let Some(path) = &args.config {
+        // Absolute so that the reload still finds it after a directory change
+        spawn_reload_on_sighup(std::path::absolute(path)?, remap.clone())?;
+    }
 
+    if args.workers > 1 && !socket::REUSE_PORT_SUPPORTED {
+        return Err("--workers above 1 requires SO_REUSEPORT load balancing, not available on this platform".into());
+    }
+
+    let transfer_slots = || args.max_transfers.map(|max_transfers| Arc::new(Semaphore::new(max_transfers.clamp(1, Semaphore::MAX_PERMITS))));
+    let mut sockets = Vec::new();
     #[cfg(unix)]
-    privdrop::PrivDrop::default()
-        .chroot(args.directory) 
-        .user(args.user)
-        .apply()
-        .unwrap_or_else(|e| { panic!("Failed to drop privileges: {}", e) });
-    
+    if args.systemd_socket {
+        for socket in socket::systemd_sockets(&args.marking())? {
+            info!("Listening on: {} ({}), passed by systemd", socket.local_addr()?, socket::family_description(&socket)?);
+            sockets.push((socket, transfer_slots(), None));
+        }
+        if sockets.is_empty() {
+            info!("No socket passed by systemd, binding the addresses");
+        }
+    }
+
+    // All sockets are bound before dropping privileges, the workers of an address share its transfer limit
+    let binds = if sockets.is_empty() { &args.bind[..] } else { &[] };
+    for bind in binds {
+        let slots = transfer_slots();
+        // Absolute before moving to the served directory
+        let root = match &bind.root {
+            Some(dir) => {
+                let dir = dir.canonicalize().map_err(|e| format!("Cannot serve directory {} on {}: {}", dir.display(), bind, e))?;
+                info!("Serving directory {} on {}", dir.display(), bind.socket_addr(args.port));
+                Some(dir)
+            }
+            None => None,
+        };
+        let mut addr = bind.socket_addr(args.port);
+        for worker in 0..args.workers {
+            let socket = socket::bind_udp(addr, &args.listen_options()).map_err(|e| bind_error(addr, e, privileges))?;
+            // With port 0 the next workers join the port picked for the first one
+            addr = socket.local_addr()?;
+            match args.workers {
+                1 => info!("Listening on: {} ({})", addr, socket::family_description(&socket)?),
+                _ => info!("Listening on: {} ({}), worker {}", addr, socket::family_description(&socket)?, worker),
+            }
+            sockets.push((socket, slots.clone(), root.clone()));
+        }
+    }
 
-    let server = Server {
-        socket,
-        buf: vec![0; 1024],
-        to_send: None,
+    let alive = Arc::new(AtomicBool::new(false));
+    if let Some(health_addr) = args.health_addr {
+        let listener = TcpListener::bind(health_addr).await?;
+        info!("Health check listening on: {}", listener.local_addr()?);
+        tokio::spawn(health::serve(listener, alive.clone()));
+    }
+    // Bound before the chroot, only the owner (root before the privilege drop) can connect
+    #[cfg(unix)]
+    let control = match &args.control_socket {
+        Some(path) => Some(control_listener(path).map_err(|e| format!("Cannot bind control socket {}: {}", path.display(), e))?),
+        None => None,
     };
 
-    // This starts the server task.
-    server.run().await?;
+    // Consumers of the finished transfers
+    let mut results = Vec::new();
+    if let Some(path) = &args.audit_log {
+        let file = audit::open(path).await
+            .map_err(|e| format!("Cannot open audit log {}: {}", path.display(), e))?;
+        let (sender, receiver) = mpsc::channel(64);
+        tokio::spawn(async move {
+            if let Err(e) = audit::write(file, receiver).await {
+                error!("Error {} writing audit log, audit stopped", e);
+            }
+        });
+        results.push(sender);
+    }
+    if let Some(path) = &args.access_log {
+        // Absolute so that rotation still finds it after a directory change
+        let file = std::path::absolute(path)
+            .and_then(|path| RotatingFile::open(&path, args.log_rotate_size, args.log_keep))
+            .map_err(|e| format!("Cannot open access log {}: {}", path.display(), e))?;
+        let (sender, receiver) = mpsc::channel(64);
+        access_log::spawn_writer(file, receiver);
+        results.push(sender);
+    }
+    if let Some(program) = &args.on_complete {
+        let (sender, receiver) = mpsc::channel(64);
+        hook::spawn_runner(program.clone(), receiver);
+        results.push(sender);
+    }
+    
+    match startup {
+        Startup::Serve { directory } => {
+            if privileges.net_bind_service {
+                info!("Running unprivileged with CAP_NET_BIND_SERVICE");
+            }
+            serve_directory(directory)?;
+        }
+        Startup::ServeAsRoot { directory } => {
+            warn!("Running as root, drop privileges with --user or start unprivileged with CAP_NET_BIND_SERVICE");
+            serve_directory(directory)?;
+        }
+        #[cfg(all(unix, feature = "privdrop"))]
+        Startup::DropPrivileges { user, chroot } => {
+            info!("Dropping privileges");
+            let mut privdrop = privdrop::PrivDrop::default();
+            if let Some(directory) = chroot {
+                privdrop = privdrop.chroot(directory);
+            }
+            let drop = || privdrop.user(&user).apply().map_err(|e| format!("Failed to drop privileges: {}", e));
+            match upload_owner {
+                Some(_) => {
+                    if let Err(e) = owner::drop_keeping_chown(drop)? {
+                        warn!("Cannot keep CAP_CHOWN for --upload-owner, uploads keep the owner {}: {}", user, e);
+                    }
+                }
+                None => drop()?,
+            }
+        }
+    }
+
+    // Transfers and counters of all the servers, logged on SIGUSR1, the transfers at each progress interval too
+    let sessions = Sessions::new();
+    let stats = Arc::new(ServerStats::new().with_tracked_files(args.tracked_files));
+    #[cfg(unix)]
+    tokio_tftpserver::session::spawn_dump_on_sigusr1(sessions.clone(), stats.clone())?;
+    if args.progress_interval > 0 {
+        tokio_tftpserver::session::spawn_progress_log(sessions.clone(), Duration::from_secs(args.progress_interval));
+    }
+    #[cfg(unix)]
+    if let Some(listener) = control {
+        tokio::spawn(tokio_tftpserver::control::serve(listener, sessions.clone()));
+    }
+
+    // All the servers upload to the same directory
+    let write_locks = WriteLocks::new();
+
+    // Counted across all the addresses
+    let quota = args.client_quota.map(QuotaTracker::new);
+    let error_limit = ErrorLimiter::new(args.max_error_rate, args.max_error_rate_global);
+
+    // One server loop per listening socket, stop on the first failing one
+    let mut servers = JoinSet::new();
+    for (socket, slots, root) in sockets {
+        let root = root.unwrap_or_default();
+        let mut server = Server::new(socket, alive.clone())
+            .with_options(ServerOptions { upload_owner, remap: remap.clone(), root, extra_roots: extra_roots.clone(), ..args.server_options() })
+            .with_sessions(sessions.clone())
+            .with_stats(stats.clone())
+            .with_write_locks(write_locks.clone())
+            .with_error_limit(error_limit.clone())
+            .with_allowed_subnets(args.allow_subnet.clone())
+            .with_reply_busy(args.reply_busy)
+            .with_injection(injection.clone())
+            .with_marking(args.marking());
+        if let Some(slots) = slots {
+            server = server.with_transfer_slots(slots);
+        }
+        if let Some(max_retries) = args.max_retries {
+            server = server.with_max_retries(max_retries);
+        }
+        if let Some(quota) = &quota {
+            server = server.with_quota(quota.clone());
+        }
+        if let Some(tag) = &args.server_tag {
+            server = server.with_server_tag(tag.clone());
+        }
+        server = server.with_error_detail(args.error_detail);
+        for results in &results {
+            server = server.with_results(results.clone());
+        }
+        servers.spawn(server.run());
+    }
+    while let Some(result) = servers.join_next().await {
+        result??;
+    }
 
     Ok(())
 }
+
+/// Reload the remap rules from the configuration file each time the process receives SIGHUP,
+/// the other settings are only read at startup
+#[cfg(unix)]
+fn spawn_reload_on_sighup(path: PathBuf, remap: Remap) -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    // Registered before returning so that no signal is missed (or kills the process)
+    let mut signals = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while signals.recv().await.is_some() {
+            let rules = Config::load(&path).map(|config| config.remap.unwrap_or_default());
+            match rules.and_then(|rules| remap.reload(&rules)) {
+                Ok(()) => info!("Reloaded {} remap rules from {}", remap.len(), path.display()),
+                Err(e) => error!("{}, remap rules unchanged", e),
+            }
+        }
+    });
+    return Ok(());
+}
+
+/// Listener of the control socket, replacing the file left by a previous run
+#[cfg(unix)]
+fn control_listener(path: &std::path::Path) -> std::io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    if std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    let listener = tokio::net::UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    info!("Control socket listening on: {}", path.display());
+    return Ok(listener);
+}
+
+#[cfg(unix)]
+fn apply_umask(args: &Args) {
+    if let Some(umask) = args.umask {
+        // SAFETY: umask has no preconditions and cannot fail
+        unsafe { libc::umask(umask.0 as libc::mode_t) };
+    }
+}
+
+#[cfg(not(unix))]
+fn apply_umask(args: &Args) {
+    if args.upload_mode.is_some() || args.umask.is_some() {
+        warn!("--upload-mode and --umask are ignored on this platform");
+    }
+}
+
+/// Move to the served directory, confined to it by the path checks
+fn serve_directory(directory: Option<PathBuf>) -> Result<(), String> {
+    match directory {
+        Some(directory) => {
+            std::env::set_current_dir(&directory)
+                .map_err(|e| format!("Cannot serve directory {}: {}", directory.display(), e))?;
+            info!("Serving directory {}", directory.display());
+        }
+        None => info!("Serving current directory"),
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{bind_error, effective_capability, health, startup_plan, Args, Config, FileMode, Injection, MaxBlksize, Privileges, Server,
+                Startup, TftpOption, CAP_NET_BIND_SERVICE};
+    use clap::Parser;
+    use clap::CommandFactory;
+    use log::LevelFilter;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicBool;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+    fn fixture(name: &str) -> PathBuf {
+        return [env!("CARGO_MANIFEST_DIR"), "tests", "fixtures", "config", name].iter().collect();
+    }
+
+    fn parse(cli: &[&str]) -> Result<Args, String> {
+        let mut argv = vec!["tokio_tftpserver"];
+        argv.extend(cli);
+        let matches = Args::command().try_get_matches_from(argv).map_err(|e| e.to_string())?;
+        return Args::from_matches(&matches).map_err(|e| e.to_string());
+    }
+
+    #[test]
+    fn config_file_values_are_used() {
+        let path = fixture("tftpd.toml");
+        let args = parse(&["--config", path.to_str().unwrap()]).unwrap();
+        assert_eq!(args.bind[0].to_string(), "0.0.0.0");
+        assert_eq!(args.port, 6969);
+        assert_eq!(args.log_level, Some(LevelFilter::Debug));
+        // Relative to the configuration file
+        assert_eq!(args.log_file, Some(fixture("tftpd.log")));
+    }
+
+    #[test]
+    fn command_line_overrides_config_file() {
+        let path = fixture("tftpd.toml");
+        let args = parse(&["--config", path.to_str().unwrap(), "--port", "1069"]).unwrap();
+        assert_eq!(args.bind[0].to_string(), "0.0.0.0");
+        assert_eq!(args.port, 1069);
+    }
+
+    #[test]
+    fn defaults_without_config_file() {
+        let args = parse(&[]).unwrap();
+        assert_eq!(args.bind[0].to_string(), "127.0.0.1");
+        assert_eq!(args.port, 69);
+    }
+
+    #[test]
+    fn repeated_bind() {
+        let args = parse(&["--bind", "10.0.0.1", "-b", "[fe80::1%2]:6969"]).unwrap();
+        assert_eq!(args.bind.len(), 2);
+        assert_eq!(args.bind[0].socket_addr(args.port), "10.0.0.1:69".parse().unwrap());
+        assert_eq!(args.bind[1].to_string(), "[fe80::1%2]:6969");
+    }
+
+    #[test]
+    fn injection_options_are_hidden() {
+        assert!(!Args::command().render_long_help().to_string().contains("inject"));
+        let args = parse(&["--inject-delay", "250", "--inject-drop", "0.5"]).unwrap();
+        assert_eq!(args.injection(), Injection { delay: Duration::from_millis(250), drop_probability: 0.5 });
+        assert!(parse(&["--inject-drop", "1.5"]).is_err());
+    }
+
+    #[test]
+    fn octal_modes() {
+        let args = parse(&["--upload-mode", "0660", "--umask", "007"]).unwrap();
+        assert_eq!((args.upload_mode, args.umask), (Some(FileMode(0o660)), Some(FileMode(0o007))));
+        assert_eq!(args.server_options().upload_mode, Some(0o660));
+        assert_eq!(parse(&["--upload-mode", "0o640"]).unwrap().upload_mode, Some(FileMode(0o640)));
+        assert_eq!(FileMode(0o640).to_string(), "0640");
+        for invalid in ["0680", "rw-rw----", "4755", "-1", "+660", ""] {
+            assert!(parse(&["--upload-mode", invalid]).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn marking_ranges() {
+        let args = parse(&["--dscp", "8", "--ttl", "2"]).unwrap();
+        assert_eq!((args.dscp, args.ttl), (Some(8), Some(2)));
+        assert!(parse(&["--dscp", "64"]).is_err());
+        assert!(parse(&["--ttl", "0"]).is_err());
+        assert!(parse(&["--ttl", "256"]).is_err());
+    }
+
+    #[test]
+    fn server_tag() {
+        assert_eq!(parse(&["--server-tag", "srv-dc1"]).unwrap().server_tag.as_deref(), Some("srv-dc1"));
+        assert!(parse(&["--server-tag", ""]).is_err());
+        assert!(parse(&["--server-tag", "srv\ndc1"]).is_err());
+        assert!(parse(&["--server-tag", &"x".repeat(65)]).is_err());
+    }
+
+    #[test]
+    fn max_blksize_range() {
+        assert_eq!(parse(&[]).unwrap().server_options().limits.max_blksize, 1428);
+        assert_eq!(parse(&["--max-blksize", "65464"]).unwrap().server_options().limits.max_blksize, 65464);
+        assert!(parse(&["--max-blksize", "7"]).is_err());
+        assert!(parse(&["--max-blksize", "65465"]).is_err());
+        let limits = parse(&["--max-blksize", "auto"]).unwrap().server_options().limits;
+        assert_eq!((limits.max_blksize, limits.blksize_from_mtu), (65464, true));
+        let config = Config::parse("max_blksize = \"auto\"").unwrap();
+        assert_eq!(config.max_blksize, Some(MaxBlksize::Auto));
+        assert_eq!(Config::parse("max_blksize = 1468").unwrap().max_blksize, Some(MaxBlksize::Bytes(1468)));
+        assert!(Config::parse("max_blksize = 70000").is_err());
+    }
+
+    #[test]
+    fn max_filename_length_alias() {
+        assert_eq!(parse(&[]).unwrap().server_options().max_filename_len, Some(255));
+        assert_eq!(parse(&["--max-filename-length", "64"]).unwrap().server_options().max_filename_len, Some(64));
+        assert_eq!(parse(&["--max-filename-len", "64"]).unwrap().server_options().max_filename_len, Some(64));
+        assert!(parse(&["--max-filename-len", "0"]).is_err());
+        assert_eq!(Config::parse("max_filename_len = 64").unwrap().max_filename_length, Some(64));
+    }
+
+    #[test]
+    fn allowed_subnets() {
+        let args = parse(&["--allow-subnet", "10.20.0.0/16", "--allow-subnet", "2001:db8::/32"]).unwrap();
+        assert_eq!(args.allow_subnet.iter().map(|subnet| subnet.to_string()).collect::<Vec<_>>(), ["10.20.0.0/16", "2001:db8::/32"]);
+        assert!(parse(&[]).unwrap().allow_subnet.is_empty());
+        assert!(parse(&["--allow-subnet", "10.20.0.0/33"]).is_err());
+        let config = Config::parse("allow_subnet = [\"192.168.1.0/24\"]").unwrap();
+        assert_eq!(config.allow_subnet, Some(vec!["192.168.1.0/24".parse().unwrap()]));
+    }
+
+    #[test]
+    fn disabled_options() {
+        let args = parse(&["--disable-option", "tsize", "--disable-option", "blksize"]).unwrap();
+        assert_eq!(args.server_options().limits.disabled, vec![TftpOption::Tsize, TftpOption::Blksize]);
+        assert!(!args.server_options().limits.no_options);
+        assert!(parse(&["--no-options"]).unwrap().server_options().limits.no_options);
+        assert!(parse(&["--disable-option", "offset"]).is_err());
+        let config = Config::parse("disable_option = [\"windowsize\"]\nno_options = true\n").unwrap();
+        assert_eq!(config.disable_option, Some(vec![TftpOption::Windowsize]));
+        assert_eq!(config.no_options, Some(true));
+    }
+
+    #[test]
+    fn config_file_remap_rules() {
+        let path = fixture("remap.toml");
+        let args = parse(&["--config", path.to_str().unwrap()]).unwrap();
+        assert_eq!(args.remap.len(), 2);
+        assert_eq!((args.remap[0].pattern.as_str(), args.remap[0].regex), ("pxelinux.0", false));
+        assert_eq!((args.remap[1].replacement.as_str(), args.remap[1].regex), ("$1", true));
+        assert!(parse(&[]).unwrap().remap.is_empty());
+    }
+
+    #[test]
+    fn unknown_config_key_is_reported() {
+        let path = fixture("unknown_key.toml");
+        let error = parse(&["--config", path.to_str().unwrap()]).unwrap_err();
+        assert!(error.contains("listen_queue"), "{}", error);
+    }
+
+    #[test]
+    fn startup_without_user_or_directory() {
+        let args = Args::try_parse_from(["tokio_tftpserver", "--port", "6969"]).unwrap();
+        assert_eq!(startup_plan(&args, Privileges::default()), Ok(Startup::Serve { directory: None }));
+    }
+
+    #[test]
+    fn startup_checks_prefix() {
+        let args = Args::try_parse_from(["tokio_tftpserver", "--prefix", "boot"]).unwrap();
+        assert!(startup_plan(&args, Privileges::default()).is_ok());
+        let args = Args::try_parse_from(["tokio_tftpserver", "--prefix", "../boot"]).unwrap();
+        assert!(startup_plan(&args, Privileges::default()).is_err());
+        let args = Args::try_parse_from(["tokio_tftpserver", "--default-file", "../boot.cfg"]).unwrap();
+        assert!(startup_plan(&args, Privileges::default()).is_err());
+        let args = Args::try_parse_from(["tokio_tftpserver", "--fallback-file", "hosts/${ip}.cfg"]).unwrap();
+        assert!(startup_plan(&args, Privileges::default()).is_ok());
+        let args = Args::try_parse_from(["tokio_tftpserver", "--fallback-file", "hosts/${mac}.cfg"]).unwrap();
+        assert!(startup_plan(&args, Privileges::default()).unwrap_err().contains("${mac}"));
+    }
+
+    #[test]
+    fn startup_as_root_without_user() {
+        let args = Args::try_parse_from(["tokio_tftpserver", "-d", "/srv/tftp"]).unwrap();
+        let root = Privileges { root: true, net_bind_service: true };
+        assert_eq!(startup_plan(&args, root), Ok(Startup::ServeAsRoot { directory: Some(PathBuf::from("/srv/tftp")) }));
+    }
+
+    #[test]
+    fn startup_with_capability() {
+        let args = Args::try_parse_from(["tokio_tftpserver", "-d", "/srv/tftp"]).unwrap();
+        let capability = Privileges { root: false, net_bind_service: true };
+        assert_eq!(startup_plan(&args, capability), Ok(Startup::Serve { directory: Some(PathBuf::from("/srv/tftp")) }));
+    }
+
+    #[test]
+    fn bind_error_hints_at_capability() {
+        let addr = "0.0.0.0:69".parse().unwrap();
+        let denied = || std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+        assert!(bind_error(addr, denied(), Privileges::default()).contains("CAP_NET_BIND_SERVICE"));
+        let capability = Privileges { root: false, net_bind_service: true };
+        assert!(!bind_error(addr, denied(), capability).contains("CAP_NET_BIND_SERVICE"));
+        let in_use = std::io::Error::from(std::io::ErrorKind::AddrInUse);
+        assert!(!bind_error(addr, in_use, Privileges::default()).contains("CAP_NET_BIND_SERVICE"));
+    }
+
+    #[test]
+    fn capability_from_proc_status() {
+        let status = "Name:\ttokio_tftpserve\nCapInh:\t0000000000000000\nCapEff:\t0000000000000400\n";
+        assert!(effective_capability(status, CAP_NET_BIND_SERVICE));
+        assert!(!effective_capability(&status.replace("0400", "0000"), CAP_NET_BIND_SERVICE));
+        assert!(!effective_capability("Name:\tother\n", CAP_NET_BIND_SERVICE));
+    }
+
+    #[test]
+    fn startup_with_directory_only() {
+        let args = Args::try_parse_from(["tokio_tftpserver", "--directory", "/srv/tftp"]).unwrap();
+        assert_eq!(startup_plan(&args, Privileges::default()), Ok(Startup::Serve { directory: Some(PathBuf::from("/srv/tftp")) }));
+    }
+
+    #[cfg(all(unix, feature = "privdrop"))]
+    #[test]
+    fn startup_drops_privileges_as_root() {
+        let args = Args::try_parse_from(["tokio_tftpserver", "-u", "tftp", "-d", "/srv/tftp"]).unwrap();
+        assert_eq!(startup_plan(&args, Privileges { root: true, net_bind_service: false }),
+                   Ok(Startup::DropPrivileges { user: "tftp".to_string(), chroot: Some(PathBuf::from("/srv/tftp")) }));
+        let args = Args::try_parse_from(["tokio_tftpserver", "-u", "tftp"]).unwrap();
+        assert_eq!(startup_plan(&args, Privileges { root: true, net_bind_service: false }),
+                   Ok(Startup::DropPrivileges { user: "tftp".to_string(), chroot: None }));
+        let args = Args::try_parse_from(["tokio_tftpserver", "-u", "tftp", "-d", "/srv/tftp/site", "-d", "/srv/tftp/base"]).unwrap();
+        assert!(startup_plan(&args, Privileges { root: true, net_bind_service: false }).is_err());
+        // Out of the chroot
+        let args = Args::try_parse_from(["tokio_tftpserver", "-u", "tftp", "-d", "/srv/tftp", "-b", "10.0.0.1=/srv/mgmt"]).unwrap();
+        assert!(startup_plan(&args, Privileges { root: true, net_bind_service: false }).is_err());
+    }
+
+    #[cfg(all(unix, feature = "privdrop"))]
+    #[test]
+    fn startup_user_requires_root() {
+        let args = Args::try_parse_from(["tokio_tftpserver", "-u", "tftp", "-d", "/srv/tftp"]).unwrap();
+        let error = startup_plan(&args, Privileges::default()).unwrap_err();
+        assert!(error.contains("requires starting as root"), "{}", error);
+    }
+
+    #[cfg(all(unix, feature = "privdrop"))]
+    #[test]
+    fn config_file_relative_directory() {
+        let path = fixture("privdrop.toml");
+        let matches = Args::command()
+            .try_get_matches_from(["tokio_tftpserver", "--config", path.to_str().unwrap(), "--user", "tftp"])
+            .unwrap();
+        let args = Args::from_matches(&matches).unwrap();
+        assert_eq!(args.user.as_deref(), Some("tftp"));
+        assert_eq!(args.directory, [fixture("srv")]);
+    }
+
+    #[test]
+    fn config_file_roots() {
+        let path = fixture("roots.toml");
+        let args = parse(&["--config", path.to_str().unwrap()]).unwrap();
+        assert_eq!(args.directory, [fixture("site"), fixture("base")]);
+        let args = parse(&["--config", path.to_str().unwrap(), "-d", "/srv/tftp"]).unwrap();
+        assert_eq!(args.directory, [PathBuf::from("/srv/tftp")]);
+        let args = parse(&["-d", "/srv/tftp/site", "--directory", "/srv/tftp/base"]).unwrap();
+        assert_eq!(startup_plan(&args, Privileges::default()), Ok(Startup::Serve { directory: Some(PathBuf::from("/srv/tftp/site")) }));
+        // Same search path with the names of an overlay
+        let args = parse(&["--search-dir", "/srv/tftp/site", "--search-dir", "/srv/tftp/base"]).unwrap();
+        assert_eq!(args.directory, [PathBuf::from("/srv/tftp/site"), PathBuf::from("/srv/tftp/base")]);
+        let path = fixture("search_dir.toml");
+        let args = parse(&["--config", path.to_str().unwrap()]).unwrap();
+        assert_eq!(args.directory, [fixture("site"), fixture("base")]);
+    }
+
+    async fn health_check(addr: std::net::SocketAddr) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"GET /healthz HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        return response;
+    }
+
+    #[tokio::test]
+    async fn health_check_while_server_runs() {
+        let alive = Arc::new(AtomicBool::new(false));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let health_addr = listener.local_addr().unwrap();
+        tokio::spawn(health::serve(listener, alive.clone()));
+
+        // UDP loop not started yet
+        assert!(health_check(health_addr).await.starts_with("HTTP/1.1 503"));
+
+        let server = Server::new(UdpSocket::bind("127.0.0.1:0").await.unwrap(), alive);
+        let server_task = tokio::spawn(server.run());
+        tokio::task::yield_now().await;
+        let response = health_check(health_addr).await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("\r\n\r\nok"));
+        server_task.abort();
+    }
+}
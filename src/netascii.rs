@@ -0,0 +1,217 @@
+//! netascii mode (RFC 764): line ends are CR LF on the wire and a lone CR is CR NUL
+//!
+//! Files keep the Unix line ends on disk, so a block number does not give a file offset as in
+//! octet mode: the start of the last block and of the next one are tracked instead. A pair cut
+//! by the end of a block carries its second byte to the next block.
+
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+
+/// Where a block starts in the file
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Position {
+    /// Offset of the next byte of the file
+    pub offset: u64,
+    /// Byte of a pair cut by the previous block: the LF or NUL of a read, the CR of a write
+    pub pending: Option<u8>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Block {
+    number: u16,
+    start: Position,
+    end: Position,
+    /// Shorter than the block size, the last of the transfer
+    short: bool,
+}
+
+/// Block positions of a netascii transfer
+#[derive(Debug, Default)]
+pub struct Netascii {
+    /// Start of block 1
+    first: Position,
+    /// Last block read or written
+    last: Option<Block>,
+}
+
+impl Netascii {
+    /// Transfer starting at this offset of the file
+    pub fn new(offset: u64) -> Netascii {
+        return Netascii { first: Position { offset, pending: None }, last: None };
+    }
+
+    /// Fill buf with block blocknum translated from the file, None once past the last block.
+    /// The last block is sent again as is, an older one is found again from the first block.
+    pub fn read_block<F: Read + Seek>(&mut self, file: &mut F, blocknum: u16, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        let start = match self.last {
+            Some(last) if last.number == blocknum => last.start,
+            Some(last) if last.number.wrapping_add(1) == blocknum && last.short => return Ok(None),
+            Some(last) if last.number.wrapping_add(1) == blocknum => last.end,
+            _ if blocknum == 1 => self.first,
+            _ => {
+                for number in 1..blocknum {
+                    if self.read_block(file, number, buf)?.is_none() {
+                        return Ok(None);
+                    }
+                }
+                match self.last {
+                    Some(last) if !last.short => last.end,
+                    _ => return Ok(None),
+                }
+            }
+        };
+        file.seek(SeekFrom::Start(start.offset))?;
+        // Each byte of the file is at least one byte on the wire
+        let mut input = vec![0; buf.len()];
+        let read = read_full(file, &mut input)?;
+        let (consumed, filled, pending) = encode(&input[..read], start.pending, buf);
+        let end = Position { offset: start.offset + consumed as u64, pending };
+        self.last = Some(Block { number: blocknum, start, end, short: filled < buf.len() });
+        return Ok(Some(filled));
+    }
+
+    /// Write block blocknum translated to the file, last when shorter than the block size.
+    /// The last block is written again in place, an older one was already written and is skipped,
+    /// a block after the next one cannot be placed and fails with InvalidInput.
+    pub fn write_block<F: Write + Seek>(&mut self, file: &mut F, blocknum: u16, data: &[u8], last: bool) -> io::Result<()> {
+        let start = match self.last {
+            Some(block) if block.number == blocknum => block.start,
+            Some(block) if block.number.wrapping_add(1) == blocknum => block.end,
+            None if blocknum == 1 => self.first,
+            Some(block) if blocknum < block.number => return Ok(()),
+            _ => return Err(io::Error::new(ErrorKind::InvalidInput, format!("netascii block {} out of order", blocknum))),
+        };
+        let mut output = Vec::with_capacity(data.len() + 1);
+        let mut pending = decode(data, start.pending, &mut output);
+        if last {
+            output.extend(pending.take());
+        }
+        file.seek(SeekFrom::Start(start.offset))?;
+        file.write_all(&output)?;
+        let end = Position { offset: start.offset + output.len() as u64, pending };
+        self.last = Some(Block { number: blocknum, start, end, short: last });
+        return Ok(());
+    }
+}
+
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    return Ok(filled);
+}
+
+/// Translate input to the wire into out, starting with the pending byte of the previous block.
+/// Returns the input bytes consumed, the output bytes filled and the byte of a pair cut by the end of out.
+pub fn encode(input: &[u8], mut pending: Option<u8>, out: &mut [u8]) -> (usize, usize, Option<u8>) {
+    let mut filled = 0;
+    let mut consumed = 0;
+    while filled < out.len() {
+        if let Some(byte) = pending.take() {
+            out[filled] = byte;
+            filled += 1;
+            continue;
+        }
+        let Some(&byte) = input.get(consumed) else { break };
+        consumed += 1;
+        out[filled] = match byte {
+            b'\n' | b'\r' => b'\r',
+            _ => byte,
+        };
+        filled += 1;
+        pending = match byte {
+            b'\n' => Some(b'\n'),
+            b'\r' => Some(0),
+            _ => None,
+        };
+    }
+    return (consumed, filled, pending);
+}
+
+/// Translate input from the wire to the end of out, after the CR ending the previous block if any.
+/// Returns the CR ending this block, only known once the next block starts.
+pub fn decode(input: &[u8], mut pending: Option<u8>, out: &mut Vec<u8>) -> Option<u8> {
+    for &byte in input {
+        match (pending.take(), byte) {
+            (Some(_), b'\n') => out.push(b'\n'),
+            (Some(_), 0) => out.push(b'\r'),
+            // Not netascii, the CR is kept
+            (Some(cr), _) => {
+                out.push(cr);
+                if byte == b'\r' {
+                    pending = Some(byte);
+                } else {
+                    out.push(byte);
+                }
+            }
+            (None, b'\r') => pending = Some(byte),
+            (None, _) => out.push(byte),
+        }
+    }
+    return pending;
+}
+
+#[cfg(test)]
+mod test {
+    use crate::netascii::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn encode_and_decode() {
+        let mut out = [0; 8];
+        assert_eq!(encode(b"ab\ncd\re", None, &mut out), (6, 8, None));
+        assert_eq!(&out, b"ab\r\ncd\r\0");
+        // The pair is cut by the end of the block, its second byte starts the next one
+        let mut out = [0; 3];
+        assert_eq!(encode(b"ab\ncd", None, &mut out), (3, 3, Some(b'\n')));
+        assert_eq!(encode(b"cd", Some(b'\n'), &mut out), (2, 3, None));
+        assert_eq!(&out, b"\ncd");
+
+        let mut decoded = Vec::new();
+        assert_eq!(decode(b"ab\r\ncd\r\0e\rf\r", None, &mut decoded), Some(b'\r'));
+        assert_eq!(decode(b"\nx", Some(b'\r'), &mut decoded), None);
+        assert_eq!(decoded, b"ab\ncd\re\rf\nx");
+    }
+
+    #[test]
+    fn blocks_of_a_file() {
+        let content = b"line 1\nline 2\r\n\n".to_vec();
+        let mut file = Cursor::new(content.clone());
+        let mut netascii = Netascii::new(0);
+        let mut wire = Vec::new();
+        let mut buf = [0; 4];
+        let mut blocknum = 1;
+        while let Some(filled) = netascii.read_block(&mut file, blocknum, &mut buf).unwrap() {
+            // Sent again identical, then a jump back to an older block
+            let mut again = [0; 4];
+            assert_eq!(netascii.read_block(&mut file, blocknum, &mut again).unwrap(), Some(filled));
+            assert_eq!(again[..filled], buf[..filled]);
+            if blocknum > 2 {
+                assert_eq!(netascii.read_block(&mut file, blocknum - 2, &mut again).unwrap(), Some(4));
+                netascii.read_block(&mut file, blocknum, &mut again).unwrap();
+            }
+            wire.extend_from_slice(&buf[..filled]);
+            blocknum += 1;
+        }
+        assert_eq!(wire, b"line 1\r\nline 2\r\0\r\n\r\n");
+        // 20 bytes, the last block is empty
+        assert_eq!(blocknum, 7);
+
+        let mut written = Cursor::new(Vec::new());
+        let mut netascii = Netascii::new(0);
+        let blocks: Vec<_> = wire.chunks(4).chain([&[][..]]).collect();
+        for (index, block) in blocks.iter().enumerate() {
+            let last = index == blocks.len() - 1;
+            netascii.write_block(&mut written, index as u16 + 1, block, last).unwrap();
+            // A retransmitted block is written again in place
+            netascii.write_block(&mut written, index as u16 + 1, block, last).unwrap();
+        }
+        assert_eq!(written.into_inner(), content);
+        assert_eq!(netascii.write_block(&mut Cursor::new(Vec::new()), 9, b"", true).unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,334 @@
+//! TFTP options negotiation (RFC 2347), all bounds are checked here
+//!
+//! Supported: `blksize` (RFC 2348), `timeout` and `tsize` (RFC 2349), `windowsize` (RFC 7440),
+//! and the non standard `offset` to resume a read at this byte.
+//! Unknown or invalid options are left out of the OACK, the client then uses the default value.
+//! Disabled options (`Limits::disabled`, `Limits::no_options`) are handled as if they were not requested,
+//! for clients failing on an OACK or on one of its options.
+
+use serde::Deserialize;
+use std::fmt;
+use std::str::FromStr;
+
+/// Block size without the blksize option
+pub const DEFAULT_BLKSIZE: u16 = 512;
+/// Bounds of the blksize option
+pub const MIN_BLKSIZE: u16 = 8;
+pub const MAX_BLKSIZE: u16 = 65464;
+/// Largest blksize granted by the server binary by default: a block in a single packet on a 1500 bytes MTU
+/// with IPv6 (1448), less room for a tunnel header
+pub const DEFAULT_MAX_BLKSIZE: u16 = 1428;
+/// IPv4 and UDP headers and the DATA opcode and block number, a block of MTU - 32 bytes fills a packet
+pub const IPV4_DATA_OVERHEAD: u16 = 32;
+/// Same with the IPv6 header
+pub const IPV6_DATA_OVERHEAD: u16 = 52;
+/// Limits::blksize_from_mtu when the path MTU is unknown: Ethernet (1500) less the IPv4 overhead
+pub const FALLBACK_MTU_BLKSIZE: u16 = 1468;
+/// Bounds of the timeout option, in seconds
+pub const MIN_TIMEOUT: u8 = 1;
+pub const MAX_TIMEOUT: u8 = 255;
+/// Bounds of the windowsize option
+pub const MIN_WINDOWSIZE: u16 = 1;
+pub const MAX_WINDOWSIZE: u16 = 65535;
+
+/// Option that can be disabled, written as its lowercase name
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TftpOption {
+    Blksize,
+    Timeout,
+    Tsize,
+    Windowsize,
+}
+
+impl TftpOption {
+    pub fn name(self) -> &'static str {
+        match self {
+            TftpOption::Blksize => return "blksize",
+            TftpOption::Timeout => return "timeout",
+            TftpOption::Tsize => return "tsize",
+            TftpOption::Windowsize => return "windowsize",
+        }
+    }
+}
+
+impl FromStr for TftpOption {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<TftpOption, String> {
+        for option in [TftpOption::Blksize, TftpOption::Timeout, TftpOption::Tsize, TftpOption::Windowsize] {
+            if name.eq_ignore_ascii_case(option.name()) {
+                return Ok(option);
+            }
+        }
+        return Err(format!("Unknown option {}, expected blksize, timeout, tsize or windowsize", name));
+    }
+}
+
+impl fmt::Display for TftpOption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return f.write_str(self.name());
+    }
+}
+
+/// Server side limits, a larger requested value is lowered to it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Limits {
+    pub max_blksize: u16,
+    /// max_blksize is also lowered to the path MTU of each client, known once its transfer socket is connected
+    pub blksize_from_mtu: bool,
+    /// The granted blksize is rounded down to a power of two, for clients only working with those
+    pub blksize_power_of_two: bool,
+    /// Blocks are sent one at a time, only 1 can be accepted for now
+    pub max_windowsize: u16,
+    /// Options ignored when requested
+    pub disabled: Vec<TftpOption>,
+    /// All the options are ignored, offset included: no OACK is ever sent (RFC 1350 transfers)
+    pub no_options: bool,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        return Limits {
+            max_blksize: MAX_BLKSIZE,
+            blksize_from_mtu: false,
+            blksize_power_of_two: false,
+            max_windowsize: 1,
+            disabled: Vec::new(),
+            no_options: false,
+        };
+    }
+}
+
+impl Limits {
+    /// The option is handled as if the client had not requested it
+    fn is_disabled(&self, name: &str) -> bool {
+        return self.no_options || self.disabled.iter().any(|option| option.name() == name);
+    }
+
+    /// Largest blksize granted for a request of blksize, at most max
+    pub fn grant_blksize(&self, blksize: u16, max: u16) -> u16 {
+        let blksize = blksize.min(max);
+        if self.blksize_power_of_two {
+            return 1 << blksize.ilog2();
+        }
+        return blksize;
+    }
+}
+
+/// Options in effect for a transfer
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransferOptions {
+    pub blksize: u16,
+    /// Retransmission timeout requested by the client, in seconds
+    pub timeout: Option<u8>,
+    /// Transfer size sent by the client (0 for a RRQ, the server answers with the file size)
+    pub tsize: Option<u64>,
+    pub windowsize: u16,
+    /// Bytes of the file skipped by a read, block 1 starts there
+    pub offset: u64,
+}
+
+impl Default for TransferOptions {
+    fn default() -> TransferOptions {
+        return TransferOptions { blksize: DEFAULT_BLKSIZE, timeout: None, tsize: None, windowsize: 1, offset: 0 };
+    }
+}
+
+/// Largest blksize whose DATA packets fit in mtu bytes, within the bounds of the option
+pub fn blksize_for_mtu(mtu: u32, ipv6: bool) -> u16 {
+    let overhead = if ipv6 { IPV6_DATA_OVERHEAD } else { IPV4_DATA_OVERHEAD };
+    return mtu.saturating_sub(overhead as u32).clamp(MIN_BLKSIZE as u32, MAX_BLKSIZE as u32) as u16;
+}
+
+/// Options to use and the accepted ones to send back in the OACK, names are case insensitive
+pub fn negotiate(requested: &[(String, String)], limits: &Limits) -> (TransferOptions, Vec<(String, String)>) {
+    let mut options = TransferOptions::default();
+    let mut accepted = Vec::new();
+    for (name, value) in requested {
+        let name = name.to_ascii_lowercase();
+        if limits.is_disabled(&name) {
+            continue;
+        }
+        match name.as_str() {
+            "blksize" => {
+                // Out of range values are invalid, a too large valid one is lowered
+                let Some(blksize) = value.parse::<u16>().ok().filter(|size| (MIN_BLKSIZE..=MAX_BLKSIZE).contains(size)) else {
+                    continue;
+                };
+                options.blksize = limits.grant_blksize(blksize, limits.max_blksize);
+                accepted.push((name, options.blksize.to_string()));
+            }
+            "timeout" => {
+                // The server cannot choose another value than the client one
+                let Some(timeout) = value.parse::<u8>().ok().filter(|timeout| (MIN_TIMEOUT..=MAX_TIMEOUT).contains(timeout)) else {
+                    continue;
+                };
+                options.timeout = Some(timeout);
+                accepted.push((name, timeout.to_string()));
+            }
+            "tsize" => {
+                let Ok(tsize) = value.parse::<u64>() else {
+                    continue;
+                };
+                options.tsize = Some(tsize);
+                accepted.push((name, tsize.to_string()));
+            }
+            "windowsize" => {
+                let Some(windowsize) = value.parse::<u16>().ok().filter(|size| (MIN_WINDOWSIZE..=MAX_WINDOWSIZE).contains(size)) else {
+                    continue;
+                };
+                options.windowsize = windowsize.min(limits.max_windowsize);
+                accepted.push((name, options.windowsize.to_string()));
+            }
+            "offset" => {
+                let Ok(offset) = value.parse::<u64>() else {
+                    continue;
+                };
+                options.offset = offset;
+                accepted.push((name, offset.to_string()));
+            }
+            _ => (),
+        }
+    }
+    return (options, accepted);
+}
+
+#[cfg(test)]
+mod test {
+    use crate::options::*;
+
+    fn pairs(options: &[(&str, &str)]) -> Vec<(String, String)> {
+        return options.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+    }
+
+    #[test]
+    fn no_options() {
+        let (options, oack) = negotiate(&[], &Limits::default());
+        assert_eq!(options, TransferOptions::default());
+        assert!(oack.is_empty());
+    }
+
+    #[test]
+    fn blksize() {
+        let limits = Limits { max_blksize: 1468, ..Limits::default() };
+        let (options, oack) = negotiate(&pairs(&[("blksize", "1024")]), &limits);
+        assert_eq!(options.blksize, 1024);
+        assert_eq!(oack, pairs(&[("blksize", "1024")]));
+        // Clamped to the server limit
+        let (options, oack) = negotiate(&pairs(&[("BLKSIZE", "8192")]), &limits);
+        assert_eq!(options.blksize, 1468);
+        assert_eq!(oack, pairs(&[("blksize", "1468")]));
+        // Exactly the limit
+        let (options, oack) = negotiate(&pairs(&[("blksize", "1468")]), &limits);
+        assert_eq!(options.blksize, 1468);
+        assert_eq!(oack, pairs(&[("blksize", "1468")]));
+        // Bounds of the RFC range
+        let (options, _) = negotiate(&pairs(&[("blksize", "8")]), &limits);
+        assert_eq!(options.blksize, 8);
+        let (options, oack) = negotiate(&pairs(&[("blksize", "65464")]), &Limits { max_blksize: DEFAULT_MAX_BLKSIZE, ..Limits::default() });
+        assert_eq!(options.blksize, DEFAULT_MAX_BLKSIZE);
+        assert_eq!(oack, pairs(&[("blksize", "1428")]));
+        // Outside of the RFC range
+        for invalid in ["7", "0", "65465", "abc", "-1"] {
+            let (options, oack) = negotiate(&pairs(&[("blksize", invalid)]), &limits);
+            assert_eq!(options.blksize, DEFAULT_BLKSIZE);
+            assert!(oack.is_empty(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn blksize_power_of_two() {
+        let limits = Limits { blksize_power_of_two: true, ..Limits::default() };
+        let (options, oack) = negotiate(&pairs(&[("blksize", "1500")]), &limits);
+        assert_eq!(options.blksize, 1024);
+        assert_eq!(oack, pairs(&[("blksize", "1024")]));
+        for (requested, granted) in [("8", 8), ("512", 512), ("1024", 1024), ("65464", 32768)] {
+            assert_eq!(negotiate(&pairs(&[("blksize", requested)]), &limits).0.blksize, granted, "{}", requested);
+        }
+        // After the server limit
+        let limits = Limits { max_blksize: 1468, ..limits };
+        assert_eq!(negotiate(&pairs(&[("blksize", "8192")]), &limits).0.blksize, 1024);
+        // Exact without the option
+        assert_eq!(negotiate(&pairs(&[("blksize", "1500")]), &Limits::default()).0.blksize, 1500);
+    }
+
+    #[test]
+    fn blksize_from_mtu() {
+        assert_eq!(blksize_for_mtu(1500, false), 1468);
+        assert_eq!(blksize_for_mtu(1500, true), 1448);
+        assert_eq!(blksize_for_mtu(9000, false), 8968);
+        // Loopback
+        assert_eq!(blksize_for_mtu(65536, false), MAX_BLKSIZE);
+        assert_eq!(blksize_for_mtu(20, false), MIN_BLKSIZE);
+    }
+
+    #[test]
+    fn timeout() {
+        let (options, oack) = negotiate(&pairs(&[("timeout", "5")]), &Limits::default());
+        assert_eq!(options.timeout, Some(5));
+        assert_eq!(oack, pairs(&[("timeout", "5")]));
+        for invalid in ["0", "256", "1.5"] {
+            let (options, oack) = negotiate(&pairs(&[("timeout", invalid)]), &Limits::default());
+            assert_eq!(options.timeout, None);
+            assert!(oack.is_empty(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn tsize() {
+        let (options, oack) = negotiate(&pairs(&[("tsize", "0")]), &Limits::default());
+        assert_eq!(options.tsize, Some(0));
+        assert_eq!(oack, pairs(&[("tsize", "0")]));
+        let (options, oack) = negotiate(&pairs(&[("tsize", "big")]), &Limits::default());
+        assert_eq!(options.tsize, None);
+        assert!(oack.is_empty());
+    }
+
+    #[test]
+    fn windowsize() {
+        // Clamped to the single block window
+        let (options, oack) = negotiate(&pairs(&[("windowsize", "16")]), &Limits::default());
+        assert_eq!(options.windowsize, 1);
+        assert_eq!(oack, pairs(&[("windowsize", "1")]));
+        let limits = Limits { max_windowsize: 8, ..Limits::default() };
+        let (options, _) = negotiate(&pairs(&[("windowsize", "4")]), &limits);
+        assert_eq!(options.windowsize, 4);
+        for invalid in ["0", "65536"] {
+            let (options, oack) = negotiate(&pairs(&[("windowsize", invalid)]), &limits);
+            assert_eq!(options.windowsize, 1);
+            assert!(oack.is_empty(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn offset() {
+        let (options, oack) = negotiate(&pairs(&[("offset", "1024")]), &Limits::default());
+        assert_eq!(options.offset, 1024);
+        assert_eq!(oack, pairs(&[("offset", "1024")]));
+        let (options, oack) = negotiate(&pairs(&[("offset", "-1")]), &Limits::default());
+        assert_eq!(options.offset, 0);
+        assert!(oack.is_empty());
+    }
+
+    #[test]
+    fn disabled_options() {
+        let requested = pairs(&[("blksize", "1024"), ("TSIZE", "0"), ("timeout", "3"), ("offset", "512")]);
+        let limits = Limits { disabled: vec![TftpOption::Tsize, TftpOption::Blksize], ..Limits::default() };
+        let (options, oack) = negotiate(&requested, &limits);
+        assert_eq!((options.blksize, options.tsize, options.timeout, options.offset), (DEFAULT_BLKSIZE, None, Some(3), 512));
+        assert_eq!(oack, pairs(&[("timeout", "3"), ("offset", "512")]));
+        let (options, oack) = negotiate(&requested, &Limits { no_options: true, ..Limits::default() });
+        assert_eq!(options, TransferOptions::default());
+        assert!(oack.is_empty());
+        assert_eq!("WindowSize".parse::<TftpOption>(), Ok(TftpOption::Windowsize));
+        assert!("offset".parse::<TftpOption>().is_err());
+    }
+
+    #[test]
+    fn unknown_options_ignored() {
+        let (options, oack) = negotiate(&pairs(&[("multicast", ""), ("blksize", "1428")]), &Limits::default());
+        assert_eq!(options.blksize, 1428);
+        assert_eq!(oack, pairs(&[("blksize", "1428")]));
+    }
+}
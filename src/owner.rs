@@ -0,0 +1,139 @@
+//! `--upload-owner USER[:GROUP]`: owner given to the completed uploads
+//!
+//! The names are resolved at startup, before the chroot and the sandbox hide /etc/passwd.
+//! Changing the owner of a file requires CAP_CHOWN: when privileges are dropped, Linux keeps
+//! this single capability across the user switch, elsewhere the uploads keep the served user.
+
+use std::ffi::CString;
+#[cfg(feature = "privdrop")]
+use std::io;
+
+/// uid and gid of USER[:GROUP], numeric ids are accepted, the primary group of the user by default
+pub fn resolve(owner: &str) -> Result<(u32, u32), String> {
+    let (user, group) = match owner.split_once(':') {
+        Some((user, group)) => (user, Some(group)),
+        None => (owner, None),
+    };
+    let (uid, primary_gid) = match lookup_user(user) {
+        Some(ids) => ids,
+        None => match user.parse::<u32>() {
+            // Without a passwd entry there is no primary group
+            Ok(uid) if group.is_some() => (uid, 0),
+            _ => return Err(format!("--upload-owner: unknown user {}", user)),
+        },
+    };
+    let gid = match group {
+        None => primary_gid,
+        Some(group) => match lookup_group(group).or_else(|| group.parse().ok()) {
+            Some(gid) => gid,
+            None => return Err(format!("--upload-owner: unknown group {}", group)),
+        },
+    };
+    return Ok((uid, gid));
+}
+
+fn lookup_user(name: &str) -> Option<(u32, u32)> {
+    let name = CString::new(name).ok()?;
+    let mut buf = vec![0 as libc::c_char; 4096];
+    // SAFETY: all zero is a valid passwd, only read once filled by getpwnam_r
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut found = std::ptr::null_mut();
+    // SAFETY: the pointers are valid for the call and buf.len() is the size of buf
+    let result = unsafe { libc::getpwnam_r(name.as_ptr(), &mut passwd, buf.as_mut_ptr(), buf.len(), &mut found) };
+    if result != 0 || found.is_null() {
+        return None;
+    }
+    return Some((passwd.pw_uid, passwd.pw_gid));
+}
+
+fn lookup_group(name: &str) -> Option<u32> {
+    let name = CString::new(name).ok()?;
+    // Groups list their members, large ones do not fit in a small buffer
+    let mut buf = vec![0 as libc::c_char; 65536];
+    // SAFETY: all zero is a valid group, only read once filled by getgrnam_r
+    let mut group: libc::group = unsafe { std::mem::zeroed() };
+    let mut found = std::ptr::null_mut();
+    // SAFETY: the pointers are valid for the call and buf.len() is the size of buf
+    let result = unsafe { libc::getgrnam_r(name.as_ptr(), &mut group, buf.as_mut_ptr(), buf.len(), &mut found) };
+    if result != 0 || found.is_null() {
+        return None;
+    }
+    return Some(group.gr_gid);
+}
+
+/// Run drop, the switch to an unprivileged user, keeping CAP_CHOWN only
+#[cfg(all(target_os = "linux", feature = "privdrop"))]
+pub fn drop_keeping_chown<E>(drop: impl FnOnce() -> Result<(), E>) -> Result<io::Result<()>, E> {
+    // SAFETY: prctl with PR_SET_KEEPCAPS only changes a flag of the process
+    if unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0) } != 0 {
+        let error = io::Error::last_os_error();
+        drop()?;
+        return Ok(Err(error));
+    }
+    drop()?;
+    // SAFETY: as above
+    unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, 0, 0, 0, 0) };
+    return Ok(capabilities::set_only(CAP_CHOWN));
+}
+
+#[cfg(all(not(target_os = "linux"), feature = "privdrop"))]
+pub fn drop_keeping_chown<E>(drop: impl FnOnce() -> Result<(), E>) -> Result<io::Result<()>, E> {
+    drop()?;
+    return Ok(Err(io::Error::new(io::ErrorKind::Unsupported, "capabilities are Linux only")));
+}
+
+#[cfg(all(target_os = "linux", feature = "privdrop"))]
+const CAP_CHOWN: u32 = 0;
+
+#[cfg(all(target_os = "linux", feature = "privdrop"))]
+mod capabilities {
+    use std::io;
+
+    /// _LINUX_CAPABILITY_VERSION_3, 64 bits capability sets as two u32
+    const VERSION_3: u32 = 0x2008_0522;
+
+    /// struct __user_cap_header_struct of the kernel ABI, not in libc
+    #[repr(C)]
+    struct Header {
+        version: u32,
+        pid: libc::c_int,
+    }
+
+    /// struct __user_cap_data_struct of the kernel ABI
+    #[repr(C)]
+    #[derive(Clone, Copy, Default)]
+    struct Data {
+        effective: u32,
+        permitted: u32,
+        inheritable: u32,
+    }
+
+    /// Keep this capability, effective and permitted, and drop all the others
+    pub fn set_only(capability: u32) -> io::Result<()> {
+        let mut header = Header { version: VERSION_3, pid: 0 };
+        let mut data = [Data::default(); 2];
+        let bit = 1 << (capability % 32);
+        data[capability as usize / 32] = Data { effective: bit, permitted: bit, inheritable: 0 };
+        // SAFETY: header and the two data structs of version 3 are valid for the call
+        if unsafe { libc::syscall(libc::SYS_capset, &mut header as *mut Header, data.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::owner::*;
+
+    #[test]
+    fn resolve_owner() {
+        assert_eq!(resolve("root"), Ok((0, 0)));
+        assert_eq!(resolve("root:0"), Ok((0, 0)));
+        assert_eq!(resolve("1234:5678"), Ok((1234, 5678)));
+        assert!(resolve("no-such-user-tftp").unwrap_err().contains("unknown user"));
+        assert!(resolve("root:no-such-group-tftp").unwrap_err().contains("unknown group"));
+        // A numeric user alone has no primary group to take
+        assert!(resolve("1234").is_err());
+    }
+}
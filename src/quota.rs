@@ -0,0 +1,94 @@
+//! Bytes transferred per client IP, across its transfers and ports, reset every day at 00:00 UTC
+//!
+//! Once a client reached the limit its new requests are refused, the transfers in progress
+//! are not interrupted so the limit can be exceeded by their remaining blocks.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SECONDS_PER_DAY: u64 = 86_400;
+
+#[derive(Debug)]
+pub struct QuotaTracker {
+    limit: u64,
+    usage: Mutex<DailyUsage>,
+}
+
+#[derive(Debug, Default)]
+struct DailyUsage {
+    /// Days since the epoch of the counted bytes
+    day: u64,
+    bytes: HashMap<IpAddr, u64>,
+}
+
+impl QuotaTracker {
+    /// Limit in bytes per client and per day, both directions counted
+    pub fn new(limit: u64) -> Arc<QuotaTracker> {
+        return Arc::new(QuotaTracker { limit, usage: Mutex::new(DailyUsage::default()) });
+    }
+
+    pub fn limit(&self) -> u64 {
+        return self.limit;
+    }
+
+    /// Bytes transferred with this client today
+    pub fn used(&self, ip: IpAddr) -> u64 {
+        return self.used_at(ip, SystemTime::now());
+    }
+
+    pub fn exceeded(&self, ip: IpAddr) -> bool {
+        return self.used(ip) >= self.limit;
+    }
+
+    pub(crate) fn add(&self, ip: IpAddr, bytes: u64) {
+        self.add_at(ip, bytes, SystemTime::now());
+    }
+
+    fn used_at(&self, ip: IpAddr, now: SystemTime) -> u64 {
+        return self.today(now, |usage| usage.get(&client(ip)).copied().unwrap_or(0));
+    }
+
+    fn add_at(&self, ip: IpAddr, bytes: u64, now: SystemTime) {
+        self.today(now, |usage| {
+            let used = usage.entry(client(ip)).or_insert(0);
+            *used = used.saturating_add(bytes);
+        });
+    }
+
+    /// Run f on the usage of the day, dropping the previous days
+    fn today<T>(&self, now: SystemTime, f: impl FnOnce(&mut HashMap<IpAddr, u64>) -> T) -> T {
+        let day = now.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs() / SECONDS_PER_DAY);
+        let mut usage = self.usage.lock().unwrap_or_else(|e| e.into_inner());
+        if usage.day != day {
+            usage.day = day;
+            usage.bytes.clear();
+        }
+        return f(&mut usage.bytes);
+    }
+}
+
+/// An IPv4 client reaching a dual stack socket is counted as the same client
+fn client(ip: IpAddr) -> IpAddr {
+    return ip.to_canonical();
+}
+
+#[cfg(test)]
+mod test {
+    use crate::quota::*;
+    use std::time::Duration;
+
+    #[test]
+    fn daily_usage_per_ip() {
+        let quota = QuotaTracker::new(1000);
+        let ip: IpAddr = "10.0.0.42".parse().unwrap();
+        let monday = UNIX_EPOCH + Duration::from_secs(19_800 * SECONDS_PER_DAY + 3600);
+        quota.add_at(ip, 600, monday);
+        quota.add_at("::ffff:10.0.0.42".parse().unwrap(), 600, monday + Duration::from_secs(60));
+        assert_eq!(quota.used_at(ip, monday), 1200);
+        assert_eq!(quota.used_at("10.0.0.43".parse().unwrap(), monday), 0);
+        // Next day
+        assert_eq!(quota.used_at(ip, monday + Duration::from_secs(SECONDS_PER_DAY)), 0);
+    }
+}
@@ -0,0 +1,116 @@
+//! Read ahead of the served files: a chunk of the file is read at once and the blocks are sliced out of it
+//!
+//! A block per read costs a syscall (and an open) per ACK, with the default 512 bytes blocks a large file
+//! takes many of them. The chunk is refilled only when a block is not in it, a retransmitted block
+//! is usually still there.
+
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom};
+
+/// Bytes read at once, above the largest block size (65464) so that a chunk holds any block
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Default)]
+pub struct ReadAhead {
+    /// File offset of the first byte of data
+    start: u64,
+    data: Vec<u8>,
+    /// data ends at the end of the file
+    eof: bool,
+    /// data holds a chunk, false before the first fill and after a failed one
+    filled: bool,
+}
+
+impl ReadAhead {
+    pub fn new() -> ReadAhead {
+        return ReadAhead::default();
+    }
+
+    /// The block of len bytes at offset can be taken without filling, including the short last one
+    pub fn holds(&self, offset: u64, len: usize) -> bool {
+        let end = self.start + self.data.len() as u64;
+        if offset < self.start || !self.filled {
+            return false;
+        }
+        return offset + len as u64 <= end || self.eof;
+    }
+
+    /// Read the chunk starting at offset from file
+    pub fn fill<R: Read + Seek>(&mut self, file: &mut R, offset: u64) -> io::Result<()> {
+        self.filled = false;
+        file.seek(SeekFrom::Start(offset))?;
+        self.data.resize(CHUNK_SIZE, 0);
+        let mut filled = 0;
+        while filled < self.data.len() {
+            match file.read(&mut self.data[filled..]) {
+                Ok(0) => break,
+                Ok(read) => filled += read,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        self.eof = filled < self.data.len();
+        self.data.truncate(filled);
+        self.start = offset;
+        self.filled = true;
+        return Ok(());
+    }
+
+    /// The block of len bytes at offset, shorter at the end of the file, None when it starts past the end.
+    /// Only valid once holds is true.
+    pub fn block(&self, offset: u64, len: usize) -> Option<&[u8]> {
+        let from = (offset - self.start) as usize;
+        if from > self.data.len() {
+            return None;
+        }
+        return Some(&self.data[from..self.data.len().min(from + len)]);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::read_ahead::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn blocks_across_chunks() {
+        // The last chunk ends with a short block
+        let content: Vec<u8> = (0..CHUNK_SIZE * 2 + 700).map(|i| (i % 251) as u8).collect();
+        let mut file = Cursor::new(content.clone());
+        let mut ahead = ReadAhead::new();
+        let mut fills = 0;
+        let mut read = Vec::new();
+        for blocksize in [512, 1468] {
+            read.clear();
+            let mut offset = 0;
+            loop {
+                if !ahead.holds(offset, blocksize) {
+                    ahead.fill(&mut file, offset).unwrap();
+                    fills += 1;
+                }
+                let block = ahead.block(offset, blocksize).unwrap();
+                read.extend_from_slice(block);
+                offset += blocksize as u64;
+                if block.len() < blocksize {
+                    break;
+                }
+            }
+            assert_eq!(read, content);
+        }
+        // 3 chunks per pass, the first block of the second pass is not buffered anymore
+        assert_eq!(fills, 6);
+        assert!(ahead.holds(content.len() as u64 + 1, 512));
+        assert_eq!(ahead.block(content.len() as u64 + 1, 512), None);
+    }
+
+    #[test]
+    fn block_size_multiple() {
+        let mut file = Cursor::new(vec![7; 1024]);
+        let mut ahead = ReadAhead::new();
+        assert!(!ahead.holds(0, 512));
+        ahead.fill(&mut file, 0).unwrap();
+        assert_eq!(ahead.block(512, 512), Some(&[7; 512][..]));
+        // Ends with an empty block
+        assert!(ahead.holds(1024, 512));
+        assert_eq!(ahead.block(1024, 512), Some(&[][..]));
+    }
+}
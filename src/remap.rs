@@ -0,0 +1,176 @@
+//! Rewrite rules of the requested filenames, `[[remap]]` in the configuration file
+//!
+//! Rules are tried in order on the sanitized filename, the first one matching rewrites it
+//! and the others are skipped. The result is sanitized again when the file is looked up,
+//! so a rule cannot lead out of the served directory. Replacements can hold the variables of
+//! the client, `${ip}` for example (see `variables`).
+
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use log::debug;
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::variables;
+
+/// Rule as written in the configuration file
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RemapRule {
+    /// Exact filename, or a regex when regex is set
+    #[serde(rename = "match")]
+    pub pattern: String,
+    /// Filename replacing it, with `$1` or `${name}` for the groups of a regex.
+    /// The variables of the client take precedence over groups of the same name.
+    pub replacement: String,
+    #[serde(default)]
+    pub regex: bool,
+}
+
+#[derive(Debug)]
+enum Pattern {
+    Exact(String),
+    /// Replaces the first match, anchor it with `^...$` to match whole filenames
+    Regex(Regex),
+}
+
+#[derive(Debug)]
+struct Rule {
+    pattern: Pattern,
+    replacement: String,
+}
+
+/// Compiled rules, shared by all the transfers and replaced as a whole on reload
+#[derive(Debug, Clone, Default)]
+pub struct Remap {
+    rules: Arc<RwLock<Vec<Rule>>>,
+}
+
+impl Remap {
+    pub fn new(rules: &[RemapRule]) -> Result<Remap, String> {
+        return Ok(Remap { rules: Arc::new(RwLock::new(compile(rules)?)) });
+    }
+
+    /// Replace the rules, the current ones are kept when one of the new ones is invalid
+    pub fn reload(&self, rules: &[RemapRule]) -> Result<(), String> {
+        let rules = compile(rules)?;
+        *self.rules.write().unwrap_or_else(|e| e.into_inner()) = rules;
+        return Ok(());
+    }
+
+    pub fn len(&self) -> usize {
+        return self.rules.read().unwrap_or_else(|e| e.into_inner()).len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+
+    /// Filename rewritten by the first matching rule for this client, None when no rule matches.
+    /// Names which are not UTF-8 never match.
+    pub fn apply(&self, filename: &Path, peer: SocketAddr) -> Option<PathBuf> {
+        let name = filename.to_str()?;
+        let rules = self.rules.read().unwrap_or_else(|e| e.into_inner());
+        for (index, rule) in rules.iter().enumerate() {
+            let rewritten = match &rule.pattern {
+                Pattern::Exact(exact) if exact == name => variables::expand(&rule.replacement, peer),
+                Pattern::Regex(regex) if regex.is_match(name) => regex.replace(name, variables::expand(&rule.replacement, peer)).into_owned(),
+                _ => continue,
+            };
+            debug!("{} remapped to {} by rule {}", name, rewritten, index + 1);
+            return Some(PathBuf::from(rewritten));
+        }
+        return None;
+    }
+}
+
+fn compile(rules: &[RemapRule]) -> Result<Vec<Rule>, String> {
+    let mut compiled = Vec::with_capacity(rules.len());
+    for (index, rule) in rules.iter().enumerate() {
+        let pattern = match rule.regex {
+            true => Pattern::Regex(Regex::new(&rule.pattern).map_err(|e| format!("Invalid regex in remap rule {}: {}", index + 1, e))?),
+            false => Pattern::Exact(rule.pattern.clone()),
+        };
+        // Fails at load rather than at the first request matching the rule
+        let group = |name: &str| match &pattern {
+            Pattern::Regex(regex) => regex.capture_names().flatten().any(|group| group == name)
+                || name.parse::<usize>().is_ok_and(|group| group < regex.captures_len()),
+            Pattern::Exact(_) => false,
+        };
+        variables::check(&rule.replacement, group).map_err(|e| format!("Invalid replacement in remap rule {}: {}", index + 1, e))?;
+        compiled.push(Rule { pattern, replacement: rule.replacement.clone() });
+    }
+    return Ok(compiled);
+}
+
+#[cfg(test)]
+mod test {
+    use crate::remap::*;
+
+    const PEER: &str = "10.0.0.42:2001";
+
+    fn apply(remap: &Remap, filename: &str) -> Option<PathBuf> {
+        return remap.apply(Path::new(filename), PEER.parse().unwrap());
+    }
+
+    fn rule(pattern: &str, replacement: &str, regex: bool) -> RemapRule {
+        return RemapRule { pattern: pattern.to_string(), replacement: replacement.to_string(), regex };
+    }
+
+    #[test]
+    fn first_matching_rule() {
+        let remap = Remap::new(&[
+            rule("pxelinux.0", "pxelinux-6.04.0", false),
+            rule(r"^[^/]+/(pxelinux\.cfg/.*)$", "$1", true),
+            rule(r"^pxelinux.*", "never", true),
+        ]).unwrap();
+        assert_eq!(apply(&remap, "pxelinux.0"), Some(PathBuf::from("pxelinux-6.04.0")));
+        assert_eq!(apply(&remap, "bogus/pxelinux.cfg/default"), Some(PathBuf::from("pxelinux.cfg/default")));
+        // Exact names are not prefixes
+        assert_eq!(apply(&remap, "pxelinux.0.bak"), Some(PathBuf::from("never")));
+    }
+
+    #[test]
+    fn regex_captures() {
+        let remap = Remap::new(&[rule(r"^boot/(?P<mac>[0-9a-f]{12})\.(cfg|ipxe)$", "hosts/${mac}/boot.$2", true)]).unwrap();
+        assert_eq!(apply(&remap, "boot/0a1b2c3d4e5f.ipxe"), Some(PathBuf::from("hosts/0a1b2c3d4e5f/boot.ipxe")));
+    }
+
+    #[test]
+    fn no_match_passthrough() {
+        let remap = Remap::new(&[rule("pxelinux.0", "pxelinux-6.04.0", false)]).unwrap();
+        assert_eq!(apply(&remap, "ldlinux.c32"), None);
+        assert_eq!(apply(&Remap::default(), "pxelinux.0"), None);
+    }
+
+    #[test]
+    fn reload_keeps_rules_on_error() {
+        let remap = Remap::new(&[rule("a", "b", false)]).unwrap();
+        let shared = remap.clone();
+        assert!(remap.reload(&[rule("(", "b", true)]).unwrap_err().contains("rule 1"));
+        assert_eq!(apply(&shared, "a"), Some(PathBuf::from("b")));
+        remap.reload(&[rule("a", "c", false), rule("x", "y", false)]).unwrap();
+        assert_eq!(apply(&shared, "a"), Some(PathBuf::from("c")));
+        assert_eq!(shared.len(), 2);
+    }
+
+    #[test]
+    fn client_variables() {
+        let remap = Remap::new(&[
+            rule("pxelinux.cfg/default", "pxelinux.cfg/${ip_dashed}", false),
+            rule(r"^configs/(?P<name>[^/]+)$", "configs/byip/${ip}/${name}", true),
+        ]).unwrap();
+        assert_eq!(apply(&remap, "pxelinux.cfg/default"), Some(PathBuf::from("pxelinux.cfg/10-0-0-42")));
+        assert_eq!(apply(&remap, "configs/switch.cfg"), Some(PathBuf::from("configs/byip/10.0.0.42/switch.cfg")));
+        let ipv6 = "[fd00::42]:2001".parse().unwrap();
+        assert_eq!(remap.apply(Path::new("configs/switch.cfg"), ipv6), Some(PathBuf::from("configs/byip/fd00::42/switch.cfg")));
+        // Unknown at load, groups only exist in regex rules
+        assert!(Remap::new(&[rule("a", "${host}", false)]).unwrap_err().contains("rule 1"));
+        assert!(Remap::new(&[rule("(?P<name>a)", "${name}", false)]).is_err());
+        assert!(Remap::new(&[rule("(a)", "${2}", true)]).is_err());
+        assert!(remap.reload(&[rule("a", "${host}", false)]).is_err());
+        assert_eq!(remap.len(), 2);
+    }
+}
@@ -0,0 +1,107 @@
+//! `--landlock`: kernel enforced confinement to the served directory, without root
+//!
+//! Landlock (Linux 5.13+) restricts the calling thread and the threads it creates afterwards,
+//! so the ruleset is applied before the runtime starts its workers. The served directory
+//! is readable and writable for uploads, the other `--directory` are readable, the log directories are writable for rotation,
+//! any other file access is refused. Older kernels run unconfined with a warning.
+
+use std::error::Error;
+use std::path::PathBuf;
+
+use landlock::{Access, AccessFs, LandlockStatus, PathBeneath, PathFd, RestrictionStatus, Ruleset, RulesetAttr,
+               RulesetCreatedAttr, RulesetStatus, ABI};
+use log::{info, warn};
+
+/// Rights up to this ABI are requested, a kernel with an older one enforces what it knows
+const ABI_TARGET: ABI = ABI::V3;
+
+#[derive(Debug)]
+pub struct Sandbox {
+    /// Served directory
+    pub root: PathBuf,
+    /// Directories served on a bind address instead of root, with the same rights
+    pub bind_roots: Vec<PathBuf>,
+    /// Directories searched by reads after root, read only
+    pub read_roots: Vec<PathBuf>,
+    /// Uploads can only replace existing files, no file creation in the root
+    pub no_create: bool,
+    /// Uploads create the missing directories
+    pub create_dirs: bool,
+    /// Directories of the log files
+    pub log_dirs: Vec<PathBuf>,
+}
+
+impl Sandbox {
+    /// Restrict the current thread and its future threads
+    pub fn restrict_self(&self) -> Result<RestrictionStatus, Box<dyn Error>> {
+        let root = PathFd::new(&self.root)
+            .map_err(|e| format!("Cannot sandbox directory {}: {}", self.root.display(), e))?;
+        let mut root_access = AccessFs::from_read(ABI_TARGET) | AccessFs::WriteFile | AccessFs::Truncate | AccessFs::RemoveFile;
+        if !self.no_create {
+            root_access |= AccessFs::MakeReg;
+        }
+        if self.create_dirs {
+            root_access |= AccessFs::MakeDir;
+        }
+        let mut ruleset = Ruleset::default()
+            .handle_access(AccessFs::from_all(ABI_TARGET))?
+            .create()?
+            .add_rule(PathBeneath::new(root, root_access))?;
+        for dir in &self.bind_roots {
+            let fd = PathFd::new(dir).map_err(|e| format!("Cannot sandbox directory {}: {}", dir.display(), e))?;
+            ruleset = ruleset.add_rule(PathBeneath::new(fd, root_access))?;
+        }
+        for dir in &self.read_roots {
+            let fd = PathFd::new(dir).map_err(|e| format!("Cannot sandbox directory {}: {}", dir.display(), e))?;
+            ruleset = ruleset.add_rule(PathBeneath::new(fd, AccessFs::from_read(ABI_TARGET)))?;
+        }
+        // A missing log directory fails later when opening the log anyway
+        for dir in &self.log_dirs {
+            if let Ok(fd) = PathFd::new(dir) {
+                ruleset = ruleset.add_rule(PathBeneath::new(fd, AccessFs::from_all(ABI_TARGET)))?;
+            }
+        }
+        return Ok(ruleset.restrict_self()?);
+    }
+}
+
+/// Log how much of the ruleset the kernel enforces
+pub fn report(status: &RestrictionStatus) {
+    let abi = match status.landlock {
+        LandlockStatus::Available { effective_abi, .. } => effective_abi,
+        _ => ABI::Unsupported,
+    };
+    match status.ruleset {
+        RulesetStatus::FullyEnforced => info!("Landlock sandbox fully enforced (ABI {})", abi),
+        RulesetStatus::PartiallyEnforced => warn!("Landlock sandbox partially enforced (ABI {}), a newer kernel confines more operations", abi),
+        RulesetStatus::NotEnforced => warn!("Landlock not available ({:?}), running without sandbox", status.landlock),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::sandbox::*;
+    use std::fs;
+    use std::io::ErrorKind;
+
+    #[test]
+    fn open_outside_root_fails() {
+        let root = std::env::temp_dir().join(format!("tftp-landlock-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("inside.txt"), "inside").unwrap();
+        let outside = std::path::absolute("Cargo.toml").unwrap();
+        let sandbox = Sandbox { root: root.clone(), bind_roots: Vec::new(), read_roots: Vec::new(), no_create: false, create_dirs: false, log_dirs: Vec::new() };
+
+        // Only this thread is restricted, the other tests keep running unconfined
+        std::thread::spawn(move || {
+            let status = sandbox.restrict_self().unwrap();
+            if status.ruleset == RulesetStatus::NotEnforced {
+                eprintln!("Landlock not supported by this kernel, skipping");
+                return;
+            }
+            assert_eq!(fs::read_to_string(root.join("inside.txt")).unwrap(), "inside");
+            fs::write(root.join("uploaded.txt"), "uploaded").unwrap();
+            assert_eq!(fs::File::open(&outside).unwrap_err().kind(), ErrorKind::PermissionDenied);
+        }).join().unwrap();
+    }
+}
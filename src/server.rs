@@ -0,0 +1,2123 @@
+//! UDP loop: the listening socket only receives new requests,
+//! each transfer then runs in its own task with its own socket (RFC 1350 transfer ID)
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use bytes::BytesMut;
+use ipnet::IpNet;
+use log::{debug, info, warn};
+use serde::Deserialize;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{self, Sender};
+use tokio::sync::{Notify, Semaphore};
+use tokio::task::JoinError;
+use tokio::time::timeout;
+
+use crate::buffer_pool::BufferPool;
+use crate::error_limit::ErrorLimiter;
+#[cfg(feature = "chaos")]
+use crate::fault::{FaultInjector, TransferFaults};
+use crate::health;
+use crate::inject::Injection;
+use crate::options::{self, FALLBACK_MTU_BLKSIZE};
+use crate::quota::QuotaTracker;
+use crate::session::{Cancel, Session, Sessions};
+use crate::socket::{self, PacketMarking};
+use crate::stats::ServerStats;
+use crate::tftp::tftpprotocol;
+use crate::tftp::tftpprotocol::{Action, Command, FileData, OpContext, Reply, ServerOptions, TftpError};
+#[cfg(target_os = "linux")]
+use crate::zero_copy;
+use crate::virtual_file::VirtualFiles;
+use crate::write_lock::WriteLocks;
+
+/// A transfer without any packet from the client for this long is abandoned
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Room after a full DATA packet in the transfer receive buffer, for ERROR packets with long
+/// messages, a packet filling the buffer is truncated
+const RECV_HEADROOM: usize = 512;
+
+/// Delay before sending the last packet again, unless the client negotiated a timeout
+const DEFAULT_RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Requests accepted but waiting for a free transfer slot, beyond them new requests are rejected
+const DEFAULT_QUEUE_SIZE: usize = 64;
+
+/// Idle buffers kept for the control packets (ACK, OACK, ERROR), one is used per transfer
+const DEFAULT_BUFFER_POOL_CAP: usize = 32;
+
+/// At most one log line per interval for the packets dropped for their source address
+const INVALID_SOURCE_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Shared by all the servers of the process so that IDs stay unique
+static NEXT_TRANSFER_ID: AtomicU64 = AtomicU64::new(1);
+
+tokio::task_local! {
+    static TRANSFER_ID: u64;
+}
+
+/// ID of the transfer whose task is running, for loggers to tag the lines with it
+pub fn current_transfer_id() -> Option<u64> {
+    return TRANSFER_ID.try_with(|id| *id).ok();
+}
+
+/// Sent for each new block transferred, to follow transfers from an embedding application
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProgressEvent {
+    pub transfer_id: u64,
+    pub peer: SocketAddr,
+    pub filename: String,
+    pub blocks_done: u64,
+    /// Known for reads (file size), None for writes
+    pub total_blocks: Option<u64>,
+}
+
+/// Message of the ERROR packets sent to the clients, written in lowercase
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorDetail {
+    /// The message of the server, with the detail of the failure, for trusted networks
+    #[default]
+    Full,
+    /// Only the message of the RFC for the code, nothing about the server internals
+    Generic,
+}
+
+impl FromStr for ErrorDetail {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<ErrorDetail, String> {
+        match value.to_ascii_lowercase().as_str() {
+            "full" => return Ok(ErrorDetail::Full),
+            "generic" => return Ok(ErrorDetail::Generic),
+            _ => return Err(format!("Unknown error detail {}, expected full or generic", value)),
+        }
+    }
+}
+
+impl fmt::Display for ErrorDetail {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorDetail::Full => return f.write_str("full"),
+            ErrorDetail::Generic => return f.write_str("generic"),
+        }
+    }
+}
+
+pub struct Server {
+    socket: UdpSocket,
+    alive: Arc<AtomicBool>,
+    progress: Option<Sender<ProgressEvent>>,
+    options: ServerOptions,
+    virtual_files: VirtualFiles,
+    results: Vec<Sender<TransferResult>>,
+    sessions: Sessions,
+    write_locks: WriteLocks,
+    stats: Arc<ServerStats>,
+    max_transfers: usize,
+    transfer_slots: Option<Arc<Semaphore>>,
+    queue_size: usize,
+    reply_busy: bool,
+    buffer_pool_cap: usize,
+    injection: Injection,
+    max_retries: Option<u32>,
+    marking: PacketMarking,
+    quota: Option<Arc<QuotaTracker>>,
+    error_limit: Option<Arc<ErrorLimiter>>,
+    server_tag: Option<String>,
+    error_detail: ErrorDetail,
+    allowed_subnets: Vec<IpNet>,
+    #[cfg(feature = "chaos")]
+    faults: Option<Arc<FaultInjector>>,
+}
+
+/// Server settings used by all its transfer tasks
+struct Shared {
+    progress: Option<Sender<ProgressEvent>>,
+    virtual_files: VirtualFiles,
+    results: Vec<Sender<TransferResult>>,
+    sessions: Sessions,
+    write_locks: WriteLocks,
+    stats: Arc<ServerStats>,
+    buffers: Arc<BufferPool>,
+    injection: Injection,
+    max_retries: Option<u32>,
+    marking: PacketMarking,
+    quota: Option<Arc<QuotaTracker>>,
+    error_limit: Option<Arc<ErrorLimiter>>,
+    server_tag: Option<String>,
+    error_detail: ErrorDetail,
+    #[cfg(feature = "chaos")]
+    faults: Option<Arc<FaultInjector>>,
+}
+
+/// Peers with an accepted request, a repeated request goes to their transfer rather than starting another one
+#[derive(Default)]
+struct ActivePeers {
+    peers: Mutex<HashMap<SocketAddr, Arc<Notify>>>,
+}
+
+impl ActivePeers {
+    /// None when the peer already has a transfer, which is then notified of the repeated request
+    fn accept(self: &Arc<Self>, peer: SocketAddr) -> Option<PeerGuard> {
+        let mut peers = self.peers.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(repeated) = peers.get(&peer) {
+            repeated.notify_one();
+            return None;
+        }
+        let repeated = Arc::new(Notify::new());
+        peers.insert(peer, repeated.clone());
+        return Some(PeerGuard { peers: self.clone(), peer, repeated });
+    }
+}
+
+/// Keeps the peer registered until its transfer ends or its request is rejected
+struct PeerGuard {
+    peers: Arc<ActivePeers>,
+    peer: SocketAddr,
+    repeated: Arc<Notify>,
+}
+
+impl Drop for PeerGuard {
+    fn drop(&mut self) {
+        self.peers.peers.lock().unwrap_or_else(|e| e.into_inner()).remove(&self.peer);
+    }
+}
+
+impl Server {
+    pub fn new(socket: UdpSocket, alive: Arc<AtomicBool>) -> Server {
+        return Server {
+            socket,
+            alive,
+            progress: None,
+            options: ServerOptions::default(),
+            virtual_files: VirtualFiles::new(),
+            results: Vec::new(),
+            sessions: Sessions::new(),
+            write_locks: WriteLocks::new(),
+            stats: Arc::new(ServerStats::new()),
+            max_transfers: Semaphore::MAX_PERMITS,
+            transfer_slots: None,
+            queue_size: DEFAULT_QUEUE_SIZE,
+            reply_busy: false,
+            buffer_pool_cap: DEFAULT_BUFFER_POOL_CAP,
+            injection: Injection::default(),
+            max_retries: None,
+            marking: PacketMarking::default(),
+            quota: None,
+            error_limit: None,
+            server_tag: None,
+            error_detail: ErrorDetail::default(),
+            allowed_subnets: Vec::new(),
+            #[cfg(feature = "chaos")]
+            faults: None,
+        };
+    }
+
+    /// Report transfer progress to this channel, a full channel slows down the transfers
+    pub fn with_progress(mut self, progress: Sender<ProgressEvent>) -> Server {
+        self.progress = Some(progress);
+        return self;
+    }
+
+    pub fn with_options(mut self, options: ServerOptions) -> Server {
+        self.options = options;
+        return self;
+    }
+
+    /// Serve these generated files, they take precedence over the files on disk
+    pub fn with_virtual_files(mut self, virtual_files: VirtualFiles) -> Server {
+        self.virtual_files = virtual_files;
+        return self;
+    }
+
+    /// Send the result of each finished transfer to this channel, e.g. for an audit log,
+    /// each call adds a channel
+    pub fn with_results(mut self, results: Sender<TransferResult>) -> Server {
+        self.results.push(results);
+        return self;
+    }
+
+    /// Register the transfers in this table, which can be shared by several servers
+    pub fn with_sessions(mut self, sessions: Sessions) -> Server {
+        self.sessions = sessions;
+        return self;
+    }
+
+    /// Files being uploaded, shared by the servers writing to the same directory
+    pub fn with_write_locks(mut self, write_locks: WriteLocks) -> Server {
+        self.write_locks = write_locks;
+        return self;
+    }
+
+    /// Count in these statistics, which can be shared by several servers
+    pub fn with_stats(mut self, stats: Arc<ServerStats>) -> Server {
+        self.stats = stats;
+        return self;
+    }
+
+    /// Run at most this many transfers at once, the next requests wait in the queue
+    pub fn with_max_transfers(mut self, max_transfers: usize) -> Server {
+        self.max_transfers = max_transfers.clamp(1, Semaphore::MAX_PERMITS);
+        return self;
+    }
+
+    /// Take the transfer slots from this semaphore rather than from with_max_transfers,
+    /// to share the limit between the servers of one address (workers)
+    pub fn with_transfer_slots(mut self, slots: Arc<Semaphore>) -> Server {
+        self.transfer_slots = Some(slots);
+        return self;
+    }
+
+    /// Number of requests waiting for a transfer slot, requests arriving when it is full are dropped
+    pub fn with_queue_size(mut self, queue_size: usize) -> Server {
+        self.queue_size = queue_size.max(1);
+        return self;
+    }
+
+    /// Answer the dropped requests with an ERROR rather than letting the client retry
+    pub fn with_reply_busy(mut self, reply_busy: bool) -> Server {
+        self.reply_busy = reply_busy;
+        return self;
+    }
+
+    /// Keep at most this many idle packet buffers, sized for the largest block size allowed
+    pub fn with_buffer_pool_cap(mut self, buffer_pool_cap: usize) -> Server {
+        self.buffer_pool_cap = buffer_pool_cap;
+        return self;
+    }
+
+    /// Abort a transfer after this many retransmissions of the same packet, rather than
+    /// after 10 s without answer
+    pub fn with_max_retries(mut self, max_retries: u32) -> Server {
+        self.max_retries = Some(max_retries);
+        return self;
+    }
+
+    /// DSCP and TTL of the packets sent by the transfers, see ListenOptions for the listening socket
+    pub fn with_marking(mut self, marking: PacketMarking) -> Server {
+        self.marking = marking;
+        return self;
+    }
+
+    /// Prefix the message of the ERROR packets sent with `[tag]`, to tell which server sent them
+    pub fn with_server_tag(mut self, tag: String) -> Server {
+        self.server_tag = Some(tag);
+        return self;
+    }
+
+    /// Message of the ERROR packets sent, the detailed one is logged either way
+    pub fn with_error_detail(mut self, error_detail: ErrorDetail) -> Server {
+        self.error_detail = error_detail;
+        return self;
+    }
+
+    /// Only answer the clients in these subnets, the packets of the others are dropped without reply.
+    /// Empty, the default, allows all clients
+    pub fn with_allowed_subnets(mut self, subnets: Vec<IpNet>) -> Server {
+        self.allowed_subnets = subnets;
+        return self;
+    }
+
+    /// Limit the ERROR packets answering a request, the limiter can be shared between servers
+    pub fn with_error_limit(mut self, error_limit: Arc<ErrorLimiter>) -> Server {
+        self.error_limit = Some(error_limit);
+        return self;
+    }
+
+    /// Refuse the requests of the clients over their daily quota, the tracker can be shared between servers
+    pub fn with_quota(mut self, quota: Arc<QuotaTracker>) -> Server {
+        self.quota = Some(quota);
+        return self;
+    }
+
+    /// Delay or drop the packets sent by the transfers, for client tests only
+    pub fn with_injection(mut self, injection: Injection) -> Server {
+        self.injection = injection;
+        return self;
+    }
+
+    /// Drop, duplicate or delay the packets of each transfer, reproducibly, to test the protocol logic
+    #[cfg(feature = "chaos")]
+    pub fn with_fault_injector(mut self, injector: FaultInjector) -> Server {
+        self.faults = Some(Arc::new(injector));
+        return self;
+    }
+
+    /// Counters of the server, `active_sessions` is the current transfer count
+    pub fn stats(&self) -> Arc<ServerStats> {
+        return self.stats.clone();
+    }
+
+    pub async fn run(self) -> Result<(), io::Error> {
+        let Server {
+            socket,
+            alive,
+            progress,
+            options,
+            virtual_files,
+            results,
+            sessions,
+            write_locks,
+            stats,
+            max_transfers,
+            transfer_slots,
+            queue_size,
+            reply_busy,
+            buffer_pool_cap,
+            injection,
+            max_retries,
+            marking,
+            quota,
+            error_limit,
+            server_tag,
+            error_detail,
+            allowed_subnets,
+            #[cfg(feature = "chaos")]
+            faults,
+        } = self;
+        let buffers = BufferPool::new(options.limits.max_blksize as usize + 4, buffer_pool_cap);
+        let shared = Arc::new(Shared { progress, virtual_files, results, sessions, write_locks, stats, buffers, injection, max_retries, marking, quota,
+                                   error_limit, server_tag, error_detail, #[cfg(feature = "chaos")] faults });
+
+        // Requests are small, but nothing a client sends should be truncated
+        let mut buf = vec![0; options.limits.max_blksize as usize + 4];
+        let _alive = health::AliveGuard::new(alive);
+        let local_addr = socket.local_addr()?;
+
+        // The receiving loop only queues the requests, this task starts them when a slot is free,
+        // so a flood of requests cannot grow the memory use nor slow down the receiving
+        let (queue, mut pending) = mpsc::channel::<(OpContext, SocketAddr, PeerGuard)>(queue_size);
+        let slots = transfer_slots.unwrap_or_else(|| Arc::new(Semaphore::new(max_transfers)));
+        let dispatch_shared = shared.clone();
+        tokio::spawn(async move {
+            while let Some((context, peer, guard)) = pending.recv().await {
+                // Never closed
+                let slot = slots.clone().acquire_owned().await.unwrap();
+                let shared = dispatch_shared.clone();
+                tokio::spawn(TRANSFER_ID.scope(context.transfer_id, async move {
+                    transfer(context, local_addr, peer, guard, shared).await;
+                    drop(slot);
+                }));
+            }
+        });
+
+        let active_peers = Arc::new(ActivePeers::default());
+        // Drops since the last log line of invalid sources, and its time
+        let mut invalid_sources: u64 = 0;
+        let mut invalid_logged: Option<Instant> = None;
+        let mut disallowed_sources: u64 = 0;
+        let mut disallowed_logged: Option<Instant> = None;
+        loop {
+            let (size, peer) = match socket.recv_from(&mut buf).await {
+                // Ugly single retry as recv_from sometime fails on Windows
+                Err(_) =>  socket.recv_from(&mut buf).await?,
+                Ok(v) => v
+            };
+            shared.stats.packet_received(&buf[..size]);
+            // Spoofed, any reply would go to the whole segment
+            if is_invalid_source(peer.ip()) {
+                shared.stats.invalid_source();
+                invalid_sources += 1;
+                if invalid_logged.is_none_or(|logged| logged.elapsed() >= INVALID_SOURCE_LOG_INTERVAL) {
+                    debug!("Dropped {} packets from broadcast, multicast or unspecified addresses, last from {}", invalid_sources, peer);
+                    invalid_sources = 0;
+                    invalid_logged = Some(Instant::now());
+                }
+                continue;
+            }
+            // An IPv4 client of a dual stack socket is matched against the IPv4 subnets
+            if !allowed_subnets.is_empty() && !allowed_subnets.iter().any(|subnet| subnet.contains(&peer.ip().to_canonical())) {
+                shared.stats.disallowed_source();
+                disallowed_sources += 1;
+                if disallowed_logged.is_none_or(|logged| logged.elapsed() >= INVALID_SOURCE_LOG_INTERVAL) {
+                    debug!("Dropped {} packets from outside of the allowed subnets, last from {}", disallowed_sources, peer);
+                    disallowed_sources = 0;
+                    disallowed_logged = Some(Instant::now());
+                }
+                continue;
+            }
+            // Only RRQ/WRQ create a context, the rest of the transfer goes to its own socket
+            match tftpprotocol::recv_request(&buf[..size], size, peer, &options) {
+                Some(mut context) => {
+                    if shared.quota.as_ref().is_some_and(|quota| quota.exceeded(peer.ip())) {
+                        debug!("{} is over its daily quota, refusing {}", peer.ip(), context.filename.display());
+                        let refused = TftpError::AccessViolation;
+                        if !error_allowed(&shared, peer) {
+                            continue;
+                        }
+                        if let Some(reply) = tftpprotocol::get_buffer_for_command(tag_reply(&shared, refused.to_command())) {
+                            shared.stats.error_sent(refused.error_code());
+                            let _ = socket.send_to(&reply, peer).await;
+                        }
+                        continue;
+                    }
+                    let Some(guard) = active_peers.accept(peer) else {
+                        debug!("Repeated request from {}", peer);
+                        continue;
+                    };
+                    context.transfer_id = NEXT_TRANSFER_ID.fetch_add(1, Ordering::Relaxed);
+                    debug!("Transfer {} of {} with {}", context.transfer_id, context.filename.display(), peer);
+                    match queue.try_send((context, peer, guard)) {
+                        Ok(()) => (),
+                        Err(TrySendError::Full((context, _, _))) => {
+                            debug!("Too many transfers, rejecting transfer {} with {}", context.transfer_id, peer);
+                            shared.stats.request_rejected();
+                            if reply_busy && error_allowed(&shared, peer) {
+                                let busy = TftpError::NotDefined("Server busy".to_string());
+                                if let Some(reply) = tftpprotocol::get_buffer_for_command(tag_reply(&shared, busy.to_command())) {
+                                    shared.stats.error_sent(busy.error_code());
+                                    let _ = socket.send_to(&reply, peer).await;
+                                }
+                            }
+                        }
+                        // The dispatch task only stops when the queue is dropped
+                        Err(TrySendError::Closed(_)) => unreachable!("transfer queue closed"),
+                    }
+                }
+                None => match tftpprotocol::refused_request(&buf[..size], &options) {
+                    Some(refused) => {
+                        debug!("Refusing a request from {} in strict mode", peer);
+                        if !error_allowed(&shared, peer) {
+                            continue;
+                        }
+                        let errorcode = match refused {
+                            Command::ERROR{errorcode, ..} => errorcode,
+                            _ => TftpError::MalformedPacket.error_code()
+                        };
+                        if let Some(reply) = tftpprotocol::get_buffer_for_command(tag_reply(&shared, refused)) {
+                            shared.stats.error_sent(errorcode);
+                            let _ = socket.send_to(&reply, peer).await;
+                        }
+                    }
+                    None => debug!("Ignoring packet from {} outside of a transfer", peer)
+                }
+            }
+        }
+    }
+}
+
+/// Source address no reply may be sent to: multicast, the IPv4 broadcast or unspecified.
+/// Subnet-directed broadcasts cannot be told apart without the netmask of the segment.
+pub fn is_invalid_source(ip: IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => return ip.is_multicast() || ip.is_broadcast() || ip.is_unspecified(),
+        IpAddr::V6(ip) => return ip.is_multicast() || ip.is_unspecified()
+    }
+}
+
+/// Byte count with a binary unit, e.g. 1.2 MiB
+pub(crate) fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    return format!("{:.1} {}", size, UNITS[unit]);
+}
+
+/// Outcome of a transfer, logged as a single line when it ends
+#[derive(Debug, Clone)]
+pub struct TransferResult {
+    pub transfer_id: u64,
+    pub peer: SocketAddr,
+    /// WRQ (upload) rather than RRQ
+    pub write: bool,
+    pub filename: String,
+    pub bytes: u64,
+    pub retransmits: u64,
+    pub duration: Duration,
+    pub finished: SystemTime,
+    /// Failure reason, None when the transfer completed
+    pub error: Option<String>,
+    /// Code of the ERROR packet sent or received, if the failure came with one
+    pub error_code: Option<u16>,
+}
+
+impl TransferResult {
+    fn new(context: &OpContext, peer: SocketAddr) -> TransferResult {
+        return TransferResult {
+            transfer_id: context.transfer_id,
+            peer,
+            write: matches!(context.current_op, Command::WRQ{..}),
+            filename: context.filename.to_string_lossy().into_owned(),
+            bytes: 0,
+            retransmits: 0,
+            duration: Duration::ZERO,
+            finished: SystemTime::now(),
+            error: None,
+            error_code: None,
+        };
+    }
+
+    /// Bytes per second over the whole transfer
+    pub fn throughput(&self) -> u64 {
+        return (self.bytes as f64 / self.duration.as_secs_f64().max(0.001)) as u64;
+    }
+
+    fn log(&self) {
+        let elapsed = self.duration.as_secs_f64();
+        let rate = format_size(self.throughput());
+        let (verb, direction) = match self.write {
+            true => ("Received", "from"),
+            false => ("Served", "to"),
+        };
+        match &self.error {
+            None => info!("{} {} ({}) {} {} in {:.1} s, {}/s, {} retransmits",
+                          verb, self.filename, format_size(self.bytes), direction, self.peer.ip(),
+                          elapsed, rate, self.retransmits),
+            Some(reason) => info!("Transfer of {} {} {} failed after {:.1} s, {} moved, {} retransmits: {}",
+                                  self.filename, direction, self.peer.ip(), elapsed,
+                                  format_size(self.bytes), self.retransmits, reason),
+        }
+    }
+}
+
+async fn transfer(context: OpContext, local_addr: SocketAddr, peer: SocketAddr, guard: PeerGuard, shared: Arc<Shared>) {
+    let mut result = TransferResult::new(&context, peer);
+    let _active = shared.stats.session_started();
+    let cancel = Arc::new(Cancel::default());
+    let _session = shared.sessions.insert(context.transfer_id, Session {
+        peer,
+        filename: result.filename.clone(),
+        write: result.write,
+        blksize: context.options.blksize as usize,
+        block: 0,
+        blocks: 0,
+        bytes: 0,
+        size: None,
+        retransmits: 0,
+        last_activity: Instant::now(),
+        cancel: cancel.clone(),
+    });
+    let started_at = context.started_at;
+    // In a task of its own: a panic only ends this transfer, which is then reported as failed
+    // and removed from the sessions and the active peers like any other
+    let transfer_id = context.transfer_id;
+    let (repeated, task_cancel, task_shared, mut task_result) = (guard.repeated.clone(), cancel.clone(), shared.clone(), result.clone());
+    let task = tokio::spawn(TRANSFER_ID.scope(transfer_id, async move {
+        let mut context = context;
+        // Here rather than in the receiving loop, once the request passed the quota and repeated request checks
+        tftpprotocol::resolve_request(&mut context);
+        // Counted by the path actually read, the fallback of a missing file
+        let served = match &context.current_op {
+            Command::RRQ{..} => tftpprotocol::sanitize_filename(tftpprotocol::fallback_file(&context).unwrap_or(&context.filename))
+                .ok().map(|path| context.server_options.root.join(path)),
+            _ => None
+        };
+        let outcome = run_transfer(context, local_addr, peer, &repeated, &task_cancel, &task_shared, &mut task_result).await;
+        (outcome, task_result, served)
+    }));
+    let mut served = None;
+    match task.await {
+        Ok((outcome, task_result, task_served)) => {
+            result = task_result;
+            served = task_served;
+            if let Err(reason) = outcome {
+                result.error = Some(reason);
+            }
+        }
+        Err(e) => {
+            let reason = task_failure(e);
+            warn!("Transfer {} of {} with {} aborted: {}", transfer_id, result.filename, peer, reason);
+            result.error = Some(reason);
+        }
+    }
+    result.duration = started_at.elapsed();
+    result.finished = SystemTime::now();
+    shared.stats.transfer_finished(result.error.is_none());
+    if let (None, Some(path)) = (&result.error, &served) {
+        shared.stats.file_served(path, result.bytes);
+    }
+    result.log();
+    for results in &shared.results {
+        let _ = results.send(result.clone()).await;
+    }
+}
+
+/// Failure reason of a transfer task that did not return, with the panic message
+fn task_failure(e: JoinError) -> String {
+    if !e.is_panic() {
+        return "transfer task cancelled".to_string();
+    }
+    let panic = e.into_panic();
+    let message = match (panic.downcast_ref::<&str>(), panic.downcast_ref::<String>()) {
+        (Some(message), _) => message.to_string(),
+        (None, Some(message)) => message.clone(),
+        (None, None) => "unknown cause".to_string(),
+    };
+    return format!("internal error, transfer task panicked: {}", message);
+}
+
+/// An ERROR can be sent to a peer without an established transfer, counted when it cannot
+fn error_allowed(shared: &Shared, peer: SocketAddr) -> bool {
+    if shared.error_limit.as_ref().is_none_or(|error_limit| error_limit.allow(peer.ip())) {
+        return true;
+    }
+    shared.stats.error_suppressed();
+    return false;
+}
+
+/// The reply as sent: an ERROR gets the generic message with ErrorDetail::Generic, then the server tag
+fn tag_reply(shared: &Shared, reply: Command) -> Command {
+    let reply = match (shared.error_detail, &reply) {
+        (ErrorDetail::Generic, Command::ERROR{errorcode, errmsg}) => {
+            debug!("ERROR {} sent without its detail: {}", errorcode, errmsg);
+            reply.generic()
+        }
+        _ => reply
+    };
+    match &shared.server_tag {
+        Some(tag) => return reply.tagged(tag),
+        None => return reply
+    }
+}
+
+/// Failure reason of an I/O error on the transfer socket
+fn socket_error(e: io::Error, doing: &str) -> String {
+    match e.kind() {
+        // ICMP port unreachable reported on the connected socket, retrying is pointless
+        io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset => return "client port unreachable".to_string(),
+        _ => return format!("error {e} {doing}")
+    }
+}
+
+/// Connected socket of a transfer, its packets go through the fault injector of the server (chaos feature)
+struct TransferSocket {
+    socket: UdpSocket,
+    #[cfg(feature = "chaos")]
+    faults: Option<TransferFaults>,
+}
+
+impl TransferSocket {
+    fn new(socket: UdpSocket, _shared: &Shared) -> TransferSocket {
+        return TransferSocket {
+            socket,
+            #[cfg(feature = "chaos")]
+            faults: _shared.faults.clone().map(TransferFaults::new),
+        };
+    }
+
+    async fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        #[cfg(feature = "chaos")]
+        if let Some(faults) = &self.faults {
+            return faults.send(&self.socket, packet).await;
+        }
+        return self.socket.send(packet).await;
+    }
+
+    /// Sent from the file by the kernel, read in memory where sendfile is missing and to go through the faults
+    async fn send_file_data(&self, file_data: &FileData) -> io::Result<usize> {
+        #[cfg(all(target_os = "linux", feature = "chaos"))]
+        let from_file = self.faults.is_none();
+        #[cfg(all(target_os = "linux", not(feature = "chaos")))]
+        let from_file = true;
+        #[cfg(target_os = "linux")]
+        if from_file {
+            return zero_copy::send_block(&self.socket, &file_data.header(), &file_data.file, file_data.offset, file_data.len).await;
+        }
+        match file_data.to_command() {
+            Command::DATA{data, ..} => return self.send(&data).await,
+            _ => return Err(io::Error::other("cannot read the block"))
+        }
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        #[cfg(feature = "chaos")]
+        if let Some(faults) = &self.faults {
+            return faults.recv(&self.socket, buf).await;
+        }
+        return self.socket.recv(buf).await;
+    }
+}
+
+/// DATA packets are built by the reply or sent from the file without copy, the others are serialized in the send buffer
+async fn send_reply(socket: &TransferSocket, reply: &Reply, send_buf: &mut [u8], shared: &Shared) -> Result<(), String> {
+    if !shared.injection.before_send().await {
+        return Ok(());
+    }
+    // DATA packets are not copied
+    let tagged;
+    let reply = match reply {
+        Reply::Packet(error @ Command::ERROR{..}) => {
+            tagged = Reply::Packet(tag_reply(shared, error.clone()));
+            &tagged
+        }
+        _ => reply
+    };
+    let sent = match reply {
+        Reply::Packet(Command::DATA{data, ..}) => socket.send(data).await,
+        Reply::FileData(file_data) => socket.send_file_data(file_data).await,
+        Reply::Packet(reply) => {
+            let size = tftpprotocol::write_command(reply, send_buf).map_err(|e| e.default_message())?;
+            socket.send(&send_buf[..size]).await
+        }
+    };
+    sent.map_err(|e| socket_error(e, "sending to client"))?;
+    return Ok(());
+}
+
+async fn retransmit(socket: &TransferSocket, reply: &Reply, send_buf: &mut [u8],
+                    transfer_id: u64, shared: &Shared, result: &mut TransferResult) -> Result<(), String> {
+    result.retransmits += 1;
+    shared.stats.retransmission();
+    shared.sessions.update(transfer_id, |session| session.retransmits = result.retransmits);
+    return send_reply(socket, reply, send_buf, shared).await;
+}
+
+/// Temporary file of an atomic upload, removed when the transfer ends before the last block renamed it
+struct TempUpload(Option<PathBuf>);
+
+impl Drop for TempUpload {
+    fn drop(&mut self) {
+        let Some(path) = &self.0 else {
+            return;
+        };
+        match std::fs::remove_file(path) {
+            Ok(()) => debug!("Removed the temporary upload {}", path.display()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => warn!("Cannot remove the temporary upload {}: {}", path.display(), e)
+        }
+    }
+}
+
+/// Exchange packets with the client until the transfer ends, Err gives the failure reason
+async fn run_transfer(mut context: OpContext, local_addr: SocketAddr, peer: SocketAddr, repeated: &Notify, cancel: &Cancel,
+                      shared: &Shared, result: &mut TransferResult) -> Result<(), String> {
+    let socket = socket::bind_transfer(local_addr, peer)
+        .map_err(|e| format!("error {e} creating transfer socket"))?;
+    shared.marking.apply(&socket);
+    // The kernel drops the packets of other peers and reports the ICMP errors of this one
+    socket.connect(peer).await.map_err(|e| format!("error {e} connecting transfer socket"))?;
+    // Before the OACK, the blocks then fit in a packet on the path to the client
+    if context.server_options.limits.blksize_from_mtu {
+        let ipv6 = matches!(peer, SocketAddr::V6(peer) if peer.ip().to_ipv4_mapped().is_none());
+        let max = match socket::path_mtu(&socket) {
+            Ok(mtu) => options::blksize_for_mtu(mtu, ipv6),
+            Err(e) => {
+                debug!("Path MTU to {} unknown ({}), blksize limited to {}", peer, e, FALLBACK_MTU_BLKSIZE);
+                FALLBACK_MTU_BLKSIZE
+            }
+        };
+        tftpprotocol::limit_blksize(&mut context, max);
+    }
+    let socket = TransferSocket::new(socket, shared);
+    // Virtual file names are UTF-8
+    if let (Command::RRQ{..}, Some(filename)) = (&context.current_op, context.filename.to_str()) {
+        if !shared.virtual_files.is_empty() {
+            context.content = shared.virtual_files.generate(peer, filename).await.map(Arc::new);
+        }
+    }
+    // Blocks of two uploads of the same file would interleave, the second one is refused.
+    // Held until the transfer ends
+    let _write_lock = match (&context.current_op, tftpprotocol::sanitize_filename(&context.filename)) {
+        (Command::WRQ{..}, Ok(path)) => {
+            // Servers of different roots write different files
+            let path = context.server_options.root.join(path);
+            let lock = shared.write_locks.lock(&path);
+            if lock.is_none() {
+                info!("{} is being written by another transfer, refused", path.display());
+                context.current_op = tftpprotocol::file_busy();
+            }
+            lock
+        }
+        // A new file is only locked by its upload, not yet with flock
+        (Command::RRQ{..}, Ok(path)) if shared.write_locks.is_locked(&context.server_options.root.join(&path)) => {
+            info!("{} is being written by another transfer, refused", path.display());
+            context.current_op = tftpprotocol::file_busy();
+            None
+        }
+        _ => None
+    };
+    // Shared by the reads of the file, exclusive for an upload, also against other processes
+    let _file_lock = match tftpprotocol::lock_file(&context) {
+        Ok(lock) => lock,
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+            info!("{} is locked by another transfer or process, refused", context.filename.display());
+            context.current_op = tftpprotocol::file_busy();
+            None
+        }
+        Err(e) => {
+            debug!("Cannot lock {}: {}", context.filename.display(), e);
+            None
+        }
+    };
+    // Dropped before the write lock, another upload of the file may start once it is removed
+    let _temp_upload = TempUpload(tftpprotocol::temp_upload(&context));
+    if let (None, Some(fallback)) = (&context.content, tftpprotocol::fallback_file(&context)) {
+        info!("{} not found, serving {}", context.filename.display(), fallback.display());
+        shared.stats.fallback_served();
+    }
+    let progress = &shared.progress;
+    let blksize = context.options.blksize as u64;
+    let mut send_buf = shared.buffers.checkout();
+    // Each packet is split off this buffer, its allocation is reused once the previous packet is dropped
+    let mut recv_buf = BytesMut::new();
+    // Before the size of a .gz is needed, unknown for a read without tsize nor offset
+    tftpprotocol::measure_source(&context).await;
+    // Announced by the client for a write, the percentage of the progress log
+    let size = match &context.current_op {
+        Command::WRQ{..} => context.options.tsize,
+        _ => tftpprotocol::get_transfer_size(&context)
+    };
+    shared.sessions.update(context.transfer_id, |session| session.size = size);
+    let total_blocks = match (progress, &context.current_op) {
+        (Some(_), Command::RRQ{..}) => size.map(|size| size / blksize + 1),
+        _ => None
+    };
+    let mut blocks_done = 0;
+    let mut last_block = 0;
+    // The client answered on the transfer socket, its address is not spoofed
+    let mut established = false;
+    let retransmit_timeout = context.options.timeout.map_or(DEFAULT_RETRANSMIT_TIMEOUT, |secs| Duration::from_secs(secs as u64));
+    // At least one retransmission with a long negotiated timeout
+    let silence_limit = TRANSFER_TIMEOUT.max(retransmit_timeout * 2);
+
+    loop {
+        // The block of a pipe is awaited here, get_reply does not wait
+        tftpprotocol::fill_stream(&context).await;
+        let reply = match tftpprotocol::get_reply(&context) {
+            Some(reply) => reply,
+            // Transfer complete
+            None => return Ok(())
+        };
+        // DATA sent for reads, ACK of a DATA for writes, ACK 0 of a WRQ is not a block
+        let (block, size) = match (&reply, &context.current_op) {
+            (Reply::Packet(Command::DATA{blocknum, data}), _) => (*blocknum, data.len() - 4),
+            (Reply::FileData(file_data), _) => (file_data.blocknum, file_data.len),
+            (Reply::Packet(Command::ACK{blocknum}), Command::DATA{data, ..}) => (*blocknum, data.len()),
+            _ => (0, 0)
+        };
+        if block != 0 && block == last_block {
+            result.retransmits += 1;
+            shared.stats.retransmission();
+        } else if block != 0 {
+            last_block = block;
+            blocks_done += 1;
+            result.bytes += size as u64;
+            match reply {
+                Reply::Packet(Command::DATA{..}) | Reply::FileData(_) => shared.stats.add_bytes_sent(size as u64),
+                _ => shared.stats.add_bytes_received(size as u64)
+            }
+            if let Some(quota) = &shared.quota {
+                quota.add(peer.ip(), size as u64);
+            }
+            if let Some(progress) = progress {
+                let event = ProgressEvent {
+                    transfer_id: context.transfer_id,
+                    peer,
+                    filename: context.filename.to_string_lossy().into_owned(),
+                    blocks_done,
+                    total_blocks,
+                };
+                let _ = progress.send(event).await;
+            }
+        }
+        shared.sessions.update(context.transfer_id, |session| {
+            session.block = last_block;
+            session.blocks = blocks_done;
+            session.bytes = result.bytes;
+            session.retransmits = result.retransmits;
+        });
+        // A request answered with an ERROR may have a spoofed source, the victim gets nothing over the rate
+        if matches!(reply, Reply::Packet(Command::ERROR{..})) && !established && !error_allowed(shared, peer) {
+            return Err("error reply over the error rate".to_string());
+        }
+        let error = match &reply {
+            Reply::Packet(Command::ERROR{errorcode, errmsg}) => {
+                result.error_code = Some(*errorcode);
+                shared.stats.error_sent(*errorcode);
+                Some(errmsg.clone())
+            }
+            _ => None
+        };
+        send_reply(&socket, &reply, &mut send_buf, shared).await?;
+        // An ERROR packet terminates the transfer
+        if let Some(errmsg) = error {
+            // Sent first, the client knows why its upload stopped; the end of the file was not written
+            if result.error_code == Some(TftpError::DiskFull.error_code()) {
+                tftpprotocol::remove_partial_upload(&context);
+            }
+            return Err(errmsg);
+        }
+        // ACK of a short block, the upload is complete
+        if matches!((&reply, &context.current_op), (Reply::Packet(Command::ACK{..}), Command::DATA{data, ..}) if data.len() < blksize as usize) {
+            return Ok(());
+        }
+
+        // Until the client answers the reply is sent again after each retransmission timeout,
+        // also when the client repeats its request because the first reply was lost
+        let first_reply = matches!(context.current_op, Command::RRQ{..} | Command::WRQ{..});
+        let sent_at = Instant::now();
+        let mut retries = 0;
+        // Until a packet changes the reply
+        loop {
+            recv_buf.resize(blksize as usize + 4 + RECV_HEADROOM, 0);
+            let size = loop {
+                let silence = sent_at.elapsed();
+                if silence >= silence_limit {
+                    return Err("timed out".to_string());
+                }
+                let wait = retransmit_timeout.min(silence_limit - silence);
+                let received = tokio::select! {
+                    received = timeout(wait, socket.recv(&mut recv_buf)) => received,
+                    _ = repeated.notified() => {
+                        if first_reply {
+                            debug!("Repeated request from {}, sending {:?} again", peer, reply);
+                            retransmit(&socket, &reply, &mut send_buf, context.transfer_id, shared, result).await?;
+                        }
+                        continue;
+                    }
+                    notify_client = cancel.requested() => {
+                        warn!("Transfer with {} cancelled", peer);
+                        if notify_client {
+                            let error = TftpError::NotDefined("transfer cancelled by the server administrator".to_string());
+                            result.error_code = Some(error.error_code());
+                            shared.stats.error_sent(error.error_code());
+                            let _ = send_reply(&socket, &Reply::Packet(error.to_command()), &mut send_buf, shared).await;
+                        }
+                        tftpprotocol::remove_partial_upload(&context);
+                        return Err("cancelled".to_string());
+                    }
+                };
+                match received {
+                    Err(_) if shared.max_retries.is_some_and(|max_retries| retries >= max_retries) => {
+                        warn!("No answer from {} after {} retransmits of block {}, aborting", peer, retries, last_block);
+                        let error = TftpError::NotDefined("transfer timed out".to_string());
+                        result.error_code = Some(error.error_code());
+                        shared.stats.error_sent(error.error_code());
+                        let _ = send_reply(&socket, &Reply::Packet(error.to_command()), &mut send_buf, shared).await;
+                        tftpprotocol::remove_partial_upload(&context);
+                        return Err("timed out".to_string());
+                    }
+                    Err(_) if sent_at.elapsed() < silence_limit => {
+                        retries += 1;
+                        debug!("No answer from {}, sending {:?} again", peer, reply);
+                        retransmit(&socket, &reply, &mut send_buf, context.transfer_id, shared, result).await?;
+                    }
+                    Err(_) => (),
+                    Ok(Err(e)) => return Err(socket_error(e, "receiving from client")),
+                    // Not even an opcode, the reply is not sent again for it
+                    Ok(Ok(0)) => debug!("Empty datagram from {}, ignored", peer),
+                    Ok(Ok(size)) => {
+                        established = true;
+                        shared.stats.packet_received(&recv_buf[..size]);
+                        shared.sessions.update(context.transfer_id, |session| session.last_activity = Instant::now());
+                        break size;
+                    }
+                }
+            };
+            if size == recv_buf.len() {
+                warn!("Truncated packet of at least {} bytes from {}, aborting transfer", size, peer);
+                context.current_op = TftpError::MalformedPacket.to_command();
+                break;
+            }
+            let packet = recv_buf.split_to(size).freeze();
+            recv_buf.clear();
+            match tftpprotocol::recv_packet(&mut context, &packet) {
+                Action::Reply => break,
+                Action::ClientError(error) => {
+                    result.error_code = Some(error.error_code());
+                    return Err("aborted by the client".to_string());
+                }
+                Action::Abort => return Err("aborted by the client".to_string()),
+                // A stray packet, the reply is not sent again for it
+                Action::Ignore => ()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::error_limit::ErrorLimiter;
+    use crate::file_lock::{self, LockMode};
+    use crate::inject::Injection;
+    use crate::options::{Limits, TftpOption, FALLBACK_MTU_BLKSIZE};
+    use crate::quota::QuotaTracker;
+    use crate::server::{current_transfer_id, format_size, ErrorDetail, is_invalid_source, ProgressEvent, Server};
+    use crate::session::Sessions;
+    use crate::socket::{self, ListenOptions};
+    use crate::stats::{FileCounters, ServerStats};
+    use crate::testing::TestClient;
+    use crate::tftp::tftpprotocol::{ServerOptions, Strictness};
+    use crate::virtual_file::VirtualFiles;
+    use std::net::SocketAddr;
+    use std::path::PathBuf;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::net::UdpSocket;
+    use tokio::sync::mpsc;
+    use tokio::time::timeout;
+
+    const FIXTURE: &str = "tests/fixtures/files/hello.txt";
+
+    fn rrq(filename: &str) -> Vec<u8> {
+        let mut rrq = vec![0, 1];
+        rrq.extend_from_slice(filename.as_bytes());
+        rrq.extend_from_slice(b"\0octet\0");
+        return rrq;
+    }
+
+    /// Complete read of a file, acknowledging each block
+    async fn fetch(server_addr: SocketAddr, filename: &str) -> Vec<u8> {
+        return TestClient::connect(server_addr).await.unwrap().get(filename, &[]).await.unwrap();
+    }
+
+    /// Server with these options on a free port of the loopback address
+    fn spawn_server(options: ServerOptions) -> SocketAddr {
+        return spawn_configured(|server| server.with_options(options));
+    }
+
+    /// Server set up by configure on a free port of the loopback address
+    fn spawn_configured(configure: impl FnOnce(Server) -> Server) -> SocketAddr {
+        let socket = socket::bind_udp("127.0.0.1:0".parse().unwrap(), &ListenOptions::default()).unwrap();
+        let server_addr = socket.local_addr().unwrap();
+        tokio::spawn(configure(Server::new(socket, Arc::new(AtomicBool::new(false)))).run());
+        return server_addr;
+    }
+
+    /// Messages logged by the library, the logger is global so tests look for their own lines
+    static LOGGED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    struct CaptureLogger;
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, _: &log::Metadata<'_>) -> bool {
+            return true;
+        }
+
+        fn log(&self, record: &log::Record<'_>) {
+            let line = match current_transfer_id() {
+                Some(id) => format!("[#{}] {}", id, record.args()),
+                None => record.args().to_string(),
+            };
+            LOGGED.lock().unwrap().push(line);
+        }
+
+        fn flush(&self) {}
+    }
+
+    fn capture_log() {
+        static LOGGER: CaptureLogger = CaptureLogger;
+        if log::set_logger(&LOGGER).is_ok() {
+            log::set_max_level(log::LevelFilter::Info);
+        }
+    }
+
+    /// First captured message containing pattern, waiting for it to be logged
+    async fn logged(pattern: &str) -> String {
+        for _ in 0..50 {
+            if let Some(line) = LOGGED.lock().unwrap().iter().find(|line| line.contains(pattern)) {
+                return line.clone();
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("No log line containing {}", pattern);
+    }
+
+    #[tokio::test]
+    async fn rrq_over_ipv6_loopback() {
+        let server_socket = socket::bind_udp("[::1]:0".parse().unwrap(), &ListenOptions::default()).unwrap();
+        let server_addr = server_socket.local_addr().unwrap();
+        tokio::spawn(Server::new(server_socket, Arc::new(AtomicBool::new(false))).run());
+
+        let client = UdpSocket::bind("[::1]:0").await.unwrap();
+        client.send_to(&rrq(FIXTURE), server_addr).await.unwrap();
+
+        let mut buf = [0; 1024];
+        let (size, from) = timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+        // Reply comes from the transfer socket, same family, other port
+        assert!(from.is_ipv6());
+        assert_ne!(from.port(), server_addr.port());
+        assert_eq!(&buf[..4], &[0, 3, 0, 1]);
+        assert_eq!(&buf[4..size], std::fs::read(FIXTURE).unwrap().as_slice());
+        client.send_to(&[0, 4, 0, 1], from).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rrq_over_dual_stack() {
+        let options = ListenOptions { dual_stack: true, ..ListenOptions::default() };
+        let server_socket = socket::bind_udp("[::]:0".parse().unwrap(), &options).unwrap();
+        let port = server_socket.local_addr().unwrap().port();
+        tokio::spawn(Server::new(server_socket, Arc::new(AtomicBool::new(false))).run());
+
+        // Seen as ::ffff:127.0.0.1 by the server, answered over IPv4
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(&rrq(FIXTURE), ("127.0.0.1", port)).await.unwrap();
+        let mut buf = [0; 1024];
+        let (size, from) = timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+        assert!(from.is_ipv4());
+        assert_ne!(from.port(), port);
+        assert_eq!(&buf[..4], &[0, 3, 0, 1]);
+        assert_eq!(&buf[4..size], std::fs::read(FIXTURE).unwrap().as_slice());
+        client.send_to(&[0, 4, 0, 1], from).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn transfer_on_each_bind_address() {
+        let alive = Arc::new(AtomicBool::new(false));
+        let mut addrs = Vec::new();
+        for bind in ["127.0.0.1:0", "[::1]:0"] {
+            let socket = socket::bind_udp(bind.parse().unwrap(), &ListenOptions::default()).unwrap();
+            addrs.push(socket.local_addr().unwrap());
+            tokio::spawn(Server::new(socket, alive.clone()).run());
+        }
+        let expected = std::fs::read(FIXTURE).unwrap();
+        for addr in addrs {
+            assert_eq!(fetch(addr, FIXTURE).await, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn progress_events() {
+        const MULTIBLOCK: &str = "tests/fixtures/files/multiblock.bin";
+        let (sender, mut receiver) = mpsc::channel(16);
+        let server_addr = spawn_configured(|server| server.with_progress(sender));
+
+        // 1300 bytes: 512 + 512 + 276
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(&rrq(MULTIBLOCK), server_addr).await.unwrap();
+        let mut buf = [0; 1024];
+        let mut events: Vec<ProgressEvent> = Vec::new();
+        loop {
+            let (size, from) = timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+            client.send_to(&[0, 4, buf[2], buf[3]], from).await.unwrap();
+            events.push(receiver.recv().await.unwrap());
+            if size < 516 {
+                break;
+            }
+        }
+        let blocks: Vec<u64> = events.iter().map(|event| event.blocks_done).collect();
+        assert_eq!(blocks, [1, 2, 3]);
+        assert!(events.iter().all(|event| event.total_blocks == Some(3) && event.filename == MULTIBLOCK));
+        assert!(events.iter().all(|event| event.transfer_id == events[0].transfer_id));
+    }
+
+    #[tokio::test]
+    async fn completion_summary() {
+        const BLOCK: &str = "tests/fixtures/files/block.bin";
+        let server_addr = spawn_server(ServerOptions::default());
+        capture_log();
+
+        assert_eq!(fetch(server_addr, BLOCK).await.len(), 512);
+        let summary = logged(&format!("Served {}", BLOCK)).await;
+        assert!(summary.starts_with("[#"), "{}", summary);
+        assert!(summary.contains("(512 B) to 127.0.0.1 in "), "{}", summary);
+        assert!(summary.ends_with("/s, 0 retransmits"), "{}", summary);
+    }
+
+    #[tokio::test]
+    async fn same_transfer_id_on_each_line() {
+        const MISSING: &str = "tests/fixtures/files/missing-for-transfer-id.bin";
+        let options = ServerOptions { fallback_file: Some(PathBuf::from(FIXTURE)), ..ServerOptions::default() };
+        let server_addr = spawn_server(options);
+        capture_log();
+
+        // A fallback line, then the summary, from the transfer task
+        fetch(server_addr, MISSING).await;
+        logged(&format!("Served {}", MISSING)).await;
+        let lines: Vec<String> = LOGGED.lock().unwrap().iter().filter(|line| line.contains(MISSING)).cloned().collect();
+        assert_eq!(lines.len(), 2, "{:?}", lines);
+        assert!(lines[0].contains("not found, serving"), "{}", lines[0]);
+        let id = |line: &str| line.strip_prefix("[#").and_then(|rest| rest.split_once(']')).map(|(id, _)| id.to_string());
+        assert!(id(&lines[0]).is_some_and(|id| id.parse::<u64>().is_ok()), "{}", lines[0]);
+        assert_eq!(id(&lines[0]), id(&lines[1]));
+    }
+
+    #[test]
+    fn invalid_sources() {
+        for ip in ["224.0.0.1", "239.255.255.250", "255.255.255.255", "0.0.0.0", "ff02::1", "ff0e::fb", "::", "::ffff:255.255.255.255"] {
+            assert!(is_invalid_source(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["10.0.0.42", "192.168.1.255", "127.0.0.1", "fe80::1", "2001:db8::1", "::1", "::ffff:10.0.0.42"] {
+            assert!(!is_invalid_source(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn size_units() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1024), "1.0 KiB");
+        assert_eq!(format_size(1_258_291), "1.2 MiB");
+    }
+
+    #[tokio::test]
+    async fn virtual_file_content() {
+        let mut virtual_files = VirtualFiles::new();
+        virtual_files.register("whoami.txt", |peer: SocketAddr, _path| async move { peer.ip().to_string().into_bytes() });
+        let server_addr = spawn_configured(|server| server.with_virtual_files(virtual_files));
+
+        assert_eq!(fetch(server_addr, "/whoami.txt").await, b"127.0.0.1");
+        // Other names still come from the disk
+        assert_eq!(fetch(server_addr, FIXTURE).await, std::fs::read(FIXTURE).unwrap());
+    }
+
+    #[tokio::test]
+    async fn panicking_transfer_isolated() {
+        const MULTIBLOCK: &str = "tests/fixtures/files/multiblock.bin";
+        let mut virtual_files = VirtualFiles::new();
+        virtual_files.register("panic.txt", |_peer, _path| async move { panic!("generator failure") });
+        let sessions = Sessions::new();
+        let (results, mut finished) = mpsc::channel(4);
+        let stats = Arc::new(ServerStats::new());
+        let server_addr = spawn_configured(|server| {
+            server.with_virtual_files(virtual_files).with_sessions(sessions.clone()).with_results(results).with_stats(stats.clone())
+        });
+
+        // A read in progress while the other transfer panics
+        let mut reader = TestClient::connect(server_addr).await.unwrap();
+        reader.send_rrq(MULTIBLOCK, &[]).await.unwrap();
+        let mut content = reader.expect_data(1).await.unwrap().to_vec();
+        let mut failing = TestClient::connect(server_addr).await.unwrap().with_timeout(Duration::from_millis(200));
+        failing.send_rrq("panic.txt", &[]).await.unwrap();
+        let result = timeout(Duration::from_secs(5), finished.recv()).await.unwrap().unwrap();
+        assert_eq!(result.filename, "panic.txt");
+        assert_eq!(result.error.as_deref(), Some("internal error, transfer task panicked: generator failure"));
+        assert!(failing.recv().await.is_err());
+        assert_eq!(sessions.snapshot().len(), 1);
+
+        reader.send_ack(1).await.unwrap();
+        for blocknum in 2.. {
+            let data = reader.expect_data(blocknum).await.unwrap();
+            content.extend_from_slice(&data);
+            reader.send_ack(blocknum).await.unwrap();
+            if data.len() < 512 {
+                break;
+            }
+        }
+        assert_eq!(content, std::fs::read(MULTIBLOCK).unwrap());
+        let result = timeout(Duration::from_secs(5), finished.recv()).await.unwrap().unwrap();
+        assert_eq!(result.error, None);
+        assert!(sessions.snapshot().is_empty());
+        assert_eq!((stats.completed_transfers(), stats.failed_transfers()), (1, 1));
+        // Not taken for a repeated request from the same port, its peer entry was removed
+        failing.send_raw(&rrq("panic.txt")).await.unwrap();
+        assert!(timeout(Duration::from_secs(5), finished.recv()).await.unwrap().unwrap().error.is_some());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn sessions_dumped_on_sigusr1() {
+        const MULTIBLOCK: &str = "tests/fixtures/files/multiblock.bin";
+        capture_log();
+        let sessions = Sessions::new();
+        crate::session::spawn_dump_on_sigusr1(sessions.clone(), Arc::new(ServerStats::new())).unwrap();
+        let server_addr = spawn_configured(|server| server.with_sessions(sessions.clone()));
+
+        // First block received and not acknowledged, the transfer stays active
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(&rrq(MULTIBLOCK), server_addr).await.unwrap();
+        let mut buf = [0; 1024];
+        timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(sessions.snapshot().len(), 1);
+
+        // SAFETY: raise has no preconditions, SIGUSR1 is handled by the task spawned above
+        unsafe { libc::raise(libc::SIGUSR1) };
+        let line = logged(&format!("read {}", MULTIBLOCK)).await;
+        let client_addr = client.local_addr().unwrap();
+        assert!(line.contains(&format!(" {} read", client_addr)), "{}", line);
+        assert!(line.contains("blksize 512 block 1 (512 B), last activity "), "{}", line);
+        assert!(line.ends_with(", 0 retransmits"), "{}", line);
+    }
+
+    #[tokio::test]
+    async fn progress_of_a_stalled_transfer_logged() {
+        const MULTIBLOCK: &str = "tests/fixtures/files/multiblock.bin";
+        capture_log();
+        let sessions = Sessions::new();
+        let server_addr = spawn_configured(|server| server.with_sessions(sessions.clone()));
+
+        // First block received and never acknowledged
+        let mut client = TestClient::connect(server_addr).await.unwrap();
+        client.send_rrq(MULTIBLOCK, &[]).await.unwrap();
+        client.expect_data(1).await.unwrap();
+        crate::session::spawn_progress_log(sessions.clone(), Duration::from_millis(200));
+        let line = logged(&format!("127.0.0.1 {}: 39% (512 B / 1.3 KiB), 0 B/s, block 1", MULTIBLOCK)).await;
+        assert!(line.contains(", idle "), "{}", line);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn workers_share_requests() {
+        let options = ListenOptions { reuse_port: true, ..ListenOptions::default() };
+        let first = socket::bind_udp("127.0.0.1:0".parse().unwrap(), &options).unwrap();
+        let server_addr = first.local_addr().unwrap();
+        let second = socket::bind_udp(server_addr, &options).unwrap();
+        let slots = Arc::new(tokio::sync::Semaphore::new(64));
+        let mut workers = Vec::new();
+        for socket in [first, second] {
+            let server = Server::new(socket, Arc::new(AtomicBool::new(false))).with_transfer_slots(slots.clone());
+            workers.push(server.stats());
+            tokio::spawn(server.run());
+        }
+
+        // The kernel picks the socket from a hash of the client address
+        let mut clients = Vec::new();
+        for _ in 0..64 {
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            client.send_to(&rrq(FIXTURE), server_addr).await.unwrap();
+            clients.push(client);
+        }
+        let mut buf = [0; 1024];
+        for client in &clients {
+            timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+        }
+        let requests: Vec<u64> = workers.iter().map(|stats| stats.packets(1)).collect();
+        assert_eq!(requests.iter().sum::<u64>(), 64);
+        assert!(requests.iter().all(|count| *count > 0), "{:?}", requests);
+    }
+
+    #[tokio::test]
+    async fn saturated_queue_rejects_requests() {
+        let stats = Arc::new(ServerStats::new());
+        let server_addr = spawn_configured(|server| server.with_max_transfers(1).with_queue_size(1).with_reply_busy(true).with_stats(stats.clone()));
+
+        // None of the clients acknowledges, the first transfer keeps its slot
+        let mut clients = Vec::new();
+        for _ in 0..6 {
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            client.send_to(&rrq(FIXTURE), server_addr).await.unwrap();
+            clients.push(client);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let mut buf = [0; 1024];
+        timeout(Duration::from_secs(5), clients[0].recv_from(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(&buf[..4], &[0, 3, 0, 1]);
+        // The second waits for the slot, the third in the queue
+        for client in &clients[1..3] {
+            assert!(timeout(Duration::from_millis(100), client.recv_from(&mut buf)).await.is_err());
+        }
+        for client in &clients[3..] {
+            let (size, from) = timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+            assert_eq!(from, server_addr);
+            assert_eq!(&buf[..size], b"\x00\x05\x00\x00Server busy\x00");
+        }
+        assert_eq!(stats.rejected_requests(), 3);
+        assert_eq!(stats.errors(0), 3);
+        assert_eq!(stats.active_sessions(), 1);
+    }
+
+    #[tokio::test]
+    async fn client_over_quota_is_refused() {
+        const MULTIBLOCK: &str = "tests/fixtures/files/multiblock.bin";
+        let quota = QuotaTracker::new(1000);
+        let server_addr = spawn_configured(|server| server.with_quota(quota.clone()));
+
+        // Started under the quota, the first transfer completes beyond it
+        assert_eq!(fetch(server_addr, MULTIBLOCK).await.len(), 1300);
+        assert_eq!(quota.used("127.0.0.1".parse().unwrap()), 1300);
+        // Another port of the same client
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(&rrq(MULTIBLOCK), server_addr).await.unwrap();
+        let mut buf = [0; 1024];
+        let (size, from) = timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(from, server_addr);
+        assert_eq!(&buf[..4], &[0, 5, 0, 2]);
+        assert!(size > 5);
+    }
+
+    #[tokio::test]
+    async fn disabled_options_not_acknowledged() {
+        let mut request = rrq(FIXTURE);
+        request.extend_from_slice(b"blksize\x001024\x00tsize\x000\x00");
+        let mut buf = [0; 1100];
+        for (limits, expected) in [
+            (Limits { no_options: true, ..Limits::default() }, [&[0, 3, 0, 1][..], &std::fs::read(FIXTURE).unwrap()].concat()),
+            (Limits { disabled: vec![TftpOption::Tsize], ..Limits::default() }, b"\x00\x06blksize\x001024\x00".to_vec()),
+        ] {
+            let options = ServerOptions { limits, ..ServerOptions::default() };
+            let server_addr = spawn_server(options);
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            client.send_to(&request, server_addr).await.unwrap();
+            // No OACK at all, or one without the disabled option
+            let (size, _) = timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+            assert_eq!(&buf[..size], expected.as_slice());
+        }
+    }
+
+    #[tokio::test]
+    async fn strict_mode_refuses_quirks() {
+        let mut request = rrq(FIXTURE);
+        request.extend_from_slice(b"tsize\x00");
+        let mut buf = [0; 600];
+        for (strictness, expected) in [
+            (Strictness::Lenient, [&[0, 3, 0, 1][..], &std::fs::read(FIXTURE).unwrap()].concat()),
+            (Strictness::Strict, b"\x00\x05\x00\x04Malformed packet\x00".to_vec()),
+        ] {
+            let options = ServerOptions { strictness, ..ServerOptions::default() };
+            let server_addr = spawn_server(options);
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            client.send_to(&request, server_addr).await.unwrap();
+            // The option without value is ignored, or the request refused from the server port
+            let (size, from) = timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+            assert_eq!(&buf[..size], expected.as_slice(), "{}", strictness);
+            assert_eq!(from == server_addr, strictness == Strictness::Strict);
+        }
+    }
+
+    #[tokio::test]
+    async fn requested_blksize_clamped() {
+        let mut request = rrq(FIXTURE);
+        request.extend_from_slice(b"blksize\x009000\x00");
+        let mut buf = [0; 100];
+        // Configured ceiling, then the loopback MTU (65536 on Linux) or the fallback
+        let expected = if cfg!(target_os = "linux") { 9000 } else { FALLBACK_MTU_BLKSIZE };
+        for (limits, expected) in [
+            (Limits { max_blksize: 1468, ..Limits::default() }, 1468),
+            (Limits { blksize_from_mtu: true, ..Limits::default() }, expected),
+        ] {
+            let options = ServerOptions { limits, ..ServerOptions::default() };
+            let server_addr = spawn_server(options);
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            client.send_to(&request, server_addr).await.unwrap();
+            let (size, _) = timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+            assert_eq!(&buf[..size], format!("\x00\x06blksize\x00{}\x00", expected).as_bytes());
+        }
+    }
+
+    #[tokio::test]
+    async fn oack_sent_again_when_unanswered() {
+        let stats = Arc::new(ServerStats::new());
+        let server_addr = spawn_configured(|server| server.with_stats(stats.clone()));
+        let oack = b"\x00\x06blksize\x001024\x00timeout\x001\x00";
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut request = rrq(FIXTURE);
+        request.extend_from_slice(b"blksize\x001024\x00timeout\x001\x00");
+        client.send_to(&request, server_addr).await.unwrap();
+        let mut buf = [0; 1100];
+        let (size, _) = timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(&buf[..size], oack);
+        // ACK 0 lost, the OACK comes again after the 1 s negotiated timeout
+        let (size, from) = timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(&buf[..size], oack);
+        assert_eq!(stats.retransmissions(), 1);
+        client.send_to(&[0, 4, 0, 0], from).await.unwrap();
+        let (size, _) = timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(&buf[4..size], std::fs::read(FIXTURE).unwrap().as_slice());
+        client.send_to(&[0, 4, 0, 1], from).await.unwrap();
+        for _ in 0..50 {
+            if stats.completed_transfers() == 1 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(stats.completed_transfers(), 1);
+        assert_eq!(stats.retransmissions(), 1);
+
+        // A client ignoring the OACK repeats its request, answered by the same transfer
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut request = rrq(FIXTURE);
+        request.extend_from_slice(b"blksize\x001024\x00timeout\x005\x00");
+        client.send_to(&request, server_addr).await.unwrap();
+        let (_, first) = timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+        client.send_to(&request, server_addr).await.unwrap();
+        let (size, from) = timeout(Duration::from_secs(1), client.recv_from(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(from, first);
+        assert_eq!(&buf[..size], b"\x00\x06blksize\x001024\x00timeout\x005\x00");
+        assert_eq!(stats.packets(1), 3);
+        assert_eq!(stats.retransmissions(), 2);
+    }
+
+    #[tokio::test]
+    async fn upload_aborted_after_max_retries() {
+        let (results_tx, mut results) = mpsc::channel(1);
+        let server_addr = spawn_configured(|server| server.with_max_retries(2).with_results(results_tx));
+        std::fs::create_dir_all("target/tftp-upload").unwrap();
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(b"\x00\x02target/tftp-upload/partial.bin\x00octet\x00timeout\x001\x00", server_addr).await.unwrap();
+        let mut buf = [0; 516];
+        let (_, from) = timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(&buf[..2], &[0, 6]);
+        let mut block = vec![0, 3, 0, 1];
+        block.resize(516, b'x');
+        client.send_to(&block, from).await.unwrap();
+        // Sent once, then retransmitted up to the cap
+        for _ in 0..3 {
+            let (size, _) = timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+            assert_eq!(&buf[..size], &[0, 4, 0, 1]);
+        }
+        let (size, _) = timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(&buf[..size], b"\x00\x05\x00\x00transfer timed out\x00");
+        let result = timeout(Duration::from_secs(5), results.recv()).await.unwrap().unwrap();
+        assert_eq!(result.retransmits, 2);
+        assert_eq!(result.error_code, Some(0));
+        assert!(!std::path::Path::new("target/tftp-upload/partial.bin").exists());
+    }
+
+    #[tokio::test]
+    async fn cancelled_upload() {
+        let sessions = Sessions::new();
+        let (results_tx, mut results) = mpsc::channel(1);
+        let server_addr = spawn_configured(|server| server.with_sessions(sessions.clone()).with_results(results_tx));
+        std::fs::create_dir_all("target/tftp-upload").unwrap();
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(b"\x00\x02target/tftp-upload/cancelled.bin\x00octet\x00", server_addr).await.unwrap();
+        let mut buf = [0; 516];
+        let (_, from) = timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+        let mut block = vec![0, 3, 0, 1];
+        block.resize(516, b'x');
+        client.send_to(&block, from).await.unwrap();
+        let (size, _) = timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(&buf[..size], &[0, 4, 0, 1]);
+        assert!(std::path::Path::new("target/tftp-upload/cancelled.bin").exists());
+
+        // Another port of the client has no transfer
+        let client_addr = client.local_addr().unwrap();
+        assert!(sessions.cancel(client_addr.ip(), Some(client_addr.port() + 1), true).is_empty());
+        assert_eq!(sessions.cancel(client_addr.ip(), Some(client_addr.port()), true).len(), 1);
+        let (size, _) = timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(&buf[..size], b"\x00\x05\x00\x00transfer cancelled by the server administrator\x00");
+        let result = timeout(Duration::from_secs(5), results.recv()).await.unwrap().unwrap();
+        assert_eq!(result.error.as_deref(), Some("cancelled"));
+        assert!(!std::path::Path::new("target/tftp-upload/cancelled.bin").exists());
+        for _ in 0..50 {
+            if sessions.snapshot().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(sessions.snapshot().is_empty());
+    }
+
+    #[tokio::test]
+    async fn concurrent_upload_of_same_file_refused() {
+        let stats = Arc::new(ServerStats::new());
+        let server_addr = spawn_configured(|server| server.with_stats(stats.clone()));
+        std::fs::create_dir_all("target/tftp-upload").unwrap();
+        let wrq = b"\x00\x02target/tftp-upload/locked.bin\x00octet\x00";
+        let mut buf = [0; 516];
+
+        let first = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        first.send_to(wrq, server_addr).await.unwrap();
+        let (_, first_tid) = timeout(Duration::from_secs(5), first.recv_from(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(&buf[..4], &[0, 4, 0, 0]);
+        // Same file through another path
+        let second = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        second.send_to(b"\x00\x02/target/./tftp-upload/locked.bin\x00octet\x00", server_addr).await.unwrap();
+        let (size, _) = timeout(Duration::from_secs(5), second.recv_from(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(&buf[..size], b"\x00\x05\x00\x02File is busy\x00");
+        // Nor read while it is written
+        second.send_to(&rrq("target/tftp-upload/locked.bin"), server_addr).await.unwrap();
+        let (size, _) = timeout(Duration::from_secs(5), second.recv_from(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(&buf[..size], b"\x00\x05\x00\x02File is busy\x00");
+
+        first.send_to(b"\x00\x03\x00\x01first", first_tid).await.unwrap();
+        timeout(Duration::from_secs(5), first.recv_from(&mut buf)).await.unwrap().unwrap();
+        for _ in 0..50 {
+            if stats.completed_transfers() == 1 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        // Released once the first upload completed
+        second.send_to(wrq, server_addr).await.unwrap();
+        let (_, second_tid) = timeout(Duration::from_secs(5), second.recv_from(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(&buf[..4], &[0, 4, 0, 0]);
+        second.send_to(b"\x00\x03\x00\x01second", second_tid).await.unwrap();
+        timeout(Duration::from_secs(5), second.recv_from(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(std::fs::read("target/tftp-upload/locked.bin").unwrap(), b"second");
+    }
+
+    #[tokio::test]
+    async fn error_replies_rate_limited() {
+        let stats = Arc::new(ServerStats::new());
+        let server_addr = spawn_configured(|server| server.with_error_limit(ErrorLimiter::new(5, 1000)).with_stats(stats.clone()));
+
+        // Spoofed requests for a missing file, from many ports of the victim
+        let mut clients = Vec::new();
+        for _ in 0..20 {
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            client.send_to(&rrq("missing.bin"), server_addr).await.unwrap();
+            clients.push(client);
+        }
+        for _ in 0..50 {
+            if stats.suppressed_errors() + stats.errors(1) >= 20 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let mut buf = [0; 516];
+        let mut replies = 0;
+        for client in &clients {
+            if timeout(Duration::from_millis(100), client.recv_from(&mut buf)).await.is_ok() {
+                assert_eq!(&buf[..4], &[0, 5, 0, 1]);
+                replies += 1;
+            }
+        }
+        // The bucket may refill a token while the requests arrive
+        assert!((5..=6).contains(&replies), "{}", replies);
+        assert_eq!(stats.suppressed_errors(), 20 - replies);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn locked_file_refused() {
+        let server_addr = spawn_server(ServerOptions::default());
+        std::fs::create_dir_all("target/tftp-upload").unwrap();
+        let filename = "target/tftp-upload/flocked.bin";
+        std::fs::write(filename, vec![7; 1300]).unwrap();
+        let wrq = [&b"\x00\x02"[..], filename.as_bytes(), b"\x00octet\x00"].concat();
+        let busy = b"\x00\x05\x00\x02File is busy\x00";
+        let mut buf = [0; 516];
+
+        // Written by another process
+        let external = file_lock::try_lock(std::fs::File::open(filename).unwrap(), LockMode::Exclusive).unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for request in [rrq(filename), wrq.clone()] {
+            client.send_to(&request, server_addr).await.unwrap();
+            let (size, _) = timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+            assert_eq!(&buf[..size], busy);
+        }
+        drop(external);
+
+        // Being read, the first block is not acknowledged yet
+        let reader = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        reader.send_to(&rrq(filename), server_addr).await.unwrap();
+        let (size, _) = timeout(Duration::from_secs(5), reader.recv_from(&mut buf)).await.unwrap().unwrap();
+        assert_eq!((&buf[..4], size), (&[0, 3, 0, 1][..], 516));
+        client.send_to(&wrq, server_addr).await.unwrap();
+        let (size, _) = timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(&buf[..size], busy);
+        // Other readers share the file
+        assert_eq!(fetch(server_addr, filename).await, vec![7; 1300]);
+    }
+
+    /// Needs a small filesystem, e.g. `mount -t tmpfs -o size=64k tmpfs /mnt/tftp-small`, then
+    /// `TFTP_SMALL_FS=/mnt/tftp-small cargo test upload_to_full_disk -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn upload_to_full_disk() {
+        let dir = PathBuf::from(std::env::var("TFTP_SMALL_FS").expect("TFTP_SMALL_FS, a small filesystem"));
+        let options = ServerOptions { root: dir.clone(), ..ServerOptions::default() };
+        let server_addr = spawn_server(options);
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(b"\x00\x02too-large.bin\x00octet\x00", server_addr).await.unwrap();
+        let mut buf = [0; 516];
+        let (_, tid) = timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+        for block in 1u16.. {
+            client.send_to(&[&[0, 3][..], &block.to_be_bytes(), &[b'x'; 512]].concat(), tid).await.unwrap();
+            let (size, _) = timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+            if buf[1] == 5 {
+                assert_eq!(&buf[..size], b"\x00\x05\x00\x03Disk full or allocation exceeded\x00");
+                break;
+            }
+            assert_eq!(&buf[..4], &[&[0, 4][..], &block.to_be_bytes()].concat()[..]);
+        }
+        // Removed once the ERROR is sent
+        for _ in 0..50 {
+            if !dir.join("too-large.bin").exists() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(!dir.join("too-large.bin").exists());
+    }
+
+    #[tokio::test]
+    async fn upload_ends_with_short_block() {
+        let stats = Arc::new(ServerStats::new());
+        let server_addr = spawn_configured(|server| server.with_stats(stats.clone()));
+        std::fs::create_dir_all("target/tftp-upload").unwrap();
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(b"\x00\x02target/tftp-upload/short.bin\x00octet\x00", server_addr).await.unwrap();
+        let mut buf = [0; 516];
+        let (_, from) = timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(&buf[..4], &[0, 4, 0, 0]);
+        client.send_to(b"\x00\x03\x00\x01uploaded", from).await.unwrap();
+        timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(&buf[..4], &[0, 4, 0, 1]);
+        // Complete without waiting for the transfer timeout
+        for _ in 0..50 {
+            if stats.completed_transfers() == 1 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(stats.completed_transfers(), 1);
+        assert_eq!(std::fs::read("target/tftp-upload/short.bin").unwrap(), b"uploaded");
+    }
+
+    #[tokio::test]
+    async fn upload_with_large_blksize() {
+        let stats = Arc::new(ServerStats::new());
+        let server_addr = spawn_configured(|server| server.with_stats(stats.clone()));
+        std::fs::create_dir_all("target/tftp-upload").unwrap();
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(b"\x00\x02target/tftp-upload/large.bin\x00octet\x00blksize\x002048\x00", server_addr).await.unwrap();
+        let mut buf = [0; 4096];
+        let (size, from) = timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(&buf[..size], b"\x00\x06blksize\x002048\x00");
+        let mut block = vec![0, 3, 0, 1];
+        block.extend((0..2048).map(|i| (i % 251) as u8));
+        client.send_to(&block, from).await.unwrap();
+        timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(&buf[..4], &[0, 4, 0, 1]);
+        client.send_to(b"\x00\x03\x00\x02tail", from).await.unwrap();
+        timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(&buf[..4], &[0, 4, 0, 2]);
+        for _ in 0..50 {
+            if stats.completed_transfers() == 1 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(stats.completed_transfers(), 1);
+        let mut expected = block[4..].to_vec();
+        expected.extend(b"tail");
+        assert_eq!(std::fs::read("target/tftp-upload/large.bin").unwrap(), expected);
+    }
+
+    /// Read of a file with the blksize option, the third block is only acknowledged once sent again
+    async fn fetch_with_blksize(server_addr: SocketAddr, filename: &str, blksize: usize) -> Vec<u8> {
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let request = [&b"\x00\x01"[..], filename.as_bytes(), b"\x00octet\x00blksize\x00", blksize.to_string().as_bytes(), b"\x00"].concat();
+        client.send_to(&request, server_addr).await.unwrap();
+        let mut buf = vec![0; blksize + 4];
+        let (_, from) = timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(buf[1], 6);
+        client.send_to(&[0, 4, 0, 0], from).await.unwrap();
+        let mut content = Vec::new();
+        for block in 1u16.. {
+            let (size, _) = timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+            assert_eq!(&buf[..4], &[&[0, 3][..], &block.to_be_bytes()].concat()[..]);
+            if block == 3 {
+                let first = buf[..size].to_vec();
+                let (size, _) = timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+                assert_eq!(&buf[..size], &first[..]);
+            }
+            content.extend_from_slice(&buf[4..size]);
+            client.send_to(&[&[0, 4][..], &block.to_be_bytes()].concat(), from).await.unwrap();
+            if size < blksize + 4 {
+                break;
+            }
+        }
+        return content;
+    }
+
+    #[tokio::test]
+    async fn stray_ack_not_answered() {
+        const MULTIBLOCK: &str = "tests/fixtures/files/multiblock.bin";
+        let server_addr = spawn_server(ServerOptions::default());
+
+        let mut client = TestClient::connect(server_addr).await.unwrap().with_timeout(Duration::from_millis(300));
+        client.send_rrq(MULTIBLOCK, &[]).await.unwrap();
+        let mut content = client.expect_data(1).await.unwrap().to_vec();
+        // Block 65535 was never sent, DATA 1 is neither sent again nor followed by DATA 0
+        client.send_ack(65535).await.unwrap();
+        assert_eq!(client.recv().await.unwrap_err().kind(), std::io::ErrorKind::TimedOut);
+        for blocknum in 1..=2 {
+            client.send_ack(blocknum).await.unwrap();
+            content.extend_from_slice(&client.expect_data(blocknum + 1).await.unwrap());
+        }
+        assert_eq!(content, std::fs::read(MULTIBLOCK).unwrap());
+    }
+
+    #[tokio::test]
+    async fn zero_copy_sends_the_same_bytes() {
+        let filename = format!("target/tftp-zero-copy-{}.bin", std::process::id());
+        let content: Vec<u8> = (0..crate::read_ahead::CHUNK_SIZE * 2 + 1000).map(|i| (i % 253) as u8).collect();
+        std::fs::write(&filename, &content).unwrap();
+        for zero_copy in [false, true] {
+            let options = ServerOptions { zero_copy, ..ServerOptions::default() };
+            let (results, mut received) = mpsc::channel(4);
+            let server_addr = spawn_configured(|server| server.with_options(options).with_results(results));
+            assert!(fetch(server_addr, &filename).await == content, "zero copy {}", zero_copy);
+            assert!(fetch_with_blksize(server_addr, &filename, 1468).await == content, "zero copy {}", zero_copy);
+            for retransmits in [0, 1] {
+                let result = timeout(Duration::from_secs(5), received.recv()).await.unwrap().unwrap();
+                assert_eq!((result.error, result.retransmits, result.bytes), (None, retransmits, content.len() as u64));
+            }
+        }
+        // A multiple of blksize ends with an empty block
+        std::fs::write(&filename, &content[..1468 * 4]).unwrap();
+        let options = ServerOptions { zero_copy: true, ..ServerOptions::default() };
+        let server_addr = spawn_server(options);
+        assert_eq!(fetch_with_blksize(server_addr, &filename, 1468).await, &content[..1468 * 4]);
+        std::fs::remove_file(&filename).unwrap();
+    }
+
+    #[tokio::test]
+    async fn oversized_data_aborts_upload() {
+        let server_addr = spawn_server(ServerOptions::default());
+        std::fs::create_dir_all("target/tftp-upload").unwrap();
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(b"\x00\x02target/tftp-upload/oversized.bin\x00octet\x00", server_addr).await.unwrap();
+        let mut buf = [0; 4096];
+        let (_, from) = timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(&buf[..4], &[0, 4, 0, 0]);
+        // Larger than the whole receive buffer of a 512 bytes transfer
+        let mut block = vec![0, 3, 0, 1];
+        block.resize(4 + 2048, b'x');
+        client.send_to(&block, from).await.unwrap();
+        let (size, _) = timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(&buf[..size], b"\x00\x05\x00\x04Malformed packet\x00");
+    }
+
+    #[tokio::test]
+    async fn completed_transfer_result() {
+        const MULTIBLOCK: &str = "tests/fixtures/files/multiblock.bin";
+        let (results_tx, mut results) = mpsc::channel(1);
+        let server_addr = spawn_configured(|server| server.with_results(results_tx));
+        let content = fetch(server_addr, MULTIBLOCK).await;
+        let result = timeout(Duration::from_secs(5), results.recv()).await.unwrap().unwrap();
+        assert_eq!(result.error, None);
+        assert_eq!(result.bytes, content.len() as u64);
+        assert!(result.duration > Duration::ZERO);
+        assert!(result.throughput() > 0);
+    }
+
+    #[tokio::test]
+    async fn closed_client_aborts_transfer() {
+        const MULTIBLOCK: &str = "tests/fixtures/files/multiblock.bin";
+        let (results_tx, mut results) = mpsc::channel(1);
+        let server_addr = spawn_configured(|server| server.with_results(results_tx));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(&rrq(MULTIBLOCK), server_addr).await.unwrap();
+        let mut buf = [0; 1024];
+        timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+        // The retransmission of block 1 is answered by an ICMP port unreachable
+        drop(client);
+        let result = timeout(Duration::from_secs(5), results.recv()).await.unwrap().unwrap();
+        assert_eq!(result.error.as_deref(), Some("client port unreachable"));
+        assert!(result.duration < Duration::from_secs(5), "{:?}", result.duration);
+    }
+
+    #[tokio::test]
+    async fn injected_drop_sends_nothing() {
+        let injection = Injection { drop_probability: 1.0, ..Injection::default() };
+        let stats = Arc::new(ServerStats::new());
+        let server_addr = spawn_configured(|server| server.with_injection(injection).with_stats(stats.clone()));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(&rrq(FIXTURE), server_addr).await.unwrap();
+        let mut buf = [0; 1024];
+        // Past the first retransmission
+        assert!(timeout(Duration::from_millis(1500), client.recv_from(&mut buf)).await.is_err());
+        assert!(stats.retransmissions() >= 1);
+    }
+
+    #[tokio::test]
+    async fn stats_counters() {
+        let stats = Arc::new(ServerStats::new());
+        let server_addr = spawn_configured(|server| server.with_stats(stats.clone()));
+
+        // 512 bytes: a full block then an empty one, acknowledged by the client
+        assert_eq!(fetch(server_addr, "tests/fixtures/files/block.bin").await.len(), 512);
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(&rrq("../outside.txt"), server_addr).await.unwrap();
+        let mut buf = [0; 1024];
+        timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+        for _ in 0..50 {
+            if stats.completed_transfers() + stats.failed_transfers() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.completed_transfers, 1);
+        assert_eq!(snapshot.failed_transfers, 1);
+        assert_eq!(snapshot.active_sessions, 0);
+        assert_eq!(stats.packets(1), 2);
+        assert_eq!(stats.packets(4), 2);
+        assert_eq!(snapshot.bytes_sent, 512);
+        assert_eq!(snapshot.bytes_received, 0);
+        assert_eq!(snapshot.retransmissions, 0);
+        assert_eq!(stats.errors(2), 1);
+        assert_eq!(snapshot.errors_by_code.iter().sum::<u64>(), 1);
+    }
+
+    #[tokio::test]
+    async fn clients_outside_allowed_subnets_ignored() {
+        let stats = Arc::new(ServerStats::new());
+        let server_addr = spawn_configured(|server| {
+            server.with_allowed_subnets(vec!["10.0.0.0/8".parse().unwrap(), "fd00::/8".parse().unwrap()]).with_stats(stats.clone())
+        });
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(&rrq(FIXTURE), server_addr).await.unwrap();
+        client.send_to(&rrq("missing.bin"), server_addr).await.unwrap();
+        let mut buf = [0; 516];
+        assert!(timeout(Duration::from_millis(300), client.recv_from(&mut buf)).await.is_err());
+        assert_eq!(stats.disallowed_sources(), 2);
+        assert_eq!(stats.active_sessions(), 0);
+
+        // Inside
+        let server_addr = spawn_configured(|server| server.with_allowed_subnets(vec!["10.0.0.0/8".parse().unwrap(), "127.0.0.0/30".parse().unwrap()]));
+        assert_eq!(fetch(server_addr, FIXTURE).await, std::fs::read(FIXTURE).unwrap());
+    }
+
+    #[tokio::test]
+    async fn server_tag_in_errors() {
+        let server_addr = spawn_configured(|server| server.with_server_tag("srv-dc1".to_string()));
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(&rrq("missing.bin"), server_addr).await.unwrap();
+        let mut buf = [0; 516];
+        let (size, _) = timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(&buf[..size], b"\x00\x05\x00\x01[srv-dc1] File not found\x00");
+    }
+
+    #[tokio::test]
+    async fn error_detail_on_the_wire() {
+        let mut request = rrq(FIXTURE);
+        request.extend_from_slice(b"offset\x00999999\x00");
+        let mut buf = [0; 516];
+        for (detail, expected) in [
+            (ErrorDetail::Full, &b"\x00\x05\x00\x00Offset beyond the end of the file\x00"[..]),
+            (ErrorDetail::Generic, b"\x00\x05\x00\x00Not defined\x00"),
+        ] {
+            let (results, mut receiver) = mpsc::channel(1);
+            let server_addr = spawn_configured(|server| server.with_error_detail(detail).with_results(results));
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            client.send_to(&request, server_addr).await.unwrap();
+            let (size, _) = timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+            assert_eq!(&buf[..size], expected, "{}", detail);
+            // The detail is kept for the logs
+            let result = timeout(Duration::from_secs(5), receiver.recv()).await.unwrap().unwrap();
+            assert_eq!(result.error.as_deref(), Some("Offset beyond the end of the file"));
+        }
+    }
+
+    #[tokio::test]
+    async fn empty_datagrams_ignored() {
+        const MULTIBLOCK: &str = "tests/fixtures/files/multiblock.bin";
+        let server_addr = spawn_server(ServerOptions::default());
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = [0; 1024];
+
+        client.send_to(&[], server_addr).await.unwrap();
+        assert!(timeout(Duration::from_millis(200), client.recv_from(&mut buf)).await.is_err());
+        client.send_to(&rrq(MULTIBLOCK), server_addr).await.unwrap();
+        let (_, tid) = timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(&buf[..4], &[0, 3, 0, 1]);
+        // Block 1 is not sent again before its timeout
+        client.send_to(&[], tid).await.unwrap();
+        assert!(timeout(Duration::from_millis(300), client.recv_from(&mut buf)).await.is_err());
+        client.send_to(&[0, 4, 0, 1], tid).await.unwrap();
+        timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(&buf[..4], &[0, 3, 0, 2]);
+    }
+
+    /// On the single thread of the test runtime, a blocking read of the pipe would stop the whole server
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn silent_pipe_producer_blocks_nothing() {
+        use std::io::Write;
+        let fifo = "target/tftp-silent.fifo";
+        let _ = std::fs::remove_file(fifo);
+        let path = std::ffi::CString::new(fifo).unwrap();
+        // SAFETY: mkfifo with a valid C string
+        assert_eq!(unsafe { libc::mkfifo(path.as_ptr(), 0o600) }, 0);
+        // Opened read-write it does not wait for a reader (Linux), and writes nothing yet
+        let mut producer = std::fs::File::options().read(true).write(true).open(fifo).unwrap();
+        let server_addr = spawn_server(ServerOptions::default());
+
+        let mut reader = TestClient::connect(server_addr).await.unwrap();
+        reader.send_rrq(fifo, &[]).await.unwrap();
+        // Another transfer completes meanwhile
+        assert_eq!(fetch(server_addr, FIXTURE).await, std::fs::read(FIXTURE).unwrap());
+        producer.write_all(b"late").unwrap();
+        drop(producer);
+        assert_eq!(&reader.expect_data(1).await.unwrap()[..], b"late");
+        std::fs::remove_file(fifo).unwrap();
+    }
+
+    #[tokio::test]
+    async fn root_per_bind_address() {
+        let dir = std::path::absolute("target/tftp-bind-roots").unwrap();
+        for (root, file) in [("mgmt", "firmware.bin"), ("customer", "image.bin")] {
+            std::fs::create_dir_all(dir.join(root)).unwrap();
+            std::fs::write(dir.join(root).join(file), root).unwrap();
+        }
+        let mut addrs = Vec::new();
+        for root in ["mgmt", "customer"] {
+            addrs.push(spawn_server(ServerOptions { root: dir.join(root), ..ServerOptions::default() }));
+        }
+
+        assert_eq!(fetch(addrs[0], "firmware.bin").await, b"mgmt");
+        assert_eq!(fetch(addrs[1], "/image.bin").await, b"customer");
+        // Each file only through its own address
+        for (addr, filename) in [(addrs[0], "image.bin"), (addrs[1], "firmware.bin"), (addrs[1], "../mgmt/firmware.bin")] {
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            client.send_to(&rrq(filename), addr).await.unwrap();
+            let mut buf = [0; 516];
+            timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+            assert_eq!(&buf[..2], &[0, 5], "{}", filename);
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_counted_by_file() {
+        const MULTIBLOCK: &str = "tests/fixtures/files/multiblock.bin";
+        let stats = Arc::new(ServerStats::new().with_tracked_files(2));
+        let server_addr = spawn_configured(|server| server.with_stats(stats.clone()));
+
+        for _ in 0..3 {
+            fetch(server_addr, FIXTURE).await;
+        }
+        fetch(server_addr, MULTIBLOCK).await;
+        // Same file through another name, a missing one is not counted
+        fetch(server_addr, "/tests/./fixtures/files/multiblock.bin").await;
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(&rrq("missing.bin"), server_addr).await.unwrap();
+        let mut buf = [0; 516];
+        timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+        for _ in 0..50 {
+            if stats.completed_transfers() + stats.failed_transfers() == 6 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let multiblock_size = std::fs::metadata(MULTIBLOCK).unwrap().len();
+        let fixture_size = std::fs::metadata(FIXTURE).unwrap().len();
+        assert_eq!(stats.snapshot().files, [
+            (PathBuf::from(FIXTURE), FileCounters { hits: 3, bytes: 3 * fixture_size }),
+            (PathBuf::from(MULTIBLOCK), FileCounters { hits: 2, bytes: 2 * multiblock_size }),
+        ]);
+
+        // Bounded, the least recently served file is forgotten
+        fetch(server_addr, "tests/fixtures/files/block.bin").await;
+        for _ in 0..50 {
+            if stats.completed_transfers() == 6 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let files: Vec<PathBuf> = stats.files().into_iter().map(|(path, _)| path).collect();
+        assert_eq!(files, [PathBuf::from(MULTIBLOCK), PathBuf::from("tests/fixtures/files/block.bin")]);
+    }
+
+    #[tokio::test]
+    async fn fallback_counted() {
+        let options = ServerOptions { fallback_file: Some(FIXTURE.into()), ..ServerOptions::default() };
+        let stats = Arc::new(ServerStats::new());
+        let server_addr = spawn_configured(|server| server.with_options(options).with_stats(stats.clone()));
+        assert_eq!(fetch(server_addr, "tests/fixtures/files/missing.txt").await, std::fs::read(FIXTURE).unwrap());
+        assert_eq!(fetch(server_addr, "tests/fixtures/files/block.bin").await.len(), 512);
+        assert_eq!(stats.fallbacks(), 1);
+    }
+}
@@ -0,0 +1,261 @@
+//! Table of the transfers in progress, to inspect hung transfers
+//!
+//! On Unix, `spawn_dump_on_sigusr1` logs one line per session on each `kill -USR1`, then the most requested files.
+//! `Sessions::cancel` stops the transfers of a peer, e.g. from the control socket (`control`).
+//! `spawn_progress_log` logs the progress of each transfer at a regular interval (`--progress-interval`).
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::Notify;
+use tokio::time::MissedTickBehavior;
+
+use crate::server::format_size;
+use crate::stats::ServerStats;
+
+/// Most requested files logged by the SIGUSR1 dump
+pub const DUMPED_FILES: usize = 20;
+
+/// State of an active transfer, updated by its task
+#[derive(Debug, Clone)]
+pub(crate) struct Session {
+    pub peer: SocketAddr,
+    pub filename: String,
+    pub write: bool,
+    pub blksize: usize,
+    pub block: u16,
+    /// Blocks transferred, unlike block it does not wrap
+    pub blocks: u64,
+    pub bytes: u64,
+    /// Bytes to transfer: file size of a read, tsize of a write, None when unknown
+    pub size: Option<u64>,
+    pub retransmits: u64,
+    pub last_activity: Instant,
+    pub cancel: Arc<Cancel>,
+}
+
+/// Request to stop a transfer, waited for by its task
+#[derive(Debug, Default)]
+pub(crate) struct Cancel {
+    requested: Notify,
+    /// Send an ERROR to the client before stopping
+    notify_client: AtomicBool,
+}
+
+impl Cancel {
+    /// Wait for the request, true when the client is to be told
+    pub async fn requested(&self) -> bool {
+        self.requested.notified().await;
+        return self.notify_client.load(Ordering::Relaxed);
+    }
+
+    fn request(&self, notify_client: bool) {
+        self.notify_client.store(notify_client, Ordering::Relaxed);
+        // Kept until the task waits again if it is busy sending
+        self.requested.notify_one();
+    }
+}
+
+/// Copy of a session at the time of the snapshot
+#[derive(Debug, Clone)]
+pub struct SessionSnapshot {
+    pub transfer_id: u64,
+    pub peer: SocketAddr,
+    pub filename: String,
+    pub write: bool,
+    pub blksize: usize,
+    pub block: u16,
+    pub blocks: u64,
+    pub bytes: u64,
+    pub size: Option<u64>,
+    pub last_activity_age: Duration,
+    pub retransmits: u64,
+}
+
+impl fmt::Display for SessionSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(
+            f,
+            "#{} {} {} {} blksize {} block {} ({}), last activity {:.1} s ago, {} retransmits",
+            self.transfer_id,
+            self.peer,
+            if self.write { "write" } else { "read" },
+            self.filename,
+            self.blksize,
+            self.block,
+            format_size(self.bytes),
+            self.last_activity_age.as_secs_f64(),
+            self.retransmits
+        );
+    }
+}
+
+/// Shared by the servers and whoever wants to inspect them, clones share the same table
+#[derive(Debug, Clone, Default)]
+pub struct Sessions {
+    table: Arc<Mutex<HashMap<u64, Session>>>,
+}
+
+impl Sessions {
+    pub fn new() -> Sessions {
+        return Sessions::default();
+    }
+
+    /// Active sessions ordered by transfer ID
+    pub fn snapshot(&self) -> Vec<SessionSnapshot> {
+        let now = Instant::now();
+        let table = self.table.lock().unwrap_or_else(|e| e.into_inner());
+        let mut snapshot: Vec<SessionSnapshot> = table
+            .iter()
+            .map(|(id, session)| SessionSnapshot {
+                transfer_id: *id,
+                peer: session.peer,
+                filename: session.filename.clone(),
+                write: session.write,
+                blksize: session.blksize,
+                block: session.block,
+                blocks: session.blocks,
+                bytes: session.bytes,
+                size: session.size,
+                last_activity_age: now.saturating_duration_since(session.last_activity),
+                retransmits: session.retransmits,
+            })
+            .collect();
+        snapshot.sort_by_key(|session| session.transfer_id);
+        return snapshot;
+    }
+
+    /// Register a session, removed when the guard is dropped, even if the transfer task panics
+    pub(crate) fn insert(&self, transfer_id: u64, session: Session) -> SessionGuard {
+        self.table.lock().unwrap_or_else(|e| e.into_inner()).insert(transfer_id, session);
+        return SessionGuard { sessions: self.clone(), transfer_id };
+    }
+
+    /// Stop the transfers of this client, from any of its ports when port is None. Their tasks remove
+    /// the partial uploads and leave the table. Returns the IDs of the cancelled transfers.
+    pub fn cancel(&self, ip: IpAddr, port: Option<u16>, notify_client: bool) -> Vec<u64> {
+        let table = self.table.lock().unwrap_or_else(|e| e.into_inner());
+        let mut cancelled: Vec<u64> = table
+            .iter()
+            .filter(|(_, session)| session.peer.ip().to_canonical() == ip.to_canonical())
+            .filter(|(_, session)| port.is_none_or(|port| session.peer.port() == port))
+            .map(|(id, session)| {
+                session.cancel.request(notify_client);
+                return *id;
+            })
+            .collect();
+        cancelled.sort();
+        return cancelled;
+    }
+
+    pub(crate) fn update(&self, transfer_id: u64, update: impl FnOnce(&mut Session)) {
+        if let Some(session) = self.table.lock().unwrap_or_else(|e| e.into_inner()).get_mut(&transfer_id) {
+            update(session);
+        }
+    }
+}
+
+pub(crate) struct SessionGuard {
+    sessions: Sessions,
+    transfer_id: u64,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.sessions.table.lock().unwrap_or_else(|e| e.into_inner()).remove(&self.transfer_id);
+    }
+}
+
+/// Log the active sessions and the most requested files each time the process receives SIGUSR1
+#[cfg(unix)]
+pub fn spawn_dump_on_sigusr1(sessions: Sessions, stats: Arc<ServerStats>) -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    // Registered before returning so that no signal is missed (or kills the process)
+    let mut signals = signal(SignalKind::user_defined1())?;
+    tokio::spawn(async move {
+        while signals.recv().await.is_some() {
+            let snapshot = sessions.snapshot();
+            log::info!("{} active sessions", snapshot.len());
+            for session in snapshot {
+                log::info!("{}", session);
+            }
+            let files = stats.files();
+            log::info!("{} files served", files.len());
+            for (path, counters) in files.iter().take(DUMPED_FILES) {
+                log::info!("{}: {} reads, {}", path.display(), counters.hits, format_size(counters.bytes));
+            }
+        }
+    });
+    return Ok(());
+}
+
+/// Log a line per active transfer at each interval, with its rate over the interval: a stalled transfer
+/// is logged too. A transfer is first logged one interval after it started.
+pub fn spawn_progress_log(sessions: Sessions, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // Bytes of each transfer at the previous tick
+        let mut previous: HashMap<u64, u64> = HashMap::new();
+        let mut previous_tick = Instant::now();
+        loop {
+            ticks.tick().await;
+            let elapsed = previous_tick.elapsed().as_secs_f64().max(0.001);
+            previous_tick = Instant::now();
+            let snapshot = sessions.snapshot();
+            for session in &snapshot {
+                if let Some(bytes) = previous.get(&session.transfer_id) {
+                    let rate = (session.bytes.saturating_sub(*bytes) as f64 / elapsed) as u64;
+                    log::info!("{}", progress_line(session, rate, interval));
+                }
+            }
+            previous = snapshot.iter().map(|session| (session.transfer_id, session.bytes)).collect();
+        }
+    });
+}
+
+/// e.g. `10.0.0.42 image.bin: 45% (120.3 MiB / 268.0 MiB), 9.8 MiB/s, block 246012`, the bytes only
+/// without the size, and how long the client has been silent once it exceeds the interval
+fn progress_line(session: &SessionSnapshot, rate: u64, interval: Duration) -> String {
+    let done = match session.size {
+        Some(size) if size > 0 => format!("{}% ({} / {})", session.bytes * 100 / size, format_size(session.bytes), format_size(size)),
+        _ => format_size(session.bytes),
+    };
+    let mut line = format!("{} {}: {}, {}/s, block {}", session.peer.ip(), session.filename, done, format_size(rate), session.blocks);
+    if session.last_activity_age >= interval {
+        line.push_str(&format!(", idle {:.0} s", session.last_activity_age.as_secs_f64()));
+    }
+    return line;
+}
+
+#[cfg(test)]
+mod test {
+    use crate::session::*;
+
+    #[test]
+    fn progress_lines() {
+        let mut session = SessionSnapshot {
+            transfer_id: 1,
+            peer: "10.0.0.42:40123".parse().unwrap(),
+            filename: "image.bin".to_string(),
+            write: false,
+            blksize: 512,
+            block: 49404,
+            blocks: 246012,
+            bytes: 126_143_693,
+            size: Some(268 * 1024 * 1024),
+            last_activity_age: Duration::from_millis(20),
+            retransmits: 0,
+        };
+        let interval = Duration::from_secs(10);
+        assert_eq!(progress_line(&session, 10_276_045, interval), "10.0.0.42 image.bin: 44% (120.3 MiB / 268.0 MiB), 9.8 MiB/s, block 246012");
+        // Upload without tsize, stalled
+        session.size = None;
+        session.last_activity_age = Duration::from_secs(25);
+        assert_eq!(progress_line(&session, 0, interval), "10.0.0.42 image.bin: 120.3 MiB, 0 B/s, block 246012, idle 25 s");
+    }
+}
@@ -0,0 +1,551 @@
+//! UDP socket creation, with the options tokio `UdpSocket::bind` does not expose
+
+use serde::Deserialize;
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::path::PathBuf;
+use std::str::FromStr;
+use tokio::net::UdpSocket;
+
+/// Listening address as given with `--bind`: `IP`, `IP%zone` or `[IP%zone]:PORT`, then optionally `=DIR`
+///
+/// The zone (interface name or index) is needed for IPv6 link-local addresses,
+/// a port given here takes precedence over `--port`. DIR is served on this address instead of `--directory`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct BindSpec {
+    pub ip: IpAddr,
+    pub zone: Option<String>,
+    pub scope_id: u32,
+    pub port: Option<u16>,
+    pub root: Option<PathBuf>,
+}
+
+impl BindSpec {
+    pub fn socket_addr(&self, default_port: u16) -> SocketAddr {
+        let port = self.port.unwrap_or(default_port);
+        return match self.ip {
+            IpAddr::V4(_) => SocketAddr::new(self.ip, port),
+            IpAddr::V6(ip) => SocketAddr::V6(SocketAddrV6::new(ip, port, 0, self.scope_id)),
+        };
+    }
+}
+
+impl FromStr for BindSpec {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<BindSpec, String> {
+        let (spec, root) = match spec.split_once('=') {
+            Some((_, "")) => return Err(format!("Missing directory after '=' in {}", spec)),
+            Some((spec, root)) => (spec, Some(PathBuf::from(root))),
+            None => (spec, None),
+        };
+        let (host, port) = match spec.strip_prefix('[') {
+            Some(rest) => {
+                let (host, after) = rest.split_once(']').ok_or(format!("Missing ']' in {}", spec))?;
+                let port = match after {
+                    "" => None,
+                    _ => {
+                        let port = after.strip_prefix(':').ok_or(format!("Expected :PORT after ']' in {}", spec))?;
+                        Some(port.parse::<u16>().map_err(|e| format!("Invalid port in {}: {}", spec, e))?)
+                    }
+                };
+                (host, port)
+            }
+            // IPv4 address with a port, an unbracketed IPv6 address cannot have one
+            None => match spec.rsplit_once(':') {
+                Some((host, port)) if !host.contains(':') => {
+                    (host, Some(port.parse::<u16>().map_err(|e| format!("Invalid port in {}: {}", spec, e))?))
+                }
+                _ => (spec, None),
+            },
+        };
+        let (ip, zone) = match host.split_once('%') {
+            Some((ip, zone)) => (ip, Some(zone.to_string())),
+            None => (host, None),
+        };
+        let ip = IpAddr::from_str(ip).map_err(|e| format!("Invalid address {}: {}", ip, e))?;
+        let scope_id = match (&ip, &zone) {
+            (_, None) => 0,
+            (IpAddr::V4(_), Some(_)) => return Err(format!("Zone is only supported for IPv6 addresses: {}", spec)),
+            (IpAddr::V6(_), Some(zone)) => interface_index(zone)?,
+        };
+        return Ok(BindSpec { ip, zone, scope_id, port, root });
+    }
+}
+
+impl TryFrom<String> for BindSpec {
+    type Error = String;
+
+    fn try_from(spec: String) -> Result<BindSpec, String> {
+        return BindSpec::from_str(&spec);
+    }
+}
+
+impl fmt::Display for BindSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let host = match &self.zone {
+            Some(zone) => format!("{}%{}", self.ip, zone),
+            None => self.ip.to_string(),
+        };
+        match (self.port, self.ip) {
+            (None, _) => write!(f, "{}", host)?,
+            (Some(port), IpAddr::V4(_)) => write!(f, "{}:{}", host, port)?,
+            (Some(port), IpAddr::V6(_)) => write!(f, "[{}]:{}", host, port)?,
+        };
+        if let Some(root) = &self.root {
+            write!(f, "={}", root.display())?;
+        }
+        return Ok(());
+    }
+}
+
+/// Scope id of a zone, either numeric or the name of an existing interface
+fn interface_index(zone: &str) -> Result<u32, String> {
+    if let Ok(index) = zone.parse::<u32>() {
+        return Ok(index);
+    }
+    #[cfg(unix)]
+    {
+        let name = std::ffi::CString::new(zone).map_err(|_| format!("Invalid interface name {}", zone))?;
+        // SAFETY: name is a valid NUL terminated string for the duration of the call
+        let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if index == 0 {
+            return Err(format!("No network interface named {}", zone));
+        }
+        return Ok(index);
+    }
+    #[cfg(not(unix))]
+    return Err(format!("Interface names are not supported, use the numeric zone instead of {}", zone));
+}
+
+/// Options of the listening sockets, the defaults are the system ones
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ListenOptions {
+    /// An IPv6 socket also accepts IPv4 clients
+    pub dual_stack: bool,
+    /// SO_REUSEADDR, to bind again while the sockets of a previous process linger
+    pub reuse_addr: bool,
+    /// SO_RCVBUF in bytes, the kernel may cap or round it
+    pub recv_buffer_size: Option<usize>,
+    /// SO_REUSEPORT, several sockets bound to the same address share its datagrams
+    pub reuse_port: bool,
+    /// Applied to the listening socket, the server applies it to the transfer sockets
+    pub marking: PacketMarking,
+}
+
+/// Marking of the packets sent, for the QoS and the reach of the traffic
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PacketMarking {
+    /// Differentiated services code point, 0 to 63, the upper bits of IP_TOS / IPV6_TCLASS
+    pub dscp: Option<u8>,
+    /// IP_TTL / IPV6_UNICAST_HOPS, 1 to 255
+    pub ttl: Option<u8>,
+    /// Never fragmented (IP_MTU_DISCOVER, Linux only): a packet over the path MTU fails to send
+    pub dont_fragment: bool,
+}
+
+impl PacketMarking {
+    /// Set the options for the family of the bound socket, a failure is only logged,
+    /// the packets are then sent with the system defaults
+    pub fn apply(&self, socket: &UdpSocket) {
+        let socket = SockRef::from(socket);
+        let ipv6 = matches!(socket.local_addr().ok().and_then(|addr| addr.as_socket()), Some(SocketAddr::V6(_)));
+        if let Some(dscp) = self.dscp {
+            // The 2 lower bits are ECN
+            let tos = (dscp as u32) << 2;
+            let set = if ipv6 { set_tclass_v6(&socket, tos) } else { socket.set_tos(tos) };
+            if let Err(e) = set {
+                log::warn!("Cannot set DSCP {} on {}: {}", dscp, describe(&socket), e);
+            }
+        }
+        if let Some(ttl) = self.ttl {
+            let set = if ipv6 { socket.set_unicast_hops_v6(ttl as u32) } else { socket.set_ttl(ttl as u32) };
+            if let Err(e) = set {
+                log::warn!("Cannot set TTL {} on {}: {}", ttl, describe(&socket), e);
+            }
+        }
+        if self.dont_fragment {
+            if let Err(e) = set_dont_fragment(&socket, ipv6) {
+                log::warn!("Cannot forbid the fragmentation on {}: {}", describe(&socket), e);
+            }
+        }
+    }
+}
+
+/// Path MTU discovery without fallback to fragmentation, also for the IPv4 clients of a dual stack socket
+#[cfg(target_os = "linux")]
+fn set_dont_fragment(socket: &SockRef<'_>, ipv6: bool) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+    let set = |level, name, value: libc::c_int| {
+        // SAFETY: value outlives the call and its size is given
+        let result = unsafe {
+            libc::setsockopt(socket.as_raw_fd(), level, name, &value as *const libc::c_int as *const libc::c_void,
+                             std::mem::size_of::<libc::c_int>() as libc::socklen_t)
+        };
+        return if result == 0 { Ok(()) } else { Err(io::Error::last_os_error()) };
+    };
+    if ipv6 {
+        set(libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER, libc::IPV6_PMTUDISC_DO)?;
+        // Not available on an IPv6 only socket, which has no IPv4 client anyway
+        let _ = set(libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, libc::IP_PMTUDISC_DO);
+        return Ok(());
+    }
+    return set(libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, libc::IP_PMTUDISC_DO);
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_dont_fragment(_socket: &SockRef<'_>, _ipv6: bool) -> io::Result<()> {
+    return Err(io::Error::new(io::ErrorKind::Unsupported, "IP_MTU_DISCOVER is not available on this platform"));
+}
+
+/// Path MTU to the peer of a connected socket, as known by the kernel (IP_MTU / IPV6_MTU)
+#[cfg(target_os = "linux")]
+pub fn path_mtu(socket: &UdpSocket) -> io::Result<u32> {
+    use std::os::fd::AsRawFd;
+    let (level, name) = match socket.local_addr()? {
+        SocketAddr::V4(_) => (libc::IPPROTO_IP, libc::IP_MTU),
+        SocketAddr::V6(_) => (libc::IPPROTO_IPV6, libc::IPV6_MTU),
+    };
+    let mut mtu: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: mtu and len outlive the call, len is the size of mtu
+    let result = unsafe { libc::getsockopt(socket.as_raw_fd(), level, name, &mut mtu as *mut libc::c_int as *mut libc::c_void, &mut len) };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    return Ok(mtu as u32);
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn path_mtu(_socket: &UdpSocket) -> io::Result<u32> {
+    return Err(io::Error::new(io::ErrorKind::Unsupported, "IP_MTU is not available on this platform"));
+}
+
+fn describe(socket: &SockRef<'_>) -> String {
+    return match socket.local_addr().ok().and_then(|addr| addr.as_socket()) {
+        Some(addr) => addr.to_string(),
+        None => "socket".to_string(),
+    };
+}
+
+#[cfg(any(target_os = "android", target_os = "dragonfly", target_os = "freebsd", target_os = "fuchsia",
+          target_os = "linux", target_os = "macos", target_os = "netbsd", target_os = "openbsd"))]
+fn set_tclass_v6(socket: &SockRef<'_>, tclass: u32) -> io::Result<()> {
+    return socket.set_tclass_v6(tclass);
+}
+
+#[cfg(not(any(target_os = "android", target_os = "dragonfly", target_os = "freebsd", target_os = "fuchsia",
+              target_os = "linux", target_os = "macos", target_os = "netbsd", target_os = "openbsd")))]
+fn set_tclass_v6(_socket: &SockRef<'_>, _tclass: u32) -> io::Result<()> {
+    return Err(io::Error::new(io::ErrorKind::Unsupported, "IPV6_TCLASS is not available on this platform"));
+}
+
+/// Whether SO_REUSEPORT spreads the datagrams between the sockets, elsewhere one socket gets them all
+pub const REUSE_PORT_SUPPORTED: bool = cfg!(any(target_os = "linux", target_os = "android"));
+
+/// Bind a listening socket with its options applied before the bind
+pub fn bind_udp(addr: SocketAddr, options: &ListenOptions) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(!options.dual_stack)?;
+    }
+    if options.reuse_addr {
+        socket.set_reuse_address(true)?;
+    }
+    if options.reuse_port {
+        set_reuse_port(&socket)?;
+    }
+    if let Some(size) = options.recv_buffer_size {
+        socket.set_recv_buffer_size(size)?;
+        let effective = socket.recv_buffer_size()?;
+        if effective < size {
+            log::warn!("Receive buffer of {} limited to {} bytes by the system", addr, effective);
+        }
+    }
+    socket.bind(&addr.into())?;
+    socket.set_nonblocking(true)?;
+    let socket = UdpSocket::from_std(socket.into())?;
+    options.marking.apply(&socket);
+    return Ok(socket);
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_reuse_port(socket: &Socket) -> io::Result<()> {
+    return socket.set_reuse_port(true);
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn set_reuse_port(_socket: &Socket) -> io::Result<()> {
+    return Err(io::Error::new(io::ErrorKind::Unsupported, "SO_REUSEPORT load balancing is not available on this platform"));
+}
+
+/// First descriptor passed by systemd socket activation, SD_LISTEN_FDS_START
+#[cfg(unix)]
+pub const LISTEN_FDS_START: std::os::fd::RawFd = 3;
+
+/// UDP sockets passed by systemd socket activation, in the order of the socket unit,
+/// empty when none was passed to this process
+#[cfg(unix)]
+pub fn systemd_sockets(marking: &PacketMarking) -> io::Result<Vec<UdpSocket>> {
+    let pid = std::env::var("LISTEN_PID").ok();
+    let fds = std::env::var("LISTEN_FDS").ok();
+    let count = listen_fds(pid.as_deref(), fds.as_deref(), std::process::id());
+    let mut sockets = Vec::new();
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + count as std::os::fd::RawFd {
+        sockets.push(adopt_udp(fd, marking)?);
+    }
+    return Ok(sockets);
+}
+
+/// Number of descriptors passed when LISTEN_PID is this process, the variables are inherited by its children
+#[cfg(unix)]
+fn listen_fds(pid: Option<&str>, fds: Option<&str>, own_pid: u32) -> u32 {
+    if pid.and_then(|pid| pid.parse::<u32>().ok()) != Some(own_pid) {
+        return 0;
+    }
+    return fds.and_then(|fds| fds.parse().ok()).unwrap_or(0);
+}
+
+/// Take ownership of an inherited descriptor, which must be a bound UDP socket:
+/// its options come from where it was created, only the marking is applied
+#[cfg(unix)]
+pub fn adopt_udp(fd: std::os::fd::RawFd, marking: &PacketMarking) -> io::Result<UdpSocket> {
+    use std::os::fd::FromRawFd;
+    // SAFETY: the descriptor was passed to this process to own, nothing else uses it
+    let socket = unsafe { Socket::from_raw_fd(fd) };
+    let addr = socket.local_addr().ok().and_then(|addr| addr.as_socket());
+    if socket.r#type()? != Type::DGRAM || addr.is_none() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("descriptor {} is not a UDP socket", fd)));
+    }
+    // Not inherited by the processes started later
+    socket.set_cloexec(true)?;
+    socket.set_nonblocking(true)?;
+    let socket = UdpSocket::from_std(socket.into())?;
+    marking.apply(&socket);
+    return Ok(socket);
+}
+
+/// Human readable address family of a bound socket, for the startup log
+pub fn family_description(socket: &UdpSocket) -> io::Result<&'static str> {
+    if socket.local_addr()?.is_ipv4() {
+        return Ok("IPv4");
+    }
+    return match socket2::SockRef::from(socket).only_v6()? {
+        true => Ok("IPv6 only"),
+        false => Ok("IPv6 and IPv4"),
+    };
+}
+
+/// Local address of a transfer socket: the listening IP when it is a specific one,
+/// otherwise the unspecified address of the client family
+pub fn transfer_bind_addr(local: SocketAddr, peer: SocketAddr) -> SocketAddr {
+    if !local.ip().is_unspecified() {
+        // Keeps the scope id of a link-local address
+        let mut addr = local;
+        addr.set_port(0);
+        return addr;
+    }
+    let ip = match peer {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    return SocketAddr::new(ip, 0);
+}
+
+/// Socket of a transfer (its TID), in the family of the client. An IPv4 client of a dual stack socket
+/// has a mapped IPv6 address, its socket accepts IPv4 whatever the system default (IPv6 only on Windows)
+pub fn bind_transfer(local: SocketAddr, peer: SocketAddr) -> io::Result<UdpSocket> {
+    let addr = transfer_bind_addr(local, peer);
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if let SocketAddr::V6(peer) = peer {
+        if peer.ip().to_ipv4_mapped().is_some() {
+            socket.set_only_v6(false)?;
+        }
+    }
+    socket.bind(&addr.into())?;
+    socket.set_nonblocking(true)?;
+    return UdpSocket::from_std(socket.into());
+}
+
+#[cfg(test)]
+mod test {
+    use crate::socket::*;
+
+    #[tokio::test]
+    async fn loopback_path_mtu() {
+        let socket = bind_transfer("127.0.0.1:0".parse().unwrap(), "127.0.0.1:69".parse().unwrap()).unwrap();
+        socket.connect("127.0.0.1:69").await.unwrap();
+        let mtu = path_mtu(&socket);
+        #[cfg(target_os = "linux")]
+        assert!(mtu.unwrap() >= 1280);
+        #[cfg(not(target_os = "linux"))]
+        assert_eq!(mtu.unwrap_err().kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn bind_spec_parsing() {
+        let spec: BindSpec = "127.0.0.1".parse().unwrap();
+        assert_eq!(spec.socket_addr(69), "127.0.0.1:69".parse().unwrap());
+        let spec: BindSpec = "10.0.0.1:6969".parse().unwrap();
+        assert_eq!(spec.socket_addr(69), "10.0.0.1:6969".parse().unwrap());
+        let spec: BindSpec = "::".parse().unwrap();
+        assert_eq!(spec.socket_addr(69), "[::]:69".parse().unwrap());
+        let spec: BindSpec = "[::1]:6969".parse().unwrap();
+        assert_eq!(spec.socket_addr(69), "[::1]:6969".parse().unwrap());
+        assert!("10.0.0.1%1".parse::<BindSpec>().is_err());
+        assert!("[::1".parse::<BindSpec>().is_err());
+    }
+
+    #[test]
+    fn bind_spec_root() {
+        let spec: BindSpec = "10.0.0.1:69=/srv/mgmt".parse().unwrap();
+        assert_eq!(spec.socket_addr(6969), "10.0.0.1:69".parse().unwrap());
+        assert_eq!(spec.root, Some(PathBuf::from("/srv/mgmt")));
+        assert_eq!(spec.to_string(), "10.0.0.1:69=/srv/mgmt");
+        let spec: BindSpec = "[::1]=images".parse().unwrap();
+        assert_eq!(spec.socket_addr(69), "[::1]:69".parse().unwrap());
+        assert_eq!(spec.root, Some(PathBuf::from("images")));
+        assert_eq!("127.0.0.1".parse::<BindSpec>().unwrap().root, None);
+        assert!("10.0.0.1=".parse::<BindSpec>().is_err());
+    }
+
+    #[test]
+    fn bind_spec_numeric_zone() {
+        let spec: BindSpec = "[fe80::1%3]:69".parse().unwrap();
+        assert_eq!(spec.scope_id, 3);
+        match spec.socket_addr(6969) {
+            SocketAddr::V6(addr) => {
+                assert_eq!(addr.scope_id(), 3);
+                assert_eq!(addr.port(), 69);
+            }
+            SocketAddr::V4(_) => panic!("fe80::1 must give an IPv6 address"),
+        }
+        assert_eq!(spec.to_string(), "[fe80::1%3]:69");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn bind_spec_interface_zone() {
+        let spec: BindSpec = "fe80::1%lo".parse().unwrap();
+        assert_ne!(spec.scope_id, 0);
+        assert_eq!(spec.zone.as_deref(), Some("lo"));
+        let error = "fe80::1%nosuchif0".parse::<BindSpec>().unwrap_err();
+        assert!(error.contains("nosuchif0"), "{}", error);
+    }
+
+    #[test]
+    fn transfer_socket_family_follows_client() {
+        let any_v6: SocketAddr = "[::]:69".parse().unwrap();
+        let v4_peer: SocketAddr = "192.0.2.1:1234".parse().unwrap();
+        let v6_peer: SocketAddr = "[2001:db8::1]:1234".parse().unwrap();
+        assert_eq!(transfer_bind_addr(any_v6, v4_peer), "0.0.0.0:0".parse().unwrap());
+        assert_eq!(transfer_bind_addr(any_v6, v6_peer), "[::]:0".parse().unwrap());
+        let local_v6: SocketAddr = "[::1]:69".parse().unwrap();
+        assert_eq!(transfer_bind_addr(local_v6, v6_peer), "[::1]:0".parse().unwrap());
+        let link_local = BindSpec::from_str("fe80::1%3").unwrap().socket_addr(69);
+        assert_eq!(transfer_bind_addr(link_local, v6_peer), BindSpec::from_str("fe80::1%3").unwrap().socket_addr(0));
+    }
+
+    #[tokio::test]
+    async fn dual_stack_option() {
+        let any_v6: SocketAddr = "[::]:0".parse().unwrap();
+        let dual_stack = ListenOptions { dual_stack: true, ..ListenOptions::default() };
+        let socket = bind_udp(any_v6, &dual_stack).unwrap();
+        assert_eq!(family_description(&socket).unwrap(), "IPv6 and IPv4");
+        let socket = bind_udp(any_v6, &ListenOptions::default()).unwrap();
+        assert_eq!(family_description(&socket).unwrap(), "IPv6 only");
+        let socket = bind_udp("127.0.0.1:0".parse().unwrap(), &dual_stack).unwrap();
+        assert_eq!(family_description(&socket).unwrap(), "IPv4");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn reuse_addr_option() {
+        let options = ListenOptions { reuse_addr: true, ..ListenOptions::default() };
+        let first = bind_udp("127.0.0.1:0".parse().unwrap(), &options).unwrap();
+        let addr = first.local_addr().unwrap();
+        // The port is still taken by the first socket
+        assert!(bind_udp(addr, &ListenOptions::default()).is_err());
+        let second = bind_udp(addr, &options).unwrap();
+        assert_eq!(second.local_addr().unwrap(), addr);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn reuse_port_option() {
+        let options = ListenOptions { reuse_port: true, ..ListenOptions::default() };
+        let first = bind_udp("127.0.0.1:0".parse().unwrap(), &options).unwrap();
+        let addr = first.local_addr().unwrap();
+        assert!(bind_udp(addr, &ListenOptions::default()).is_err());
+        let second = bind_udp(addr, &options).unwrap();
+        assert_eq!(second.local_addr().unwrap(), addr);
+    }
+
+    #[tokio::test]
+    async fn packet_marking() {
+        // CS1
+        let marking = PacketMarking { dscp: Some(8), ttl: Some(2), dont_fragment: true };
+        let options = ListenOptions { marking, ..ListenOptions::default() };
+        let socket = bind_udp("127.0.0.1:0".parse().unwrap(), &options).unwrap();
+        let socket = SockRef::from(&socket);
+        assert_eq!(socket.tos().unwrap(), 32);
+        assert_eq!(socket.ttl().unwrap(), 2);
+        let socket = UdpSocket::bind("[::1]:0").await.unwrap();
+        marking.apply(&socket);
+        let socket = SockRef::from(&socket);
+        assert_eq!(socket.unicast_hops_v6().unwrap(), 2);
+        #[cfg(target_os = "linux")]
+        assert_eq!(socket.tclass_v6().unwrap(), 32);
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::fd::AsRawFd;
+            let mut value: libc::c_int = 0;
+            let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+            // SAFETY: value and len outlive the call, len is the size of value
+            let result = unsafe {
+                libc::getsockopt(socket.as_raw_fd(), libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER,
+                                 &mut value as *mut libc::c_int as *mut libc::c_void, &mut len)
+            };
+            assert_eq!((result, value), (0, libc::IPV6_PMTUDISC_DO));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn listen_fds_of_this_process() {
+        assert_eq!(listen_fds(Some("42"), Some("2"), 42), 2);
+        // Inherited from the parent
+        assert_eq!(listen_fds(Some("41"), Some("2"), 42), 0);
+        assert_eq!(listen_fds(None, Some("2"), 42), 0);
+        assert_eq!(listen_fds(Some("42"), Some("two"), 42), 0);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn adopt_passed_socket() {
+        use std::os::fd::IntoRawFd;
+        let passed = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = passed.local_addr().unwrap();
+        let socket = adopt_udp(passed.into_raw_fd(), &PacketMarking { ttl: Some(3), ..PacketMarking::default() }).unwrap();
+        assert_eq!(socket.local_addr().unwrap(), addr);
+        assert_eq!(SockRef::from(&socket).ttl().unwrap(), 3);
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(b"hello", addr).await.unwrap();
+        let mut buf = [0; 16];
+        let (size, from) = socket.recv_from(&mut buf).await.unwrap();
+        assert_eq!((&buf[..size], from), (&b"hello"[..], client.local_addr().unwrap()));
+
+        let stream = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        assert_eq!(adopt_udp(stream.into_raw_fd(), &PacketMarking::default()).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn recv_buffer_size_option() {
+        let options = ListenOptions { recv_buffer_size: Some(65536), ..ListenOptions::default() };
+        let socket = bind_udp("127.0.0.1:0".parse().unwrap(), &options).unwrap();
+        assert!(socket2::SockRef::from(&socket).recv_buffer_size().unwrap() >= 65536);
+    }
+}
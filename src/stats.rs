@@ -0,0 +1,281 @@
+//! Server counters, updated by the request and transfer paths
+//!
+//! Shared with `Arc`, reads are cheap atomic loads so they can be polled by a metrics exporter.
+//! The counters by file are behind a lock, updated once per completed read.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Opcodes 1 (RRQ) to 6 (OACK), index 0 counts the packets with an unknown opcode
+const OPCODE_SLOTS: usize = 7;
+/// RFC 1350 error codes 0 to 7
+const ERROR_SLOTS: usize = 8;
+
+/// Files counted without ServerStats::with_tracked_files, the least recently served one is forgotten beyond
+pub const DEFAULT_TRACKED_FILES: usize = 1000;
+
+/// Completed reads of a file
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FileCounters {
+    pub hits: u64,
+    pub bytes: u64,
+}
+
+/// Counters of the most recently served files, bounded so that requests of random names cannot grow it
+#[derive(Debug)]
+struct FileTable {
+    capacity: usize,
+    /// Counters and last use of each file
+    files: HashMap<PathBuf, (FileCounters, u64)>,
+    /// Files by last use, the first one is evicted
+    recent: BTreeMap<u64, PathBuf>,
+    uses: u64,
+}
+
+impl Default for FileTable {
+    fn default() -> FileTable {
+        return FileTable::new(DEFAULT_TRACKED_FILES);
+    }
+}
+
+impl FileTable {
+    fn new(capacity: usize) -> FileTable {
+        return FileTable { capacity, files: HashMap::new(), recent: BTreeMap::new(), uses: 0 };
+    }
+
+    fn add(&mut self, path: &Path, bytes: u64) {
+        self.uses += 1;
+        match self.files.get_mut(path) {
+            Some((_, used)) => {
+                self.recent.remove(used);
+                *used = self.uses;
+            }
+            None => {
+                if self.files.len() >= self.capacity {
+                    if let Some((_, evicted)) = self.recent.pop_first() {
+                        self.files.remove(&evicted);
+                    }
+                }
+                self.files.insert(path.to_path_buf(), (FileCounters::default(), self.uses));
+            }
+        }
+        self.recent.insert(self.uses, path.to_path_buf());
+        let counters = &mut self.files.get_mut(path).unwrap().0;
+        counters.hits += 1;
+        counters.bytes += bytes;
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ServerStats {
+    packets_by_opcode: [AtomicU64; OPCODE_SLOTS],
+    active_sessions: AtomicU64,
+    completed_transfers: AtomicU64,
+    failed_transfers: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    retransmissions: AtomicU64,
+    rejected_requests: AtomicU64,
+    fallbacks: AtomicU64,
+    invalid_sources: AtomicU64,
+    disallowed_sources: AtomicU64,
+    suppressed_errors: AtomicU64,
+    errors_by_code: [AtomicU64; ERROR_SLOTS],
+    files: Mutex<FileTable>,
+}
+
+/// Plain copy of the counters
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StatsSnapshot {
+    /// Received packets, index 0 for unknown opcodes then by opcode
+    pub packets_by_opcode: [u64; OPCODE_SLOTS],
+    pub active_sessions: u64,
+    pub completed_transfers: u64,
+    pub failed_transfers: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub retransmissions: u64,
+    /// Requests dropped because too many transfers were waiting
+    pub rejected_requests: u64,
+    /// Reads of a missing file answered with the fallback file
+    pub fallbacks: u64,
+    /// Packets dropped for their broadcast, multicast or unspecified source address
+    pub invalid_sources: u64,
+    /// Packets dropped for their source outside of the allowed subnets
+    pub disallowed_sources: u64,
+    /// ERROR packets not sent, over the error rate of the client or of the server
+    pub suppressed_errors: u64,
+    /// ERROR packets sent, by error code
+    pub errors_by_code: [u64; ERROR_SLOTS],
+    /// Completed reads of the most recently served files, most requested first
+    pub files: Vec<(PathBuf, FileCounters)>,
+}
+
+impl ServerStats {
+    pub fn new() -> ServerStats {
+        return ServerStats::default();
+    }
+
+    /// Count the reads of this many files, DEFAULT_TRACKED_FILES otherwise
+    pub fn with_tracked_files(mut self, capacity: usize) -> ServerStats {
+        self.files = Mutex::new(FileTable::new(capacity));
+        return self;
+    }
+
+    /// Received packets with this opcode, 0 for the unknown ones
+    pub fn packets(&self, opcode: u16) -> u64 {
+        return self.packets_by_opcode.get(opcode as usize).map_or(0, |counter| counter.load(Ordering::Relaxed));
+    }
+
+    pub fn active_sessions(&self) -> u64 {
+        return self.active_sessions.load(Ordering::Relaxed);
+    }
+
+    pub fn completed_transfers(&self) -> u64 {
+        return self.completed_transfers.load(Ordering::Relaxed);
+    }
+
+    pub fn failed_transfers(&self) -> u64 {
+        return self.failed_transfers.load(Ordering::Relaxed);
+    }
+
+    pub fn bytes_sent(&self) -> u64 {
+        return self.bytes_sent.load(Ordering::Relaxed);
+    }
+
+    pub fn bytes_received(&self) -> u64 {
+        return self.bytes_received.load(Ordering::Relaxed);
+    }
+
+    pub fn retransmissions(&self) -> u64 {
+        return self.retransmissions.load(Ordering::Relaxed);
+    }
+
+    pub fn rejected_requests(&self) -> u64 {
+        return self.rejected_requests.load(Ordering::Relaxed);
+    }
+
+    pub fn fallbacks(&self) -> u64 {
+        return self.fallbacks.load(Ordering::Relaxed);
+    }
+
+    pub fn invalid_sources(&self) -> u64 {
+        return self.invalid_sources.load(Ordering::Relaxed);
+    }
+
+    pub fn disallowed_sources(&self) -> u64 {
+        return self.disallowed_sources.load(Ordering::Relaxed);
+    }
+
+    pub fn suppressed_errors(&self) -> u64 {
+        return self.suppressed_errors.load(Ordering::Relaxed);
+    }
+
+    /// ERROR packets sent with this error code
+    pub fn errors(&self, errorcode: u16) -> u64 {
+        return self.errors_by_code.get(errorcode as usize).map_or(0, |counter| counter.load(Ordering::Relaxed));
+    }
+
+    /// Completed reads by served path, most requested first
+    pub fn files(&self) -> Vec<(PathBuf, FileCounters)> {
+        let table = self.files.lock().unwrap_or_else(|e| e.into_inner());
+        let mut files: Vec<(PathBuf, FileCounters)> = table.files.iter().map(|(path, (counters, _))| (path.clone(), *counters)).collect();
+        files.sort_by(|(path_a, a), (path_b, b)| b.hits.cmp(&a.hits).then_with(|| path_a.cmp(path_b)));
+        return files;
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        return StatsSnapshot {
+            packets_by_opcode: self.packets_by_opcode.each_ref().map(|counter| counter.load(Ordering::Relaxed)),
+            active_sessions: self.active_sessions(),
+            completed_transfers: self.completed_transfers(),
+            failed_transfers: self.failed_transfers(),
+            bytes_sent: self.bytes_sent(),
+            bytes_received: self.bytes_received(),
+            retransmissions: self.retransmissions(),
+            rejected_requests: self.rejected_requests(),
+            fallbacks: self.fallbacks(),
+            invalid_sources: self.invalid_sources(),
+            disallowed_sources: self.disallowed_sources(),
+            suppressed_errors: self.suppressed_errors(),
+            errors_by_code: self.errors_by_code.each_ref().map(|counter| counter.load(Ordering::Relaxed)),
+            files: self.files(),
+        };
+    }
+
+    /// Count a received packet from its opcode
+    pub(crate) fn packet_received(&self, packet: &[u8]) {
+        let opcode = match packet {
+            [high, low, ..] => u16::from_be_bytes([*high, *low]) as usize,
+            _ => 0,
+        };
+        let slot = if opcode < OPCODE_SLOTS { opcode } else { 0 };
+        self.packets_by_opcode[slot].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a transfer as active until the returned guard is dropped
+    pub(crate) fn session_started(self: &Arc<Self>) -> ActiveSession {
+        self.active_sessions.fetch_add(1, Ordering::Relaxed);
+        return ActiveSession { stats: self.clone() };
+    }
+
+    pub(crate) fn transfer_finished(&self, success: bool) {
+        let counter = if success { &self.completed_transfers } else { &self.failed_transfers };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_bytes_sent(&self, bytes: u64) {
+        self.bytes_sent.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_bytes_received(&self, bytes: u64) {
+        self.bytes_received.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn retransmission(&self) {
+        self.retransmissions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn request_rejected(&self) {
+        self.rejected_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn fallback_served(&self) {
+        self.fallbacks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn invalid_source(&self) {
+        self.invalid_sources.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn disallowed_source(&self) {
+        self.disallowed_sources.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn error_suppressed(&self) {
+        self.suppressed_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A read of path completed
+    pub(crate) fn file_served(&self, path: &Path, bytes: u64) {
+        self.files.lock().unwrap_or_else(|e| e.into_inner()).add(path, bytes);
+    }
+
+    pub(crate) fn error_sent(&self, errorcode: u16) {
+        if let Some(counter) = self.errors_by_code.get(errorcode as usize) {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+pub(crate) struct ActiveSession {
+    stats: Arc<ServerStats>,
+}
+
+impl Drop for ActiveSession {
+    fn drop(&mut self) {
+        self.stats.active_sessions.fetch_sub(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,178 @@
+//! Sources that can only be read in order, e.g. a named pipe fed by the process generating a boot image
+//!
+//! The blocks are read as they are sent: the size is unknown (no `tsize`), a transfer cannot resume
+//! with `offset`, and only the last block can be sent again. A block waits for the producer to write it,
+//! the content ends when the producer closes the pipe. The pipe is never read in blocking mode: `fill`
+//! awaits the next block in the transfer task, `block` then hands it out without waiting.
+
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+use std::sync::Mutex;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::beneath::Symlinks;
+
+pub struct Stream {
+    /// Taken by fill while it waits for the producer
+    reader: Option<Box<dyn AsyncRead + Send + Unpin>>,
+    /// Number and content of the last block handed out
+    last: Option<(u16, Vec<u8>)>,
+    /// Read by fill, handed out by the next call to block
+    next: Option<(u16, io::Result<Vec<u8>>)>,
+    /// The last block was the short one
+    ended: bool,
+}
+
+impl fmt::Debug for Stream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return f.debug_struct("Stream").field("last", &self.last.as_ref().map(|(blocknum, _)| blocknum)).field("ended", &self.ended).finish();
+    }
+}
+
+impl Stream {
+    pub fn new(reader: impl AsyncRead + Send + Unpin + 'static) -> Stream {
+        return Stream { reader: Some(Box::new(reader)), last: None, next: None, ended: false };
+    }
+
+    /// Block read after the last one handed out
+    fn expected(&self) -> u16 {
+        return self.last.as_ref().map_or(1, |(last, _)| last.wrapping_add(1));
+    }
+
+    /// Content of blocknum, which must be the next block or the last one again, None after the short block.
+    /// The next block must have been read by fill, it fails with ErrorKind::WouldBlock otherwise.
+    /// Any other block fails with ErrorKind::Unsupported.
+    pub fn block(&mut self, blocknum: u16, blksize: usize) -> io::Result<Option<&[u8]>> {
+        if self.last.as_ref().is_some_and(|(last, _)| *last == blocknum) {
+            return Ok(self.last.as_ref().map(|(_, block)| block.as_slice()));
+        }
+        let expected = self.expected();
+        if blocknum != expected {
+            let message = format!("block {} requested, only block {} can be read from a pipe", blocknum, expected);
+            return Err(io::Error::new(ErrorKind::Unsupported, message));
+        }
+        if self.ended {
+            return Ok(None);
+        }
+        let block = match self.next.take() {
+            Some((next, block)) if next == blocknum => block?,
+            _ => return Err(io::Error::new(ErrorKind::WouldBlock, format!("block {} not read from the pipe yet", blocknum)))
+        };
+        self.ended = block.len() < blksize;
+        self.last = Some((blocknum, block));
+        return Ok(self.last.as_ref().map(|(_, block)| block.as_slice()));
+    }
+}
+
+/// Read blocknum ahead of Stream::block when it is the next block, waiting for the producer without holding
+/// the lock nor a thread. Nothing is read for the last block sent again or after the short block.
+/// A read error is kept for block.
+pub async fn fill(stream: &Mutex<Stream>, blocknum: u16, blksize: usize) {
+    let mut reader = {
+        let mut stream = stream.lock().unwrap_or_else(|e| e.into_inner());
+        if stream.ended || stream.next.is_some() || blocknum != stream.expected() {
+            return;
+        }
+        // Lost when a previous fill was cancelled, block then reports it as not read
+        match stream.reader.take() {
+            Some(reader) => reader,
+            None => return
+        }
+    };
+    let mut block = vec![0; blksize];
+    let mut filled = 0;
+    let mut error = None;
+    while filled < blksize {
+        match reader.read(&mut block[filled..]).await {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => {
+                error = Some(e);
+                break;
+            }
+        }
+    }
+    block.truncate(filled);
+    let mut stream = stream.lock().unwrap_or_else(|e| e.into_inner());
+    stream.reader = Some(reader);
+    stream.next = Some((blocknum, error.map_or(Ok(block), Err)));
+}
+
+/// The named pipe at path inside root as a Stream, None when path is another kind of file.
+/// Registered with the tokio reactor, it must be opened from a task.
+#[cfg(unix)]
+pub fn open_fifo(root: &Path, path: &Path, symlinks: Symlinks) -> io::Result<Option<Stream>> {
+    use std::os::unix::fs::FileTypeExt;
+    use crate::beneath::{self, Access};
+    // Checked again on the opened file, the path may have been replaced
+    if !root.join(path).metadata().is_ok_and(|metadata| metadata.file_type().is_fifo()) {
+        return Ok(None);
+    }
+    // Opened with O_NONBLOCK, which the reads keep: they wait in the reactor, not in the kernel
+    let f = beneath::open_in(root, path, Access::Read, symlinks)?;
+    if !f.metadata()?.file_type().is_fifo() {
+        return Ok(None);
+    }
+    return Ok(Some(Stream::new(tokio::net::unix::pipe::Receiver::from_file(f)?)));
+}
+
+/// Named pipes are a Unix thing
+#[cfg(not(unix))]
+pub fn open_fifo(_root: &Path, _path: &Path, _symlinks: Symlinks) -> io::Result<Option<Stream>> {
+    return Ok(None);
+}
+
+#[cfg(test)]
+mod test {
+    use crate::stream::*;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::ReadBuf;
+
+    /// Reader handing out a few bytes at a time, like a pipe written in small pieces
+    struct Trickle {
+        content: Vec<u8>,
+        position: usize,
+    }
+
+    impl AsyncRead for Trickle {
+        fn poll_read(mut self: Pin<&mut Self>, _: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+            let read = buf.remaining().min(100).min(self.content.len() - self.position);
+            let position = self.position;
+            buf.put_slice(&self.content[position..position + read]);
+            self.position += read;
+            return Poll::Ready(Ok(()));
+        }
+    }
+
+    #[tokio::test]
+    async fn sequential_blocks() {
+        let content: Vec<u8> = (0..1300).map(|i| (i % 251) as u8).collect();
+        let stream = Mutex::new(Stream::new(Trickle { content: content.clone(), position: 0 }));
+        // Not read yet
+        assert_eq!(stream.lock().unwrap().block(1, 512).unwrap_err().kind(), ErrorKind::WouldBlock);
+        fill(&stream, 1, 512).await;
+        {
+            let mut locked = stream.lock().unwrap();
+            assert_eq!(locked.block(1, 512).unwrap(), Some(&content[..512]));
+            // Retransmission of the last block
+            assert_eq!(locked.block(1, 512).unwrap(), Some(&content[..512]));
+        }
+        // Nothing read for it
+        fill(&stream, 1, 512).await;
+        fill(&stream, 2, 512).await;
+        {
+            let mut locked = stream.lock().unwrap();
+            assert_eq!(locked.block(2, 512).unwrap(), Some(&content[512..1024]));
+            assert_eq!(locked.block(1, 512).unwrap_err().kind(), ErrorKind::Unsupported);
+            assert_eq!(locked.block(4, 512).unwrap_err().kind(), ErrorKind::Unsupported);
+        }
+        fill(&stream, 3, 512).await;
+        assert_eq!(stream.lock().unwrap().block(3, 512).unwrap(), Some(&content[1024..]));
+        fill(&stream, 4, 512).await;
+        assert_eq!(stream.lock().unwrap().block(4, 512).unwrap(), None);
+    }
+}
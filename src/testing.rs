@@ -0,0 +1,232 @@
+//! Scripted TFTP client for the tests of the server and of the programs embedding it
+//!
+//! A `TestClient` talks to a server bound on an ephemeral port, packet by packet with the `send_*` and
+//! `expect_*` methods, or a whole transfer with `get` and `put`. Requests go to the server address and
+//! the other packets to the transfer port, learnt from the first answer to the request. Each `expect_*`
+//! waits at most the timeout of the client, and fails with `TimedOut`, or `InvalidData` when another
+//! packet is received (an ERROR of the server included, its code and message in the error). Like a real
+//! client, each request is sent from a new port: the previous transfer, even unfinished, does not
+//! interfere with the next one.
+//!
+//! ```no_run
+//! # async fn example(server_addr: std::net::SocketAddr) -> std::io::Result<()> {
+//! use tokio_tftpserver::testing::TestClient;
+//!
+//! let mut client = TestClient::connect(server_addr).await?;
+//! client.send_rrq("pxelinux.0", &[("blksize", "1468")]).await?;
+//! assert_eq!(client.expect_oack().await?, [("blksize".to_string(), "1468".to_string())]);
+//! client.send_ack(0).await?;
+//! let first = client.expect_data(1).await?;
+//! # return Ok(());
+//! # }
+//! ```
+
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+
+use crate::codec::{get_buffer_for_command, parse_packet, Command, Opcode, Strictness};
+
+/// Wait for each expected packet, above the 1 second retransmission timeout of the server
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Largest packet: the largest block size (65464) and the header
+const MAX_PACKET: usize = 65468;
+
+#[derive(Debug)]
+pub struct TestClient {
+    socket: UdpSocket,
+    server: SocketAddr,
+    /// Source of the first answer to the last request
+    transfer: Option<SocketAddr>,
+    timeout: Duration,
+    buf: Vec<u8>,
+}
+
+impl TestClient {
+    /// Bound to an ephemeral port of the address the server is bound to (e.g. 127.0.0.1)
+    pub async fn connect(server: SocketAddr) -> io::Result<TestClient> {
+        let socket = UdpSocket::bind(SocketAddr::new(server.ip(), 0)).await?;
+        return Ok(TestClient { socket, server, transfer: None, timeout: DEFAULT_TIMEOUT, buf: vec![0; MAX_PACKET] });
+    }
+
+    /// Wait of each expect_* and recv, DEFAULT_TIMEOUT otherwise
+    pub fn with_timeout(mut self, timeout: Duration) -> TestClient {
+        self.timeout = timeout;
+        return self;
+    }
+
+    /// Port of the current transfer on the client side, a new one for each request
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        return self.socket.local_addr();
+    }
+
+    /// Port of the current transfer, None until the server answered the request
+    pub fn transfer_addr(&self) -> Option<SocketAddr> {
+        return self.transfer;
+    }
+
+    /// RRQ in octet mode, starts a new transfer
+    pub async fn send_rrq(&mut self, filename: &str, options: &[(&str, &str)]) -> io::Result<()> {
+        return self.send_request(&Command::RRQ { filename: filename.as_bytes().to_vec(), mode: "octet".to_string(), options: to_options(options) }).await;
+    }
+
+    /// WRQ in octet mode, starts a new transfer
+    pub async fn send_wrq(&mut self, filename: &str, options: &[(&str, &str)]) -> io::Result<()> {
+        return self.send_request(&Command::WRQ { filename: filename.as_bytes().to_vec(), mode: "octet".to_string(), options: to_options(options) }).await;
+    }
+
+    /// Any command to the server port, e.g. a request in netascii mode, starts a new transfer from a new port
+    pub async fn send_request(&mut self, request: &Command) -> io::Result<()> {
+        let packet = get_buffer_for_command(request.clone()).ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "cannot serialize the request"))?;
+        self.socket = UdpSocket::bind(SocketAddr::new(self.server.ip(), 0)).await?;
+        self.transfer = None;
+        self.socket.send_to(&packet, self.server).await?;
+        return Ok(());
+    }
+
+    /// Bytes as they are from the current port, to the transfer port once known, to the server port
+    /// before (e.g. a request repeated by the client)
+    pub async fn send_raw(&self, packet: &[u8]) -> io::Result<()> {
+        self.socket.send_to(packet, self.transfer.unwrap_or(self.server)).await?;
+        return Ok(());
+    }
+
+    pub async fn send_ack(&self, blocknum: u16) -> io::Result<()> {
+        return self.send_to_transfer(&[&(Opcode::ACK as u16).to_be_bytes()[..], &blocknum.to_be_bytes()].concat()).await;
+    }
+
+    pub async fn send_data(&self, blocknum: u16, payload: &[u8]) -> io::Result<()> {
+        let mut packet = BytesMut::with_capacity(payload.len() + 4);
+        packet.put_u16(Opcode::DATA as u16);
+        packet.put_u16(blocknum);
+        packet.put_slice(payload);
+        return self.send_to_transfer(&packet).await;
+    }
+
+    pub async fn send_error(&self, errorcode: u16, errmsg: &str) -> io::Result<()> {
+        let error = Command::ERROR { errorcode, errmsg: errmsg.to_string() };
+        return self.send_to_transfer(&get_buffer_for_command(error).unwrap_or_default()).await;
+    }
+
+    async fn send_to_transfer(&self, packet: &[u8]) -> io::Result<()> {
+        let Some(transfer) = self.transfer else {
+            return Err(io::Error::new(ErrorKind::NotConnected, "no answer from the server yet"));
+        };
+        self.socket.send_to(packet, transfer).await?;
+        return Ok(());
+    }
+
+    /// Next packet from the server, parsed; the first one gives the transfer port
+    pub async fn recv(&mut self) -> io::Result<Command> {
+        let received = timeout(self.timeout, self.socket.recv_from(&mut self.buf)).await;
+        let (size, from) = received.map_err(|_| io::Error::new(ErrorKind::TimedOut, format!("nothing received in {:?}", self.timeout)))??;
+        if self.transfer.is_none() {
+            self.transfer = Some(from);
+        }
+        return Ok(parse_packet(&self.buf[..size], Strictness::Lenient));
+    }
+
+    /// Payload of DATA blocknum
+    pub async fn expect_data(&mut self, blocknum: u16) -> io::Result<Bytes> {
+        match self.recv().await? {
+            Command::DATA { blocknum: received, data } if received == blocknum => return Ok(data),
+            other => return Err(unexpected(&format!("DATA {}", blocknum), &other)),
+        }
+    }
+
+    pub async fn expect_ack(&mut self, blocknum: u16) -> io::Result<()> {
+        match self.recv().await? {
+            Command::ACK { blocknum: received } if received == blocknum => return Ok(()),
+            other => return Err(unexpected(&format!("ACK {}", blocknum), &other)),
+        }
+    }
+
+    /// Options accepted by the server, in its order
+    pub async fn expect_oack(&mut self) -> io::Result<Vec<(String, String)>> {
+        match self.recv().await? {
+            Command::OACK { options } => return Ok(options),
+            other => return Err(unexpected("OACK", &other)),
+        }
+    }
+
+    /// Code and message of an ERROR
+    pub async fn expect_error(&mut self) -> io::Result<(u16, String)> {
+        match self.recv().await? {
+            Command::ERROR { errorcode, errmsg } => return Ok((errorcode, errmsg)),
+            other => return Err(unexpected("ERROR", &other)),
+        }
+    }
+
+    /// Whole read of a file in octet mode, acknowledging each block
+    pub async fn get(&mut self, filename: &str, options: &[(&str, &str)]) -> io::Result<Vec<u8>> {
+        self.send_rrq(filename, options).await?;
+        let mut blksize = 512;
+        let mut content = Vec::new();
+        let mut blocknum: u16 = 1;
+        let mut first = Some(self.recv().await?);
+        if let Some(Command::OACK { options }) = &first {
+            blksize = negotiated_blksize(options);
+            first = None;
+            self.send_ack(0).await?;
+        }
+        loop {
+            let data = match first.take() {
+                Some(Command::DATA { blocknum: 1, data }) => data,
+                Some(other) => return Err(unexpected("DATA 1 or OACK", &other)),
+                None => self.expect_data(blocknum).await?,
+            };
+            content.extend_from_slice(&data);
+            self.send_ack(blocknum).await?;
+            if data.len() < blksize {
+                return Ok(content);
+            }
+            blocknum = blocknum.wrapping_add(1);
+        }
+    }
+
+    /// Whole upload of content in octet mode, ending with a short block (empty for a multiple of the block size)
+    pub async fn put(&mut self, filename: &str, options: &[(&str, &str)], content: &[u8]) -> io::Result<()> {
+        self.send_wrq(filename, options).await?;
+        let blksize = match self.recv().await? {
+            Command::OACK { options } => negotiated_blksize(&options),
+            Command::ACK { blocknum: 0 } => 512,
+            other => return Err(unexpected("ACK 0 or OACK", &other)),
+        };
+        let mut blocknum: u16 = 1;
+        let mut offset = 0;
+        loop {
+            let block = &content[offset..content.len().min(offset + blksize)];
+            self.send_data(blocknum, block).await?;
+            self.expect_ack(blocknum).await?;
+            if block.len() < blksize {
+                return Ok(());
+            }
+            offset += blksize;
+            blocknum = blocknum.wrapping_add(1);
+        }
+    }
+}
+
+fn to_options(options: &[(&str, &str)]) -> Vec<(String, String)> {
+    return options.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+}
+
+fn negotiated_blksize(options: &[(String, String)]) -> usize {
+    let blksize = options.iter().find(|(name, _)| name.eq_ignore_ascii_case("blksize"));
+    return blksize.and_then(|(_, value)| value.parse().ok()).unwrap_or(512);
+}
+
+fn unexpected(expected: &str, received: &Command) -> io::Error {
+    let received = match received {
+        Command::DATA { blocknum, data } => format!("DATA {} ({} bytes)", blocknum, data.len()),
+        Command::ACK { blocknum } => format!("ACK {}", blocknum),
+        Command::ERROR { errorcode, errmsg } => format!("ERROR {}: {}", errorcode, errmsg),
+        other => format!("{:?}", other),
+    };
+    return io::Error::new(ErrorKind::InvalidData, format!("expected {}, received {}", expected, received));
+}
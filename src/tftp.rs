@@ -1,277 +1,1268 @@
 pub mod tftpprotocol {
-   use std::io::Cursor;
-   use std::io::BufRead;
-   use std::io::Read;
    use std::io::Write;
-   use byteorder::{BigEndian};
-   use byteorder::{ReadBytesExt,WriteBytesExt};
-   use std::convert::TryFrom;
-   use std::fs::File;
-   use std::fs::OpenOptions;
-   use std::io::Seek;
-   use std::io::SeekFrom;
+   use bytes::{BufMut, Bytes, BytesMut};
+   use std::fs::{File, Metadata};
+   use std::io::ErrorKind;
+   use std::net::SocketAddr;
+   use std::path::{Component, Path, PathBuf};
+   use std::sync::{Arc, Mutex};
+   use std::time::{Instant, SystemTime};
+   use log::{debug, info, trace, warn};
+   pub use crate::codec::{get_buffer_for_command, get_client_error, get_client_error_message, parse_packet, process_buffer,
+                          process_packet, write_command, Command, Opcode, Strictness, TftpError};
+   use crate::beneath::{self, Access, Symlinks};
+   use crate::file_lock::{self, FileLock, LockMode};
+   use crate::gzip::GzipFile;
+   use crate::netascii::Netascii;
+   use crate::options::{self, Limits, TransferOptions};
+   use crate::read_ahead::ReadAhead;
+   use crate::remap::Remap;
+   use crate::stream::{self, Stream};
+   use crate::variables;
+   use crate::zero_copy::{self, FileWindow};
 
-   enum Opcode {
-       RRQ = 1, // Read request
-       WRQ = 2, // Write request
-       DATA = 3,
-       ACK  = 4,
-       ERROR = 5,
-       UNKNOWN = -1
-   }
-
-   impl TryFrom<u16> for Opcode {
-      type Error = &'static str;
-
-      fn try_from(opcode: u16) -> Result<Self, Self::Error> {
-         match opcode {
-            1 => Ok(Opcode::RRQ),
-            2 => Ok(Opcode::WRQ),
-            3 => Ok(Opcode::DATA),
-            4 => Ok(Opcode::ACK),
-            5 => Ok(Opcode::ERROR),
-            _ => Ok(Opcode::UNKNOWN)
-         }
-      }
-   }
-
-   #[derive(Debug, Clone)]
-   pub enum Command {
-      RRQ  {filename : String, mode:String},
-      WRQ  {filename : String, mode:String},
-      DATA {blocknum : u16, data:Vec<u8>},
-      ACK  {blocknum : u16},
-      ERROR {errorcode :u16, errmsg:String}
+   /// Server wide settings applied to every transfer
+   #[derive(Debug, Clone, Default)]
+   pub struct ServerOptions {
+      pub no_create : bool,     // WRQ can only update existing files
+      pub ignore_case : bool,   // RRQ of a missing file retries with a case-insensitive match
+      pub auto_decompress : bool,  // RRQ of a missing file serves <name>.gz decompressed
+      pub lowercase_names : bool,  // requested filenames are lowercased
+      pub prefix : Option<PathBuf>,  // directory prepended to the requested filenames
+      pub upload_mode : Option<u32>,  // permissions of the uploaded files (Unix), whatever the umask
+      pub upload_owner : Option<(u32, u32)>,  // uid and gid given to the completed uploads (Unix)
+      pub create_upload_dirs : bool,  // WRQ creates the missing directories of the path
+      pub atomic_uploads : bool,  // WRQ writes a temporary file renamed to the requested one with the last block
+      pub max_out_of_order : Option<u32>,  // WRQ aborted beyond this many consecutive out of order DATA blocks, never when None
+      pub strictness : Strictness,  // deviations from the RFCs tolerated in the received packets
+      pub zero_copy : bool,     // RRQ of a file in octet mode, the blocks are sent from the file by the kernel (Linux)
+      pub remap : Remap,        // rewrite rules of the requested filenames
+      pub default_file : Option<PathBuf>,  // read by a RRQ of an empty filename
+      pub fallback_file : Option<PathBuf>,  // read by a RRQ of a missing file, client variables expanded
+      pub no_symlinks : bool,   // a requested path going through a symlink is refused, even inside the served directory
+      pub serve_hidden : bool,  // a requested path with a component starting with '.' is served
+      pub max_filename_len : Option<usize>,  // longest requested filename in bytes, MAX_FILENAME_LEN when None
+      pub ascii_filenames : bool,  // a requested filename with non-ASCII bytes is refused
+      pub root : PathBuf,       // served directory of the bind address, absolute, the working directory when empty
+      pub extra_roots : Vec<PathBuf>,  // absolute, searched in order by a RRQ of a file missing from the served directory
+      pub limits : Limits,      // bounds of the negotiated options
    }
 
    #[derive(Debug, Clone)]
    pub struct OpContext {
       pub current_op : Command,  // RRQ or WRQ
-      pub reply_to_send : Option<Command>,
-      _block_num : u16,      // For RRQ last read block, for WRQ, last written
+      written   : u64,       // WRQ, DATA blocks written, each one right after the previous one
+      reack     : bool,      // WRQ, the last DATA was not written, the last written block is acknowledged again
+      out_of_order : u32,    // WRQ, consecutive DATA blocks received out of order
       ack_num   : u16,       // last ACK received (to detect timeout)
-      filename  : String,
-      mode      : String
+      acked     : u64,       // RRQ, blocks acknowledged by the client, ack_num is its low 16 bits: the file offsets
+                             // are computed from it once the block numbers wrap around
+      sent      : u64,       // RRQ, highest block sent, the client can go back to an earlier one but not past it
+      pub filename : PathBuf,    // as requested, the served path comes from sanitize_filename
+      refused   : Option<TftpError>,  // requested filename refused by check_filename, never looked up
+      peer      : SocketAddr,  // client, its variables are expanded in the rewritten filenames
+      mode      : String,
+      pub transfer_id : u64,     // set by the server, correlates the log lines of a transfer
+      pub started_at : Instant,  // reception of the request, a queued transfer counts its wait
+      pub server_options : ServerOptions,
+      pub options : TransferOptions,   // negotiated with the client
+      oack : Vec<(String,String)>,     // accepted options, sent before the transfer starts
+      pub content : Option<Arc<Vec<u8>>>,  // RRQ of a generated file, served instead of the disk
+      gzip : Option<Arc<Mutex<GzipFile>>>,  // RRQ of a missing file served from its .gz, decoder state
+      stream : Option<Arc<Mutex<Stream>>>,  // RRQ of a named pipe, read as the blocks are sent
+      netascii : Option<Arc<Mutex<Netascii>>>,  // netascii mode, file positions of the blocks
+      read_ahead : Arc<Mutex<ReadAhead>>,  // RRQ of a file in octet mode, chunk the blocks are taken from
+      file_window : Arc<Mutex<FileWindow>>,  // same with zero_copy, file the blocks are sent from
+      identity : Arc<Mutex<Option<FileIdentity>>>,  // RRQ of a file, as found at the start, checked at each open
+      fallback : Option<PathBuf>  // RRQ of a missing file, server_options.fallback_file expanded is read instead
    }
 
-   fn build_new_context(current_op: Command) -> Option<OpContext> {
-      // TODO find how to do that without clone 
-      let saved_op = current_op.clone();
-      match current_op {
-         Command::RRQ{filename, mode} | Command::WRQ{filename, mode} =>
+   fn build_new_context(mut current_op: Command, peer: SocketAddr, server_options: &ServerOptions) -> Option<OpContext> {
+      // The strings move to the context, the request is then only kept to tell a RRQ from a WRQ
+      let write = matches!(current_op, Command::WRQ{..});
+      match &mut current_op {
+         Command::RRQ{filename, mode, options} | Command::WRQ{filename, mode, options} => {
+             let (mut negotiated, mut oack) = options::negotiate(options, &server_options.limits);
+             // An upload always starts at the beginning of the file
+             if write && negotiated.offset != 0 {
+                negotiated.offset = 0;
+                oack.retain(|(name, _)| name != "offset");
+             }
+             let refused = check_filename(filename, server_options).err();
+             let filename = normalize_filename(std::mem::take(filename), write, peer, server_options);
+             let mode = std::mem::take(mode);
+             // A block number only gives the file offset in octet mode
+             let netascii = match mode.eq_ignore_ascii_case("netascii") {
+                true => Some(Arc::new(Mutex::new(Netascii::new(negotiated.offset)))),
+                false => None
+             };
+             if !options.is_empty() {
+                debug!("Options of {}: requested {}, granted {}", peer, format_options(options), format_options(&oack));
+             }
              return Some( OpContext {
-               current_op: saved_op,
-               reply_to_send : None,
-               _block_num:0,
+               current_op,
+               written:0,
+               reack:false,
+               out_of_order:0,
                ack_num:0,
+               acked:0,
+               sent:1,
                filename,
-               mode
-            }),
+               refused,
+               peer,
+               mode,
+               transfer_id: 0,
+               started_at: Instant::now(),
+               server_options: server_options.clone(),
+               options: negotiated,
+               oack,
+               content: None,
+               gzip: None,
+               stream: None,
+               netascii,
+               read_ahead: Arc::new(Mutex::new(ReadAhead::new())),
+               file_window: Arc::new(Mutex::new(FileWindow::new())),
+               identity: Arc::new(Mutex::new(None)),
+               fallback: None
+            })
+         },
          _ => return None
       }     
    }
 
 
-   fn parse_command(opcode: Opcode, reader: &mut Cursor<&[u8]>) -> Command {
-
-      // Inner function for RRQ/WRQ shared parsing logic 
-      fn parse_filename_mode(reader: &mut Cursor<&[u8]>) -> (String,String) {
-         let mut buffer: Vec<u8> = Vec::new();
-         reader.read_until(0, &mut buffer).unwrap();
-         // Remove delimiter (\0)
-         buffer.pop();
-         // Todo Manage Error
-         let filename = String::from_utf8(buffer).unwrap();
-         // First buffer was moved above, create buffer for Mode
-         let mut _mode_buf: Vec<u8> = Vec::new();
-         reader.read_until(0, &mut _mode_buf).unwrap();
-         _mode_buf.pop();
-         let mode = String::from_utf8(_mode_buf).unwrap();
-   
-         return (filename, mode);
-      }
-
-      match opcode {
-         Opcode::RRQ => {
-             println!("Read");
-             let (filename, mode) = parse_filename_mode(reader);
-             println!("FileName: {}, Mode: {}",filename, mode);
-             return Command::RRQ {filename: filename, mode: mode};
-         },
-         Opcode::WRQ => {
-            println!("Write");
-            let (filename, mode) = parse_filename_mode(reader);
-            println!("FileName: {}, Mode: {}",filename, mode);
-            return Command::WRQ{filename, mode};
-         },
-         Opcode::ACK => {
-            let blocknum = reader.read_u16::<BigEndian>().unwrap();
-            println!("ACK {}",blocknum);
-            return Command::ACK{blocknum};
-         },
-         Opcode::ERROR => {
-            println!("ERROR");
-            let errcode = reader.read_u16::<BigEndian>().unwrap();
-            let mut buffer: Vec<u8> = Vec::new();
-            let _error_read = reader.read_until(0, &mut buffer).unwrap();
-            buffer.pop();
-            // Todo Manage Error
-            let error = String::from_utf8(buffer).unwrap();
-            return Command::ERROR{errorcode:errcode, errmsg: error};
-         }
-         Opcode::DATA => {
-            println!("DATA");
-            let blocknum = reader.read_u16::<BigEndian>().unwrap();
-            let mut buf: [u8; 512] = [0;512];
-            let n = reader.read(&mut buf).unwrap();
-            println!("Blknum: {}, len: {}",blocknum,n);
-            return Command::DATA{blocknum, data: buf[0..n].to_vec()};
-         },
+   /// Look up the file of a RRQ: the decompressed .gz or the named pipe served instead of a regular file,
+   /// the fallback of a missing file. Done by the transfer task rather than by recv_request, the receiving
+   /// loop then never waits for the disk, not even for the requests it refuses or ignores
+   pub fn resolve_request(context: &mut OpContext) {
+      // A refused name is never looked up
+      if !matches!(context.current_op, Command::RRQ{..}) || context.refused.is_some() {
+         return;
+      }
+      let (filename, server_options) = (&context.filename, &context.server_options);
+      let gzip = open_gzip_fallback(filename, server_options);
+      let stream = match &gzip {
+         None => open_stream(filename, server_options),
+         Some(_) => None
+      };
+      let fallback = match (&gzip, &server_options.fallback_file) {
+         (None, Some(fallback)) if is_missing(filename, server_options) => Some(expand_variables(fallback, context.peer)),
+         _ => None
+      };
+      if !server_options.extra_roots.is_empty() {
+         match lookup_filename(filename, server_options) {
+            Ok(path) if path.exists() => match split_root(&path, server_options) {
+               (root, _) if root.as_os_str().is_empty() => debug!("{} found in the served directory", filename.display()),
+               (root, _) => debug!("{} found in {}", filename.display(), root.display())
+            },
+            _ => ()
+         }
+      }
+      // Its size is only known at the end
+      if stream.is_some() {
+         context.oack.retain(|(name, _)| name != "tsize");
+      }
+      context.gzip = gzip.map(|gzip| Arc::new(Mutex::new(gzip)));
+      context.stream = stream.map(|stream| Arc::new(Mutex::new(stream)));
+      context.fallback = fallback;
+   }
 
-         _ => {
-            println!("Other Opcode");
-            return Command::ERROR{errorcode :1, errmsg:"NOT IMPLEMENTED".to_string()};
+   /// Lower the negotiated blksize to max, in the OACK too, before anything is sent
+   pub fn limit_blksize(context: &mut OpContext, max: u16) {
+      if context.options.blksize <= max {
+         return;
+      }
+      let blksize = context.server_options.limits.grant_blksize(context.options.blksize, max);
+      context.options.blksize = blksize;
+      for (name, value) in context.oack.iter_mut() {
+         if name == "blksize" {
+            *value = blksize.to_string();
          }
-            
       }
+   }
 
+   /// name=value pairs separated by spaces, "none" for an empty list
+   fn format_options(options: &[(String, String)]) -> String {
+      if options.is_empty() {
+         return "none".to_string();
+      }
+      return options.iter().map(|(name, value)| format!("{}={}", name, value)).collect::<Vec<_>>().join(" ");
    }
 
-   pub fn get_reply_command(context:OpContext) -> Option<Command> {
-      match context.current_op {
+   /// Requested filename with the lowercase, default file, remap and prefix options applied,
+   /// sanitized afterwards like any request
+   fn normalize_filename(mut filename: Vec<u8>, write: bool, peer: SocketAddr, server_options: &ServerOptions) -> PathBuf {
+      if server_options.lowercase_names {
+         filename = match String::from_utf8(filename) {
+            Ok(filename) => filename.to_lowercase().into_bytes(),
+            Err(e) => e.into_bytes().to_ascii_lowercase()
+         };
+      }
+      let mut filename = filename_from_bytes(filename);
+      // Like the index of a web server, an upload still needs a name
+      if let (false, Some(default_file)) = (write, &server_options.default_file) {
+         if matches!(sanitize_filename(&filename), Err(TftpError::FileNotFound)) {
+            filename = default_file.clone();
+         }
+      }
+      // Rules see the name as it is served, a refused name is left to fail its lookup
+      if let Ok(sanitized) = sanitize_filename(&filename) {
+         if let Some(remapped) = server_options.remap.apply(&sanitized, peer) {
+            filename = remapped;
+         }
+      }
+      match &server_options.prefix {
+         // Leading '/' are ignored by the sanitization, they must not replace the prefix either
+         Some(prefix) => return prefix.join(filename.strip_prefix("/").unwrap_or(&filename)),
+         None => return filename
+      }
+   }
+
+   /// Path with the variables of the client expanded, a path which is not UTF-8 has none
+   fn expand_variables(path: &Path, peer: SocketAddr) -> PathBuf {
+      match path.to_str() {
+         Some(template) => return PathBuf::from(variables::expand(template, peer)),
+         None => return path.to_path_buf()
+      }
+   }
+
+   /// Filenames are bytes on Unix, they can be served whatever their encoding
+   #[cfg(unix)]
+   fn filename_from_bytes(filename: Vec<u8>) -> PathBuf {
+      use std::os::unix::ffi::OsStringExt;
+      return PathBuf::from(std::ffi::OsString::from_vec(filename));
+   }
+
+   /// Elsewhere filenames are Unicode, bytes which are not UTF-8 cannot match a file
+   #[cfg(not(unix))]
+   fn filename_from_bytes(filename: Vec<u8>) -> PathBuf {
+      return PathBuf::from(String::from_utf8_lossy(&filename).into_owned());
+   }
+
+   /// With auto_decompress, the decoder of <name>.gz when the requested file does not exist
+   fn open_gzip_fallback(filename: &Path, server_options: &ServerOptions) -> Option<GzipFile> {
+      if !server_options.auto_decompress || lookup_filename(filename, server_options).ok()?.exists() {
+         return None;
+      }
+      let mut compressed = filename.as_os_str().to_owned();
+      compressed.push(".gz");
+      let path = lookup_filename(Path::new(&compressed), server_options).ok()?;
+      regular_file_size(&path, server_options).ok()?;
+      let (root, inside) = split_root(&path, server_options);
+      match GzipFile::open(root, inside, symlinks(server_options)) {
+         Ok(gzip) => {
+            debug!("Serving {} decompressed", path.display());
+            return Some(gzip);
+         }
+         Err(e) => {
+            warn!("Cannot open {}: {}", path.display(), e);
+            return None;
+         }
+      }
+   }
+
+   /// Named pipe read in order when the requested file is one, instead of the regular file reads
+   fn open_stream(filename: &Path, server_options: &ServerOptions) -> Option<Stream> {
+      let path = lookup_filename(filename, server_options).ok()?;
+      let (root, inside) = split_root(&path, server_options);
+      match stream::open_fifo(root, inside, symlinks(server_options)) {
+         Ok(Some(stream)) => {
+            debug!("Serving the pipe {} in order", path.display());
+            return Some(stream);
+         }
+         Ok(None) => return None,
+         Err(e) => {
+            warn!("Cannot open the pipe {}: {}", path.display(), e);
+            return None;
+         }
+      }
+   }
+
+   /// A RRQ of this file would fail with FileNotFound, not with AccessViolation
+   fn is_missing(filename: &Path, server_options: &ServerOptions) -> bool {
+      match lookup_filename(filename, server_options) {
+         Ok(path) => return !path.exists(),
+         Err(e) => return e == TftpError::FileNotFound
+      }
+   }
+
+   /// Fallback file read instead of the missing requested one, the requested name is kept for the logs
+   pub fn fallback_file(context: &OpContext) -> Option<&Path> {
+      return context.fallback.as_deref();
+   }
+
+   /// File read by a RRQ: the requested one or the fallback
+   fn source_filename(context: &OpContext) -> &Path {
+      return fallback_file(context).unwrap_or(&context.filename);
+   }
+
+   /// Size of the served content: generated, decompressed or the file. None when unknown: always for
+   /// a pipe, for a .gz until measure_source counted it or its last block was read
+   fn source_size(context: &OpContext) -> Result<Option<u64>, TftpError> {
+      if let Some(content) = &context.content {
+         return Ok(Some(content.len() as u64));
+      }
+      if context.stream.is_some() {
+         return Ok(None);
+      }
+      if let Some(gzip) = &context.gzip {
+         let gzip = gzip.lock().unwrap_or_else(|e| e.into_inner());
+         return gzip.known_size().map_err(|e| corrupt_gzip(&gzip, e));
+      }
+      let path = lookup_filename(source_filename(context), &context.server_options)?;
+      let (f, size) = open_regular_file(&path, &context.server_options)?;
+      check_unchanged(&f, &path, &context.identity)?;
+      return Ok(Some(size));
+   }
+
+   /// Decompressed size of a .gz read with tsize or offset, which need it before the first block: the whole
+   /// file is decompressed, by a blocking thread rather than by the transfer task. Other reads only learn
+   /// it once the last block is read, a failure is reported by the first reply
+   pub async fn measure_source(context: &OpContext) {
+      let (None, Some(gzip)) = (&context.content, &context.gzip) else {
+         return;
+      };
+      if context.options.offset == 0 && !context.oack.iter().any(|(name, _)| name == "tsize") {
+         return;
+      }
+      let gzip = gzip.clone();
+      let _ = tokio::task::spawn_blocking(move || gzip.lock().unwrap_or_else(|e| e.into_inner()).size()).await;
+   }
+
+   /// Size, modification time and inode of a served file, the file is reopened for each chunk read
+   /// so a file replaced or rewritten during a RRQ would mix two contents
+   #[derive(Debug, Clone, PartialEq)]
+   struct FileIdentity {
+      len : u64,
+      modified : Option<SystemTime>,
+      inode : Option<(u64, u64)>  // device and inode (Unix)
+   }
+
+   impl FileIdentity {
+      fn of(metadata: &Metadata) -> FileIdentity {
+         #[cfg(unix)]
+         let inode = {
+            use std::os::unix::fs::MetadataExt;
+            Some((metadata.dev(), metadata.ino()))
+         };
+         #[cfg(not(unix))]
+         let inode = None;
+         return FileIdentity { len: metadata.len(), modified: metadata.modified().ok(), inode };
+      }
+   }
+
+   /// Record the identity of the opened file at the first open of the transfer, compare it at the next ones
+   fn check_unchanged(f: &File, path: &Path, identity: &Mutex<Option<FileIdentity>>) -> Result<(), TftpError> {
+      let current = match f.metadata() {
+         Ok(metadata) => FileIdentity::of(&metadata),
+         Err(_) => return Err(TftpError::AccessViolation)
+      };
+      let mut identity = identity.lock().unwrap_or_else(|e| e.into_inner());
+      match &*identity {
+         None => *identity = Some(current),
+         Some(first) if *first == current => (),
+         Some(first) => {
+            warn!("{} changed during the transfer ({} bytes, now {}), aborted", path.display(), first.len, current.len);
+            return Err(TftpError::NotDefined("file changed during transfer".to_string()));
+         }
+      }
+      return Ok(());
+   }
+
+   fn corrupt_gzip(gzip: &GzipFile, error: std::io::Error) -> TftpError {
+      warn!("Cannot decompress {}: {}", gzip.path().display(), error);
+      return TftpError::NotDefined("Cannot decompress the file".to_string());
+   }
+
+   /// DATA packet of the block at index, from the source of the context. The index starts at 1 and does
+   /// not wrap like the block numbers. Generated and decompressed content is sent as is whatever the mode
+   fn data_reply(context: &OpContext, index: u64) -> Option<Command> {
+      if let (None, Some(stream)) = (&context.content, &context.stream) {
+         return prepare_stream_reply(&context.filename, &mut stream.lock().unwrap_or_else(|e| e.into_inner()), index as u16, context.options.blksize);
+      }
+      if let (None, Some(gzip)) = (&context.content, &context.gzip) {
+         return prepare_gzip_reply(&mut gzip.lock().unwrap_or_else(|e| e.into_inner()), index, context.options.blksize, context.options.offset);
+      }
+      if let (None, Some(netascii)) = (&context.content, &context.netascii) {
+         return prepare_netascii_reply(context, &mut netascii.lock().unwrap_or_else(|e| e.into_inner()), index as u16);
+      }
+      return prepare_data_reply(context, index);
+   }
+
+   /// With a named pipe, read the block of the next DATA before get_reply builds it, waiting for the
+   /// producer in the transfer task: a silent producer holds neither a thread of the runtime nor the lock
+   pub async fn fill_stream(context: &OpContext) {
+      let (None, Some(stream)) = (&context.content, &context.stream) else {
+         return;
+      };
+      // Refused before any block is sent
+      let index = match &context.current_op {
+         Command::RRQ{..} if context.oack.is_empty() && context.options.offset == 0
+            && check_filters(&context.filename, &context.server_options).is_ok() => 1,
+         Command::ACK{..} => context.acked + 1,
+         _ => return
+      };
+      stream::fill(stream, index as u16, context.options.blksize as usize).await;
+   }
+
+   /// DATA packet whose payload is sent from the file rather than built in memory (zero_copy)
+   #[derive(Debug, Clone)]
+   pub struct FileData {
+      pub blocknum : u16,
+      pub file : Arc<File>,
+      pub offset : u64,     // of the block in the file
+      pub len : usize       // of the block, shorter than blksize for the last one
+   }
+
+   impl FileData {
+      /// Opcode and block number, the payload follows
+      pub fn header(&self) -> [u8; 4] {
+         let mut header = [0; 4];
+         header[..2].copy_from_slice(&(Opcode::DATA as u16).to_be_bytes());
+         header[2..].copy_from_slice(&self.blocknum.to_be_bytes());
+         return header;
+      }
+
+      /// The DATA packet read in memory
+      pub fn to_command(&self) -> Command {
+         let block = match zero_copy::read_block(&self.file, self.offset, self.len) {
+            Ok(block) => block,
+            Err(e) => {
+               warn!("Cannot read block {}: {}", self.blocknum, e);
+               return TftpError::NotDefined("Cannot read the file".to_string()).to_command();
+            }
+         };
+         let mut data = BytesMut::with_capacity(block.len() + 4);
+         data.put_slice(&self.header());
+         data.put_slice(&block);
+         return Command::DATA{blocknum: self.blocknum, data: data.freeze()};
+      }
+   }
+
+   /// What the server sends next, see get_reply
+   #[derive(Debug, Clone)]
+   pub enum Reply {
+      Packet(Command),
+      FileData(FileData)
+   }
+
+   impl Reply {
+      /// The packet in memory, a FileData is read
+      pub fn to_command(&self) -> Command {
+         match self {
+            Reply::Packet(command) => return command.clone(),
+            Reply::FileData(file_data) => return file_data.to_command()
+         }
+      }
+   }
+
+   /// Same as get_reply_command, but with zero_copy the DATA of a file in octet mode are FileData,
+   /// sent from the file by the server. Only on Linux, the first DATA of a RRQ without options is
+   /// built in memory as it comes with the checks of the request.
+   pub fn get_reply(context: &OpContext) -> Option<Reply> {
+      let from_file = context.server_options.zero_copy && cfg!(target_os = "linux")
+         && context.content.is_none() && context.gzip.is_none() && context.stream.is_none() && context.netascii.is_none();
+      if let (true, Command::ACK{..}) = (from_file, &context.current_op) {
+         return prepare_file_data_reply(context, context.acked + 1);
+      }
+      return get_reply_command(context).map(Reply::Packet);
+   }
+
+   pub fn get_reply_command(context: &OpContext) -> Option<Command> {
+      match &context.current_op {
          Command::RRQ { .. } => {
-            return Some(prepare_data_reply(context.filename, 1, context.mode));
+            // Refuse before the OACK
+            if let Some(e) = &context.refused {
+               return Some(e.to_command());
+            }
+            if let Err(e) = check_filters(&context.filename, &context.server_options) {
+               return Some(e.to_command());
+            }
+            // A pipe has no size and is read from its start
+            if context.stream.is_some() && context.options.offset != 0 {
+               return Some(TftpError::NotDefined("The file is a pipe, it cannot be resumed".to_string()).to_command());
+            }
+            // Unknown for a .gz read from its start without tsize, its blocks end where the content does
+            match source_size(context) {
+               Ok(Some(size)) if context.options.offset > size => {
+                  return Some(TftpError::NotDefined("Offset beyond the end of the file".to_string()).to_command());
+               }
+               Ok(_) => (),
+               Err(e) => return Some(e.to_command())
+            }
+            if !context.oack.is_empty() {
+               return Some(prepare_oack_reply(context));
+            }
+            return data_reply(context, 1);
          },
          Command::WRQ { .. } => {
+            // Refuse before the client sends any data
+            if let Some(e) = &context.refused {
+               return Some(e.to_command());
+            }
+            if let Err(e) = check_filters(&context.filename, &context.server_options) {
+               return Some(e.to_command());
+            }
+            if context.server_options.no_create {
+               match sanitize_filename(&context.filename) {
+                  Ok(path) if context.server_options.root.join(&path).is_file() => (),
+                  Ok(_) => return Some(TftpError::FileNotFound.to_command()),
+                  Err(e) => return Some(e.to_command())
+               }
+            }
+            if !context.oack.is_empty() {
+               return Some(prepare_oack_reply(context));
+            }
             return Some(Command::ACK{blocknum:0});
          },
-         Command::ACK {blocknum} => {
-            return Some(prepare_data_reply(context.filename, blocknum+1, context.mode));
+         Command::ACK { .. } => {
+            return data_reply(context, context.acked + 1);
          },
          Command::DATA{blocknum, data} => {
-            return Some(prepare_ack_reply(context.filename, blocknum, context.mode, data));
+            return Some(prepare_ack_reply(context, *blocknum, data));
          },
-         _ => {
-            println!("Not Implemented");
-            return None;
+         // Set by recv on a protocol violation, sent to the client to end the transfer
+         Command::ERROR { .. } => {
+            return Some(context.current_op.clone());
+         },
+         // Only sent by the server
+         Command::OACK { .. } => {
+            return Some(TftpError::IllegalOperation.to_command());
          }
       }
       
    }
 
-   fn prepare_ack_reply(filename :String, blocknum: u16, mode: String, data: Vec<u8>) -> Command {
-      // Todo manage error
-      println!("OPENING FILE: FileName: {} (len:{}), Mode: {}(len:{}), block:{} ",filename,filename.len(), mode, mode.len(), blocknum);
-      //let mut f = OpenOptions::new().write(true).create(true).open(filename).unwrap();
-      let mut f : File;
+   /// OACK of the accepted options, tsize of a RRQ is answered with the file size
+   fn prepare_oack_reply(context: &OpContext) -> Command {
+      let mut options = context.oack.clone();
+      if matches!(context.current_op, Command::RRQ{..}) {
+         for (name, value) in options.iter_mut() {
+            if name == "tsize" {
+               match get_transfer_size(context) {
+                  Some(size) => *value = size.to_string(),
+                  None => return TftpError::FileNotFound.to_command()
+               }
+            }
+         }
+      }
+      return Command::OACK{options};
+   }
 
-      if blocknum == 1 {
-         f = File::create(filename).unwrap();
+   /// Bytes sent by a RRQ, from the offset to the end of the file, None when unknown (WRQ)
+   pub fn get_transfer_size(context: &OpContext) -> Option<u64> {
+      match context.current_op {
+         Command::RRQ { .. } => {
+            return Some(source_size(context).ok()??.saturating_sub(context.options.offset));
+         },
+         _ => return None
+      }
+   }
+
+   /// Confine a requested filename to the served directory (chroot or not):
+   /// leading '/' are ignored and components going up the tree are refused
+   pub fn sanitize_filename(filename: &Path) -> Result<PathBuf, TftpError> {
+      let mut path = PathBuf::new();
+      for component in filename.components() {
+         match component {
+            Component::Normal(part) => path.push(part),
+            Component::RootDir | Component::CurDir => (),
+            // ParentDir, or a Windows drive prefix
+            _ => return Err(TftpError::AccessViolation)
+         }
+      }
+      if path.as_os_str().is_empty() {
+         return Err(TftpError::FileNotFound);
+      }
+      return Ok(path);
+   }
+
+   /// Longest requested filename without ServerOptions::max_filename_len, in bytes
+   pub const MAX_FILENAME_LEN: usize = 255;
+
+   /// Refusal of a requested filename as received, before any rewriting or file access: longer than the
+   /// maximum (illegal operation), with control characters (malformed), with non-ASCII bytes under
+   /// ascii_filenames (access violation). The codec does not know the configured maximum, the length is
+   /// checked here right after parsing rather than in parse_filename_mode.
+   pub fn check_filename(filename: &[u8], server_options: &ServerOptions) -> Result<(), TftpError> {
+      let max_len = server_options.max_filename_len.unwrap_or(MAX_FILENAME_LEN);
+      let (reason, error) = if filename.len() > max_len {
+         (format!("longer than {} bytes", max_len), TftpError::IllegalOperation)
+      } else if filename.iter().any(|byte| byte.is_ascii_control()) {
+         ("with control characters".to_string(), TftpError::MalformedPacket)
+      } else if server_options.ascii_filenames && !filename.is_ascii() {
+         ("not ASCII (--ascii-filenames)".to_string(), TftpError::AccessViolation)
       } else {
-         f = OpenOptions::new().write(true).create(true).open(filename).unwrap();
-         let blknum64 = blocknum as u64; //safe upsizing for below multiplication
-         f.seek(SeekFrom::Start((blknum64-1)*512)).unwrap();
+         return Ok(());
+      };
+      info!("Filename {} refused, {}", escape_filename(filename), reason);
+      return Err(error);
+   }
+
+   /// Filename quoted for the logs: control characters, `"`, `\` and bytes which are not UTF-8 are escaped as `\xNN`
+   pub fn escape_filename(filename: &[u8]) -> String {
+      let mut escaped = String::from("\"");
+      for chunk in filename.utf8_chunks() {
+         for c in chunk.valid().chars() {
+            match c {
+               '"' | '\\' => {
+                  escaped.push('\\');
+                  escaped.push(c);
+               }
+               c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+               c => escaped.push(c)
+            }
+         }
+         for byte in chunk.invalid() {
+            escaped.push_str(&format!("\\x{:02x}", byte));
+         }
       }
+      escaped.push('"');
+      return escaped;
+   }
 
-      f.write(&data).unwrap();
-      
-      // Todo Handle write error and respond Command:ERROR if so
-      
+   /// Marker in the names of the temporary files of the server, `.NAME.tftp-tmp.SUFFIX`
+   pub const TEMP_FILE_MARKER: &str = ".tftp-tmp.";
 
-      return Command::ACK{blocknum: blocknum};
+   /// A temporary file of the server, never served even with serve_hidden
+   pub fn is_temp_file(name: &std::ffi::OsStr) -> bool {
+      let name = name.as_encoded_bytes();
+      let marker = TEMP_FILE_MARKER.as_bytes();
+      return name.starts_with(b".") && name.windows(marker.len()).any(|window| window == marker);
    }
 
-   fn prepare_data_reply(filename :String, blocknum: u16, mode: String) -> Command {
-      // Todo manage error
-      println!("OPENING FILE: FileName: {} (len:{}), Mode: {}(len:{}), block:{} ",filename,filename.len(), mode, mode.len(), blocknum);
-      let mut f = File::open(filename).unwrap();
-      let blknum64 = blocknum as u64; //safe upsizing for below multiplication
-      f.seek(SeekFrom::Start((blknum64-1)*512)).unwrap();
-      // TFTP Protocol define a max size of 512 bytes.
-      // First two bytes is the u16 chuck num
-      let writer = vec![0;516];
-      let mut cursor_writer = Cursor::new(writer);
-      // TODO SEE HOW TO DERIVE 3 from Opnum::DATA
-      cursor_writer.write_u16::<BigEndian>(3).unwrap();
-      cursor_writer.write_u16::<BigEndian>(blocknum).unwrap();
-      // Todo manage error 
-      let sz = f.read(&mut cursor_writer.get_mut()[4..]).unwrap();
+   /// Temporary file of an atomic upload of path, in the same directory: `.NAME.tftp-tmp.PID-TRANSFER`
+   pub fn temp_upload_path(path: &Path, transfer_id: u64) -> PathBuf {
+      let mut name = std::ffi::OsString::from(".");
+      name.push(path.file_name().unwrap_or_default());
+      name.push(format!("{}{}-{}", TEMP_FILE_MARKER, std::process::id(), transfer_id));
+      return path.with_file_name(name);
+   }
 
-      return Command::DATA{blocknum: blocknum, data: cursor_writer.get_ref()[0..sz+4].to_vec()}
+   /// Temporary file written by a WRQ with atomic_uploads, None for the other transfers
+   pub fn temp_upload(context: &OpContext) -> Option<PathBuf> {
+      if !context.server_options.atomic_uploads || !matches!(context.current_op, Command::WRQ{..} | Command::DATA{..}) {
+         return None;
+      }
+      let path = sanitize_filename(&context.filename).ok()?;
+      return Some(context.server_options.root.join(temp_upload_path(&path, context.transfer_id)));
    }
 
-   pub fn get_buffer_for_command(command: Command) -> Option<Vec<u8>> {
-      match command {
-         Command::DATA {blocknum: _, data} => {
-            return Some(data);
+   /// Advisory lock of the file of a RRQ (shared) or WRQ (exclusive), None when there is no file to lock:
+   /// generated or decompressed content, a pipe, a missing file. Fails with ErrorKind::WouldBlock when
+   /// another transfer or process holds a conflicting lock
+   pub fn lock_file(context: &OpContext) -> std::io::Result<Option<FileLock>> {
+      let (path, mode) = match &context.current_op {
+         Command::RRQ{..} if context.content.is_none() && context.gzip.is_none() && context.stream.is_none() => {
+            match lookup_filename(source_filename(context), &context.server_options) {
+               Ok(path) => (path, LockMode::Shared),
+               Err(_) => return Ok(None)
+            }
          },
-         Command::ACK {blocknum} => {
-            // u16 to two Big Endian bytes
-            let beblocknum = blocknum.to_be_bytes();
-            // Todo use enum ACK value and be_bytes
-            let result=vec![0,4,beblocknum[0],beblocknum[1]];
-            return Some(result);
-         }
-
-         _ => {return None;}
-      }
-   }
-
-   pub fn recv(buf: &[u8], size: usize, prev_ctx: Option<OpContext>) -> Option<OpContext> {
-      let recv_cmd = process_buffer(buf,size);
-      match prev_ctx{
-         Some(ctx) => {
-            // Allow Continuation of RRQ, other cases return None/NO-OP
-            match recv_cmd {
-               Command::ACK{ blocknum } | Command::DATA{blocknum, data:_} => {
-                  match ctx.current_op {
-                     Command::RRQ{..} | Command::ACK{..} | Command::WRQ{..}| Command::DATA{..} => {
-                        print!("ACK/DATA {} Post RRQ/WRQ", blocknum);
-                        let mut new_ctx = ctx;
-                        new_ctx.ack_num = blocknum;
-                        // TODO Need to only change current op on new base commands WRQ/RRQ
-                        new_ctx.current_op = recv_cmd;
-                        return Some(new_ctx);
-                     }
-                     _ => {print!("Orphan ACK, ignore"); return None;}
+         Command::WRQ{..} => match sanitize_filename(&context.filename) {
+            Ok(path) => (context.server_options.root.join(path), LockMode::Exclusive),
+            Err(_) => return Ok(None)
+         },
+         _ => return Ok(None)
+      };
+      let (root, inside) = split_root(&path, &context.server_options);
+      let f = match beneath::open_in(root, inside, Access::Read, symlinks(&context.server_options)) {
+         Ok(f) => f,
+         Err(_) => return Ok(None)
+      };
+      return file_lock::try_lock(f, mode).map(Some);
+   }
+
+   /// ERROR of a request for a file another transfer is reading or writing
+   pub fn file_busy() -> Command {
+      return Command::ERROR{ errorcode: TftpError::AccessViolation.error_code(), errmsg: "File is busy".to_string() };
+   }
+
+   /// Refusal of a requested path by name, before any lookup: a temporary file of the server in any
+   /// component, or a hidden component (`.git`, `.config.swp`) unless serve_hidden.
+   /// A name refused by sanitize_filename is left to fail its lookup.
+   pub fn check_filters(filename: &Path, server_options: &ServerOptions) -> Result<(), TftpError> {
+      let Ok(path) = sanitize_filename(filename) else {
+         return Ok(());
+      };
+      for component in path.iter() {
+         if is_temp_file(component) {
+            info!("{} refused, temporary file of the server", filename.display());
+            return Err(TftpError::AccessViolation);
+         }
+         if !server_options.serve_hidden && component.as_encoded_bytes().starts_with(b".") {
+            info!("{} refused, hidden file (--serve-hidden)", filename.display());
+            return Err(TftpError::AccessViolation);
+         }
+      }
+      return Ok(());
+   }
+
+   /// Path of the file read by a RRQ, in the served directory or else in the first of the extra roots
+   /// holding it. With ignore_case, when the exact path does not exist,
+   /// each missing component is looked up case-insensitively in its directory,
+   /// several candidates for the same component are refused.
+   pub fn lookup_filename(filename: &Path, server_options: &ServerOptions) -> Result<PathBuf, TftpError> {
+      let path = sanitize_filename(filename)?;
+      let found = lookup_in(&server_options.root, &path, filename, server_options);
+      if server_options.extra_roots.is_empty() || found.as_ref().is_ok_and(|found| found.exists()) {
+         return found;
+      }
+      for root in &server_options.extra_roots {
+         match lookup_in(root, &path, filename, server_options) {
+            Ok(other) if other.exists() => return Ok(other),
+            _ => continue
+         }
+      }
+      // Missing everywhere, failing as in the served directory
+      return found;
+   }
+
+   /// lookup_filename of a sanitized path inside a single root
+   fn lookup_in(root: &Path, path: &Path, filename: &Path, server_options: &ServerOptions) -> Result<PathBuf, TftpError> {
+      let exact = root.join(path);
+      if !server_options.ignore_case || exact.exists() {
+         return Ok(exact);
+      }
+      let mut resolved = root.to_path_buf();
+      for component in path.iter() {
+         let exact = resolved.join(component);
+         if exact.exists() {
+            resolved = exact;
+            continue;
+         }
+         let wanted = component.to_string_lossy().to_lowercase();
+         let dir = if resolved.as_os_str().is_empty() { Path::new(".") } else { resolved.as_path() };
+         let entries = std::fs::read_dir(dir).map_err(|_| TftpError::FileNotFound)?;
+         let candidates: Vec<_> = entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name())
+            .filter(|name| name.to_string_lossy().to_lowercase() == wanted)
+            .collect();
+         match candidates.as_slice() {
+            [name] => resolved.push(name),
+            [] => return Err(TftpError::FileNotFound),
+            _ => {
+               warn!("{} matches {} entries of {} ignoring case, refused", filename.display(), candidates.len(), dir.display());
+               return Err(TftpError::FileNotFound);
+            }
+         }
+      }
+      debug!("{} found as {}", filename.display(), resolved.display());
+      return Ok(resolved);
+   }
+
+   /// Root of a path found by lookup_filename and the path inside it, the working directory is empty
+   fn split_root<'a>(path: &'a Path, server_options: &'a ServerOptions) -> (&'a Path, &'a Path) {
+      // Requests are relative once sanitized, only the roots are absolute
+      for root in &server_options.extra_roots {
+         if let Ok(inside) = path.strip_prefix(root) {
+            return (root, inside);
+         }
+      }
+      return (&server_options.root, path.strip_prefix(&server_options.root).unwrap_or(path));
+   }
+
+   fn symlinks(server_options: &ServerOptions) -> Symlinks {
+      match server_options.no_symlinks {
+         true => return Symlinks::Refused,
+         false => return Symlinks::Inside
+      }
+   }
+
+   /// Open a requested file, through the single choke point confining it to its served directory
+   fn open_beneath(root: &Path, path: &Path, access: Access, server_options: &ServerOptions) -> Result<File, TftpError> {
+      match beneath::open_in(root, path, access, symlinks(server_options)) {
+         Ok(f) => return Ok(f),
+         Err(e) if e.kind() == ErrorKind::NotFound => return Err(TftpError::FileNotFound),
+         // Creating an upload without a free inode
+         Err(e) if from_write_error(&e) == TftpError::DiskFull => return Err(TftpError::DiskFull),
+         Err(_) => return Err(TftpError::AccessViolation)
+      }
+   }
+
+   /// TFTP error of a failed write of an upload, a full disk or an exceeded quota is DiskFull
+   pub fn from_write_error(error: &std::io::Error) -> TftpError {
+      match error.kind() {
+         ErrorKind::StorageFull | ErrorKind::QuotaExceeded => return TftpError::DiskFull,
+         _ => return TftpError::NotDefined("Cannot write the file".to_string())
+      }
+   }
+
+   /// File that can be read and its size, directories and special files (devices) are refused,
+   /// named pipes are read through open_stream
+   fn open_regular_file(path: &Path, server_options: &ServerOptions) -> Result<(File, u64), TftpError> {
+      let (root, inside) = split_root(path, server_options);
+      let f = open_beneath(root, inside, Access::Read, server_options)?;
+      match f.metadata() {
+         Ok(metadata) if metadata.is_file() => return Ok((f, metadata.len())),
+         Ok(_) => {
+            warn!("{} is not a regular file", path.display());
+            return Err(TftpError::AccessViolation);
+         }
+         Err(_) => return Err(TftpError::AccessViolation)
+      }
+   }
+
+   fn regular_file_size(path: &Path, server_options: &ServerOptions) -> Result<u64, TftpError> {
+      return open_regular_file(path, server_options).map(|(_, size)| size);
+   }
+
+   fn prepare_ack_reply(context: &OpContext, blocknum: u16, data: &[u8]) -> Command {
+      let (filename, mode, options, blksize) = (&context.filename, &context.mode, &context.server_options, context.options.blksize);
+      let root = &options.root;
+      // Duplicate or out of order, nothing written
+      if context.reack {
+         return Command::ACK{blocknum};
+      }
+      let first = context.written == 1;
+      let path = match sanitize_filename(filename) {
+         Ok(path) => path,
+         Err(e) => return e.to_command()
+      };
+      trace!("OPENING FILE: FileName: {} (len:{}), Mode: {}(len:{}), block:{} ",filename.display(),filename.as_os_str().len(), mode, mode.len(), blocknum);
+      // An atomic upload writes beside the file, which is only replaced by the last block
+      let written = match options.atomic_uploads {
+         true => temp_upload_path(&path, context.transfer_id),
+         false => path.clone()
+      };
+      // With no_create the file may have been removed since the WRQ
+      if first && options.atomic_uploads && options.no_create && !root.join(&path).is_file() {
+         return TftpError::FileNotFound.to_command();
+      }
+      let create = !options.no_create || options.atomic_uploads;
+      let mode = options.upload_mode.unwrap_or(0o666);
+      if first && options.create_upload_dirs && !options.no_create {
+         if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            // Searchable where the files are readable: 0o660 gives 0o770
+            let dir_mode = options.upload_mode.map(|mode| mode | (mode & 0o444) >> 2);
+            if let Err(e) = beneath::create_dirs_in(root, dir, dir_mode, symlinks(options)) {
+               warn!("Cannot create the directory {}: {}", dir.display(), e);
+               return TftpError::AccessViolation.to_command();
+            }
+         }
+      }
+      // The blocks come in sequence, each one goes after the previous one
+      let access = match (first, &context.netascii) {
+         (true, _) => Access::Truncate { create, mode },
+         (false, Some(_)) => Access::Update { create, mode },
+         (false, None) => Access::Append { create, mode }
+      };
+      // Uploads always go to the served directory
+      let mut f = match open_beneath(root, &written, access, options) {
+         Ok(f) => f,
+         Err(e) => return e.to_command()
+      };
+      // The creation mode goes through the umask and an existing file keeps its own
+      #[cfg(unix)]
+      if let (true, Some(mode)) = (first, options.upload_mode) {
+         use std::os::unix::fs::PermissionsExt;
+         if let Err(e) = f.set_permissions(std::fs::Permissions::from_mode(mode)) {
+            warn!("Cannot set mode {:o} on {}: {}", mode, path.display(), e);
+         }
+      }
+      if let Some(netascii) = &context.netascii {
+         let mut netascii = netascii.lock().unwrap_or_else(|e| e.into_inner());
+         match netascii.write_block(&mut f, blocknum, data, data.len() < blksize as usize) {
+            Ok(()) => (),
+            Err(e) if e.kind() == ErrorKind::InvalidInput => {
+               warn!("{} for {}, aborting transfer", e, path.display());
+               return TftpError::IllegalOperation.to_command();
+            }
+            Err(e) => {
+               warn!("Cannot write {}: {}", path.display(), e);
+               return from_write_error(&e).to_command();
+            }
+         }
+      } else {
+         if let Err(e) = f.write_all(data) {
+            warn!("Cannot write {}: {}", path.display(), e);
+            return from_write_error(&e).to_command();
+         }
+      }
+
+      // Once complete: the file is reopened for each block, which the new owner may not allow
+      #[cfg(unix)]
+      if let (true, Some((uid, gid))) = (data.len() < blksize as usize, options.upload_owner) {
+         if let Err(e) = std::os::unix::fs::fchown(&f, Some(uid), Some(gid)) {
+            warn!("Cannot change the owner of {} to {}:{}, kept: {}", path.display(), uid, gid, e);
+         }
+      }
+
+      if options.atomic_uploads && data.len() < blksize as usize {
+         // A replaced file keeps its mode, as when written in place
+         if let (None, Ok(metadata)) = (options.upload_mode, root.join(&path).metadata()) {
+            if let Err(e) = f.set_permissions(metadata.permissions()) {
+               warn!("Cannot keep the mode of {}: {}", path.display(), e);
+            }
+         }
+         drop(f);
+         if let Err(e) = std::fs::rename(root.join(&written), root.join(&path)) {
+            warn!("Cannot rename {} to {}: {}", written.display(), path.display(), e);
+            let _ = std::fs::remove_file(root.join(&written));
+            return from_write_error(&e).to_command();
+         }
+         debug!("Upload complete, renamed to {}", path.display());
+      }
+
+      return Command::ACK{blocknum};
+   }
+
+   /// DATA packet for the block at index, block 1 starting at the start byte of the file,
+   /// None once the client acknowledged the last block
+   fn prepare_data_reply(context: &OpContext, index: u64) -> Option<Command> {
+      let (filename, content, options, start) = (source_filename(context), context.content.as_deref(), &context.server_options, context.options.offset);
+      let blksize = context.options.blksize as usize;
+      let blocknum = index as u16;
+      let offset = start + index.checked_sub(1)? * blksize as u64;
+      if let Some(content) = content {
+         // Same end of transfer rule as for files below
+         let block = match usize::try_from(offset).ok().and_then(|offset| content.get(offset..)) {
+            Some(rest) => &rest[..rest.len().min(blksize)],
+            None if index > 1 => return None,
+            None => return Some(TftpError::NotDefined("Offset beyond the end of the file".to_string()).to_command())
+         };
+         let mut data = BytesMut::with_capacity(block.len() + 4);
+         data.put_u16(Opcode::DATA as u16);
+         data.put_u16(blocknum);
+         data.put_slice(block);
+         return Some(Command::DATA{blocknum, data: data.freeze()});
+      }
+      let mut read_ahead = context.read_ahead.lock().unwrap_or_else(|e| e.into_inner());
+      // The file is only opened to read the next chunk
+      if !read_ahead.holds(offset, blksize) {
+         let path = match lookup_filename(filename, options) {
+            Ok(path) => path,
+            Err(e) => return Some(e.to_command())
+         };
+         trace!("OPENING FILE: FileName: {} (len:{}), block:{} ",filename.display(),filename.as_os_str().len(), blocknum);
+         let (mut f, _) = match open_regular_file(&path, options) {
+            Ok(opened) => opened,
+            Err(e) => return Some(e.to_command())
+         };
+         if let Err(e) = check_unchanged(&f, &path, &context.identity) {
+            return Some(e.to_command());
+         }
+         if let Err(e) = read_ahead.fill(&mut f, offset) {
+            warn!("Cannot read {}: {}", path.display(), e);
+            return Some(TftpError::NotDefined("Cannot read the file".to_string()).to_command());
+         }
+      }
+      // The last block is the first one shorter than the negotiated blksize, so an empty file
+      // or a file size multiple of blksize ends with an empty block, which is sent
+      let block = match read_ahead.block(offset, blksize) {
+         Some(block) => block,
+         None if index > 1 => return None,
+         None => &[]
+      };
+      // First two bytes is the u16 chuck num
+      let mut data = BytesMut::with_capacity(block.len() + 4);
+      data.put_u16(Opcode::DATA as u16);
+      data.put_u16(blocknum);
+      data.put_slice(block);
+      return Some(Command::DATA{blocknum, data: data.freeze()});
+   }
+
+   /// Same as prepare_data_reply, with the block left in the file
+   fn prepare_file_data_reply(context: &OpContext, index: u64) -> Option<Reply> {
+      let (options, blksize) = (&context.server_options, context.options.blksize as usize);
+      let blocknum = index as u16;
+      let offset = context.options.offset + index.checked_sub(1)? * blksize as u64;
+      let mut window = context.file_window.lock().unwrap_or_else(|e| e.into_inner());
+      if !window.holds(offset) {
+         let path = match lookup_filename(source_filename(context), options) {
+            Ok(path) => path,
+            Err(e) => return Some(Reply::Packet(e.to_command()))
+         };
+         let (f, size) = match open_regular_file(&path, options) {
+            Ok(opened) => opened,
+            Err(e) => return Some(Reply::Packet(e.to_command()))
+         };
+         if let Err(e) = check_unchanged(&f, &path, &context.identity) {
+            return Some(Reply::Packet(e.to_command()));
+         }
+         window.open(f, size, offset);
+      }
+      let (file, len) = match window.block(offset, blksize) {
+         Some(block) => block,
+         None if index > 1 => return None,
+         None => return Some(Reply::Packet(TftpError::NotDefined("Offset beyond the end of the file".to_string()).to_command()))
+      };
+      return Some(Reply::FileData(FileData{blocknum, file, offset, len}));
+   }
+
+   /// DATA packet for blocknum in netascii mode, from the position of the previous block
+   fn prepare_netascii_reply(context: &OpContext, netascii: &mut Netascii, blocknum: u16) -> Option<Command> {
+      let (options, blksize) = (&context.server_options, context.options.blksize);
+      let path = match lookup_filename(source_filename(context), options) {
+         Ok(path) => path,
+         Err(e) => return Some(e.to_command())
+      };
+      let (mut f, _) = match open_regular_file(&path, options) {
+         Ok(opened) => opened,
+         Err(e) => return Some(e.to_command())
+      };
+      if let Err(e) = check_unchanged(&f, &path, &context.identity) {
+         return Some(e.to_command());
+      }
+      let mut data = BytesMut::zeroed(blksize as usize + 4);
+      data[..2].copy_from_slice(&(Opcode::DATA as u16).to_be_bytes());
+      data[2..4].copy_from_slice(&blocknum.to_be_bytes());
+      match netascii.read_block(&mut f, blocknum, &mut data[4..]) {
+         Ok(Some(filled)) => data.truncate(filled + 4),
+         Ok(None) => return None,
+         Err(e) => {
+            warn!("Cannot read {}: {}", path.display(), e);
+            return Some(TftpError::NotDefined("Cannot read the file".to_string()).to_command());
+         }
+      }
+      return Some(Command::DATA{blocknum, data: data.freeze()});
+   }
+
+   /// DATA packet for the block at index of a decompressed file, same end of transfer rule as for files
+   fn prepare_gzip_reply(gzip: &mut GzipFile, index: u64, blksize: u16, start: u64) -> Option<Command> {
+      let blocknum = index as u16;
+      let offset = start + index.checked_sub(1)? * blksize as u64;
+      // Known once the short block was read, an empty block follows a full last one
+      let size = match gzip.known_size() {
+         Ok(size) => size,
+         Err(e) => return Some(corrupt_gzip(gzip, e).to_command())
+      };
+      if index > 1 && size.is_some_and(|size| offset > size) {
+         return None;
+      }
+      let mut data = BytesMut::zeroed(blksize as usize + 4);
+      data[..2].copy_from_slice(&(Opcode::DATA as u16).to_be_bytes());
+      data[2..4].copy_from_slice(&blocknum.to_be_bytes());
+      match gzip.read_at(offset, &mut data[4..]) {
+         Ok(read) => data.truncate(read + 4),
+         Err(e) => return Some(corrupt_gzip(gzip, e).to_command())
+      }
+      return Some(Command::DATA{blocknum, data: data.freeze()});
+   }
+
+   /// DATA packet for blocknum of a pipe, only the next block or the last one again can be sent
+   fn prepare_stream_reply(filename: &Path, stream: &mut Stream, blocknum: u16, blksize: u16) -> Option<Command> {
+      let block = match stream.block(blocknum, blksize as usize) {
+         Ok(Some(block)) => block,
+         Ok(None) => return None,
+         Err(e) if e.kind() == ErrorKind::Unsupported => {
+            warn!("{}: {}", filename.display(), e);
+            return Some(TftpError::NotDefined(format!("Cannot send {}", e)).to_command());
+         }
+         Err(e) => {
+            warn!("Cannot read {}: {}", filename.display(), e);
+            return Some(TftpError::NotDefined("Cannot read the file".to_string()).to_command());
+         }
+      };
+      let mut data = BytesMut::with_capacity(block.len() + 4);
+      data.put_u16(Opcode::DATA as u16);
+      data.put_u16(blocknum);
+      data.put_slice(block);
+      return Some(Command::DATA{blocknum, data: data.freeze()});
+   }
+
+   /// Remove the file of an interrupted upload, once a block was written to it.
+   /// With atomic_uploads it is the temporary file, the requested one is left as it was
+   pub fn remove_partial_upload(context: &OpContext) {
+      if !matches!(context.current_op, Command::DATA{..}) {
+         return;
+      }
+      let in_root = |path: PathBuf| context.server_options.root.join(path);
+      if let Some(path) = temp_upload(context).or_else(|| sanitize_filename(&context.filename).ok().map(in_root)) {
+         match std::fs::remove_file(&path) {
+            Ok(()) => debug!("Removed partial upload {}", path.display()),
+            Err(e) => warn!("Cannot remove partial upload {}: {}", path.display(), e)
+         }
+      }
+   }
+
+   /// What the transfer does after a packet from the client, its context being updated in place
+   #[derive(Debug, PartialEq)]
+   pub enum Action {
+      Reply,                     // get_reply_command gives the answer
+      ClientError(TftpError),    // ERROR sent by the client, the transfer stops
+      Abort,                     // nothing to answer in this state, the transfer stops
+      Ignore                     // empty datagram or stray ACK, the context is unchanged and nothing is sent
+   }
+
+   pub fn recv(context: &mut OpContext, buf: &[u8]) -> Action {
+      if buf.is_empty() {
+         debug!("Empty datagram from {}, ignored", context.peer);
+         return Action::Ignore;
+      }
+      // The payload of a DATA is copied: at most the negotiated block size and a byte, which tells a larger one
+      let buf = match buf.starts_with(&(Opcode::DATA as u16).to_be_bytes()) {
+         true => buf.get(..context.options.blksize as usize + 5).unwrap_or(buf),
+         false => buf
+      };
+      return handle_command(context, parse_packet(buf, context.server_options.strictness));
+   }
+
+   /// Same as recv, the payload of a DATA is kept as a slice of the packet rather than copied
+   pub fn recv_packet(context: &mut OpContext, packet: &Bytes) -> Action {
+      if packet.is_empty() {
+         debug!("Empty datagram from {}, ignored", context.peer);
+         return Action::Ignore;
+      }
+      return handle_command(context, process_packet(packet, context.server_options.strictness));
+   }
+
+   fn handle_command(context: &mut OpContext, recv_cmd: Command) -> Action {
+      match recv_cmd {
+         Command::ACK{ blocknum } | Command::DATA{blocknum, data:_} => {
+            // A read transfer only expects ACK from the client, a write one only DATA
+            let expected = match (&context.current_op, &recv_cmd) {
+               (Command::RRQ{..} | Command::ACK{..}, Command::ACK{..}) => true,
+               (Command::WRQ{..} | Command::DATA{..}, Command::DATA{..}) => true,
+               (Command::RRQ{..} | Command::ACK{..} | Command::WRQ{..} | Command::DATA{..}, _) => false,
+               _ => {debug!("Orphan ACK, ignore"); return Action::Abort;}
+            };
+            if !expected {
+               warn!("Unexpected {:?} block {} for {}, aborting transfer", recv_cmd, blocknum, context.filename.display());
+               context.current_op = TftpError::IllegalOperation.to_command();
+               return Action::Reply;
+            }
+            // A block is never longer than the negotiated size, a client sending one does not follow the protocol
+            if let Command::DATA{data, ..} = &recv_cmd {
+               if data.len() > context.options.blksize as usize {
+                  // Its payload may have been cut by recv, the size is not logged
+                  warn!("DATA block {} for {} larger than the block size {}, aborting transfer",
+                        blocknum, context.filename.display(), context.options.blksize);
+                  context.current_op = TftpError::MalformedPacket.to_command();
+                  return Action::Reply;
+               }
+               // Only the block after the last written one is written, anything else would leave a hole
+               // or overwrite written data: the last written block is acknowledged again instead
+               let last = match context.current_op {
+                  Command::DATA{blocknum, ..} => blocknum,
+                  _ => 0
+               };
+               if blocknum != last.wrapping_add(1) {
+                  context.reack = true;
+                  // Its ACK was lost
+                  if blocknum == last {
+                     return Action::Reply;
+                  }
+                  context.out_of_order += 1;
+                  // Quirk of lenient mode, strict mode aborts at the first one
+                  let max_out_of_order = match context.server_options.strictness {
+                     Strictness::Strict => Some(0),
+                     Strictness::Lenient => context.server_options.max_out_of_order
+                  };
+                  if max_out_of_order.is_some_and(|max| context.out_of_order > max) {
+                     warn!("{} DATA blocks out of order for {}, aborting transfer", context.out_of_order, context.filename.display());
+                     context.current_op = TftpError::MalformedPacket.to_command();
+                     return Action::Reply;
                   }
-               },
-               Command::ERROR{errorcode, errmsg} => {
-                  eprint!("Aborting command, received from client error {} with message {}",errorcode,errmsg);
-                  return None;
-               },
-               // Other commands create new context (RRQ/WRQ)
-               _ => {return build_new_context(recv_cmd);}
+                  debug!("DATA block {} for {} while expecting {}, block {} acknowledged again",
+                         blocknum, context.filename.display(), last.wrapping_add(1), last);
+                  return Action::Reply;
+               }
+               context.reack = false;
+               context.out_of_order = 0;
+               context.written += 1;
             }
+            // The highest block sent, or an earlier one: an ACK ahead of it cannot be for this transfer
+            if let Command::ACK{..} = recv_cmd {
+               let sent = context.sent;
+               match sent.checked_sub((sent as u16).wrapping_sub(blocknum) as u64) {
+                  Some(acked) => {
+                     context.acked = acked;
+                     context.sent = sent.max(acked + 1);
+                  }
+                  None => {
+                     debug!("ACK of block {} for {} not sent yet, ignored", blocknum, context.filename.display());
+                     return Action::Ignore;
+                  }
+               }
+            }
+            trace!("ACK/DATA {} Post RRQ/WRQ", blocknum);
+            context.ack_num = blocknum;
+            context.current_op = recv_cmd;
+            return Action::Reply;
+         },
+         Command::ERROR{errorcode, errmsg} => {
+            let error = get_client_error(errorcode, errmsg);
+            warn!("{}", get_client_error_message(&error));
+            return Action::ClientError(error);
          },
-         // No Previous operations, create new for required commands, ignore orphans ones
-         None => return build_new_context(recv_cmd)
+         // A new request (RRQ/WRQ) replaces the transfer
+         _ => {
+            match build_new_context(recv_cmd, context.peer, &context.server_options) {
+               Some(new_context) => {
+                  *context = new_context;
+                  return Action::Reply;
+               }
+               None => return Action::Abort
+            }
+         }
       }
    }
-      
-   pub fn process_buffer(buf: &[u8], _size: usize) -> Command {
-      let mut reader = Cursor::new(buf);
-      // Todo, handle Errors without panic!
-      let opcode = Opcode::try_from(reader.read_u16::<BigEndian>().unwrap()).unwrap();
-      return parse_command(opcode, &mut reader);
+
+   /// New transfer for a RRQ/WRQ received by the server from peer, options negotiated within its limits
+   pub fn recv_request(buf: &[u8], size: usize, peer: SocketAddr, server_options: &ServerOptions) -> Option<OpContext> {
+      // Not a request, nothing to answer
+      let packet = match buf.get(..size) {
+         Some(packet) if !packet.is_empty() => packet,
+         _ => return None
+      };
+      return build_new_context(parse_packet(packet, server_options.strictness), peer, server_options);
    }
 
+   /// ERROR answering a RRQ/WRQ for which recv_request gave no transfer, in strict mode only:
+   /// otherwise, like any packet outside of a transfer, it is ignored
+   pub fn refused_request(buf: &[u8], server_options: &ServerOptions) -> Option<Command> {
+      let request = buf.starts_with(&(Opcode::RRQ as u16).to_be_bytes()) || buf.starts_with(&(Opcode::WRQ as u16).to_be_bytes());
+      if server_options.strictness != Strictness::Strict || !request {
+         return None;
+      }
+      match parse_packet(buf, Strictness::Strict) {
+         error @ Command::ERROR{..} => return Some(error),
+         _ => return None
+      }
+   }
+      
 }
 
 #[cfg(test)]
 mod test {
-    use crate::tftpprotocol::*;
+    use crate::tftp::tftpprotocol::*;
+    use crate::remap::{Remap, RemapRule};
+    use bytes::Bytes;
     use std::matches;
+    use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+    use std::path::{Path, PathBuf};
+
+    const PEER: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 42), 2001));
+
+    /// Request as received, then looked up like by the transfer task
+    fn resolved(buf: &[u8], peer: SocketAddr, options: &ServerOptions) -> OpContext {
+       let mut ctx = recv_request(buf, buf.len(), peer, options).unwrap();
+       resolve_request(&mut ctx);
+       return ctx;
+    }
     
+    #[test]
+    fn empty_datagram_ignored() {
+       assert!(recv_request(&[], 0, PEER, &ServerOptions::default()).is_none());
+       let request = rrq("tests/fixtures/files/hello.txt");
+       let mut ctx = recv_request(&request, request.len(), PEER, &ServerOptions::default()).unwrap();
+       let before = format!("{:?}", ctx);
+       assert_eq!(recv(&mut ctx, &[]), Action::Ignore);
+       assert_eq!(recv_packet(&mut ctx, &Bytes::new()), Action::Ignore);
+       assert_eq!(format!("{:?}", ctx), before);
+    }
+
     #[test]
     fn recv_rrq() {
         // 0 1 in big endian + Filename + 0 + mode + 0
         let rrq: [u8; 18] = [0, 1, b'f',b'i',b'l',b'e',b'n',b'm',
                              0, b'n',b'e',b't',b'a',b's',b'c',b'i',b'i',0];
         match process_buffer(&rrq,18) {
-           Command::RRQ{ filename, mode } => {
+           Command::RRQ{ filename, mode, .. } => {
               // Got good command, check parsing is OK
-              assert_eq!(filename,"filenm");
+              assert_eq!(filename,b"filenm");
               assert_eq!(mode,"netascii");
            }
            _ => { panic!("RECV with 0 1 optype must return RRQ command");}
@@ -284,15 +1275,34 @@ mod test {
         let wrq: [u8; 18] = [0, 2, b'f',b'i',b'l',b'e',b'n',b'm',
                              0, b'n',b'e',b't',b'a',b's',b'c',b'i',b'i',0];
         match process_buffer(&wrq,18) {
-           Command::WRQ{ filename, mode } => {
+           Command::WRQ{ filename, mode, .. } => {
               // Got good command, check parsing is OK
-              assert_eq!(filename,"filenm");
+              assert_eq!(filename,b"filenm");
               assert_eq!(mode,"netascii");
            }
            _ => { panic!("RECV with 0 2 optype must return WRQ command");}
         }
     }
 
+    #[test]
+    fn rrq_trailing_garbage() {
+       let rrq = b"\x00\x01pxelinux.0\x00octet\x00x";
+       match process_buffer(rrq, rrq.len()) {
+          Command::RRQ{ filename, mode, options } => {
+             assert_eq!(filename, b"pxelinux.0");
+             assert_eq!(mode, "octet");
+             assert!(options.is_empty());
+          }
+          other => { panic!("RRQ with a trailing byte must still be a RRQ, got {:?}", other);}
+       }
+       // Complete pairs before the fragment are kept, a name without value is not
+       let rrq = b"\x00\x01pxelinux.0\x00octet\x00blksize\x001024\x00tsize\x00tim";
+       match process_buffer(rrq, rrq.len()) {
+          Command::RRQ{ options, .. } => assert_eq!(options, [("blksize".to_string(), "1024".to_string())]),
+          other => { panic!("RRQ with a trailing fragment must still be a RRQ, got {:?}", other);}
+       }
+    }
+
     #[test]
     fn recv_ack() {
       // 0 4 in big endian + 2 bytes ACK number in Big Endian
@@ -320,6 +1330,37 @@ mod test {
        }
       }
 
+     #[test]
+     fn recv_error_not_defined_keeps_message() {
+       // 0 5 in big endian + error code 0 + client message
+       let mut error: Vec<u8> = vec![0, 5, 0, 0];
+       error.extend_from_slice(b"custom reason");
+       error.push(0);
+       match process_buffer(&error, error.len()) {
+          Command::ERROR{ errorcode, errmsg } => {
+             let client_error = get_client_error(errorcode, errmsg);
+             assert_eq!(client_error, TftpError::NotDefined("custom reason".to_string()));
+             assert!(get_client_error_message(&client_error).contains("custom reason"));
+          }
+          _ => { panic!("ERROR with code 0 was not correctly parsed");}
+       }
+      }
+
+      #[test]
+      fn recv_data_packet() {
+         // Payload shares the packet memory
+         let packet = Bytes::from_static(&[0, 3, 0, 7, b'a', b'b']);
+         match process_packet(&packet, Strictness::Lenient) {
+            Command::DATA{ blocknum, data } => {
+               assert_eq!(blocknum, 7);
+               assert_eq!(data, &b"ab"[..]);
+               assert_eq!(data.as_ptr(), packet[4..].as_ptr());
+            }
+            other => { panic!("DATA packet was not parsed as DATA, got {:?}", other);}
+         }
+         assert!(matches!(process_packet(&Bytes::from_static(&[0, 4, 0, 7]), Strictness::Lenient), Command::ACK{ blocknum: 7 }));
+      }
+
       #[test]
       fn recv_data() {
          // 0 3 in big endian + 2 bytes Block number in Big Endian + Data
@@ -328,17 +1369,992 @@ mod test {
             Command::DATA{ blocknum, data} => {
                // Got good command, check parsing is OK
                assert_eq!(blocknum,0xabcd);
-               assert_eq!(data,[b'a',b'b',b'c',b'd',b'!']);
+               assert_eq!(data,&[b'a',b'b',b'c',b'd',b'!'][..]);
             }
             _ => { panic!("DATA with blknum abcd +  data \"abcd!\" was not correctly parsed");}
          }
         }     
 
     #[test]
-    fn recv_invalid() {
-       // Invalid Opcode
-       let invalid: [u8; 3] = [9,9,9];
-       assert!(matches!(process_buffer(&invalid, 3), Command::ERROR{..}));
+    fn sanitize_filename_stays_in_root() {
+       assert_eq!(sanitize_filename(Path::new("pxelinux.0")), Ok(PathBuf::from("pxelinux.0")));
+       assert_eq!(sanitize_filename(Path::new("/boot/./kernel")), Ok(PathBuf::from("boot/kernel")));
+       assert_eq!(sanitize_filename(Path::new("../etc/passwd")), Err(TftpError::AccessViolation));
+       assert_eq!(sanitize_filename(Path::new("boot/../../etc/passwd")), Err(TftpError::AccessViolation));
+       assert_eq!(sanitize_filename(Path::new("/")), Err(TftpError::FileNotFound));
+    }
+
+    fn rrq(filename: &str) -> Vec<u8> {
+       let mut rrq = vec![0, 1];
+       rrq.extend_from_slice(filename.as_bytes());
+       rrq.extend_from_slice(b"\0octet\0");
+       return rrq;
+    }
+
+    #[test]
+    fn rrq_empty_file() {
+       let rrq = rrq("tests/fixtures/files/empty.bin");
+       let mut ctx = recv_request(&rrq, rrq.len(), PEER, &ServerOptions::default()).unwrap();
+       // A single DATA block with only the header
+       match get_reply_command(&ctx) {
+          Some(Command::DATA{ blocknum, data }) => {
+             assert_eq!(blocknum, 1);
+             assert_eq!(data, &[0, 3, 0, 1][..]);
+          }
+          other => { panic!("RRQ of an empty file must reply an empty DATA block, got {:?}", other);}
+       }
+       // ACK of this block ends the transfer
+       assert_eq!(recv(&mut ctx, &[0, 4, 0, 1]), Action::Reply);
+       assert!(get_reply_command(&ctx).is_none());
+    }
+
+    #[test]
+    fn rrq_block_size_multiple() {
+       // 512 bytes file, a full block then an empty one
+       let rrq = rrq("tests/fixtures/files/block.bin");
+       let mut ctx = recv_request(&rrq, rrq.len(), PEER, &ServerOptions::default()).unwrap();
+       assert!(matches!(get_reply_command(&ctx), Some(Command::DATA{ blocknum: 1, ref data }) if data.len() == 516));
+       assert_eq!(recv(&mut ctx, &[0, 4, 0, 1]), Action::Reply);
+       assert!(matches!(get_reply_command(&ctx), Some(Command::DATA{ blocknum: 2, ref data }) if data.len() == 4));
+       assert_eq!(recv(&mut ctx, &[0, 4, 0, 2]), Action::Reply);
+       assert!(get_reply_command(&ctx).is_none());
+    }
+
+    #[test]
+    fn rrq_short_block_of_negotiated_size() {
+       let filename = format!("target/tftp-blksize-{}.bin", std::process::id());
+       let content: Vec<u8> = (0..1500).map(|i| (i % 251) as u8).collect();
+       std::fs::write(&filename, &content).unwrap();
+       let mut rrq = rrq(&filename);
+       rrq.extend_from_slice(b"blksize\x001024\x00");
+       let mut ctx = recv_request(&rrq, rrq.len(), PEER, &ServerOptions::default()).unwrap();
+       assert!(matches!(get_reply_command(&ctx), Some(Command::OACK{..})));
+       assert_eq!(recv(&mut ctx, &[0, 4, 0, 0]), Action::Reply);
+       assert!(matches!(get_reply_command(&ctx), Some(Command::DATA{ blocknum: 1, ref data }) if data[4..] == content[..1024]));
+       assert_eq!(recv(&mut ctx, &[0, 4, 0, 1]), Action::Reply);
+       // 476 bytes, shorter than 1024 although longer than 512: the last one
+       assert!(matches!(get_reply_command(&ctx), Some(Command::DATA{ blocknum: 2, ref data }) if data[4..] == content[1024..]));
+       assert_eq!(recv(&mut ctx, &[0, 4, 0, 2]), Action::Reply);
+       assert!(get_reply_command(&ctx).is_none());
+       std::fs::remove_file(&filename).unwrap();
+    }
+
+    #[test]
+    fn rrq_across_read_ahead_chunks() {
+       let filename = format!("target/tftp-read-ahead-{}.bin", std::process::id());
+       let content: Vec<u8> = (0..crate::read_ahead::CHUNK_SIZE * 3 + 100).map(|i| (i % 253) as u8).collect();
+       std::fs::write(&filename, &content).unwrap();
+       let rrq = rrq(&filename);
+       let mut ctx = recv_request(&rrq, rrq.len(), PEER, &ServerOptions::default()).unwrap();
+       let mut received = Vec::new();
+       let mut blocknum: u16 = 1;
+       loop {
+          let data = match get_reply_command(&ctx) {
+             Some(Command::DATA{ blocknum: number, data }) if number == blocknum => data,
+             other => panic!("expected block {}, got {:?}", blocknum, other)
+          };
+          received.extend_from_slice(&data[4..]);
+          // The block before the first refill is sent again once past it
+          if blocknum == 130 {
+             assert_eq!(recv(&mut ctx, &[0, 4, 0, 127]), Action::Reply);
+             assert!(matches!(get_reply_command(&ctx), Some(Command::DATA{ blocknum: 128, data }) if data[4..] == content[127 * 512..128 * 512]));
+          }
+          assert_eq!(recv(&mut ctx, &[0, 4, (blocknum >> 8) as u8, blocknum as u8]), Action::Reply);
+          if data.len() < 516 {
+             break;
+          }
+          blocknum += 1;
+       }
+       assert!(get_reply_command(&ctx).is_none());
+       assert_eq!(received, content);
+       std::fs::remove_file(&filename).unwrap();
+    }
+
+    #[test]
+    fn rrq_ack_of_a_block_not_sent() {
+       let rrq = rrq("tests/fixtures/files/block.bin");
+       let mut ctx = recv_request(&rrq, rrq.len(), PEER, &ServerOptions::default()).unwrap();
+       // Block 65535 before the numbers wrapped around, never sent: nothing is answered
+       assert_eq!(recv(&mut ctx, &[0, 4, 0xff, 0xff]), Action::Ignore);
+       assert!(matches!(get_reply_command(&ctx), Some(Command::DATA{ blocknum: 1, ref data }) if data.len() == 516));
+       assert_eq!(recv(&mut ctx, &[0, 4, 0, 1]), Action::Reply);
+       assert_eq!(recv(&mut ctx, &[0, 4, 0, 3]), Action::Ignore);
+       assert!(matches!(get_reply_command(&ctx), Some(Command::DATA{ blocknum: 2, ref data }) if data.len() == 4));
+    }
+
+    #[test]
+    fn generated_content_out_of_range() {
+       let rrq = rrq("whoami.txt");
+       let mut ctx = recv_request(&rrq, rrq.len(), PEER, &ServerOptions::default()).unwrap();
+       ctx.content = Some(std::sync::Arc::new(b"1.2.3.4".to_vec()));
+       assert!(matches!(get_reply_command(&ctx), Some(Command::DATA{ blocknum: 1, ref data }) if data[4..] == b"1.2.3.4"[..]));
+       // The start of the next block is past the end
+       assert_eq!(recv(&mut ctx, &[0, 4, 0, 1]), Action::Reply);
+       assert_eq!(get_reply_command(&ctx), None);
+       // The first block starting past the end, not refused with the request
+       assert_eq!(recv(&mut ctx, &[0, 4, 0, 0]), Action::Reply);
+       ctx.options.offset = 8;
+       assert!(matches!(get_reply_command(&ctx), Some(Command::ERROR{ errorcode: 0, .. })));
+    }
+
+    #[test]
+    fn rrq_block_numbers_wrap_around() {
+       // More than 65535 blocks of 8 bytes
+       let filename = format!("target/tftp-wrap-{}.bin", std::process::id());
+       let content: Vec<u8> = (0..70_000 * 8 + 3).map(|i| (i % 249) as u8).collect();
+       std::fs::write(&filename, &content).unwrap();
+       let mut rrq = rrq(&filename);
+       rrq.extend_from_slice(b"blksize\x008\x00");
+       let mut ctx = recv_request(&rrq, rrq.len(), PEER, &ServerOptions::default()).unwrap();
+       assert!(matches!(get_reply_command(&ctx), Some(Command::OACK{..})));
+       assert_eq!(recv(&mut ctx, &[0, 4, 0, 0]), Action::Reply);
+       let mut received = Vec::new();
+       for index in 1u64.. {
+          let data = match get_reply_command(&ctx) {
+             Some(Command::DATA{ blocknum, data }) if blocknum == index as u16 => data,
+             other => panic!("expected block {}, got {:?}", index, other)
+          };
+          received.extend_from_slice(&data[4..]);
+          // ACK with the block number of the DATA
+          assert_eq!(recv(&mut ctx, &[&[0, 4], &data[2..4]].concat()), Action::Reply);
+          if data.len() < 12 {
+             assert_eq!(index, 70_001);
+             break;
+          }
+       }
+       assert!(get_reply_command(&ctx).is_none());
+       assert_eq!(received, content);
+       std::fs::remove_file(&filename).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn rrq_blocks_left_in_the_file() {
+       let filename = format!("target/tftp-file-data-{}.bin", std::process::id());
+       std::fs::write(&filename, vec![9; 1300]).unwrap();
+       let options = ServerOptions { zero_copy: true, ..ServerOptions::default() };
+       let rrq = rrq(&filename);
+       let mut ctx = recv_request(&rrq, rrq.len(), PEER, &options).unwrap();
+       // Built with the checks of the request
+       assert!(matches!(get_reply(&ctx), Some(Reply::Packet(Command::DATA{blocknum: 1, ..}))));
+       for (blocknum, len) in [(2, 512), (3, 276)] {
+          assert_eq!(recv(&mut ctx, &[0, 4, 0, blocknum as u8 - 1]), Action::Reply);
+          let Some(Reply::FileData(file_data)) = get_reply(&ctx) else { panic!("block {} in memory", blocknum) };
+          assert_eq!((file_data.blocknum, file_data.offset, file_data.len), (blocknum, (blocknum as u64 - 1) * 512, len));
+          assert_eq!(file_data.to_command(), get_reply_command(&ctx).unwrap());
+       }
+       assert_eq!(recv(&mut ctx, &[0, 4, 0, 3]), Action::Reply);
+       assert!(get_reply(&ctx).is_none());
+       std::fs::remove_file(&filename).unwrap();
+    }
+
+    #[test]
+    fn rrq_file_changed() {
+       let filename = format!("target/tftp-changed-{}.bin", std::process::id());
+       std::fs::write(&filename, vec![1; crate::read_ahead::CHUNK_SIZE + 1000]).unwrap();
+       let rrq = rrq(&filename);
+       let mut ctx = recv_request(&rrq, rrq.len(), PEER, &ServerOptions::default()).unwrap();
+       for blocknum in 1..=128u16 {
+          assert!(matches!(get_reply_command(&ctx), Some(Command::DATA{ blocknum: number, .. }) if number == blocknum));
+          assert_eq!(recv(&mut ctx, &[&[0, 4][..], &blocknum.to_be_bytes()].concat()), Action::Reply);
+       }
+       // Truncated while the first chunk was sent, noticed when reading the next one
+       std::fs::File::options().write(true).open(&filename).unwrap().set_len(100).unwrap();
+       let error = get_reply_command(&ctx).unwrap();
+       std::fs::remove_file(&filename).unwrap();
+       assert!(matches!(error, Command::ERROR{ errorcode: 0, ref errmsg } if errmsg == "file changed during transfer"), "{:?}", error);
+    }
+
+    #[test]
+    fn blksize_limited_after_negotiation() {
+       let mut rrq = rrq("tests/fixtures/files/multiblock.bin");
+       rrq.extend_from_slice(b"blksize\x009000\x00");
+       let mut ctx = recv_request(&rrq, rrq.len(), PEER, &ServerOptions::default()).unwrap();
+       assert_eq!(ctx.options.blksize, 9000);
+       limit_blksize(&mut ctx, 1468);
+       assert_eq!(ctx.options.blksize, 1468);
+       assert!(matches!(get_reply_command(&ctx), Some(Command::OACK{ ref options }) if options[..] == [("blksize".to_string(), "1468".to_string())]));
+       // Never raised
+       limit_blksize(&mut ctx, 8000);
+       assert_eq!(ctx.options.blksize, 1468);
+       // Still a power of two under the path MTU
+       let mut server_options = ServerOptions::default();
+       server_options.limits.blksize_power_of_two = true;
+       let mut ctx = recv_request(&rrq, rrq.len(), PEER, &server_options).unwrap();
+       assert_eq!(ctx.options.blksize, 8192);
+       limit_blksize(&mut ctx, 1468);
+       assert!(matches!(get_reply_command(&ctx), Some(Command::OACK{ ref options }) if options[..] == [("blksize".to_string(), "1024".to_string())]));
+    }
+
+    #[test]
+    fn rrq_directory() {
+       let rrq = rrq("tests/fixtures/files");
+       let ctx = recv_request(&rrq, rrq.len(), PEER, &ServerOptions::default()).unwrap();
+       assert!(matches!(get_reply_command(&ctx), Some(Command::ERROR{ errorcode: 2, .. })));
+       // Refused before the OACK too
+       let mut rrq = rrq;
+       rrq.extend_from_slice(b"tsize\x000\x00");
+       let ctx = recv_request(&rrq, rrq.len(), PEER, &ServerOptions::default()).unwrap();
+       assert!(matches!(get_reply_command(&ctx), Some(Command::ERROR{ errorcode: 2, .. })));
+    }
+
+    #[test]
+    fn rrq_with_options() {
+       // 1300 bytes file read with 1024 bytes blocks
+       let mut rrq = rrq("tests/fixtures/files/multiblock.bin");
+       rrq.extend_from_slice(b"blksize\x001024\x00tsize\x000\x00unknown\x00x\x00");
+       let mut ctx = recv_request(&rrq, rrq.len(), PEER, &ServerOptions::default()).unwrap();
+       match get_reply_command(&ctx) {
+          Some(Command::OACK{ options }) => {
+             assert_eq!(options, [("blksize".to_string(), "1024".to_string()), ("tsize".to_string(), "1300".to_string())]);
+          }
+          other => { panic!("RRQ with options must reply an OACK, got {:?}", other);}
+       }
+       let buffer = get_buffer_for_command(Command::OACK{ options: vec![("blksize".to_string(), "1024".to_string())] }).unwrap();
+       assert_eq!(buffer, &b"\x00\x06blksize\x001024\x00"[..]);
+       // ACK 0 of the OACK starts the transfer
+       assert_eq!(recv(&mut ctx, &[0, 4, 0, 0]), Action::Reply);
+       assert!(matches!(get_reply_command(&ctx), Some(Command::DATA{ blocknum: 1, ref data }) if data.len() == 1028));
+       assert_eq!(recv(&mut ctx, &[0, 4, 0, 1]), Action::Reply);
+       assert!(matches!(get_reply_command(&ctx), Some(Command::DATA{ blocknum: 2, ref data }) if data.len() == 280));
+    }
+
+    #[test]
+    fn rrq_with_offset() {
+       let file = std::fs::read("tests/fixtures/files/multiblock.bin").unwrap();
+       let mut rrq = rrq("tests/fixtures/files/multiblock.bin");
+       rrq.extend_from_slice(b"offset\x00700\x00tsize\x000\x00");
+       let mut ctx = recv_request(&rrq, rrq.len(), PEER, &ServerOptions::default()).unwrap();
+       match get_reply_command(&ctx) {
+          Some(Command::OACK{ options }) => {
+             assert_eq!(options, [("offset".to_string(), "700".to_string()), ("tsize".to_string(), "600".to_string())]);
+          }
+          other => { panic!("RRQ with an offset must reply an OACK, got {:?}", other);}
+       }
+       assert_eq!(recv(&mut ctx, &[0, 4, 0, 0]), Action::Reply);
+       match get_reply_command(&ctx) {
+          Some(Command::DATA{ blocknum: 1, data }) => assert_eq!(&data[4..], &file[700..1212]),
+          other => { panic!("First block must start at the offset, got {:?}", other);}
+       }
+       assert_eq!(recv(&mut ctx, &[0, 4, 0, 1]), Action::Reply);
+       assert!(matches!(get_reply_command(&ctx), Some(Command::DATA{ blocknum: 2, ref data }) if data[4..] == file[1212..]));
+       // Past the end of the file
+       let mut rrq = self::rrq("tests/fixtures/files/multiblock.bin");
+       rrq.extend_from_slice(b"offset\x001301\x00");
+       let ctx = recv_request(&rrq, rrq.len(), PEER, &ServerOptions::default()).unwrap();
+       assert!(matches!(get_reply_command(&ctx), Some(Command::ERROR{ errorcode: 0, .. })));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn rrq_of_named_pipe() {
+       use std::io::Write;
+       let fifo = "target/tftp-pipe.fifo";
+       let _ = std::fs::remove_file(fifo);
+       let path = std::ffi::CString::new(fifo).unwrap();
+       // SAFETY: mkfifo with a valid C string
+       assert_eq!(unsafe { libc::mkfifo(path.as_ptr(), 0o600) }, 0);
+       // Opened read-write it does not wait for a reader (Linux)
+       let mut producer = std::fs::File::options().read(true).write(true).open(fifo).unwrap();
+       let content: Vec<u8> = (0..700).map(|i| (i % 251) as u8).collect();
+       producer.write_all(&content).unwrap();
+
+       let mut rrq = rrq(fifo);
+       rrq.extend_from_slice(b"tsize\x000\x00blksize\x00512\x00");
+       let mut ctx = resolved(&rrq, PEER, &ServerOptions::default());
+       // No size to answer
+       assert_eq!(get_reply_command(&ctx), Some(Command::OACK{ options: vec![("blksize".to_string(), "512".to_string())] }));
+       assert_eq!(get_transfer_size(&ctx), None);
+       drop(producer);
+       assert_eq!(recv(&mut ctx, &[0, 4, 0, 0]), Action::Reply);
+       fill_stream(&ctx).await;
+       assert!(matches!(get_reply_command(&ctx), Some(Command::DATA{ blocknum: 1, ref data }) if data[4..] == content[..512]));
+       // A duplicate ACK gets the block again
+       assert!(matches!(get_reply_command(&ctx), Some(Command::DATA{ blocknum: 1, ref data }) if data[4..] == content[..512]));
+       assert_eq!(recv(&mut ctx, &[0, 4, 0, 1]), Action::Reply);
+       fill_stream(&ctx).await;
+       assert!(matches!(get_reply_command(&ctx), Some(Command::DATA{ blocknum: 2, ref data }) if data[4..] == content[512..]));
+       // Going further back
+       assert_eq!(recv(&mut ctx, &[0, 4, 0, 0]), Action::Reply);
+       assert!(matches!(get_reply_command(&ctx), Some(Command::ERROR{ errorcode: 0, .. })));
+       assert_eq!(recv(&mut ctx, &[0, 4, 0, 2]), Action::Reply);
+       fill_stream(&ctx).await;
+       assert_eq!(get_reply_command(&ctx), None);
+
+       // Cannot be resumed
+       let _producer = std::fs::File::options().read(true).write(true).open(fifo).unwrap();
+       let mut rrq = self::rrq(fifo);
+       rrq.extend_from_slice(b"offset\x00512\x00");
+       let ctx = resolved(&rrq, PEER, &ServerOptions::default());
+       assert!(matches!(get_reply_command(&ctx), Some(Command::ERROR{ errorcode: 0, .. })));
+    }
+
+    #[tokio::test]
+    async fn rrq_of_gzipped_file() {
+       let original: Vec<u8> = (0..1300u32).map(|i| (i * 7 % 253) as u8).collect();
+       let options = ServerOptions { auto_decompress: true, ..ServerOptions::default() };
+       let mut rrq = rrq("tests/fixtures/files/compressed.bin");
+       rrq.extend_from_slice(b"tsize\x000\x00");
+       let mut ctx = resolved(&rrq, PEER, &options);
+       measure_source(&ctx).await;
+       match get_reply_command(&ctx) {
+          Some(Command::OACK{ options }) => assert_eq!(options, [("tsize".to_string(), "1300".to_string())]),
+          other => { panic!("tsize of a gzipped file must be its decompressed size, got {:?}", other);}
+       }
+       assert_eq!(recv(&mut ctx, &[0, 4, 0, 0]), Action::Reply);
+       let mut received = Vec::new();
+       for block in 1u16..=3 {
+          let Some(Command::DATA{ blocknum, data }) = get_reply_command(&ctx) else { panic!("Block {} missing", block) };
+          assert_eq!(blocknum, block);
+          // A retransmission sends the same block again
+          assert!(matches!(get_reply_command(&ctx), Some(Command::DATA{ data: ref again, .. }) if *again == data));
+          received.extend_from_slice(&data[4..]);
+          assert_eq!(recv(&mut ctx, &[&[0, 4][..], &block.to_be_bytes()].concat()), Action::Reply);
+       }
+       assert_eq!(received, original);
+       assert!(get_reply_command(&ctx).is_none());
+       // Without the option the compressed file is not used
+       let rrq = self::rrq("tests/fixtures/files/compressed.bin");
+       let ctx = resolved(&rrq, PEER, &ServerOptions::default());
+       assert!(matches!(get_reply_command(&ctx), Some(Command::ERROR{ errorcode: 1, .. })));
+    }
+
+    /// Without tsize nor offset the .gz is not decompressed ahead, the end is found by the last block
+    #[tokio::test]
+    async fn rrq_of_gzipped_file_unmeasured() {
+       let original: Vec<u8> = (0..1300u32).map(|i| (i * 7 % 253) as u8).collect();
+       let options = ServerOptions { auto_decompress: true, ..ServerOptions::default() };
+       let mut ctx = resolved(&rrq("tests/fixtures/files/compressed.bin"), PEER, &options);
+       measure_source(&ctx).await;
+       assert_eq!(get_transfer_size(&ctx), None);
+       let mut received = Vec::new();
+       for block in 1u16..=3 {
+          let Some(Command::DATA{ blocknum, data }) = get_reply_command(&ctx) else { panic!("Block {} missing", block) };
+          assert_eq!(blocknum, block);
+          received.extend_from_slice(&data[4..]);
+          assert_eq!(recv(&mut ctx, &[&[0, 4][..], &block.to_be_bytes()].concat()), Action::Reply);
+       }
+       assert_eq!(received, original);
+       assert!(get_reply_command(&ctx).is_none());
+       // Measured for an offset, which cannot go beyond the end
+       let mut rrq = rrq("tests/fixtures/files/compressed.bin");
+       rrq.extend_from_slice(b"offset\x001301\x00");
+       let ctx = resolved(&rrq, PEER, &options);
+       measure_source(&ctx).await;
+       assert!(matches!(get_reply_command(&ctx), Some(Command::ERROR{ errorcode: 0, .. })));
+    }
+
+    /// The CR LF of the newline ending byte 511 is cut by the end of block 1
+    #[test]
+    fn netascii_blocks() {
+       let dir = "target/tftp-netascii";
+       std::fs::create_dir_all(dir).unwrap();
+       let mut original = vec![b'a'; 511];
+       original.extend_from_slice(b"\nbare\rcr\n");
+       original.extend_from_slice(&b"line\n".repeat(120));
+       let filename = format!("{}/lines.txt", dir);
+       std::fs::write(&filename, &original).unwrap();
+       let mut expected = Vec::new();
+       for &byte in &original {
+          match byte {
+             b'\n' => expected.extend_from_slice(b"\r\n"),
+             b'\r' => expected.extend_from_slice(b"\r\0"),
+             _ => expected.push(byte)
+          }
+       }
+
+       let mut rrq = vec![0, 1];
+       rrq.extend_from_slice(filename.as_bytes());
+       rrq.extend_from_slice(b"\0NETASCII\0");
+       let mut ctx = recv_request(&rrq, rrq.len(), PEER, &ServerOptions::default()).unwrap();
+       let mut blocks = Vec::new();
+       for block in 1u16.. {
+          let Some(Command::DATA{ blocknum, data }) = get_reply_command(&ctx) else { panic!("Block {} missing", block) };
+          assert_eq!(blocknum, block);
+          assert!(matches!(get_reply_command(&ctx), Some(Command::DATA{ data: ref again, .. }) if *again == data));
+          blocks.push(data[4..].to_vec());
+          assert_eq!(recv(&mut ctx, &[&[0, 4][..], &block.to_be_bytes()].concat()), Action::Reply);
+          if data.len() < 516 {
+             break;
+          }
+       }
+       assert!(get_reply_command(&ctx).is_none());
+       assert_eq!(blocks.iter().map(Vec::len).collect::<Vec<_>>(), [512, 512, 219]);
+       assert_eq!(blocks[0][511], b'\r');
+       assert_eq!(blocks[1][0], b'\n');
+       assert_eq!(blocks.concat(), expected);
+
+       let uploaded = format!("{}/uploaded.txt", dir);
+       let mut wrq = vec![0, 2];
+       wrq.extend_from_slice(uploaded.as_bytes());
+       wrq.extend_from_slice(b"\0netascii\0");
+       let mut ctx = recv_request(&wrq, wrq.len(), PEER, &ServerOptions::default()).unwrap();
+       assert!(matches!(get_reply_command(&ctx), Some(Command::ACK{ blocknum: 0 })));
+       for (index, block) in blocks.iter().enumerate() {
+          let blocknum = (index as u16 + 1).to_be_bytes();
+          let data = [&[0, 3][..], &blocknum, block].concat();
+          // Written twice when the ACK is lost
+          for _ in 0..2 {
+             assert_eq!(recv(&mut ctx, &data), Action::Reply);
+             assert!(matches!(get_reply_command(&ctx), Some(Command::ACK{ blocknum: acked }) if acked == index as u16 + 1));
+          }
+       }
+       assert_eq!(std::fs::read(&uploaded).unwrap(), original);
+    }
+
+    #[test]
+    fn remapped_names() {
+       let rule = |pattern: &str, replacement: &str| RemapRule { pattern: pattern.to_string(), replacement: replacement.to_string(), regex: true };
+       let remap = Remap::new(&[rule("^firmware/(.*)$", "tests/fixtures/files/$1"), rule("^escape$", "../Cargo.toml")]).unwrap();
+       let options = ServerOptions { remap, ..ServerOptions::default() };
+       // Sanitized before the rules, without the leading '/'
+       let rrq = rrq("/firmware/hello.txt");
+       let ctx = recv_request(&rrq, rrq.len(), PEER, &options).unwrap();
+       assert_eq!(ctx.filename, Path::new("tests/fixtures/files/hello.txt"));
+       assert!(matches!(get_reply_command(&ctx), Some(Command::DATA{ blocknum: 1, .. })));
+       // Sanitized again after
+       let rrq = self::rrq("escape");
+       let ctx = recv_request(&rrq, rrq.len(), PEER, &options).unwrap();
+       assert!(matches!(get_reply_command(&ctx), Some(Command::ERROR{ errorcode: 2, .. })));
+    }
+
+    #[test]
+    fn empty_filename_default_file() {
+       let rrq = self::rrq("");
+       let ctx = recv_request(&rrq, rrq.len(), PEER, &ServerOptions::default()).unwrap();
+       assert!(matches!(get_reply_command(&ctx), Some(Command::ERROR{ errorcode: 1, .. })));
+       let options = ServerOptions { default_file: Some(PathBuf::from("tests/fixtures/files/boot.cfg")), ..ServerOptions::default() };
+       let ctx = recv_request(&rrq, rrq.len(), PEER, &options).unwrap();
+       match get_reply_command(&ctx) {
+          Some(Command::DATA{ blocknum: 1, data }) => {
+             assert_eq!(&data[4..], std::fs::read("tests/fixtures/files/boot.cfg").unwrap().as_slice());
+          }
+          other => { panic!("RRQ of an empty filename must serve the default file, got {:?}", other);}
+       }
+       // Named requests and uploads are unchanged
+       let rrq = self::rrq("tests/fixtures/files/hello.txt");
+       assert_eq!(recv_request(&rrq, rrq.len(), PEER, &options).unwrap().filename, Path::new("tests/fixtures/files/hello.txt"));
+       let wrq = [0, 2, 0, b'o', b'c', b't', b'e', b't', 0];
+       let ctx = recv_request(&wrq, wrq.len(), PEER, &options).unwrap();
+       assert_eq!(ctx.filename, Path::new(""));
+    }
+
+    #[test]
+    fn missing_file_fallback() {
+       let options = ServerOptions { fallback_file: Some(PathBuf::from("tests/fixtures/files/boot.cfg")), ..ServerOptions::default() };
+       let fallback = std::fs::read("tests/fixtures/files/boot.cfg").unwrap();
+       let mut rrq = rrq("tests/fixtures/files/01-aa-bb-cc-dd-ee-ff");
+       rrq.extend_from_slice(b"tsize\x000\x00");
+       // Not looked up by the receiving loop
+       assert_eq!(fallback_file(&recv_request(&rrq, rrq.len(), PEER, &options).unwrap()), None);
+       let mut ctx = resolved(&rrq, PEER, &options);
+       assert_eq!(ctx.filename, Path::new("tests/fixtures/files/01-aa-bb-cc-dd-ee-ff"));
+       assert_eq!(fallback_file(&ctx), Some(Path::new("tests/fixtures/files/boot.cfg")));
+       match get_reply_command(&ctx) {
+          Some(Command::OACK{ options }) => assert_eq!(options, [("tsize".to_string(), fallback.len().to_string())]),
+          other => { panic!("tsize must be the size of the fallback file, got {:?}", other);}
+       }
+       assert_eq!(recv(&mut ctx, &[0, 4, 0, 0]), Action::Reply);
+       assert!(matches!(get_reply_command(&ctx), Some(Command::DATA{ blocknum: 1, data }) if data[4..] == fallback[..]));
+       // An existing file is served
+       let rrq = self::rrq("tests/fixtures/files/hello.txt");
+       let ctx = resolved(&rrq, PEER, &options);
+       assert_eq!(fallback_file(&ctx), None);
+       assert!(matches!(get_reply_command(&ctx), Some(Command::DATA{ blocknum: 1, data }) if data[4..] == std::fs::read("tests/fixtures/files/hello.txt").unwrap()[..]));
+       // Neither a refused path nor an upload
+       let rrq = self::rrq("../Cargo.toml");
+       let ctx = resolved(&rrq, PEER, &options);
+       assert!(matches!(get_reply_command(&ctx), Some(Command::ERROR{ errorcode: 2, .. })));
+       let mut wrq = vec![0, 2];
+       wrq.extend_from_slice(b"target/tftp-fallback.bin\0octet\0");
+       assert_eq!(fallback_file(&resolved(&wrq, PEER, &options)), None);
+    }
+
+    #[test]
+    fn client_variables() {
+       let options = ServerOptions { fallback_file: Some(PathBuf::from("hosts/${ip_dashed}.cfg")), ..ServerOptions::default() };
+       let rrq = rrq("tests/fixtures/files/missing.cfg");
+       let ctx = resolved(&rrq, PEER, &options);
+       assert_eq!(fallback_file(&ctx), Some(Path::new("hosts/10-0-0-42.cfg")));
+       let ipv6 = "[fd00::42]:2001".parse().unwrap();
+       let ctx = resolved(&rrq, ipv6, &options);
+       assert_eq!(fallback_file(&ctx), Some(Path::new("hosts/fd00--42.cfg")));
+       // Expanded, then sanitized
+       let rule = RemapRule { pattern: "^(.*)$".to_string(), replacement: "${ip}/../../$1".to_string(), regex: true };
+       let options = ServerOptions { remap: Remap::new(&[rule]).unwrap(), ..ServerOptions::default() };
+       let rrq = self::rrq("Cargo.toml");
+       let ctx = resolved(&rrq, ipv6, &options);
+       assert_eq!(ctx.filename, Path::new("fd00::42/../../Cargo.toml"));
+       assert!(matches!(get_reply_command(&ctx), Some(Command::ERROR{ errorcode: 2, .. })));
+    }
+
+    #[test]
+    fn extra_roots() {
+       let base = std::path::absolute(format!("target/tftp-roots-{}", std::process::id())).unwrap();
+       let _ = std::fs::remove_dir_all(&base);
+       std::fs::create_dir_all(base.join("tests/fixtures/files")).unwrap();
+       std::fs::write(base.join("tests/fixtures/files/hello.txt"), "shadowed").unwrap();
+       std::fs::write(base.join("tests/fixtures/files/base-only.txt"), "base").unwrap();
+       #[cfg(unix)]
+       std::os::unix::fs::symlink(std::path::absolute("Cargo.toml").unwrap(), base.join("tests/fixtures/files/escape.txt")).unwrap();
+       let options = ServerOptions { extra_roots: vec![base.clone()], ..ServerOptions::default() };
+       let data = |filename: &str| match get_reply_command(&recv_request(&rrq(filename), rrq(filename).len(), PEER, &options).unwrap()) {
+          Some(Command::DATA{ blocknum: 1, data }) => Ok(data[4..].to_vec()),
+          Some(Command::ERROR{ errorcode, .. }) => Err(errorcode),
+          other => panic!("unexpected reply {:?}", other)
+       };
+       // The served directory comes first
+       assert_eq!(data("tests/fixtures/files/hello.txt"), Ok(std::fs::read("tests/fixtures/files/hello.txt").unwrap()));
+       assert_eq!(data("tests/fixtures/files/base-only.txt"), Ok(b"base".to_vec()));
+       assert_eq!(data("tests/fixtures/files/missing.txt"), Err(1));
+       // Confined to its own root
+       #[cfg(unix)]
+       assert_eq!(data("tests/fixtures/files/escape.txt"), Err(2));
+       // Uploads go to the served directory
+       let mut wrq = vec![0, 2];
+       wrq.extend_from_slice(b"target/tftp-roots-upload.bin\0octet\0");
+       let mut ctx = recv_request(&wrq, wrq.len(), PEER, &options).unwrap();
+       get_reply_command(&ctx);
+       assert_eq!(recv(&mut ctx, &[0, 3, 0, 1, b'u']), Action::Reply);
+       assert!(matches!(get_reply_command(&ctx), Some(Command::ACK{ blocknum: 1 })));
+       assert_eq!(std::fs::read("target/tftp-roots-upload.bin").unwrap(), b"u");
+       assert!(!base.join("target").exists());
+    }
+
+    #[test]
+    fn lowercase_names() {
+       let options = ServerOptions { lowercase_names: true, ..ServerOptions::default() };
+       let rrq = rrq("TESTS/Fixtures/FILES/Hello.TXT");
+       let ctx = recv_request(&rrq, rrq.len(), PEER, &options).unwrap();
+       assert_eq!(ctx.filename, Path::new("tests/fixtures/files/hello.txt"));
+       assert!(matches!(get_reply_command(&ctx), Some(Command::DATA{ blocknum: 1, .. })));
+    }
+
+    #[test]
+    fn prefix_names() {
+       let options = ServerOptions { prefix: Some(PathBuf::from("boot")), ..ServerOptions::default() };
+       for filename in ["kernel", "/kernel"] {
+          let rrq = rrq(filename);
+          assert_eq!(recv_request(&rrq, rrq.len(), PEER, &options).unwrap().filename, Path::new("boot/kernel"));
+       }
+       // Still confined once prefixed
+       let rrq = rrq("../../etc/passwd");
+       let ctx = recv_request(&rrq, rrq.len(), PEER, &options).unwrap();
+       assert!(matches!(get_reply_command(&ctx), Some(Command::ERROR{ errorcode: 2, .. })));
+       let options = ServerOptions { prefix: Some(PathBuf::from("tests/fixtures")), lowercase_names: true, ..ServerOptions::default() };
+       let rrq = self::rrq("Files/Hello.txt");
+       let ctx = recv_request(&rrq, rrq.len(), PEER, &options).unwrap();
+       assert!(matches!(get_reply_command(&ctx), Some(Command::DATA{ blocknum: 1, .. })));
+    }
+
+    #[test]
+    fn hostile_filenames() {
+       let options = ServerOptions::default();
+       let mut packet = vec![0, 1];
+       packet.extend_from_slice(b"pxelinux.cfg/\x1b[2J\r\nfake line\x7f\0octet\0");
+       let Command::RRQ{ filename, .. } = process_buffer(&packet, packet.len()) else { panic!("RRQ expected") };
+       assert_eq!(check_filename(&filename, &options), Err(TftpError::MalformedPacket));
+       assert_eq!(escape_filename(&filename), "\"pxelinux.cfg/\\x1b[2J\\x0d\\x0afake line\\x7f\"");
+       assert_eq!(check_filename(&[b'a'; MAX_FILENAME_LEN], &options), Ok(()));
+       assert_eq!(check_filename(&[b'a'; MAX_FILENAME_LEN + 1], &options), Err(TftpError::IllegalOperation));
+       let short = ServerOptions { max_filename_len: Some(8), ..ServerOptions::default() };
+       assert_eq!(check_filename(b"pxelinux.0", &short), Err(TftpError::IllegalOperation));
+       // Latin-1 and UTF-8 bytes are accepted unless ascii_filenames
+       let latin1 = b"caf\xe9.cfg";
+       assert_eq!(check_filename(latin1, &options), Ok(()));
+       let ascii = ServerOptions { ascii_filenames: true, ..ServerOptions::default() };
+       assert_eq!(check_filename(latin1, &ascii), Err(TftpError::AccessViolation));
+       assert_eq!(check_filename("café.cfg".as_bytes(), &ascii), Err(TftpError::AccessViolation));
+       assert_eq!(escape_filename(latin1), "\"caf\\xe9.cfg\"");
+       assert_eq!(escape_filename(b"a\"b\\c"), "\"a\\\"b\\\\c\"");
+    }
+
+    #[test]
+    fn hostile_filenames_refused_before_lookup() {
+       std::fs::create_dir_all("target/tftp-hostile").unwrap();
+       let filename = "target/tftp-hostile/gr\u{fc}n.cfg";
+       std::fs::write(filename, b"x").unwrap();
+       let mut rrq = rrq("target/tftp-hostile/a\tb");
+       let ctx = recv_request(&rrq, rrq.len(), PEER, &ServerOptions::default()).unwrap();
+       assert!(matches!(get_reply_command(&ctx), Some(Command::ERROR{ errorcode: 4, .. })));
+       // An existing file is refused as well
+       rrq = self::rrq(filename);
+       let ascii = ServerOptions { ascii_filenames: true, ..ServerOptions::default() };
+       let ctx = recv_request(&rrq, rrq.len(), PEER, &ascii).unwrap();
+       assert!(matches!(get_reply_command(&ctx), Some(Command::ERROR{ errorcode: 2, .. })));
+       let ctx = recv_request(&rrq, rrq.len(), PEER, &ServerOptions::default()).unwrap();
+       assert!(matches!(get_reply_command(&ctx), Some(Command::DATA{ blocknum: 1, .. })));
+       let mut wrq = vec![0, 2];
+       wrq.extend_from_slice(&[b'x'; 300]);
+       wrq.extend_from_slice(b"\0octet\0");
+       let ctx = recv_request(&wrq, wrq.len(), PEER, &ServerOptions::default()).unwrap();
+       assert!(matches!(get_reply_command(&ctx), Some(Command::ERROR{ errorcode: 4, .. })));
+       // Up to the configured maximum only, in bytes and not in characters
+       let short = ServerOptions { max_filename_len: Some(filename.len() - 1), ..ServerOptions::default() };
+       let ctx = recv_request(&rrq, rrq.len(), PEER, &short).unwrap();
+       assert!(matches!(get_reply_command(&ctx), Some(Command::ERROR{ errorcode: 4, .. })));
+       let long_enough = ServerOptions { max_filename_len: Some(filename.len()), ..ServerOptions::default() };
+       let ctx = recv_request(&rrq, rrq.len(), PEER, &long_enough).unwrap();
+       assert!(matches!(get_reply_command(&ctx), Some(Command::DATA{ blocknum: 1, .. })));
+    }
+
+    #[test]
+    fn hidden_and_temp_files_refused() {
+       let dir = "target/tftp-hidden/.git";
+       std::fs::create_dir_all(dir).unwrap();
+       std::fs::write(format!("{}/config", dir), b"[core]").unwrap();
+       std::fs::write("target/tftp-hidden/.switch.cfg.tftp-tmp.42", b"partial").unwrap();
+       let refused = |filename: &str, options: &ServerOptions| {
+          let rrq = rrq(filename);
+          let ctx = recv_request(&rrq, rrq.len(), PEER, options).unwrap();
+          return matches!(get_reply_command(&ctx), Some(Command::ERROR{ errorcode: 2, .. }));
+       };
+       let options = ServerOptions::default();
+       assert!(refused("target/tftp-hidden/.git/config", &options));
+       assert!(refused("/target/tftp-hidden/.git/missing", &options));
+       assert!(refused("target/tftp-hidden/.switch.cfg.tftp-tmp.42", &options));
+       let serve_hidden = ServerOptions { serve_hidden: true, ..ServerOptions::default() };
+       assert!(!refused("target/tftp-hidden/.git/config", &serve_hidden));
+       assert!(refused("target/tftp-hidden/.switch.cfg.tftp-tmp.42", &serve_hidden));
+       // Dots inside a name are not hidden
+       assert!(!refused("tests/fixtures/files/hello.txt", &options));
+       assert!(!is_temp_file(std::ffi::OsStr::new("switch.cfg.tftp-tmp.42")));
+
+       let mut wrq = vec![0, 2];
+       wrq.extend_from_slice(b"target/tftp-hidden/.git/hooks/post-update\0octet\0");
+       let ctx = recv_request(&wrq, wrq.len(), PEER, &options).unwrap();
+       assert!(matches!(get_reply_command(&ctx), Some(Command::ERROR{ errorcode: 2, .. })));
+    }
+
+    #[test]
+    fn reply_to_error_and_oack_states() {
+       let rrq = rrq("tests/fixtures/files/hello.txt");
+       let mut ctx = recv_request(&rrq, rrq.len(), PEER, &ServerOptions::default()).unwrap();
+       // An ERROR set on a protocol violation is sent as is
+       ctx.current_op = TftpError::UnknownTransferId.to_command();
+       assert_eq!(get_reply_command(&ctx), Some(TftpError::UnknownTransferId.to_command()));
+       // The server never receives an OACK
+       ctx.current_op = Command::OACK{ options: Vec::new() };
+       assert_eq!(get_reply_command(&ctx), Some(TftpError::IllegalOperation.to_command()));
+    }
+
+    #[test]
+    fn data_during_rrq_is_illegal() {
+       let rrq = rrq("tests/fixtures/files/hello.txt");
+       let mut ctx = recv_request(&rrq, rrq.len(), PEER, &ServerOptions::default()).unwrap();
+       assert_eq!(recv(&mut ctx, &[0, 3, 0, 1, b'x']), Action::Reply);
+       assert!(matches!(get_reply_command(&ctx), Some(Command::ERROR{ errorcode: 4, .. })));
+    }
+
+    #[test]
+    fn ack_during_wrq_is_illegal() {
+       let wrq: [u8; 15] = [0, 2, b'u', b'p', b'l', b'o', b'a', b'd', 0, b'o', b'c', b't', b'e', b't', 0];
+       let mut ctx = recv_request(&wrq, wrq.len(), PEER, &ServerOptions::default()).unwrap();
+       assert_eq!(recv(&mut ctx, &[0, 4, 0, 1]), Action::Reply);
+       assert!(matches!(get_reply_command(&ctx), Some(Command::ERROR{ errorcode: 4, .. })));
+    }
+
+    #[test]
+    fn oversized_data_is_malformed() {
+       let wrq = b"\x00\x02target/tftp-oversized.bin\x00octet\x00";
+       let mut ctx = recv_request(wrq, wrq.len(), PEER, &ServerOptions::default()).unwrap();
+       let mut data = vec![0, 3, 0, 1];
+       data.resize(4 + 513, b'x');
+       assert_eq!(recv(&mut ctx, &data), Action::Reply);
+       assert_eq!(get_reply_command(&ctx), Some(Command::ERROR{ errorcode: 4, errmsg: "Malformed packet".to_string() }));
+    }
+
+    #[test]
+    fn recv_updates_context_in_place() {
+       let rrq = rrq("tests/fixtures/files/hello.txt");
+       let mut ctx = recv_request(&rrq, rrq.len(), PEER, &ServerOptions::default()).unwrap();
+       // Strings moved out of the request
+       assert_eq!(ctx.filename, Path::new("tests/fixtures/files/hello.txt"));
+       assert!(matches!(ctx.current_op, Command::RRQ{ ref filename, .. } if filename.is_empty()));
+       assert_eq!(recv(&mut ctx, &[0, 4, 0, 1]), Action::Reply);
+       assert!(matches!(ctx.current_op, Command::ACK{ blocknum: 1 }));
+       // Client ERROR stops the transfer, keeping its code
+       assert_eq!(recv(&mut ctx, b"\x00\x05\x00\x03full\x00"), Action::ClientError(TftpError::DiskFull));
+       // Nothing expected after an error
+       ctx.current_op = TftpError::IllegalOperation.to_command();
+       assert_eq!(recv(&mut ctx, &[0, 4, 0, 2]), Action::Abort);
+       // A new request replaces the transfer
+       let wrq = b"\x00\x02upload\x00octet\x00";
+       assert_eq!(recv(&mut ctx, wrq), Action::Reply);
+       assert_eq!(ctx.filename, Path::new("upload"));
+       assert!(matches!(ctx.current_op, Command::WRQ{..}));
+    }
+
+    #[test]
+    fn wrq_no_create() {
+       let server_options = ServerOptions { no_create: true, ..ServerOptions::default() };
+       let dir = "target/tftp-no-create";
+       std::fs::create_dir_all(dir).unwrap();
+       let existing = format!("{}/existing.bin", dir);
+       std::fs::write(&existing, b"previous content").unwrap();
+       let mut wrq = vec![0, 2];
+       wrq.extend_from_slice(existing.as_bytes());
+       wrq.extend_from_slice(b"\0octet\0");
+       let mut ctx = recv_request(&wrq, wrq.len(), PEER, &server_options).unwrap();
+       assert!(matches!(get_reply_command(&ctx), Some(Command::ACK{ blocknum: 0 })));
+       assert_eq!(recv(&mut ctx, &[0, 3, 0, 1, b'n', b'e', b'w']), Action::Reply);
+       assert!(matches!(get_reply_command(&ctx), Some(Command::ACK{ blocknum: 1 })));
+       assert_eq!(std::fs::read(&existing).unwrap(), b"new");
+
+       let missing = format!("{}/missing.bin", dir);
+       let _ = std::fs::remove_file(&missing);
+       let mut wrq = vec![0, 2];
+       wrq.extend_from_slice(missing.as_bytes());
+       wrq.extend_from_slice(b"\0octet\0");
+       let ctx = recv_request(&wrq, wrq.len(), PEER, &server_options).unwrap();
+       assert!(matches!(get_reply_command(&ctx), Some(Command::ERROR{ errorcode: 1, .. })));
+       assert!(!std::path::Path::new(&missing).exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn wrq_upload_mode() {
+       use std::os::unix::fs::PermissionsExt;
+       let server_options = ServerOptions { upload_mode: Some(0o660), ..ServerOptions::default() };
+       let dir = "target/tftp-upload-mode";
+       std::fs::create_dir_all(dir).unwrap();
+       let created = format!("{}/created.bin", dir);
+       let _ = std::fs::remove_file(&created);
+       let replaced = format!("{}/replaced.bin", dir);
+       std::fs::write(&replaced, b"previous content").unwrap();
+       std::fs::set_permissions(&replaced, std::fs::Permissions::from_mode(0o600)).unwrap();
+       for filename in [&created, &replaced] {
+          let mut wrq = vec![0, 2];
+          wrq.extend_from_slice(filename.as_bytes());
+          wrq.extend_from_slice(b"\0octet\0");
+          let mut ctx = recv_request(&wrq, wrq.len(), PEER, &server_options).unwrap();
+          assert!(matches!(get_reply_command(&ctx), Some(Command::ACK{ blocknum: 0 })));
+          assert_eq!(recv(&mut ctx, &[0, 3, 0, 1, b'n', b'e', b'w']), Action::Reply);
+          assert!(matches!(get_reply_command(&ctx), Some(Command::ACK{ blocknum: 1 })));
+          assert_eq!(std::fs::metadata(filename).unwrap().permissions().mode() & 0o777, 0o660, "{}", filename);
+       }
+    }
+
+    /// The owner changes with the last block, as root. Otherwise the upload still succeeds
+    #[cfg(unix)]
+    #[test]
+    fn wrq_upload_owner() {
+       use std::os::unix::fs::MetadataExt;
+       let nobody = 65534;
+       let server_options = ServerOptions { upload_owner: Some((nobody, nobody)), ..ServerOptions::default() };
+       let dir = "target/tftp-upload-owner";
+       std::fs::create_dir_all(dir).unwrap();
+       let filename = format!("{}/owned.bin", dir);
+       let _ = std::fs::remove_file(&filename);
+       let mut wrq = vec![0, 2];
+       wrq.extend_from_slice(filename.as_bytes());
+       wrq.extend_from_slice(b"\0octet\0");
+       let mut ctx = recv_request(&wrq, wrq.len(), PEER, &server_options).unwrap();
+       assert!(matches!(get_reply_command(&ctx), Some(Command::ACK{ blocknum: 0 })));
+       let uploader = std::fs::metadata(dir).unwrap().uid();
+       let mut block = vec![0, 3, 0, 1];
+       block.extend_from_slice(&[b'x'; 512]);
+       assert_eq!(recv(&mut ctx, &block), Action::Reply);
+       assert!(matches!(get_reply_command(&ctx), Some(Command::ACK{ blocknum: 1 })));
+       assert_eq!(std::fs::metadata(&filename).unwrap().uid(), uploader);
+       assert_eq!(recv(&mut ctx, &[0, 3, 0, 2, b'e', b'n', b'd']), Action::Reply);
+       assert!(matches!(get_reply_command(&ctx), Some(Command::ACK{ blocknum: 2 })));
+       let metadata = std::fs::metadata(&filename).unwrap();
+       assert_eq!(metadata.len(), 515);
+       // SAFETY: geteuid has no preconditions and cannot fail
+       if unsafe { libc::geteuid() } == 0 {
+          assert_eq!((metadata.uid(), metadata.gid()), (nobody, nobody));
+       } else {
+          eprintln!("Not root, the owner cannot be changed");
+          assert_eq!(metadata.uid(), uploader);
+       }
+    }
+
+    #[test]
+    fn wrq_atomic_upload() {
+       let server_options = ServerOptions { atomic_uploads: true, ..ServerOptions::default() };
+       let dir = "target/tftp-atomic-upload";
+       std::fs::create_dir_all(dir).unwrap();
+       let filename = format!("{}/firmware.bin", dir);
+       let _ = std::fs::remove_file(&filename);
+       let mut wrq = vec![0, 2];
+       wrq.extend_from_slice(filename.as_bytes());
+       wrq.extend_from_slice(b"\0octet\0");
+       let mut ctx = recv_request(&wrq, wrq.len(), PEER, &server_options).unwrap();
+       ctx.transfer_id = 7;
+       assert!(matches!(get_reply_command(&ctx), Some(Command::ACK{ blocknum: 0 })));
+       let temp = temp_upload(&ctx).unwrap();
+       assert_eq!(temp, Path::new(dir).join(format!(".firmware.bin.tftp-tmp.{}-7", std::process::id())));
+       assert!(is_temp_file(temp.file_name().unwrap()));
+
+       let mut block = vec![0, 3, 0, 1];
+       block.extend_from_slice(&[b'x'; 512]);
+       assert_eq!(recv(&mut ctx, &block), Action::Reply);
+       assert!(matches!(get_reply_command(&ctx), Some(Command::ACK{ blocknum: 1 })));
+       // In progress, only the temporary file exists
+       assert!(!Path::new(&filename).exists());
+       assert_eq!(std::fs::metadata(&temp).unwrap().len(), 512);
+       assert_eq!(recv(&mut ctx, &[0, 3, 0, 2, b'e', b'n', b'd']), Action::Reply);
+       assert!(matches!(get_reply_command(&ctx), Some(Command::ACK{ blocknum: 2 })));
+       assert_eq!(std::fs::metadata(&filename).unwrap().len(), 515);
+       assert!(!temp.exists());
+
+       // An interrupted upload leaves the file as it was
+       let mut ctx = recv_request(&wrq, wrq.len(), PEER, &server_options).unwrap();
+       assert_eq!(recv(&mut ctx, &block), Action::Reply);
+       assert!(matches!(get_reply_command(&ctx), Some(Command::ACK{ blocknum: 1 })));
+       remove_partial_upload(&ctx);
+       assert!(!temp_upload(&ctx).unwrap().exists());
+       assert_eq!(std::fs::metadata(&filename).unwrap().len(), 515);
+    }
+
+    #[test]
+    fn wrq_blocks_in_sequence() {
+       let dir = "target/tftp-upload-sequence";
+       std::fs::create_dir_all(dir).unwrap();
+       let filename = format!("{}/skipped.bin", dir);
+       let mut wrq = vec![0, 2];
+       wrq.extend_from_slice(filename.as_bytes());
+       wrq.extend_from_slice(b"\0octet\0");
+       let block = |blocknum: u16, byte: u8, len: usize| {
+          let mut block = vec![0, 3];
+          block.extend_from_slice(&blocknum.to_be_bytes());
+          block.extend_from_slice(&vec![byte; len]);
+          return block;
+       };
+       let mut ctx = recv_request(&wrq, wrq.len(), PEER, &ServerOptions::default()).unwrap();
+       assert_eq!(recv(&mut ctx, &block(1, b'a', 512)), Action::Reply);
+       assert!(matches!(get_reply_command(&ctx), Some(Command::ACK{ blocknum: 1 })));
+       // Block 2 skipped: block 1 acknowledged again, nothing written
+       assert_eq!(recv(&mut ctx, &block(3, b'c', 10)), Action::Reply);
+       assert!(matches!(get_reply_command(&ctx), Some(Command::ACK{ blocknum: 1 })));
+       assert_eq!(std::fs::metadata(&filename).unwrap().len(), 512);
+       assert_eq!(recv(&mut ctx, &block(2, b'b', 512)), Action::Reply);
+       assert!(matches!(get_reply_command(&ctx), Some(Command::ACK{ blocknum: 2 })));
+       // Duplicate, acknowledged without being written again
+       assert_eq!(recv(&mut ctx, &block(2, b'x', 512)), Action::Reply);
+       assert!(matches!(get_reply_command(&ctx), Some(Command::ACK{ blocknum: 2 })));
+       assert_eq!(recv(&mut ctx, &block(3, b'c', 10)), Action::Reply);
+       assert!(matches!(get_reply_command(&ctx), Some(Command::ACK{ blocknum: 3 })));
+       let content = std::fs::read(&filename).unwrap();
+       assert_eq!(content.len(), 1034);
+       assert!(!content.contains(&0) && !content.contains(&b'x'));
+       assert_eq!(&content[510..514], b"aabb");
+
+       // Aborted beyond the limit
+       let server_options = ServerOptions { max_out_of_order: Some(2), ..ServerOptions::default() };
+       let mut ctx = recv_request(&wrq, wrq.len(), PEER, &server_options).unwrap();
+       assert_eq!(recv(&mut ctx, &block(1, b'a', 512)), Action::Reply);
+       assert!(matches!(get_reply_command(&ctx), Some(Command::ACK{ blocknum: 1 })));
+       for _ in 0..2 {
+          assert_eq!(recv(&mut ctx, &block(5, b'e', 512)), Action::Reply);
+          assert!(matches!(get_reply_command(&ctx), Some(Command::ACK{ blocknum: 1 })));
+       }
+       // Duplicates do not count
+       assert_eq!(recv(&mut ctx, &block(1, b'a', 512)), Action::Reply);
+       assert!(matches!(get_reply_command(&ctx), Some(Command::ACK{ blocknum: 1 })));
+       assert_eq!(recv(&mut ctx, &block(5, b'e', 512)), Action::Reply);
+       assert!(matches!(get_reply_command(&ctx), Some(Command::ERROR{ errorcode: 4, .. })));
+       assert_eq!(std::fs::metadata(&filename).unwrap().len(), 512);
+
+       // Strict mode aborts at the first one, a duplicate is still acknowledged
+       let server_options = ServerOptions { strictness: Strictness::Strict, ..ServerOptions::default() };
+       let mut ctx = recv_request(&wrq, wrq.len(), PEER, &server_options).unwrap();
+       assert_eq!(recv(&mut ctx, &block(1, b'a', 512)), Action::Reply);
+       assert!(matches!(get_reply_command(&ctx), Some(Command::ACK{ blocknum: 1 })));
+       assert_eq!(recv(&mut ctx, &block(1, b'a', 512)), Action::Reply);
+       assert!(matches!(get_reply_command(&ctx), Some(Command::ACK{ blocknum: 1 })));
+       assert_eq!(recv(&mut ctx, &block(3, b'c', 512)), Action::Reply);
+       assert!(matches!(get_reply_command(&ctx), Some(Command::ERROR{ errorcode: 4, .. })));
+    }
+
+    #[test]
+    fn write_errors() {
+       use std::io::{Error, ErrorKind};
+       assert_eq!(from_write_error(&Error::from(ErrorKind::StorageFull)), TftpError::DiskFull);
+       assert_eq!(from_write_error(&Error::from(ErrorKind::QuotaExceeded)), TftpError::DiskFull);
+       #[cfg(unix)]
+       assert_eq!(from_write_error(&Error::from_raw_os_error(libc::ENOSPC)), TftpError::DiskFull);
+       assert_eq!(from_write_error(&Error::from(ErrorKind::PermissionDenied)), TftpError::NotDefined("Cannot write the file".to_string()));
+    }
+
+    #[test]
+    fn wrq_create_upload_dirs() {
+       let dir = "target/tftp-upload-dirs";
+       let _ = std::fs::remove_dir_all(dir);
+       std::fs::create_dir_all(dir).unwrap();
+       let upload = |filename: &str, server_options: &ServerOptions| {
+          let mut wrq = vec![0, 2];
+          wrq.extend_from_slice(filename.as_bytes());
+          wrq.extend_from_slice(b"\0octet\0");
+          let mut ctx = recv_request(&wrq, wrq.len(), PEER, server_options).unwrap();
+          assert!(matches!(get_reply_command(&ctx), Some(Command::ACK{ blocknum: 0 })));
+          assert_eq!(recv(&mut ctx, &[0, 3, 0, 1, b'c', b'f', b'g']), Action::Reply);
+          return get_reply_command(&ctx);
+       };
+       let nested = format!("{}/backups/2024-06-01/switch-17.cfg", dir);
+       // Unchanged without the option
+       assert!(matches!(upload(&nested, &ServerOptions::default()), Some(Command::ERROR{ .. })));
+       assert!(!Path::new(dir).join("backups").exists());
+
+       let server_options = ServerOptions { create_upload_dirs: true, ..ServerOptions::default() };
+       assert!(matches!(upload(&nested, &server_options), Some(Command::ACK{ blocknum: 1 })));
+       assert_eq!(std::fs::read(&nested).unwrap(), b"cfg");
+       // Refused before any directory is created
+       let traversal = format!("{}/backups/../../../tftp-upload-dirs-escape/switch-17.cfg", dir);
+       assert!(matches!(upload(&traversal, &server_options), Some(Command::ERROR{ errorcode: 2, .. })));
+       assert!(!Path::new("../tftp-upload-dirs-escape").exists());
+       #[cfg(unix)]
+       {
+          let outside = std::env::temp_dir().join(format!("tftp-upload-dirs-{}", std::process::id()));
+          std::fs::create_dir_all(&outside).unwrap();
+          std::os::unix::fs::symlink(&outside, format!("{}/escape", dir)).unwrap();
+          let through_symlink = format!("{}/escape/new/switch-17.cfg", dir);
+          assert!(matches!(upload(&through_symlink, &server_options), Some(Command::ERROR{ errorcode: 2, .. })));
+          assert!(!outside.join("new").exists());
+       }
+    }
+
+    #[test]
+    fn rrq_ignore_case() {
+       let rrq = rrq("tests/fixtures/files/HELLO.TXT");
+       let ctx = recv_request(&rrq, rrq.len(), PEER, &ServerOptions::default()).unwrap();
+       assert!(matches!(get_reply_command(&ctx), Some(Command::ERROR{ errorcode: 1, .. })));
+       let server_options = ServerOptions { ignore_case: true, ..ServerOptions::default() };
+       let ctx = recv_request(&rrq, rrq.len(), PEER, &server_options).unwrap();
+       match get_reply_command(&ctx) {
+          Some(Command::DATA{ blocknum: 1, data }) => {
+             assert_eq!(&data[4..], std::fs::read("tests/fixtures/files/hello.txt").unwrap().as_slice());
+          }
+          other => { panic!("RRQ ignoring case must find hello.txt, got {:?}", other);}
+       }
+       // Two candidates, none is chosen
+       let dir = "target/tftp-ignore-case";
+       std::fs::create_dir_all(dir).unwrap();
+       std::fs::write(format!("{}/boot.img", dir), b"lower").unwrap();
+       std::fs::write(format!("{}/BOOT.img", dir), b"upper").unwrap();
+       assert_eq!(lookup_filename(Path::new(&format!("{}/Boot.IMG", dir)), &server_options), Err(TftpError::FileNotFound));
+       assert_eq!(lookup_filename(Path::new(&format!("{}/boot.img", dir)), &server_options), Ok(std::path::PathBuf::from(format!("{}/boot.img", dir))));
+    }
+
+    // APFS refuses the names which are not UTF-8
+    #[cfg(all(unix, not(target_os = "macos")))]
+    #[test]
+    fn rrq_latin1_filename() {
+       use std::os::unix::ffi::OsStrExt;
+       let dir = "target/tftp-latin1";
+       std::fs::create_dir_all(dir).unwrap();
+       let filename = [dir.as_bytes(), b"/caf\xe9.txt"].concat();
+       std::fs::write(std::ffi::OsStr::from_bytes(&filename), b"latin-1").unwrap();
+       let mut rrq = vec![0, 1];
+       rrq.extend_from_slice(&filename);
+       rrq.extend_from_slice(b"\0octet\0");
+       let ctx = recv_request(&rrq, rrq.len(), PEER, &ServerOptions::default()).unwrap();
+       assert_eq!(ctx.filename.as_os_str().as_bytes(), filename.as_slice());
+       match get_reply_command(&ctx) {
+          Some(Command::DATA{ blocknum: 1, data }) => assert_eq!(&data[4..], b"latin-1"),
+          other => { panic!("RRQ of a Latin-1 filename must be served, got {:?}", other);}
+       }
     }
 
 }
@@ -0,0 +1,74 @@
+//! Variables of the client expanded in the served filenames: `[[remap]]` replacements and `--fallback-file`
+//!
+//! `${ip}` is the client address, dotted for IPv4, `${ip_dashed}` the same with '-' for '.' or ':'
+//! (`10-0-0-42`, `fd00--42`), `${port}` the client port. An IPv4 client reaching a dual stack socket is
+//! expanded as IPv4. The expanded name is sanitized afterwards like any requested one.
+
+use std::net::SocketAddr;
+
+/// Names known in the `${name}` of a template
+pub const NAMES: [&str; 3] = ["ip", "ip_dashed", "port"];
+
+/// Every `${name}` of template must be a variable, or accepted by other (the groups of a regex)
+pub fn check(template: &str, other: impl Fn(&str) -> bool) -> Result<(), String> {
+    for (_, name, _) in references(template) {
+        if !NAMES.contains(&name) && !other(name) {
+            return Err(format!("unknown variable ${{{}}}, known ones are {}", name, NAMES.map(|name| format!("${{{}}}", name)).join(" ")));
+        }
+    }
+    return Ok(());
+}
+
+/// template with the variables replaced by the values of this client, other `${name}` are kept
+pub fn expand(template: &str, peer: SocketAddr) -> String {
+    let mut expanded = String::with_capacity(template.len());
+    let mut copied = 0;
+    for (start, name, end) in references(template) {
+        let ip = peer.ip().to_canonical();
+        let value = match name {
+            "ip" => ip.to_string(),
+            "ip_dashed" => ip.to_string().replace(['.', ':'], "-"),
+            "port" => peer.port().to_string(),
+            _ => continue,
+        };
+        expanded.push_str(&template[copied..start]);
+        expanded.push_str(&value);
+        copied = end;
+    }
+    expanded.push_str(&template[copied..]);
+    return expanded;
+}
+
+/// Start, name and end of each `${name}` of template
+fn references(template: &str) -> impl Iterator<Item = (usize, &str, usize)> {
+    let mut searched = 0;
+    return std::iter::from_fn(move || {
+        let start = searched + template[searched..].find("${")?;
+        let length = template[start..].find('}')?;
+        searched = start + length + 1;
+        return Some((start, &template[start + 2..start + length], searched));
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use crate::variables::*;
+
+    #[test]
+    fn expand_ipv4_and_ipv6() {
+        let template = "configs/${ip}/${ip_dashed}-${port}.cfg";
+        assert_eq!(expand(template, "10.0.0.42:2001".parse().unwrap()), "configs/10.0.0.42/10-0-0-42-2001.cfg");
+        assert_eq!(expand(template, "[fd00::42]:2001".parse().unwrap()), "configs/fd00::42/fd00--42-2001.cfg");
+        // IPv4 client of a dual stack socket
+        assert_eq!(expand(template, "[::ffff:10.0.0.42]:2001".parse().unwrap()), "configs/10.0.0.42/10-0-0-42-2001.cfg");
+        // Regex groups and unterminated references are kept
+        assert_eq!(expand("${mac}/${ip}${", "10.0.0.42:69".parse().unwrap()), "${mac}/10.0.0.42${");
+    }
+
+    #[test]
+    fn unknown_variables() {
+        assert!(check("configs/${ip}.cfg", |_| false).is_ok());
+        assert!(check("configs/${host}.cfg", |_| false).unwrap_err().contains("${host}"));
+        assert!(check("configs/${mac}.cfg", |name| name == "mac").is_ok());
+    }
+}
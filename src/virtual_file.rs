@@ -0,0 +1,62 @@
+//! Files generated on request instead of read from disk (e.g. per client boot configuration)
+//!
+//! A handler is registered for an exact path, or for all paths under a prefix with a trailing `*`
+//! (`pxelinux.cfg/*`). Paths are matched without their leading `/`, only reads are served this way.
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+
+type Handler = Box<dyn Fn(SocketAddr, String) -> Pin<Box<dyn Future<Output = Vec<u8>> + Send>> + Send + Sync>;
+
+#[derive(Default)]
+pub struct VirtualFiles {
+    handlers: Vec<(String, Handler)>,
+}
+
+impl VirtualFiles {
+    pub fn new() -> VirtualFiles {
+        return VirtualFiles::default();
+    }
+
+    /// The handler gets the client address and the requested path, the first registered match wins
+    pub fn register<F, Fut>(&mut self, pattern: &str, handler: F)
+    where
+        F: Fn(SocketAddr, String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Vec<u8>> + Send + 'static,
+    {
+        let handler: Handler = Box::new(move |peer, path| Box::pin(handler(peer, path)));
+        self.handlers.push((pattern.trim_start_matches('/').to_string(), handler));
+    }
+
+    /// Content generated for filename, None when no handler matches
+    pub async fn generate(&self, peer: SocketAddr, filename: &str) -> Option<Vec<u8>> {
+        let path = filename.trim_start_matches('/');
+        let (_, handler) = self.handlers.iter().find(|(pattern, _)| matches(pattern, path))?;
+        return Some(handler(peer, path.to_string()).await);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.handlers.is_empty();
+    }
+}
+
+fn matches(pattern: &str, path: &str) -> bool {
+    return match pattern.strip_suffix('*') {
+        Some(prefix) => path.starts_with(prefix),
+        None => pattern == path,
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use crate::virtual_file::*;
+
+    #[test]
+    fn pattern_matching() {
+        assert!(matches("pxelinux.cfg/default", "pxelinux.cfg/default"));
+        assert!(!matches("pxelinux.cfg/default", "pxelinux.cfg/default2"));
+        assert!(matches("pxelinux.cfg/*", "pxelinux.cfg/01-aa-bb-cc-dd-ee-ff"));
+        assert!(!matches("pxelinux.cfg/*", "pxelinux.0"));
+    }
+}
@@ -0,0 +1,61 @@
+//! Files being written by an upload, a second upload of the same file is refused while the first one runs
+//!
+//! Shared by the servers of a process (`Server::with_write_locks`), the paths are the sanitized ones
+//! so that `a/b` and `/a/./b` are the same file.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, Default)]
+pub struct WriteLocks {
+    files: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+impl WriteLocks {
+    pub fn new() -> WriteLocks {
+        return WriteLocks::default();
+    }
+
+    /// Lock held until the upload ends, None when the file is already being written
+    pub fn lock(&self, path: &Path) -> Option<WriteLock> {
+        let mut files = self.files.lock().unwrap_or_else(|e| e.into_inner());
+        if !files.insert(path.to_path_buf()) {
+            return None;
+        }
+        return Some(WriteLock { locks: self.clone(), path: path.to_path_buf() });
+    }
+
+    pub fn is_locked(&self, path: &Path) -> bool {
+        return self.files.lock().unwrap_or_else(|e| e.into_inner()).contains(path);
+    }
+}
+
+/// Released when dropped, whether the upload completed or not
+#[derive(Debug)]
+pub struct WriteLock {
+    locks: WriteLocks,
+    path: PathBuf,
+}
+
+impl Drop for WriteLock {
+    fn drop(&mut self) {
+        self.locks.files.lock().unwrap_or_else(|e| e.into_inner()).remove(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::write_lock::*;
+
+    #[test]
+    fn lock_until_dropped() {
+        let locks = WriteLocks::new();
+        let lock = locks.lock(Path::new("configs/switch.cfg")).unwrap();
+        assert!(locks.clone().lock(Path::new("configs/switch.cfg")).is_none());
+        assert!(locks.lock(Path::new("configs/router.cfg")).is_some());
+        drop(lock);
+        assert!(!locks.is_locked(Path::new("configs/switch.cfg")));
+        assert!(locks.lock(Path::new("configs/switch.cfg")).is_some());
+    }
+}
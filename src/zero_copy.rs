@@ -0,0 +1,124 @@
+//! Zero copy DATA packets (--zero-copy): the payload goes from the file to the socket in the kernel
+//!
+//! On Linux the 4 bytes header is corked with MSG_MORE and the block appended to the same datagram
+//! by sendfile, the file content is never copied to user space. Like the read ahead, the file is
+//! only kept open for a chunk of blocks and reopened past it. Elsewhere the block is read in memory
+//! and sent as usual.
+
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom};
+use std::sync::Arc;
+
+use crate::read_ahead::CHUNK_SIZE;
+
+/// Opened file the blocks of a chunk are sent from
+#[derive(Debug, Default)]
+pub struct FileWindow {
+    file: Option<Arc<File>>,
+    /// File offset of the first block of the chunk
+    start: u64,
+    /// Of the file when opened
+    size: u64,
+}
+
+impl FileWindow {
+    pub fn new() -> FileWindow {
+        return FileWindow::default();
+    }
+
+    /// The block at offset is sent from the opened file without reopening it
+    pub fn holds(&self, offset: u64) -> bool {
+        return self.file.is_some() && offset >= self.start && offset < self.start + CHUNK_SIZE as u64;
+    }
+
+    /// Replace the opened file, its blocks from offset are sent next
+    pub fn open(&mut self, file: File, size: u64, offset: u64) {
+        self.file = Some(Arc::new(file));
+        self.start = offset;
+        self.size = size;
+    }
+
+    /// The file and length of the block of len bytes at offset, shorter at the end of the file,
+    /// None when it starts past the end. Only valid once holds is true.
+    pub fn block(&self, offset: u64, len: usize) -> Option<(Arc<File>, usize)> {
+        let file = self.file.clone()?;
+        if offset > self.size {
+            return None;
+        }
+        return Some((file, len.min((self.size - offset) as usize)));
+    }
+}
+
+/// Read the block in memory, for the platforms without sendfile and the packets that must be copied
+pub fn read_block(file: &File, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+    let mut file = file;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut block = Vec::with_capacity(len);
+    file.take(len as u64).read_to_end(&mut block)?;
+    return Ok(block);
+}
+
+/// Send header and the block of len bytes at offset as one datagram, on the connected socket.
+/// A file shortened since opened gives a shorter packet. Returns the size of the packet.
+#[cfg(target_os = "linux")]
+pub async fn send_block(socket: &tokio::net::UdpSocket, header: &[u8], file: &File, offset: u64, len: usize) -> io::Result<usize> {
+    use std::os::fd::AsRawFd;
+    use tokio::io::Interest;
+
+    let (fd, file_fd) = (socket.as_raw_fd(), file.as_raw_fd());
+    let end = offset + len as u64;
+    let mut position = offset as libc::off_t;
+    let mut header_sent = false;
+    // Called again when the socket becomes writable, the corked part is not sent twice
+    return socket.async_io(Interest::WRITABLE, || {
+        if !header_sent {
+            let flags = if len == 0 { libc::MSG_DONTWAIT } else { libc::MSG_DONTWAIT | libc::MSG_MORE };
+            // SAFETY: header is valid for header.len() bytes
+            if unsafe { libc::send(fd, header.as_ptr().cast(), header.len(), flags) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            header_sent = true;
+        }
+        while (position as u64) < end {
+            // SAFETY: both descriptors are open for the duration of the call, position is a valid off_t
+            let sent = unsafe { libc::sendfile(fd, file_fd, &mut position, (end - position as u64) as usize) };
+            if sent < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+            if sent == 0 {
+                // End of the file reached early, the corked packet is sent as it is
+                // SAFETY: an empty send, no buffer is read
+                if unsafe { libc::send(fd, std::ptr::null(), 0, libc::MSG_DONTWAIT) } < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                break;
+            }
+        }
+        return Ok(header.len() + (position as u64 - offset) as usize);
+    }).await;
+}
+
+#[cfg(test)]
+mod test {
+    use crate::zero_copy::*;
+
+    #[test]
+    fn blocks_of_a_window() {
+        let path = format!("target/tftp-window-{}.bin", std::process::id());
+        std::fs::write(&path, vec![7; 1024]).unwrap();
+        let mut window = FileWindow::new();
+        assert!(!window.holds(0));
+        window.open(File::open(&path).unwrap(), 1024, 0);
+        assert!(window.holds(512) && !window.holds(CHUNK_SIZE as u64));
+        let (file, len) = window.block(512, 512).unwrap();
+        assert_eq!(read_block(&file, 512, len).unwrap(), vec![7; 512]);
+        // Ends with an empty block
+        assert_eq!(window.block(1024, 512).unwrap().1, 0);
+        assert!(window.block(1025, 512).is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
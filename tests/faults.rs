@@ -0,0 +1,101 @@
+//! Transfers with packets dropped or duplicated by the fault injector (chaos feature), with the scripted client
+
+#![allow(clippy::needless_return)]
+
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+
+use tokio::sync::mpsc::{self, Receiver};
+use tokio::time::timeout;
+use tokio_tftpserver::fault::{FaultInjector, Faults, Packets};
+use tokio_tftpserver::server::{Server, TransferResult};
+use tokio_tftpserver::socket::{self, ListenOptions};
+use tokio_tftpserver::testing::TestClient;
+
+/// 1300 bytes: blocks of 512, 512 and 276
+fn known_content() -> Vec<u8> {
+    return (0..1300u32).map(|i| (i % 251) as u8).collect();
+}
+
+/// The server serves the current directory, shared by all the tests of this binary
+fn serving_dir() -> &'static PathBuf {
+    static DIR: OnceLock<PathBuf> = OnceLock::new();
+    return DIR.get_or_init(|| {
+        let dir = std::env::temp_dir().join(format!("tftp-faults-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("known.bin"), known_content()).unwrap();
+        std::env::set_current_dir(&dir).unwrap();
+        return dir;
+    });
+}
+
+fn start_server(injector: FaultInjector) -> (SocketAddr, Receiver<TransferResult>) {
+    serving_dir();
+    let socket = socket::bind_udp("127.0.0.1:0".parse().unwrap(), &ListenOptions::default()).unwrap();
+    let server_addr = socket.local_addr().unwrap();
+    let (results, receiver) = mpsc::channel(4);
+    let server = Server::new(socket, Arc::new(AtomicBool::new(false))).with_fault_injector(injector).with_results(results);
+    tokio::spawn(server.run());
+    return (server_addr, receiver);
+}
+
+#[tokio::test]
+async fn first_data_dropped() {
+    let injector = FaultInjector { send: Faults { drop: Packets::numbers(&[1]), ..Faults::default() }, ..FaultInjector::default() };
+    let (server_addr, mut results) = start_server(injector);
+    let mut client = TestClient::connect(server_addr).await.unwrap();
+    let requested = Instant::now();
+    client.send_rrq("known.bin", &[]).await.unwrap();
+
+    let mut content = Vec::new();
+    for block in 1u16..=3 {
+        content.extend_from_slice(&client.expect_data(block).await.unwrap());
+        // Only sent again after the retransmission timeout
+        if block == 1 {
+            assert!(requested.elapsed() >= Duration::from_millis(900), "{:?}", requested.elapsed());
+        }
+        client.send_ack(block).await.unwrap();
+    }
+    assert_eq!(content, known_content());
+    let result = timeout(Duration::from_secs(5), results.recv()).await.unwrap().unwrap();
+    assert_eq!((result.error, result.retransmits, result.bytes), (None, 1, 1300));
+}
+
+#[tokio::test]
+async fn final_ack_duplicated() {
+    // ACK 0, 1, 2, then the final ACK 3
+    let injector = FaultInjector { send: Faults { duplicate: Packets::numbers(&[4]), ..Faults::default() }, ..FaultInjector::default() };
+    let (server_addr, mut results) = start_server(injector);
+    let mut client = TestClient::connect(server_addr).await.unwrap();
+    client.send_wrq("duplicated-ack.bin", &[]).await.unwrap();
+
+    client.expect_ack(0).await.unwrap();
+    let content = known_content();
+    for (index, chunk) in content.chunks(512).enumerate() {
+        client.send_data(index as u16 + 1, chunk).await.unwrap();
+        client.expect_ack(index as u16 + 1).await.unwrap();
+    }
+    client.expect_ack(3).await.unwrap();
+    // The transfer ended with the first one, the upload is complete
+    let result = timeout(Duration::from_secs(5), results.recv()).await.unwrap().unwrap();
+    assert_eq!((result.error, result.retransmits, result.bytes), (None, 0, 1300));
+    assert_eq!(std::fs::read(serving_dir().join("duplicated-ack.bin")).unwrap(), content);
+
+    // Received twice by the server at the end of a read, nothing is sent for the second one
+    let injector = FaultInjector { recv: Faults { duplicate: Packets::numbers(&[3]), ..Faults::default() }, ..FaultInjector::default() };
+    let (server_addr, mut results) = start_server(injector);
+    let mut client = TestClient::connect(server_addr).await.unwrap().with_timeout(Duration::from_millis(200));
+    client.send_rrq("known.bin", &[]).await.unwrap();
+    for block in 1u16..=3 {
+        client.expect_data(block).await.unwrap();
+        client.send_ack(block).await.unwrap();
+    }
+    let result = timeout(Duration::from_secs(5), results.recv()).await.unwrap().unwrap();
+    assert_eq!((result.error, result.retransmits), (None, 0));
+    assert_eq!(client.recv().await.unwrap_err().kind(), ErrorKind::TimedOut);
+}
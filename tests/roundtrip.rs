@@ -0,0 +1,132 @@
+//! Transfers through a real server over loopback UDP, with the scripted client of the testing module
+
+#![allow(clippy::needless_return)]
+
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+
+use tokio_tftpserver::server::Server;
+use tokio_tftpserver::socket::{self, ListenOptions};
+use tokio_tftpserver::testing::TestClient;
+
+/// 1300 bytes: blocks of 512, 512 and 276
+fn known_content() -> Vec<u8> {
+    return (0..1300u32).map(|i| (i % 251) as u8).collect();
+}
+
+/// The server serves the current directory, shared by all the tests of this binary
+fn serving_dir() -> &'static PathBuf {
+    static DIR: OnceLock<PathBuf> = OnceLock::new();
+    return DIR.get_or_init(|| {
+        let dir = std::env::temp_dir().join(format!("tftp-roundtrip-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("known.bin"), known_content()).unwrap();
+        std::env::set_current_dir(&dir).unwrap();
+        return dir;
+    });
+}
+
+fn start_server() -> SocketAddr {
+    serving_dir();
+    let socket = socket::bind_udp("127.0.0.1:0".parse().unwrap(), &ListenOptions::default()).unwrap();
+    let server_addr = socket.local_addr().unwrap();
+    tokio::spawn(Server::new(socket, Arc::new(AtomicBool::new(false))).run());
+    return server_addr;
+}
+
+#[tokio::test]
+async fn rrq_round_trip() {
+    let server_addr = start_server();
+    let mut client = TestClient::connect(server_addr).await.unwrap();
+    client.send_rrq("known.bin", &[]).await.unwrap();
+
+    let mut content = Vec::new();
+    for block in 1u16..=3 {
+        content.extend_from_slice(&client.expect_data(block).await.unwrap());
+        // Answered from a port of its own
+        assert_ne!(client.transfer_addr(), Some(server_addr));
+        client.send_ack(block).await.unwrap();
+    }
+    assert_eq!(content, known_content());
+}
+
+#[tokio::test]
+async fn wrq_round_trip() {
+    let server_addr = start_server();
+    let mut client = TestClient::connect(server_addr).await.unwrap();
+    client.put("uploaded.bin", &[], &known_content()).await.unwrap();
+    assert_eq!(std::fs::read(serving_dir().join("uploaded.bin")).unwrap(), known_content());
+}
+
+#[tokio::test]
+async fn negotiated_round_trip() {
+    let server_addr = start_server();
+    let mut client = TestClient::connect(server_addr).await.unwrap();
+    client.send_rrq("known.bin", &[("blksize", "1024"), ("tsize", "0")]).await.unwrap();
+    let options = client.expect_oack().await.unwrap();
+    assert_eq!(options, [("blksize".to_string(), "1024".to_string()), ("tsize".to_string(), "1300".to_string())]);
+    client.send_ack(0).await.unwrap();
+    assert_eq!(client.expect_data(1).await.unwrap().len(), 1024);
+
+    // Whole transfers, a multiple of the block size ends with an empty block
+    client.put("negotiated.bin", &[("blksize", "650")], &known_content()).await.unwrap();
+    assert_eq!(client.get("negotiated.bin", &[("blksize", "650")]).await.unwrap(), known_content());
+    client.send_rrq("missing.bin", &[]).await.unwrap();
+    assert_eq!(client.expect_error().await.unwrap(), (1, "File not found".to_string()));
+    assert!(client.get("missing.bin", &[]).await.is_err());
+}
+
+/// The server binary, stopped when dropped
+struct ServerProcess(Child);
+
+impl Drop for ServerProcess {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+#[tokio::test]
+async fn search_path_round_trip() {
+    // Site overrides first, then the base images
+    let site = serving_dir().join("site");
+    let base = serving_dir().join("base");
+    for dir in [&site, &base] {
+        std::fs::create_dir_all(dir).unwrap();
+    }
+    std::fs::write(base.join("image.bin"), known_content()).unwrap();
+    // A free port, released for the server
+    let port = std::net::UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let server = Command::new(env!("CARGO_BIN_EXE_tokio_tftpserver"))
+        .args(["--bind", "127.0.0.1", "--port", &port.to_string()])
+        .arg("--search-dir").arg(&site)
+        .arg("--search-dir").arg(&base)
+        .stdout(Stdio::null()).stderr(Stdio::null())
+        .spawn().unwrap();
+    let _server = ServerProcess(server);
+    let server_addr = SocketAddr::from(([127, 0, 0, 1], port));
+
+    let mut client = TestClient::connect(server_addr).await.unwrap().with_timeout(Duration::from_millis(200));
+    // Until the server is listening
+    let started = Instant::now();
+    let content = loop {
+        match client.get("image.bin", &[]).await {
+            Ok(content) => break content,
+            Err(e) if started.elapsed() < Duration::from_secs(10) => assert_eq!(e.kind(), ErrorKind::TimedOut),
+            Err(e) => panic!("server not started: {}", e),
+        }
+    };
+    assert_eq!(content, known_content());
+    client.send_rrq("missing.bin", &[]).await.unwrap();
+    assert_eq!(client.expect_error().await.unwrap().0, 1);
+    // Uploads go to the first directory
+    client.put("uploaded-site.bin", &[], b"site").await.unwrap();
+    assert_eq!(std::fs::read(site.join("uploaded-site.bin")).unwrap(), b"site");
+    assert!(!base.join("uploaded-site.bin").exists());
+}